@@ -0,0 +1,220 @@
+// Follows the stats stream, fetches the dependency list alongside each snapshot,
+// and drives the chaos controls.
+
+const RATE_HISTORY = 60;
+const LATENCY_WINDOW = 10;
+
+const rateHistory = [];
+const snapshots = [];
+let renderedDependencies = "";
+
+const $ = (selector) => document.querySelector(selector);
+
+function el(tag, props = {}, children = []) {
+  const node = Object.assign(document.createElement(tag), props);
+  node.append(...children);
+  return node;
+}
+
+async function api(path, body) {
+  const options = body === undefined ? {} : {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify(body),
+  };
+  const response = await fetch(path, options);
+  const json = await response.json();
+  if (!response.ok) {
+    throw new Error(json.detail || response.statusText);
+  }
+  return json;
+}
+
+function totalRequests(stats) {
+  return Object.values(stats.tenants).reduce((sum, tenant) => sum + tenant.requests, 0);
+}
+
+function formatBytes(bytes) {
+  if (bytes === null || bytes === undefined) return "-";
+  const units = ["B", "KB", "MB", "GB"];
+  let unit = 0;
+  while (bytes >= 1024 && unit < units.length - 1) {
+    bytes /= 1024;
+    unit++;
+  }
+  return `${bytes.toFixed(unit ? 1 : 0)} ${units[unit]}`;
+}
+
+function bars(container, values, labels = []) {
+  const max = Math.max(1, ...values);
+  container.replaceChildren(...values.map((value, i) => {
+    const bar = el("div", { title: `${labels[i] ?? ""} ${value}`.trim() });
+    bar.style.height = `${(value / max) * 100}%`;
+    if (labels[i]) bar.append(el("span", { textContent: labels[i] }));
+    return bar;
+  }));
+}
+
+function definitions(container, entries) {
+  container.replaceChildren(...entries.flatMap(([term, value]) => [
+    el("dt", { textContent: term }),
+    el("dd", { textContent: value }),
+  ]));
+}
+
+function renderRates(stats, previous, elapsed) {
+  const rate = previous ? (totalRequests(stats) - totalRequests(previous)) / elapsed : 0;
+  rateHistory.push(rate);
+  if (rateHistory.length > RATE_HISTORY) rateHistory.shift();
+  $("#rate").textContent = rate.toFixed(1);
+  bars($("#rate-chart"), rateHistory);
+
+  $("#tenants tbody").replaceChildren(...Object.entries(stats.tenants).map(([name, tenant]) => {
+    const before = previous?.tenants[name]?.requests ?? tenant.requests;
+    return el("tr", {}, [
+      el("td", { textContent: name }),
+      el("td", { textContent: tenant.requests }),
+      el("td", { textContent: ((tenant.requests - before) / elapsed).toFixed(1) }),
+    ]);
+  }));
+}
+
+function renderLatency(stats) {
+  // Compare with the oldest snapshot in the window to show recent latency only
+  const oldest = snapshots[0]?.latency ?? { count: 0, sum_ms: 0, buckets: [] };
+  const counts = stats.latency.buckets.map((bucket, i) => bucket.count - (oldest.buckets[i]?.count ?? 0));
+  const labels = stats.latency.buckets.map((bucket) => bucket.le_ms === null ? "more" : `${bucket.le_ms}`);
+  bars($("#latency"), counts, labels);
+
+  const count = stats.latency.count - oldest.count;
+  $("#latency-count").textContent = count;
+  $("#latency-mean").textContent = count ? ((stats.latency.sum_ms - oldest.sum_ms) / count).toFixed(1) : "-";
+}
+
+function renderPool(stats) {
+  const pool = stats.chunk_pool;
+  definitions($("#pool"), [
+    ["Chunks", pool.total_chunks],
+    ["Memory", formatBytes(pool.memory_usage_bytes)],
+    ["Hit rate", `${(pool.cache_hit_rate * 100).toFixed(1)}%`],
+    ["Background generations", pool.background_generations],
+  ]);
+  const memory = stats.memory;
+  definitions($("#memory"), [
+    ["RSS", formatBytes(memory.rss_bytes)],
+    ["Ceiling", memory.ceiling_mb === null ? "none" : `${memory.ceiling_mb} MB`],
+    ["In flight", `${memory.in_flight_responses} responses, ${formatBytes(memory.in_flight_bytes)}`],
+    ["Rejected", memory.rejected_requests],
+  ]);
+}
+
+function renderFlags(flags) {
+  $("#flags").replaceChildren(...Object.entries(flags).map(([name, flag]) => {
+    const toggle = el("button", {
+      textContent: flag.enabled ? "On" : "Off",
+      className: flag.enabled ? "on" : "",
+      onclick: () => api("/admin/flags", { flag: name, enabled: !flag.enabled }).then(poll, alert),
+    });
+    return el("div", { className: "flag" }, [
+      el("div", {}, [name, el("div", { className: "count", textContent: `triggered ${flag.triggered}` })]),
+      toggle,
+    ]);
+  }));
+}
+
+function describeFault(fault) {
+  switch (fault.mode) {
+    case "error": return `error ${fault.status}`;
+    case "slow": return `slow ${fault.delay_ms}ms`;
+    default: return fault.mode;
+  }
+}
+
+function faultControls(dependency) {
+  const mode = el("select", {}, ["none", "timeout", "error", "slow"].map((m) => el("option", { value: m, textContent: m })));
+  const value = el("input", { type: "number", min: 0 });
+  const syncInput = () => {
+    value.hidden = mode.value !== "error" && mode.value !== "slow";
+    value.placeholder = mode.value === "error" ? "status" : "delay ms";
+  };
+  mode.onchange = syncInput;
+  syncInput();
+
+  const apply = el("button", {
+    textContent: "Apply",
+    onclick: () => {
+      const fault = { mode: mode.value };
+      if (mode.value === "error" && value.value) fault.status = Number(value.value);
+      if (mode.value === "slow") fault.delay_ms = Number(value.value || 0);
+      api("/admin/dependencies", { name: dependency.name, fault }).then(poll, alert);
+    },
+  });
+  return el("td", {}, [mode, " ", value, " ", apply]);
+}
+
+function renderDependencies(dependencies) {
+  $("#no-dependencies").hidden = dependencies.length > 0;
+  const names = dependencies.map((d) => d.name).join(",");
+  const body = $("#dependencies tbody");
+
+  // Rebuild rows only when the dependencies change, so half-edited controls survive polling
+  if (names !== renderedDependencies) {
+    renderedDependencies = names;
+    body.replaceChildren(...dependencies.map((dependency) => {
+      const row = el("tr", {}, [
+        el("td", { textContent: dependency.name, title: dependency.url }),
+        el("td", { className: "calls" }),
+        el("td", { className: "failures" }),
+        el("td", { className: "fault" }),
+        faultControls(dependency),
+      ]);
+      row.dataset.name = dependency.name;
+      return row;
+    }));
+  }
+  for (const dependency of dependencies) {
+    const row = body.querySelector(`tr[data-name="${CSS.escape(dependency.name)}"]`);
+    row.querySelector(".calls").textContent = dependency.calls;
+    row.querySelector(".failures").textContent = dependency.failures;
+    row.querySelector(".fault").textContent = describeFault(dependency.fault);
+  }
+}
+
+async function render(stats) {
+  try {
+    const dependencies = await api("/admin/dependencies");
+    const previous = snapshots[snapshots.length - 1];
+    const elapsed = previous ? (Date.parse(stats.timestamp) - Date.parse(previous.timestamp)) / 1000 : 1;
+
+    renderRates(stats, previous, elapsed || 1);
+    renderLatency(stats);
+    renderPool(stats);
+    renderFlags(stats.flags);
+    renderDependencies(dependencies);
+
+    snapshots.push(stats);
+    if (snapshots.length > LATENCY_WINDOW) snapshots.shift();
+
+    $("#instance").textContent = stats.cluster ? `${stats.cluster.instance_id} (${stats.cluster.role})` : "";
+    $("#status").textContent = "live";
+    $("#status").className = "badge ok";
+  } catch (e) {
+    $("#status").textContent = "unreachable";
+    $("#status").className = "badge error";
+  }
+}
+
+// Show the effect of a control straight away rather than on the next snapshot
+async function poll() {
+  const [dependencies, flags] = await Promise.all([api("/admin/dependencies"), api("/admin/flags")]);
+  renderDependencies(dependencies);
+  renderFlags(flags);
+}
+
+const stream = new EventSource("/stats/stream");
+stream.addEventListener("stats", (event) => render(JSON.parse(event.data)));
+stream.onerror = () => {
+  // The browser reconnects on its own
+  $("#status").textContent = "reconnecting";
+  $("#status").className = "badge error";
+};
@@ -0,0 +1,33 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Dashboard for driving daddle from a browser, built into the binary. It is
+//! plain HTML and JavaScript on top of `/stats` and the admin API.
+
+use axum::{
+    http::header,
+    response::{Html, IntoResponse},
+};
+
+const INDEX_HTML: &str = include_str!("../assets/admin/index.html");
+const DASHBOARD_JS: &str = include_str!("../assets/admin/dashboard.js");
+const DASHBOARD_CSS: &str = include_str!("../assets/admin/dashboard.css");
+
+pub async fn index_handler() -> Html<&'static str> {
+    Html(INDEX_HTML)
+}
+
+pub async fn script_handler() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/javascript; charset=utf-8")],
+        DASHBOARD_JS,
+    )
+}
+
+pub async fn stylesheet_handler() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/css; charset=utf-8")],
+        DASHBOARD_CSS,
+    )
+}
@@ -0,0 +1,263 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Admission queue in front of garble generation. With the `admission` config
+//! set, at most `max_concurrent` responses are generated at once and further
+//! requests wait in a bounded queue; when that is full too, the overflow
+//! policy decides between rejecting, shedding the oldest waiter or answering
+//! with a smaller body, so saturation behaves predictably.
+
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use crate::config::{AdmissionConfig, OverflowPolicy};
+use crate::error::ApiError;
+
+/// What a queued request is told when it leaves the queue
+enum Verdict {
+    Admitted,
+    Shed,
+}
+
+struct Waiter {
+    id: u64,
+    notify: oneshot::Sender<Verdict>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    running: usize,
+    waiting: VecDeque<Waiter>,
+    next_id: u64,
+}
+
+/// Queue depth and outcomes since startup
+#[derive(Debug, Clone, Serialize)]
+pub struct AdmissionStats {
+    pub max_concurrent: usize,
+    pub queue_depth: usize,
+    pub running: usize,
+    pub queued: usize,
+    pub admitted: u64,
+    pub queued_total: u64,
+    pub rejected: u64,
+    pub shed: u64,
+    pub degraded: u64,
+    pub timed_out: u64,
+}
+
+struct Admission {
+    max_concurrent: usize,
+    queue_depth: usize,
+    overflow: OverflowPolicy,
+    degraded_body_size: usize,
+    queue_timeout: Duration,
+    state: Mutex<QueueState>,
+    admitted: AtomicU64,
+    queued_total: AtomicU64,
+    rejected: AtomicU64,
+    shed: AtomicU64,
+    degraded: AtomicU64,
+    timed_out: AtomicU64,
+}
+
+/// A generation slot, handed to the next waiter when dropped
+pub struct AdmissionPermit {
+    admission: Option<&'static Admission>,
+}
+
+impl Drop for AdmissionPermit {
+    fn drop(&mut self) {
+        if let Some(admission) = self.admission {
+            admission.release();
+        }
+    }
+}
+
+/// A place in the queue; leaving it early, such as when the client hangs up,
+/// gives up the place, or the slot if one was already handed over
+struct QueuedRequest {
+    admission: &'static Admission,
+    id: u64,
+    verdict: Option<oneshot::Receiver<Verdict>>,
+}
+
+impl Drop for QueuedRequest {
+    fn drop(&mut self) {
+        let Some(mut verdict) = self.verdict.take() else {
+            return;
+        };
+        let mut state = self.admission.state.lock().unwrap();
+        if let Some(position) = state.waiting.iter().position(|waiter| waiter.id == self.id) {
+            state.waiting.remove(position);
+            return;
+        }
+        drop(state);
+        if let Ok(Verdict::Admitted) = verdict.try_recv() {
+            self.admission.release();
+        }
+    }
+}
+
+impl Admission {
+    /// Wait for a generation slot, returning it with the body size to generate
+    async fn admit(
+        &'static self,
+        target_size: usize,
+        degradable: bool,
+    ) -> Result<(AdmissionPermit, usize), ApiError> {
+        let (id, verdict) = {
+            let mut state = self.state.lock().unwrap();
+            if state.running < self.max_concurrent {
+                state.running += 1;
+                self.admitted.fetch_add(1, Ordering::Relaxed);
+                return Ok((self.permit(), target_size));
+            }
+
+            if state.waiting.len() >= self.queue_depth {
+                let overflow = match self.overflow {
+                    OverflowPolicy::Degrade if !degradable => OverflowPolicy::Reject,
+                    overflow => overflow,
+                };
+                match overflow {
+                    OverflowPolicy::Reject => {
+                        self.rejected.fetch_add(1, Ordering::Relaxed);
+                        return Err(ApiError::queue_full(self.queue_depth));
+                    }
+                    OverflowPolicy::Degrade => {
+                        // Small bodies are cheap enough to generate beside the running ones
+                        self.degraded.fetch_add(1, Ordering::Relaxed);
+                        let size = target_size.min(self.degraded_body_size);
+                        return Ok((AdmissionPermit { admission: None }, size));
+                    }
+                    OverflowPolicy::ShedOldest => {
+                        if let Some(oldest) = state.waiting.pop_front() {
+                            self.shed.fetch_add(1, Ordering::Relaxed);
+                            let _ = oldest.notify.send(Verdict::Shed);
+                        }
+                    }
+                }
+            }
+
+            let (notify, verdict) = oneshot::channel();
+            state.next_id += 1;
+            let id = state.next_id;
+            state.waiting.push_back(Waiter { id, notify });
+            self.queued_total.fetch_add(1, Ordering::Relaxed);
+            (id, verdict)
+        };
+
+        let mut queued = QueuedRequest {
+            admission: self,
+            id,
+            verdict: Some(verdict),
+        };
+        let receiver = queued.verdict.as_mut().expect("set above");
+        match tokio::time::timeout(self.queue_timeout, receiver).await {
+            Ok(Ok(Verdict::Admitted)) => {
+                queued.verdict = None;
+                self.admitted.fetch_add(1, Ordering::Relaxed);
+                Ok((self.permit(), target_size))
+            }
+            Ok(Ok(Verdict::Shed)) | Ok(Err(_)) => {
+                queued.verdict = None;
+                Err(ApiError::queue_shed())
+            }
+            Err(_) => {
+                self.timed_out.fetch_add(1, Ordering::Relaxed);
+                Err(ApiError::queue_timeout(self.queue_timeout))
+            }
+        }
+    }
+
+    fn permit(&'static self) -> AdmissionPermit {
+        AdmissionPermit {
+            admission: Some(self),
+        }
+    }
+
+    /// Hand a finished slot to the oldest waiter still listening, or free it
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(waiter) = state.waiting.pop_front() {
+            if waiter.notify.send(Verdict::Admitted).is_ok() {
+                return;
+            }
+        }
+        state.running -= 1;
+    }
+
+    fn get_stats(&self) -> AdmissionStats {
+        let state = self.state.lock().unwrap();
+        AdmissionStats {
+            max_concurrent: self.max_concurrent,
+            queue_depth: self.queue_depth,
+            running: state.running,
+            queued: state.waiting.len(),
+            admitted: self.admitted.load(Ordering::Relaxed),
+            queued_total: self.queued_total.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            shed: self.shed.load(Ordering::Relaxed),
+            degraded: self.degraded.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Set up the queue, if a concurrency limit is configured; call once at startup
+pub fn configure(config: &AdmissionConfig) -> anyhow::Result<()> {
+    let Some(max_concurrent) = config.max_concurrent else {
+        return Ok(());
+    };
+    if max_concurrent == 0 {
+        anyhow::bail!("admission.max_concurrent must be at least 1");
+    }
+    tracing::info!(
+        "Admission queue: {} concurrent generations, {} queued, {:?} on overflow",
+        max_concurrent,
+        config.queue_depth,
+        config.overflow
+    );
+    let _ = ADMISSION.set(Admission {
+        max_concurrent,
+        queue_depth: config.queue_depth,
+        overflow: config.overflow,
+        degraded_body_size: config.degraded_body_size,
+        queue_timeout: Duration::from_millis(config.queue_timeout_ms),
+        state: Mutex::new(QueueState::default()),
+        admitted: AtomicU64::new(0),
+        queued_total: AtomicU64::new(0),
+        rejected: AtomicU64::new(0),
+        shed: AtomicU64::new(0),
+        degraded: AtomicU64::new(0),
+        timed_out: AtomicU64::new(0),
+    });
+    Ok(())
+}
+
+/// Wait for a generation slot; returns the body size to generate, which the
+/// degrade policy lowers on overflow unless the body is not `degradable`, in
+/// which case it is rejected instead. Admits everything when no queue is configured
+pub async fn admit(
+    target_size: usize,
+    degradable: bool,
+) -> Result<(AdmissionPermit, usize), ApiError> {
+    match ADMISSION.get() {
+        Some(admission) => admission.admit(target_size, degradable).await,
+        None => Ok((AdmissionPermit { admission: None }, target_size)),
+    }
+}
+
+/// Queue depth and outcomes, if a queue is configured
+pub fn get_stats() -> Option<AdmissionStats> {
+    ADMISSION.get().map(Admission::get_stats)
+}
+
+// Global admission queue, set once at startup when configured
+static ADMISSION: OnceCell<Admission> = OnceCell::new();
@@ -0,0 +1,404 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Avro output: object container files with a random schema, or single
+//! records in the Confluent wire format (a zero byte, the big-endian schema
+//! ID, then the Avro datum) whose schema is registered with a schema registry,
+//! so consumer serdes resolve it exactly as they would in production.
+
+use axum::{
+    body::Body,
+    extract::{rejection::QueryRejection, Query, RawQuery},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use once_cell::sync::OnceCell;
+use rand::prelude::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::time::sleep;
+use uuid::Uuid;
+
+use crate::config::AvroConfig;
+use crate::error::ApiError;
+use crate::generator::RandomDataGenerator;
+use crate::handlers::{relabel_content_type, resolve_targets, GarbleParams};
+use crate::metrics::METRICS;
+use crate::streaming::ResponseDecision;
+use crate::tenants::Tenant;
+use crate::validation::strict_query;
+
+/// Upper bound on the number of columns a single schema may have
+const MAX_COLUMNS: usize = 256;
+
+/// Serialized size a container file block is closed at
+const BLOCK_BYTES: usize = 64 * 1024;
+
+/// Percentage of values left null in nullable columns
+const NULL_PERCENT: u32 = 10;
+
+/// Leading byte of a Confluent wire-format message
+const WIRE_FORMAT_MAGIC: u8 = 0;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AvroFormat {
+    /// An object container file holding its own schema
+    #[default]
+    Container,
+    /// One record in the Confluent wire format, with a registered schema
+    Confluent,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AvroParams {
+    columns: Option<usize>,
+    format: Option<AvroFormat>,
+}
+
+/// Column types we know how to fill with random values
+#[derive(Debug, Clone, Copy)]
+enum ColumnKind {
+    Long,
+    Double,
+    Boolean,
+    String,
+    Uuid,
+    TimestampMillis,
+}
+
+impl ColumnKind {
+    fn random(rng: &mut impl Rng) -> Self {
+        match rng.gen_range(0..6) {
+            0 => ColumnKind::Long,
+            1 => ColumnKind::Double,
+            2 => ColumnKind::Boolean,
+            3 => ColumnKind::Uuid,
+            4 => ColumnKind::TimestampMillis,
+            _ => ColumnKind::String,
+        }
+    }
+
+    fn schema(&self) -> Value {
+        match self {
+            ColumnKind::Long => json!("long"),
+            ColumnKind::Double => json!("double"),
+            ColumnKind::Boolean => json!("boolean"),
+            ColumnKind::String => json!("string"),
+            ColumnKind::Uuid => json!({ "type": "string", "logicalType": "uuid" }),
+            ColumnKind::TimestampMillis => {
+                json!({ "type": "long", "logicalType": "timestamp-millis" })
+            }
+        }
+    }
+}
+
+struct ColumnSpec {
+    name: String,
+    kind: ColumnKind,
+    nullable: bool,
+}
+
+/// Generates Avro records with a random schema
+pub struct AvroGenerator {
+    columns: Vec<ColumnSpec>,
+}
+
+impl AvroGenerator {
+    pub fn new(column_count: usize) -> Self {
+        let mut rng = thread_rng();
+        let mut generator = RandomDataGenerator::new();
+        let mut names = HashSet::new();
+
+        let columns = (0..column_count)
+            .map(|_| {
+                // Avro names are limited to letters, digits and underscores, not starting with a digit
+                let mut name = format!(
+                    "f_{}",
+                    generator
+                        .generate_random_string(rng.gen_range(4..16))
+                        .replace('-', "_")
+                );
+                while !names.insert(name.clone()) {
+                    name.push_str(&generator.generate_random_string(2).replace('-', "_"));
+                }
+                ColumnSpec {
+                    name,
+                    kind: ColumnKind::random(&mut rng),
+                    nullable: rng.gen_bool(0.5),
+                }
+            })
+            .collect();
+
+        Self { columns }
+    }
+
+    /// The record schema as JSON text
+    pub fn schema(&self) -> String {
+        let fields: Vec<Value> = self
+            .columns
+            .iter()
+            .map(|column| {
+                if column.nullable {
+                    json!({ "name": column.name, "type": ["null", column.kind.schema()], "default": null })
+                } else {
+                    json!({ "name": column.name, "type": column.kind.schema() })
+                }
+            })
+            .collect();
+        json!({ "type": "record", "name": "Garble", "namespace": "daddle", "fields": fields })
+            .to_string()
+    }
+
+    /// Append one random record in Avro binary encoding
+    fn write_record(
+        &self,
+        out: &mut Vec<u8>,
+        rng: &mut ThreadRng,
+        generator: &mut RandomDataGenerator,
+    ) {
+        for column in &self.columns {
+            if column.nullable {
+                // Union branch 0 is null, branch 1 the column type
+                if !rng.gen_ratio(100 - NULL_PERCENT, 100) {
+                    write_long(out, 0);
+                    continue;
+                }
+                write_long(out, 1);
+            }
+            match column.kind {
+                ColumnKind::Long => write_long(out, rng.gen()),
+                ColumnKind::Double => {
+                    let value = rng.gen::<f64>() * rng.gen_range(-1e9..1e9);
+                    out.extend_from_slice(&value.to_le_bytes());
+                }
+                ColumnKind::Boolean => out.push(u8::from(rng.gen::<bool>())),
+                ColumnKind::String => {
+                    write_bytes(out, generator.generate_garbled_string().as_bytes())
+                }
+                ColumnKind::Uuid => write_bytes(out, Uuid::new_v4().to_string().as_bytes()),
+                ColumnKind::TimestampMillis => {
+                    // Within about ten years either side of now
+                    let offset = rng.gen_range(-315_360_000_000..315_360_000_000);
+                    write_long(out, chrono::Utc::now().timestamp_millis() + offset);
+                }
+            }
+        }
+    }
+
+    /// Write an object container file of approximately `target_size` bytes
+    pub fn container(&self, target_size: usize) -> Vec<u8> {
+        let mut rng = thread_rng();
+        let mut generator = RandomDataGenerator::new();
+        let mut out = Vec::with_capacity(target_size + BLOCK_BYTES);
+
+        out.extend_from_slice(b"Obj\x01");
+        write_long(&mut out, 2);
+        write_bytes(&mut out, b"avro.schema");
+        write_bytes(&mut out, self.schema().as_bytes());
+        write_bytes(&mut out, b"avro.codec");
+        write_bytes(&mut out, b"null");
+        write_long(&mut out, 0);
+        let sync: [u8; 16] = rng.gen();
+        out.extend_from_slice(&sync);
+
+        // Always write at least one record, then stop once the target is reached
+        let mut block = Vec::with_capacity(BLOCK_BYTES);
+        loop {
+            let mut count = 0;
+            block.clear();
+            while block.len() < BLOCK_BYTES && (count == 0 || out.len() + block.len() < target_size)
+            {
+                self.write_record(&mut block, &mut rng, &mut generator);
+                count += 1;
+            }
+            write_long(&mut out, count);
+            write_long(&mut out, block.len() as i64);
+            out.extend_from_slice(&block);
+            out.extend_from_slice(&sync);
+            if out.len() >= target_size {
+                return out;
+            }
+        }
+    }
+
+    /// One record in the Confluent wire format
+    pub fn message(&self, schema_id: u32) -> Vec<u8> {
+        let mut out = vec![WIRE_FORMAT_MAGIC];
+        out.extend_from_slice(&schema_id.to_be_bytes());
+        self.write_record(&mut out, &mut thread_rng(), &mut RandomDataGenerator::new());
+        out
+    }
+}
+
+/// Zig-zag variable-length encoding of `int` and `long`
+fn write_long(out: &mut Vec<u8>, value: i64) {
+    let mut n = ((value << 1) ^ (value >> 63)) as u64;
+    while n >= 0x80 {
+        out.push((n as u8 & 0x7f) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+/// Length-prefixed `bytes` and `string`
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_long(out, bytes.len() as i64);
+    out.extend_from_slice(bytes);
+}
+
+/// The schema used for wire-format output, with the ID the registry gave it
+pub struct RegisteredSchema {
+    generator: AvroGenerator,
+    id: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct RegistryResponse {
+    id: u32,
+}
+
+/// Register a fresh random schema; once it succeeds the same schema is used
+/// for the rest of the instance's life, so consumers see one stable schema
+async fn registered_schema() -> Result<&'static RegisteredSchema, ApiError> {
+    let config = AVRO.get().cloned().unwrap_or_default();
+    let Some(registry_url) = config.registry_url else {
+        return Err(ApiError::bad_request(
+            "format=confluent needs avro.registry_url in the config",
+        ));
+    };
+
+    REGISTERED
+        .get_or_try_init(|| async {
+            let generator = AvroGenerator::new(thread_rng().gen_range(3..12));
+            let url = format!(
+                "{}/subjects/{}/versions",
+                registry_url.trim_end_matches('/'),
+                config.subject
+            );
+            let registered = reqwest::Client::new()
+                .post(&url)
+                .timeout(Duration::from_millis(config.timeout_ms))
+                .header(
+                    header::CONTENT_TYPE,
+                    "application/vnd.schemaregistry.v1+json",
+                )
+                .body(json!({ "schema": generator.schema() }).to_string())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            let registered = match registered {
+                Ok(response) => response.json::<RegistryResponse>().await,
+                Err(e) => Err(e),
+            };
+
+            match registered {
+                Ok(RegistryResponse { id }) => {
+                    tracing::info!(
+                        "Registered Avro schema under {} with id {}",
+                        config.subject,
+                        id
+                    );
+                    Ok(RegisteredSchema { generator, id })
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to register Avro schema at {}: {}", url, e);
+                    Err(ApiError::new(
+                        StatusCode::BAD_GATEWAY,
+                        "registry-unavailable",
+                        "Schema registry unavailable",
+                        format!("failed to register the schema: {}", e),
+                    ))
+                }
+            }
+        })
+        .await
+}
+
+/// Set the schema registry settings; call once at startup
+pub fn configure(config: &AvroConfig) {
+    let _ = AVRO.set(config.clone());
+}
+
+pub async fn avro_handler(
+    garble_params: Result<Query<GarbleParams>, QueryRejection>,
+    avro_params: Result<Query<AvroParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    tenant: Tenant,
+) -> Result<impl IntoResponse, ApiError> {
+    let strict = tenant.garble.strict_validation;
+    let garble_params = strict_query(garble_params, raw_query.as_deref(), strict)?;
+    let avro_params = strict_query(avro_params, raw_query.as_deref(), strict)?;
+
+    let (target_size, wait_duration_ms) = resolve_targets(&garble_params, &tenant.garble)?;
+    let content_type_label = garble_params.content_type_label()?;
+    let format = avro_params.format.unwrap_or_default();
+    let registered = match format {
+        AvroFormat::Confluent => Some(registered_schema().await?),
+        AvroFormat::Container => None,
+    };
+
+    if wait_duration_ms > 0 {
+        sleep(Duration::from_millis(wait_duration_ms)).await;
+    }
+
+    // Encoding is CPU bound, keep it off the async workers
+    let body = tokio::task::spawn_blocking(move || match registered {
+        Some(registered) => registered.generator.message(registered.id),
+        None => {
+            let column_count = avro_params
+                .columns
+                .unwrap_or_else(|| thread_rng().gen_range(3..12))
+                .clamp(1, MAX_COLUMNS);
+            AvroGenerator::new(column_count).container(target_size)
+        }
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("Avro generation task failed: {}", e);
+        ApiError::internal("avro generation task failed")
+    })?;
+
+    tracing::info!(
+        "Generated GARBLED avro {:?}: tenant={}, target_size={}B, actual_size={}B, wait={}ms",
+        format,
+        tenant.name,
+        target_size,
+        body.len(),
+        wait_duration_ms
+    );
+
+    let mut builder = Response::builder().status(StatusCode::OK);
+    builder = match registered {
+        Some(registered) => builder
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header("X-Daddle-Schema-Id", registered.id),
+        None => builder
+            .header(header::CONTENT_TYPE, "application/avro")
+            .header(
+                header::CONTENT_DISPOSITION,
+                r#"attachment; filename="garble.avro""#,
+            ),
+    };
+    let mut response = builder
+        .header("X-Garble-Mode", "avro")
+        .body(Body::from(body))
+        .unwrap();
+    relabel_content_type(&mut response, content_type_label);
+    METRICS.record_response(&tenant.segment(), "avro", target_size, wait_duration_ms);
+    ResponseDecision {
+        strategy: "avro",
+        target_size,
+        wait_ms: wait_duration_ms,
+    }
+    .apply_headers(response.headers_mut());
+
+    Ok(response)
+}
+
+// Global schema registry settings and the schema registered with it
+static AVRO: OnceCell<AvroConfig> = OnceCell::new();
+static REGISTERED: tokio::sync::OnceCell<RegisteredSchema> = tokio::sync::OnceCell::const_new();
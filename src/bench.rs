@@ -0,0 +1,284 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use axum::{http::StatusCode, response::Json};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+
+use crate::chunk_pool::ChunkSize;
+use crate::error::ApiError;
+use crate::generator::RandomDataGenerator;
+use crate::streaming::{
+    create_optimal_response, GarbleBody, GarbleResponse, ResponseOptions, StreamingGarbleResponse,
+};
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+/// Whether a benchmark is running, so runs never overlap
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// System allocator wrapper that counts allocations for benchmark reports;
+/// installed when built with the `bench` feature
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size as u64, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Representative target sizes for each response strategy
+const DIRECT_BENCH_BYTES: usize = 8_000;
+const FAST_BENCH_BYTES: usize = 500_000;
+const STREAMING_BENCH_BYTES: usize = 4_000_000;
+
+const DEFAULT_ITERATIONS: usize = 5;
+const MAX_ITERATIONS: usize = 100;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct BenchOptions {
+    pub iterations: Option<usize>,
+}
+
+impl BenchOptions {
+    /// Parse `daddle bench` command line arguments
+    pub fn from_args(args: &[String]) -> anyhow::Result<Self> {
+        let mut options = BenchOptions::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--iterations" | "-n" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("{} requires a value", arg))?;
+                    options.iterations = Some(value.parse()?);
+                }
+                other => anyhow::bail!("unknown bench argument: {}", other),
+            }
+        }
+
+        Ok(options)
+    }
+
+    fn iterations(&self) -> usize {
+        self.iterations
+            .unwrap_or(DEFAULT_ITERATIONS)
+            .clamp(1, MAX_ITERATIONS)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BenchResult {
+    pub name: String,
+    pub target_bytes: usize,
+    pub iterations: usize,
+    pub total_bytes: u64,
+    pub duration_ms: f64,
+    pub throughput_mb_per_sec: f64,
+    /// Unset unless built with the `bench` feature
+    pub allocations_per_iteration: Option<u64>,
+    pub allocated_bytes_per_iteration: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    pub iterations: usize,
+    pub strategies: Vec<BenchResult>,
+    pub chunk_tiers: Vec<BenchResult>,
+    pub total_duration_ms: f64,
+    pub note: &'static str,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+/// Tracks elapsed time and allocations between construction and `finish`
+struct Measurement {
+    started: Instant,
+    allocations: u64,
+    allocated_bytes: u64,
+}
+
+impl Measurement {
+    fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            allocations: ALLOCATIONS.load(Ordering::Relaxed),
+            allocated_bytes: ALLOCATED_BYTES.load(Ordering::Relaxed),
+        }
+    }
+
+    fn finish(
+        self,
+        name: &str,
+        target_bytes: usize,
+        iterations: usize,
+        total_bytes: u64,
+    ) -> BenchResult {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - self.allocations;
+        let allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed) - self.allocated_bytes;
+        let counting = cfg!(feature = "bench");
+
+        BenchResult {
+            name: name.to_string(),
+            target_bytes,
+            iterations,
+            total_bytes,
+            duration_ms: elapsed * 1000.0,
+            throughput_mb_per_sec: if elapsed > 0.0 {
+                total_bytes as f64 / (1024.0 * 1024.0) / elapsed
+            } else {
+                0.0
+            },
+            allocations_per_iteration: counting.then(|| allocations / iterations as u64),
+            allocated_bytes_per_iteration: counting.then(|| allocated_bytes / iterations as u64),
+        }
+    }
+}
+
+fn bench_buffered_strategy(name: &str, target_bytes: usize, iterations: usize) -> BenchResult {
+    let measurement = Measurement::start();
+    let mut total_bytes = 0;
+
+    for _ in 0..iterations {
+        let response = create_optimal_response(target_bytes, ResponseOptions::default());
+        if let Ok(GarbleResponse {
+            body: GarbleBody::Json { json, .. },
+            ..
+        }) = response
+        {
+            total_bytes += json.len() as u64;
+        }
+    }
+
+    measurement.finish(name, target_bytes, iterations, total_bytes)
+}
+
+async fn bench_streaming_strategy(target_bytes: usize, iterations: usize) -> BenchResult {
+    let measurement = Measurement::start();
+    let mut total_bytes = 0;
+
+    for _ in 0..iterations {
+        let mut stream =
+            StreamingGarbleResponse::new(target_bytes, ResponseOptions::default()).into_stream();
+        while let Some(Ok(piece)) = stream.next().await {
+            total_bytes += piece.len() as u64;
+        }
+    }
+
+    measurement.finish("streaming", target_bytes, iterations, total_bytes)
+}
+
+fn bench_chunk_tier(size: ChunkSize, iterations: usize) -> BenchResult {
+    let measurement = Measurement::start();
+    let mut total_bytes = 0;
+
+    for _ in 0..iterations {
+        let mut generator = RandomDataGenerator::new();
+        let chunk = generator.generate_array_element(size.target_bytes());
+        total_bytes += serde_json::to_string(&chunk).map(|s| s.len()).unwrap_or(0) as u64;
+    }
+
+    measurement.finish(
+        &format!("{:?}", size).to_lowercase(),
+        size.target_bytes(),
+        iterations,
+        total_bytes,
+    )
+}
+
+/// Measure generation throughput for each response strategy and chunk tier
+pub async fn run(options: BenchOptions) -> BenchReport {
+    let iterations = options.iterations();
+    let started = Instant::now();
+
+    tracing::info!("Running self-benchmark with {} iterations", iterations);
+
+    // Every measurement is CPU bound, streaming included, so all of them stay
+    // off the workers serving requests
+    let runtime = tokio::runtime::Handle::current();
+    let (strategies, chunk_tiers) = tokio::task::spawn_blocking(move || {
+        let strategies = vec![
+            bench_buffered_strategy("direct", DIRECT_BENCH_BYTES, iterations),
+            bench_buffered_strategy("fast", FAST_BENCH_BYTES, iterations),
+            runtime.block_on(bench_streaming_strategy(STREAMING_BENCH_BYTES, iterations)),
+        ];
+        let chunk_tiers = ChunkSize::all()
+            .iter()
+            .map(|&size| bench_chunk_tier(size, iterations))
+            .collect::<Vec<_>>();
+        (strategies, chunk_tiers)
+    })
+    .await
+    .expect("benchmark task panicked");
+
+    BenchReport {
+        iterations,
+        strategies,
+        chunk_tiers,
+        total_duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+        note: if cfg!(feature = "bench") {
+            "allocation counts are process-wide and include concurrent requests"
+        } else {
+            "allocations are only counted when built with the bench feature"
+        },
+        timestamp: chrono::Utc::now(),
+    }
+}
+
+/// Clears `RUNNING` once a benchmark ends, however it ends
+struct RunGuard;
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        RUNNING.store(false, Ordering::Release);
+    }
+}
+
+pub async fn bench_handler(
+    options: Option<Json<BenchOptions>>,
+) -> Result<Json<BenchReport>, ApiError> {
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "bench-in-progress",
+            "Benchmark already running",
+            "a benchmark is running on this instance already",
+        ));
+    }
+    let _guard = RunGuard;
+    let options = options.map(|Json(options)| options).unwrap_or_default();
+    Ok(Json(run(options).await))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+
+    #[tokio::test]
+    async fn overlapping_runs_are_refused() {
+        let running = RunGuard;
+        RUNNING.store(true, Ordering::Release);
+        let refused = bench_handler(None).await.unwrap_err();
+        assert_eq!(refused.into_response().status(), StatusCode::CONFLICT);
+        drop(running);
+        assert!(!RUNNING.load(Ordering::Acquire));
+    }
+}
@@ -0,0 +1,113 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Time budget for generating a single garble response. With
+//! `performance.generation_budget_ms` set, generation loops check a deadline
+//! and give up once it passes, so a pathological parameter combination fails
+//! fast with diagnostics instead of spinning the generator for seconds.
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::PerformanceConfig;
+use crate::error::ApiError;
+
+/// A response whose generation was abandoned
+#[derive(Debug, Clone, Serialize)]
+pub struct BudgetOverrun {
+    pub strategy: &'static str,
+    pub target_size: usize,
+    /// Bytes generated before giving up
+    pub generated_bytes: usize,
+    pub elapsed_ms: u64,
+    pub at: DateTime<Utc>,
+}
+
+/// The budget and what exceeded it since startup
+#[derive(Debug, Clone, Serialize)]
+pub struct BudgetStats {
+    pub budget_ms: u64,
+    /// Abandoned responses per strategy
+    pub exceeded: BTreeMap<&'static str, u64>,
+    pub last: Option<BudgetOverrun>,
+}
+
+struct GenerationBudget {
+    limit: Duration,
+    exceeded: Mutex<BTreeMap<&'static str, u64>>,
+    last: Mutex<Option<BudgetOverrun>>,
+}
+
+/// Set up the budget, if one is configured; call once at startup
+pub fn configure(config: &PerformanceConfig) -> anyhow::Result<()> {
+    let Some(budget_ms) = config.generation_budget_ms else {
+        return Ok(());
+    };
+    if budget_ms == 0 {
+        anyhow::bail!("performance.generation_budget_ms must be at least 1");
+    }
+    tracing::info!("Generation budget: {}ms per response", budget_ms);
+    let _ = BUDGET.set(GenerationBudget {
+        limit: Duration::from_millis(budget_ms),
+        exceeded: Mutex::new(BTreeMap::new()),
+        last: Mutex::new(None),
+    });
+    Ok(())
+}
+
+/// How long generating one response may take, if limited
+pub fn limit() -> Option<Duration> {
+    BUDGET.get().map(|budget| budget.limit)
+}
+
+/// When generation starting now has to be done by, if limited
+pub fn deadline() -> Option<Instant> {
+    limit().map(|limit| Instant::now() + limit)
+}
+
+/// Record an abandoned response and describe it as an error
+pub fn exceeded(
+    strategy: &'static str,
+    target_size: usize,
+    generated_bytes: usize,
+    elapsed: Duration,
+) -> ApiError {
+    let overrun = BudgetOverrun {
+        strategy,
+        target_size,
+        generated_bytes,
+        elapsed_ms: elapsed.as_millis() as u64,
+        at: Utc::now(),
+    };
+    tracing::warn!(
+        "Abandoned generating a {}B {} response after {}ms with {}B generated",
+        target_size,
+        strategy,
+        overrun.elapsed_ms,
+        generated_bytes
+    );
+
+    let Some(budget) = BUDGET.get() else {
+        return ApiError::generation_budget(&overrun, elapsed);
+    };
+    *budget.exceeded.lock().unwrap().entry(strategy).or_default() += 1;
+    *budget.last.lock().unwrap() = Some(overrun.clone());
+    ApiError::generation_budget(&overrun, budget.limit)
+}
+
+/// The budget and what exceeded it, if a budget is configured
+pub fn get_stats() -> Option<BudgetStats> {
+    BUDGET.get().map(|budget| BudgetStats {
+        budget_ms: budget.limit.as_millis() as u64,
+        exceeded: budget.exceeded.lock().unwrap().clone(),
+        last: budget.last.lock().unwrap().clone(),
+    })
+}
+
+// Global generation budget, set once at startup when configured
+static BUDGET: OnceCell<GenerationBudget> = OnceCell::new();
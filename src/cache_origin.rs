@@ -0,0 +1,385 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A controllable origin for validating CDN and caching proxy setups.
+//! `/cache/stable/{key}` serves the same body for a key forever, and
+//! `/cache/rotating/{key}` a new version of it every period, answering
+//! conditional requests as the chosen revalidation behavior dictates. Both
+//! take their `Cache-Control` and `Surrogate-Control` directives from the
+//! query, and count how often each key reached the origin, so a test can
+//! tell what the cache in front absorbed.
+
+use axum::{
+    extract::{rejection::QueryRejection, Path, Query, RawQuery},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, TimeZone, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::error::ApiError;
+use crate::generator::{GeneratorOptions, RandomDataGenerator};
+use crate::tenants::Tenant;
+use crate::validation::strict_query;
+
+const DEFAULT_SIZE: usize = 1024;
+const DEFAULT_MAX_AGE: u64 = 60;
+const DEFAULT_PERIOD_S: u64 = 60;
+
+/// Longest accepted `periodS`, a day
+const MAX_PERIOD_S: u64 = 86_400;
+
+/// Keys whose origin hits are counted; later keys are served but not counted
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+/// Format of `Last-Modified` and `If-Modified-Since`
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// `Last-Modified` of stable content, which never changes
+static STABLE_SINCE: Lazy<DateTime<Utc>> = Lazy::new(|| {
+    Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0)
+        .single()
+        .expect("valid date")
+});
+
+/// Validators sent with a response, and how conditional requests are answered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Revalidation {
+    /// `ETag` and `Last-Modified`, either of which can earn a 304
+    #[default]
+    Both,
+    /// `ETag` only
+    Etag,
+    /// Weak `ETag` only
+    WeakEtag,
+    /// `Last-Modified` only
+    LastModified,
+    /// Validators are sent but conditional requests always get the full body
+    Ignore,
+    /// No validators, so caches can only refetch
+    None,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CacheParams {
+    size: Option<usize>,
+    #[serde(rename = "maxAge")]
+    max_age: Option<u64>,
+    #[serde(rename = "sMaxAge")]
+    s_max_age: Option<u64>,
+    #[serde(rename = "staleWhileRevalidate")]
+    stale_while_revalidate: Option<u64>,
+    #[serde(rename = "staleIfError")]
+    stale_if_error: Option<u64>,
+    #[serde(rename = "mustRevalidate")]
+    must_revalidate: Option<bool>,
+    private: Option<bool>,
+    #[serde(rename = "surrogateMaxAge")]
+    surrogate_max_age: Option<u64>,
+    #[serde(rename = "surrogateKey")]
+    surrogate_key: Option<String>,
+    vary: Option<String>,
+    #[serde(rename = "periodS")]
+    period_s: Option<u64>,
+    revalidate: Option<Revalidation>,
+}
+
+impl CacheParams {
+    fn cache_control(&self, default_max_age: u64) -> String {
+        let mut directives = vec![
+            if self.private.unwrap_or(false) {
+                "private".to_string()
+            } else {
+                "public".to_string()
+            },
+            format!("max-age={}", self.max_age.unwrap_or(default_max_age)),
+        ];
+        if let Some(seconds) = self.s_max_age {
+            directives.push(format!("s-maxage={}", seconds));
+        }
+        if let Some(seconds) = self.stale_while_revalidate {
+            directives.push(format!("stale-while-revalidate={}", seconds));
+        }
+        if let Some(seconds) = self.stale_if_error {
+            directives.push(format!("stale-if-error={}", seconds));
+        }
+        if self.must_revalidate.unwrap_or(false) {
+            directives.push("must-revalidate".to_string());
+        }
+        directives.join(", ")
+    }
+
+    /// Headers shared by full and 304 responses
+    fn caching_headers(&self, default_max_age: u64) -> Result<HeaderMap, ApiError> {
+        let mut headers = HeaderMap::new();
+        let value = |name: &str, value: String| {
+            HeaderValue::from_str(&value)
+                .map_err(|_| ApiError::bad_request(format!("invalid {}: {}", name, value)))
+        };
+        headers.insert(
+            header::CACHE_CONTROL,
+            value("cache control", self.cache_control(default_max_age))?,
+        );
+        if let Some(seconds) = self.surrogate_max_age {
+            headers.insert(
+                HeaderName::from_static("surrogate-control"),
+                value("surrogateMaxAge", format!("max-age={}", seconds))?,
+            );
+        }
+        if let Some(keys) = &self.surrogate_key {
+            let keys = value("surrogateKey", keys.replace(',', " "))?;
+            headers.insert(HeaderName::from_static("surrogate-key"), keys.clone());
+            headers.insert(HeaderName::from_static("cache-tag"), keys);
+        }
+        if let Some(vary) = &self.vary {
+            headers.insert(header::VARY, value("vary", vary.clone())?);
+        }
+        Ok(headers)
+    }
+}
+
+/// Origin hits since startup
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheOriginStats {
+    pub requests: u64,
+    /// Full bodies sent
+    pub full: u64,
+    /// Conditional requests answered with 304
+    pub not_modified: u64,
+    pub keys: usize,
+}
+
+struct CacheOrigin {
+    hits: Mutex<HashMap<String, u64>>,
+    requests: AtomicU64,
+    full: AtomicU64,
+    not_modified: AtomicU64,
+}
+
+impl CacheOrigin {
+    /// Count a request for `key`, returning its hits so far if it is tracked
+    fn hit(&self, key: &str) -> Option<u64> {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let mut hits = self.hits.lock().unwrap();
+        if let Some(count) = hits.get_mut(key) {
+            *count += 1;
+            return Some(*count);
+        }
+        if hits.len() >= MAX_TRACKED_KEYS {
+            return None;
+        }
+        hits.insert(key.to_string(), 1);
+        Some(1)
+    }
+
+    fn get_stats(&self) -> CacheOriginStats {
+        CacheOriginStats {
+            requests: self.requests.load(Ordering::Relaxed),
+            full: self.full.load(Ordering::Relaxed),
+            not_modified: self.not_modified.load(Ordering::Relaxed),
+            keys: self.hits.lock().unwrap().len(),
+        }
+    }
+}
+
+/// One version of the content behind a key
+struct Version {
+    /// Identifies the version in `X-Origin-Version`
+    number: u64,
+    seed: u64,
+    modified: DateTime<Utc>,
+    validators: Revalidation,
+}
+
+fn seed_for(key: &str, version: u64) -> u64 {
+    let digest = Sha256::new()
+        .chain_update(key.as_bytes())
+        .chain_update(version.to_be_bytes())
+        .finalize();
+    u64::from_be_bytes(digest[..8].try_into().expect("digest is 32 bytes"))
+}
+
+/// Whether an `If-None-Match` list names `etag`, comparing weakly as caches do
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+fn not_modified_since(request: &HeaderMap, modified: DateTime<Utc>) -> bool {
+    request
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+        .is_some_and(|since| modified.timestamp() <= since.timestamp())
+}
+
+fn respond(
+    key: &str,
+    version: Version,
+    params: &CacheParams,
+    tenant: &Tenant,
+    request: &HeaderMap,
+    default_max_age: u64,
+) -> Result<Response, ApiError> {
+    let size = params.size.unwrap_or(DEFAULT_SIZE);
+    if let Some(cap) = tenant.garble.body_size_cap.filter(|&cap| size > cap) {
+        return Err(ApiError::body_too_large(size, cap));
+    }
+    let mut headers = params.caching_headers(default_max_age)?;
+    let hits = CACHE_ORIGIN.hit(key);
+
+    let body = serde_json::to_vec(
+        &RandomDataGenerator::seeded(GeneratorOptions::default(), version.seed)
+            .generate_sized_record(size),
+    )
+    .map_err(|e| ApiError::internal(format!("Failed to serialize response: {}", e)))?;
+    let digest = hex_digest(&body);
+    let etag = match version.validators {
+        Revalidation::WeakEtag => Some(format!("W/\"{}\"", digest)),
+        Revalidation::Both | Revalidation::Etag | Revalidation::Ignore => {
+            Some(format!("\"{}\"", digest))
+        }
+        Revalidation::LastModified | Revalidation::None => None,
+    };
+    let last_modified = matches!(
+        version.validators,
+        Revalidation::Both | Revalidation::LastModified | Revalidation::Ignore
+    )
+    .then(|| version.modified.format(HTTP_DATE).to_string());
+
+    if let Some(etag) = &etag {
+        headers.insert(header::ETAG, HeaderValue::from_str(etag).expect("hex"));
+    }
+    if let Some(last_modified) = &last_modified {
+        headers.insert(
+            header::LAST_MODIFIED,
+            HeaderValue::from_str(last_modified).expect("formatted date"),
+        );
+    }
+    headers.insert(
+        HeaderName::from_static("x-origin-version"),
+        HeaderValue::from(version.number),
+    );
+    if let Some(hits) = hits {
+        headers.insert(
+            HeaderName::from_static("x-origin-hits"),
+            HeaderValue::from(hits),
+        );
+    }
+
+    // If-None-Match wins over If-Modified-Since when both are sent
+    let conditional = version.validators != Revalidation::Ignore;
+    let unchanged = match (request.get(header::IF_NONE_MATCH), &etag) {
+        (Some(if_none_match), Some(etag)) => if_none_match
+            .to_str()
+            .is_ok_and(|value| etag_matches(value, etag)),
+        (Some(_), None) => false,
+        (None, _) => last_modified.is_some() && not_modified_since(request, version.modified),
+    };
+    if conditional && unchanged {
+        CACHE_ORIGIN.not_modified.fetch_add(1, Ordering::Relaxed);
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    CACHE_ORIGIN.full.fetch_add(1, Ordering::Relaxed);
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    Ok((headers, body).into_response())
+}
+
+fn hex_digest(body: &[u8]) -> String {
+    Sha256::digest(body)[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The same body for a key on every request and across restarts
+pub async fn stable_handler(
+    Path(key): Path<String>,
+    params: Result<Query<CacheParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    tenant: Tenant,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let params = strict_query(
+        params,
+        raw_query.as_deref(),
+        tenant.garble.strict_validation,
+    )?;
+    if params.period_s.is_some() {
+        return Err(ApiError::bad_request(
+            "periodS only applies to /cache/rotating",
+        ));
+    }
+    let version = Version {
+        number: 0,
+        seed: seed_for(&key, 0),
+        modified: *STABLE_SINCE,
+        validators: params.revalidate.unwrap_or_default(),
+    };
+    respond(&key, version, &params, &tenant, &headers, DEFAULT_MAX_AGE)
+}
+
+/// A new body for a key every `periodS` seconds, cacheable until the next one
+pub async fn rotating_handler(
+    Path(key): Path<String>,
+    params: Result<Query<CacheParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    tenant: Tenant,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let params = strict_query(
+        params,
+        raw_query.as_deref(),
+        tenant.garble.strict_validation,
+    )?;
+    let period = params.period_s.unwrap_or(DEFAULT_PERIOD_S);
+    if period == 0 || period > MAX_PERIOD_S {
+        return Err(ApiError::bad_request(format!(
+            "periodS must be between 1 and {}, got {}",
+            MAX_PERIOD_S, period
+        )));
+    }
+
+    // Versions roll over on wall-clock boundaries, so every instance agrees on them
+    let now = Utc::now().timestamp().max(0) as u64;
+    let number = now / period;
+    let modified = Utc
+        .timestamp_opt((number * period) as i64, 0)
+        .single()
+        .unwrap_or_else(Utc::now);
+    let version = Version {
+        number,
+        seed: seed_for(&key, number),
+        modified,
+        validators: params.revalidate.unwrap_or_default(),
+    };
+    // Without an explicit max-age, content is fresh until the next version
+    let until_next = (number + 1) * period - now;
+    respond(&key, version, &params, &tenant, &headers, until_next)
+}
+
+/// Origin hits since startup
+pub fn get_stats() -> CacheOriginStats {
+    CACHE_ORIGIN.get_stats()
+}
+
+// Global origin hit counters
+static CACHE_ORIGIN: Lazy<CacheOrigin> = Lazy::new(|| CacheOrigin {
+    hits: Mutex::new(HashMap::new()),
+    requests: AtomicU64::new(0),
+    full: AtomicU64::new(0),
+    not_modified: AtomicU64::new(0),
+});
@@ -0,0 +1,123 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Garble for any path, so one instance can stand in for a whole API surface.
+//! Paths no endpoint or route answers get `/garble` responses, with size and
+//! latency ranges taken from the first matching entry of the `catch_all`
+//! config, such as large slow reports next to a tiny fast ping.
+
+use anyhow::Context;
+use axum::{
+    body::Body,
+    extract::{rejection::QueryRejection, Query, RawQuery},
+    http::{HeaderMap, Method, Uri},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use once_cell::sync::OnceCell;
+use std::sync::Arc;
+
+use crate::config::{CatchAllConfig, PathDefaultsConfig};
+use crate::error::ApiError;
+use crate::handlers::{garble_handler, GarbleParams};
+use crate::routes::PathPattern;
+use crate::server::ConnectionControl;
+use crate::tenants::Tenant;
+
+struct PathDefaults {
+    pattern: PathPattern,
+    config: PathDefaultsConfig,
+}
+
+impl PathDefaults {
+    fn load(config: &PathDefaultsConfig) -> anyhow::Result<Self> {
+        if let (Some(min), Some(max)) = (config.min_body_size, config.max_body_size) {
+            if min > max {
+                anyhow::bail!(
+                    "min_body_size ({}) is greater than max_body_size ({})",
+                    min,
+                    max
+                );
+            }
+        }
+        if let (Some(min), Some(max)) = (config.min_wait_duration_ms, config.max_wait_duration_ms) {
+            if min > max {
+                anyhow::bail!(
+                    "min_wait_duration_ms ({}) is greater than max_wait_duration_ms ({})",
+                    min,
+                    max
+                );
+            }
+        }
+        Ok(Self {
+            pattern: PathPattern::parse(&config.path)?,
+            config: config.clone(),
+        })
+    }
+}
+
+/// Load the path defaults, if the catch-all is enabled; call once at startup
+pub fn load(config: &CatchAllConfig) -> anyhow::Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+    let paths = config
+        .paths
+        .iter()
+        .map(|path| {
+            PathDefaults::load(path)
+                .with_context(|| format!("invalid catch_all path {}", path.path))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    tracing::info!(
+        "Catch-all garble enabled with {} path defaults",
+        paths.len()
+    );
+    let _ = PATHS.set(paths);
+    Ok(())
+}
+
+/// Whether requests no route answers get garble
+pub fn is_enabled() -> bool {
+    PATHS.get().is_some()
+}
+
+/// Answer a request no endpoint or route matched with garble, using the
+/// defaults of the first path entry that matches
+#[allow(clippy::too_many_arguments)]
+pub async fn handle(
+    garble_params: Result<Query<GarbleParams>, QueryRejection>,
+    raw_query: Option<String>,
+    mut tenant: Tenant,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    connection: Option<Extension<Arc<ConnectionControl>>>,
+    body: Body,
+) -> Result<Response, ApiError> {
+    let defaults = PATHS.get().and_then(|paths| {
+        paths
+            .iter()
+            .find(|defaults| defaults.pattern.matches(uri.path()).is_some())
+    });
+    if let Some(defaults) = defaults {
+        defaults.config.apply(&mut tenant.garble);
+    }
+
+    garble_handler(
+        garble_params,
+        RawQuery(raw_query),
+        tenant,
+        method,
+        uri,
+        headers,
+        connection,
+        body,
+    )
+    .await
+    .map(IntoResponse::into_response)
+}
+
+// Global path defaults, set at startup when the catch-all is enabled
+static PATHS: OnceCell<Vec<PathDefaults>> = OnceCell::new();
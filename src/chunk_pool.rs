@@ -4,14 +4,31 @@
 
 use once_cell::sync::Lazy;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
 
+use crate::compression::{self, Variants};
+use crate::emit;
 use crate::generator::RandomDataGenerator;
+use crate::memory::MEMORY_GUARD;
+use crate::metadata::ResponseMetadata;
+use crate::readiness::POOL_HEARTBEAT;
+use crate::streaming::{sized_document, ResponseOptions};
+
+/// Chunks overshooting the room left that are put up with before filling stops
+/// and the rest of the body is padded, as chunk sizes are only rough
+pub const MAX_MISFITS: usize = 3;
+
+/// First part number of chunks generated to fill a body, well past those of
+/// the chunks a parallel build starts with
+const FILL_PARTS: u64 = 1 << 32;
 
 /// Different chunk sizes we pre-generate
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum ChunkSize {
     Small,  // ~1KB
     Medium, // ~10KB
@@ -63,9 +80,28 @@ impl Default for ChunkPoolConfig {
     }
 }
 
+/// A pre-generated chunk, with the compressed forms made alongside it
+pub struct PooledChunk {
+    pub json: String,
+    pub variants: Variants,
+}
+
+impl PooledChunk {
+    fn new(json: String) -> Self {
+        Self {
+            variants: compression::precompress(&json),
+            json,
+        }
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.json.len() + self.variants.memory_bytes()
+    }
+}
+
 /// A pool of pre-generated chunks for fast response assembly
 pub struct ChunkPool {
-    chunks: RwLock<HashMap<ChunkSize, Vec<String>>>,
+    chunks: RwLock<HashMap<ChunkSize, Vec<PooledChunk>>>,
     config: ChunkPoolConfig,
     stats: Mutex<ChunkPoolStats>,
     #[allow(dead_code)] // Reserved for future use
@@ -124,6 +160,11 @@ impl ChunkPool {
 
     /// Get a chunk of the specified size
     pub fn get_chunk(&self, size: ChunkSize) -> Option<String> {
+        self.take_chunk(size).map(|chunk| chunk.json)
+    }
+
+    /// Get a chunk of the specified size together with its compressed forms
+    pub fn take_chunk(&self, size: ChunkSize) -> Option<PooledChunk> {
         // Ensure pool is initialized
         self.lazy_initialize();
 
@@ -159,7 +200,7 @@ impl ChunkPool {
         // Take available chunks from pool
         for _ in 0..available {
             if let Some(chunk) = chunk_vec.pop() {
-                result.push(chunk);
+                result.push(chunk.json);
             }
         }
 
@@ -180,78 +221,94 @@ impl ChunkPool {
         result
     }
 
-    /// Build a response by combining chunks to reach target size
-    pub fn build_response(&self, target_size: usize) -> String {
+    /// Build a response by combining chunks to reach target size, returning the
+    /// body and the number of chunks served from the pool
+    pub fn build_response(&self, target_size: usize, options: &ResponseOptions) -> (String, usize) {
         // Ensure pool is initialized
         self.lazy_initialize();
 
         if target_size < ChunkSize::Small.target_bytes() {
             // For very small responses, generate directly
-            let mut generator = RandomDataGenerator::new();
+            let mut generator = options.generator();
             let payload = generator.generate_payload(target_size);
-            return serde_json::to_string(&payload).unwrap_or_else(|_| "{}".to_string());
+            return (sized_document(payload, target_size, options), 0);
         }
 
         let mut result = String::with_capacity(target_size + 1024);
-        let mut remaining = target_size;
-        let mut chunk_count = 0;
+        let mut metadata = ResponseMetadata::new(&options.metadata, "chunk_pool", target_size)
+            .with_seed(options.seed);
+
+        result.push_str(&emit::chunks_open(&options.emit));
+        self.fill(&mut result, &mut metadata, options);
+        // A body past the deadline is thrown away, so it is not worth padding
+        if !options.out_of_time() {
+            result.push_str(&metadata.close(result.len(), &options.emit));
+        }
 
-        result.push_str(r#"{"garbled_chunks":["#);
+        (result, metadata.chunk_hits)
+    }
 
-        let mut first = true;
-        while remaining > 500 && chunk_count < 1000 {
+    /// Append chunks to an open chunk array until it is within 500 bytes of
+    /// the target size, leaving room to close the document
+    pub fn fill(
+        &self,
+        result: &mut String,
+        metadata: &mut ResponseMetadata,
+        options: &ResponseOptions,
+    ) {
+        let mut misfits = 0;
+        let mut generated = 0;
+        while metadata.chunk_count < 1000 && misfits < MAX_MISFITS && !options.out_of_time() {
             // Safety limits
-            if !first {
-                result.push(',');
+            let room = metadata.room(result.len(), &options.emit);
+            if room <= 500 {
+                break;
             }
-            first = false;
+            let separator =
+                emit::element_separator(metadata.chunk_count, &options.emit, emit::CHUNK_DEPTH);
 
             // Choose appropriate chunk size
-            let chunk_size = if remaining >= ChunkSize::XLarge.target_bytes() {
+            let chunk_size = if room >= ChunkSize::XLarge.target_bytes() {
                 ChunkSize::XLarge
-            } else if remaining >= ChunkSize::Large.target_bytes() {
+            } else if room >= ChunkSize::Large.target_bytes() {
                 ChunkSize::Large
-            } else if remaining >= ChunkSize::Medium.target_bytes() {
+            } else if room >= ChunkSize::Medium.target_bytes() {
                 ChunkSize::Medium
             } else {
                 ChunkSize::Small
             };
 
-            if let Some(chunk) = self.get_chunk(chunk_size) {
-                result.push_str(&chunk);
-                remaining = remaining.saturating_sub(chunk.len());
-            } else {
-                // Fallback: generate a small chunk
-                let mut generator = RandomDataGenerator::new();
-                let size = remaining.min(ChunkSize::Small.target_bytes());
-                let payload = generator.generate_array_element(size);
-                let chunk = serde_json::to_string(&payload)
-                    .unwrap_or_else(|_| r#"{"fallback":true}"#.to_string());
-                result.push_str(&chunk);
-                remaining = remaining.saturating_sub(chunk.len());
-            }
-
-            chunk_count += 1;
+            let pooled = options
+                .uses_pool()
+                .then(|| self.get_chunk(chunk_size))
+                .flatten();
+            let (chunk, from_pool) = match pooled {
+                Some(chunk) => (chunk, true),
+                None => {
+                    // Fallback: generate a small chunk, numbered apart from the
+                    // chunks of a parallel build this tops up
+                    generated += 1;
+                    let mut generator = options.part_generator(FILL_PARTS + generated);
+                    let size = room.min(ChunkSize::Small.target_bytes());
+                    let payload = generator.generate_array_element(size);
+                    let chunk = emit::to_json_at(&payload, &options.emit, emit::CHUNK_DEPTH);
+                    (chunk, false)
+                }
+            };
 
-            // Safety check to prevent infinite loops
-            if result.len() > target_size * 2 {
-                break;
+            if separator.len() + chunk.len() > room {
+                misfits += 1;
+                continue;
             }
+            result.push_str(&separator);
+            result.push_str(&chunk);
+            metadata.record_chunk(from_pool);
         }
-
-        result.push_str(r#"],"metadata":{"generated_by":"chunk_pool","target_size":"#);
-        result.push_str(&target_size.to_string());
-        result.push_str(r#","actual_size":"#);
-        result.push_str(&result.len().to_string());
-        result.push_str(r#","chunk_count":"#);
-        result.push_str(&chunk_count.to_string());
-        result.push_str(r#"}}"#);
-
-        result
     }
 
-    /// Background task to maintain chunk pool
-    pub async fn background_maintenance(&self) {
+    /// Background task to maintain chunk pool until `shutdown` is cancelled.
+    /// A generation round in progress is always allowed to finish.
+    pub async fn background_maintenance(&self, shutdown: CancellationToken) {
         // First, ensure the pool is initialized
         self.lazy_initialize();
 
@@ -269,7 +326,14 @@ impl ChunkPool {
                 self.config.background_generation_interval_ms
             };
 
-            tokio::time::sleep(Duration::from_millis(interval_ms)).await;
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_millis(interval_ms)) => {}
+                _ = shutdown.cancelled() => break,
+            }
+            POOL_HEARTBEAT.beat(Duration::from_millis(interval_ms));
+
+            // Give memory back before anything else while the node is under pressure
+            self.evict_excess();
 
             // Check if we should generate more chunks
             if self.should_generate_chunks() {
@@ -285,6 +349,95 @@ impl ChunkPool {
                 );
             }
         }
+
+        // Flush stats so the final snapshot reflects the pool as we leave it
+        self.update_stats();
+        let stats = self.get_stats();
+        tracing::info!(
+            "Background maintenance stopped: {} chunks ({}B) pooled, {} hits, {} misses, {} background generations",
+            stats.total_chunks,
+            stats.memory_usage_bytes,
+            stats.cache_hits,
+            stats.cache_misses,
+            stats.background_generations
+        );
+    }
+
+    /// Persist pooled chunks so the next start can skip the warm-up phase
+    pub async fn save_snapshot(&self, path: &Path) -> anyhow::Result<()> {
+        // Only the JSON is kept; compressed forms are made again on restore
+        let snapshot = {
+            let chunks = self.chunks.read().unwrap();
+            let snapshot: HashMap<ChunkSize, Vec<&str>> = chunks
+                .iter()
+                .map(|(size, pooled)| (*size, pooled.iter().map(|c| c.json.as_str()).collect()))
+                .collect();
+            serde_json::to_vec(&snapshot)?
+        };
+        tokio::fs::write(path, &snapshot).await?;
+        tracing::info!(
+            "Saved chunk pool snapshot ({}B) to {}",
+            snapshot.len(),
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// Restore chunks persisted by `save_snapshot`, if a snapshot exists
+    pub fn load_snapshot(&self, path: &Path) -> anyhow::Result<()> {
+        let content = match std::fs::read(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let snapshot: HashMap<ChunkSize, Vec<String>> = serde_json::from_slice(&content)?;
+
+        self.lazy_initialize();
+        {
+            let mut chunks = self.chunks.write().unwrap();
+            for (size, restored) in snapshot {
+                let restored = restored.into_par_iter().map(PooledChunk::new);
+                chunks.entry(size).or_default().par_extend(restored);
+            }
+        }
+        self.update_stats();
+
+        tracing::info!(
+            "Restored {} chunks from pool snapshot {}",
+            self.get_stats().total_chunks,
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// Chunks kept per size, lowered while memory is under pressure
+    fn target_per_size(&self) -> usize {
+        (self.config.min_chunks_per_size as f64 * MEMORY_GUARD.pool_scale()).ceil() as usize
+    }
+
+    /// Drop chunks beyond the current targets, largest sizes first
+    fn evict_excess(&self) {
+        let target = self.target_per_size();
+        let mut evicted_bytes = 0;
+        if let Ok(mut chunks) = self.chunks.write() {
+            for size in ChunkSize::all().iter().rev() {
+                if let Some(pooled) = chunks.get_mut(size) {
+                    if pooled.len() > target {
+                        evicted_bytes += pooled
+                            .drain(target..)
+                            .map(|c| c.memory_bytes())
+                            .sum::<usize>();
+                    }
+                }
+            }
+        }
+        if evicted_bytes > 0 {
+            tracing::info!(
+                "Evicted {}B of pooled chunks under memory pressure",
+                evicted_bytes
+            );
+            self.update_stats();
+        }
     }
 
     fn should_generate_chunks(&self) -> bool {
@@ -297,7 +450,7 @@ impl ChunkPool {
         let chunks = self.chunks.read().unwrap();
         for &size in ChunkSize::all() {
             let count = chunks.get(&size).map(|v| v.len()).unwrap_or(0);
-            if count < self.config.min_chunks_per_size {
+            if count < self.target_per_size() {
                 return true;
             }
         }
@@ -306,15 +459,16 @@ impl ChunkPool {
     }
 
     async fn generate_background_chunks(&self) {
+        let target = self.target_per_size();
         let chunks_to_generate = {
             let chunks = self.chunks.read().unwrap();
             let mut needed = Vec::new();
 
             for &size in ChunkSize::all() {
                 let current_count = chunks.get(&size).map(|v| v.len()).unwrap_or(0);
-                if current_count < self.config.min_chunks_per_size {
+                if current_count < target {
                     // Generate only a few chunks at a time to avoid blocking
-                    let needed_count = (self.config.min_chunks_per_size - current_count).min(3);
+                    let needed_count = (target - current_count).min(3);
                     needed.push((size, needed_count));
                 }
             }
@@ -326,7 +480,11 @@ impl ChunkPool {
             for (size, count) in chunks_to_generate.into_iter().take(1) {
                 // Only process one size per round
                 tracing::debug!("Generating {} chunks of size {:?}", count, size);
-                let new_chunks = self.generate_chunks_parallel(size, count);
+                let new_chunks: Vec<PooledChunk> = self
+                    .generate_chunks_parallel(size, count)
+                    .into_par_iter()
+                    .map(PooledChunk::new)
+                    .collect();
 
                 if let Ok(mut chunks) = self.chunks.write() {
                     chunks
@@ -348,6 +506,11 @@ impl ChunkPool {
     }
 
     fn has_memory_available(&self) -> bool {
+        // Pause entirely while the process is close to its memory ceiling
+        if MEMORY_GUARD.under_pressure() {
+            return false;
+        }
+
         let current_usage = self.estimate_memory_usage();
         let max_bytes = self.config.max_memory_mb * 1024 * 1024;
         current_usage < max_bytes
@@ -358,7 +521,7 @@ impl ChunkPool {
         chunks
             .values()
             .flat_map(|chunk_vec| chunk_vec.iter())
-            .map(|chunk| chunk.len())
+            .map(PooledChunk::memory_bytes)
             .sum()
     }
 
@@ -370,6 +533,18 @@ impl ChunkPool {
         }
     }
 
+    /// Pooled chunks and the number currently kept warm, for every size
+    pub fn fill_levels(&self) -> Vec<(ChunkSize, usize, usize)> {
+        let chunks = self.chunks.read().unwrap();
+        ChunkSize::all()
+            .iter()
+            .map(|size| {
+                let count = chunks.get(size).map_or(0, Vec::len);
+                (*size, count, self.target_per_size())
+            })
+            .collect()
+    }
+
     pub fn get_stats(&self) -> ChunkPoolStats {
         self.stats.lock().unwrap().clone()
     }
@@ -0,0 +1,342 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Instances behind a load balancer follow a leader's chaos scenario, so a
+//! change made on the leader applies to the whole fleet. Any instance can
+//! lead; followers poll its `/admin/scenario`, or any URL serving the same JSON.
+//! The same document carries the active configuration, so a game-day setup
+//! can be exported, versioned and imported again with a POST.
+
+use axum::{
+    extract::{rejection::JsonRejection, Request, State},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::{Json, Response},
+};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{ClusterConfig, Config, DependencyFault, LiveConfig};
+use crate::dependencies;
+use crate::error::ApiError;
+use crate::flags::FLAGS;
+use crate::remote_config;
+
+const INSTANCE_HEADER: HeaderName = HeaderName::from_static("x-daddle-instance");
+
+/// Chaos state shared across the fleet
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Scenario {
+    /// Fault of each dependency, by name
+    pub dependencies: BTreeMap<String, DependencyFault>,
+    /// State of each chaos flag, by name
+    pub flags: BTreeMap<String, bool>,
+    /// Configuration the scenario runs under, rules and chaos settings included;
+    /// followers leave it alone and only take the faults and flags
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config: Option<Config>,
+}
+
+impl Scenario {
+    /// The scenario this instance is currently running
+    pub fn current() -> Self {
+        Self {
+            dependencies: dependencies::faults(),
+            flags: FLAGS.states(),
+            config: None,
+        }
+    }
+
+    /// The scenario this instance is currently running, with its configuration
+    /// and its secrets redacted
+    pub fn capture(live: &LiveConfig) -> Self {
+        Self {
+            config: Some(live.get().redacted()),
+            ..Self::current()
+        }
+    }
+
+    /// Flags and dependencies this instance does not know, by kind and name
+    fn unknown_entries(&self, current: &Scenario) -> Vec<String> {
+        let flags = self
+            .flags
+            .keys()
+            .filter(|name| !current.flags.contains_key(*name))
+            .map(|name| format!("flag {}", name));
+        let dependencies = self
+            .dependencies
+            .keys()
+            .filter(|name| !current.dependencies.contains_key(*name))
+            .map(|name| format!("dependency {}", name));
+        flags.chain(dependencies).collect()
+    }
+
+    /// Whether applying this scenario would change anything here; entries this
+    /// instance does not know are ignored
+    fn differs_from(&self, current: &Scenario) -> bool {
+        fn differs<V: PartialEq>(
+            wanted: &BTreeMap<String, V>,
+            current: &BTreeMap<String, V>,
+        ) -> bool {
+            wanted
+                .iter()
+                .any(|(name, value)| current.get(name).is_some_and(|c| c != value))
+        }
+        differs(&self.dependencies, &current.dependencies) || differs(&self.flags, &current.flags)
+    }
+
+    fn apply(&self) {
+        dependencies::apply_faults(&self.dependencies);
+        FLAGS.apply(&self.flags);
+    }
+}
+
+/// This instance's identity and how following the leader is going
+pub struct Cluster {
+    instance_id: String,
+    instance_header: HeaderValue,
+    leader_url: Option<String>,
+    polls: AtomicU64,
+    poll_failures: AtomicU64,
+    changes_applied: AtomicU64,
+    last_synced: Mutex<Option<chrono::DateTime<chrono::Utc>>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClusterStats {
+    pub instance_id: String,
+    pub role: &'static str,
+    pub leader_url: Option<String>,
+    pub polls: u64,
+    pub poll_failures: u64,
+    pub changes_applied: u64,
+    pub last_synced: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Cluster {
+    pub fn get_stats(&self) -> ClusterStats {
+        ClusterStats {
+            instance_id: self.instance_id.clone(),
+            role: if self.leader_url.is_some() {
+                "follower"
+            } else {
+                "leader"
+            },
+            leader_url: self.leader_url.clone(),
+            polls: self.polls.load(Ordering::Relaxed),
+            poll_failures: self.poll_failures.load(Ordering::Relaxed),
+            changes_applied: self.changes_applied.load(Ordering::Relaxed),
+            last_synced: *self.last_synced.lock().unwrap(),
+        }
+    }
+
+    /// Fetch the leader's scenario and switch to it if it differs from ours
+    async fn sync(&self, client: &reqwest::Client, url: &str) -> Result<(), reqwest::Error> {
+        let scenario: Scenario = client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        if scenario.differs_from(&Scenario::current()) {
+            tracing::info!("Applying scenario from {}: {:?}", url, scenario);
+            scenario.apply();
+            self.changes_applied.fetch_add(1, Ordering::Relaxed);
+        }
+        *self.last_synced.lock().unwrap() = Some(chrono::Utc::now());
+        Ok(())
+    }
+}
+
+/// Identity reported by this instance: configured, else the host name, else random
+fn resolve_instance_id(config: &ClusterConfig) -> String {
+    config
+        .instance_id
+        .clone()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .filter(|id| !id.is_empty())
+        .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string()[..12].to_string())
+}
+
+/// Poll the leader until shutdown
+async fn follow(config: ClusterConfig, url: String, shutdown: CancellationToken) {
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_millis(config.timeout_ms))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!(
+                "Failed to create cluster client, not following leader: {}",
+                e
+            );
+            return;
+        }
+    };
+    let Some(cluster) = CLUSTER.get() else {
+        return;
+    };
+
+    let mut interval =
+        tokio::time::interval(Duration::from_millis(config.poll_interval_ms.max(100)));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                cluster.polls.fetch_add(1, Ordering::Relaxed);
+                if let Err(e) = cluster.sync(&client, &url).await {
+                    // Keep running the last scenario until the leader is back
+                    tracing::debug!("Failed to fetch scenario from {}: {}", url, e);
+                    cluster.poll_failures.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            _ = shutdown.cancelled() => break,
+        }
+    }
+}
+
+/// Set this instance's identity, and start following the leader when one is configured
+pub fn start(config: &ClusterConfig, shutdown: CancellationToken) -> Option<JoinHandle<()>> {
+    let instance_id = resolve_instance_id(config);
+    let instance_header = match HeaderValue::from_str(&instance_id) {
+        Ok(value) => value,
+        Err(_) => {
+            tracing::warn!("Instance id {:?} is not a valid header value", instance_id);
+            HeaderValue::from_static("invalid")
+        }
+    };
+    let cluster = Cluster {
+        instance_id: instance_id.clone(),
+        instance_header,
+        leader_url: config.leader_url.clone(),
+        polls: AtomicU64::new(0),
+        poll_failures: AtomicU64::new(0),
+        changes_applied: AtomicU64::new(0),
+        last_synced: Mutex::new(None),
+    };
+    if CLUSTER.set(cluster).is_err() {
+        return None;
+    }
+
+    let url = config.leader_url.clone()?;
+    tracing::info!("Instance {} following scenario from {}", instance_id, url);
+    Some(tokio::spawn(follow(config.clone(), url, shutdown)))
+}
+
+/// This instance's identity
+pub fn instance_id() -> Option<&'static str> {
+    CLUSTER.get().map(|cluster| cluster.instance_id.as_str())
+}
+
+/// Middleware tagging every response with this instance's identity
+pub async fn instance_header(request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    if let Some(cluster) = CLUSTER.get() {
+        response
+            .headers_mut()
+            .insert(INSTANCE_HEADER, cluster.instance_header.clone());
+    }
+    response
+}
+
+/// Outcome of importing a scenario
+#[derive(Debug, Clone, Serialize)]
+pub struct ScenarioImport {
+    /// Configuration sections the scenario changed that only take effect after a restart
+    pub restart_required: Vec<&'static str>,
+    pub scenario: Scenario,
+}
+
+/// The scenario this instance is running with its configuration, for
+/// followers to poll and for teams to save
+pub async fn scenario_handler(State(live): State<Arc<LiveConfig>>) -> Json<Scenario> {
+    Json(Scenario::capture(&live))
+}
+
+/// Switch to an exported scenario: its configuration first, as far as it
+/// changes while running, then its dependency faults and chaos flags
+pub async fn scenario_import_handler(
+    State(live): State<Arc<LiveConfig>>,
+    scenario: Result<Json<Scenario>, JsonRejection>,
+) -> Result<Json<ScenarioImport>, ApiError> {
+    let Json(mut scenario) = scenario?;
+    let unknown = scenario.unknown_entries(&Scenario::current());
+    if !unknown.is_empty() {
+        return Err(ApiError::bad_request(format!(
+            "scenario names unknown entries: {}",
+            unknown.join(", ")
+        )));
+    }
+
+    let restart_required = match scenario.config.take() {
+        Some(mut config) => {
+            // An exported scenario carries redacted secrets, which keep the current ones
+            config.restore_secrets(&live.get());
+            remote_config::validate(&config).map_err(|e| {
+                ApiError::bad_request(format!("scenario configuration is not valid: {:#}", e))
+            })?;
+            remote_config::apply(&live, config)
+        }
+        None => Vec::new(),
+    };
+    scenario.apply();
+    tracing::info!("Imported scenario: {:?}", scenario);
+
+    Ok(Json(ScenarioImport {
+        restart_required,
+        scenario: Scenario::capture(&live),
+    }))
+}
+
+// Global cluster state, set once at startup
+pub static CLUSTER: OnceCell<Cluster> = OnceCell::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TenantConfig;
+
+    fn config_with_secrets() -> Config {
+        let mut config = Config::default();
+        config.tenants.push(TenantConfig {
+            name: "team-a".to_string(),
+            api_keys: vec!["team-a-secret".to_string()],
+            garble: None,
+        });
+        config.encryption.key = Some("00112233445566778899aabbccddeeff".to_string());
+        config
+    }
+
+    #[test]
+    fn exported_scenarios_carry_no_key_material() {
+        let live = LiveConfig::new(config_with_secrets());
+        let exported = serde_json::to_string(&Scenario::capture(&live)).unwrap();
+        assert!(!exported.contains("team-a-secret"));
+        assert!(!exported.contains("00112233445566778899aabbccddeeff"));
+        assert!(exported.contains(crate::config::REDACTED));
+    }
+
+    #[test]
+    fn imported_redacted_secrets_keep_the_current_ones() {
+        let current = config_with_secrets();
+        let mut imported = current.redacted();
+        imported.tenants.push(TenantConfig {
+            name: "team-b".to_string(),
+            api_keys: vec!["team-b-secret".to_string()],
+            garble: None,
+        });
+        imported.restore_secrets(&current);
+        assert_eq!(imported.tenants[0].api_keys, vec!["team-a-secret"]);
+        assert_eq!(imported.tenants[1].api_keys, vec!["team-b-secret"]);
+        assert_eq!(imported.encryption.key, current.encryption.key);
+    }
+}
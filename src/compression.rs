@@ -0,0 +1,473 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Compressed garble responses without compression CPU per request. Pool
+//! chunks are compressed once when generated, each as an independent member,
+//! and responses are stitched together from those members: gzip bodies as one
+//! deflate stream of sync-flushed pieces under a single header and combined
+//! CRC, brotli bodies as concatenable streams joined by `BroCatli`. Separators,
+//! metadata and chunks the pool could not supply are compressed on demand.
+
+use axum::body::Bytes;
+use axum::http::{header, HeaderMap};
+use brotli::concat::{BroCatli, BroCatliResult};
+use brotli::enc::BrotliEncoderParams;
+use flate2::write::DeflateEncoder;
+use flate2::{Compression as DeflateLevel, Crc};
+use futures::{Stream, StreamExt};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::Serialize;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::CompressionConfig;
+
+/// Brotli window of every member; `BroCatli` only joins members whose window
+/// is no larger than the first one
+const BROTLI_WINDOW: u8 = 22;
+
+/// gzip header: magic, deflate, no flags, no mtime, no extra flags, unknown OS
+const GZIP_HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+
+/// An empty final deflate block, ending the stream of sync-flushed pieces
+const DEFLATE_END: [u8; 2] = [0x03, 0x00];
+
+/// Content codings garble responses can be served with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContentCoding {
+    Gzip,
+    Brotli,
+}
+
+impl ContentCoding {
+    /// Value of the `Content-Encoding` header
+    pub fn name(&self) -> &'static str {
+        match self {
+            ContentCoding::Gzip => "gzip",
+            ContentCoding::Brotli => "br",
+        }
+    }
+}
+
+/// One piece of a body compressed on its own
+pub struct Member {
+    data: Bytes,
+    /// CRC of the plain piece, which gzip trailers need
+    crc: Option<Crc>,
+}
+
+impl Member {
+    fn compress(coding: ContentCoding, plain: &[u8], settings: &Settings) -> Self {
+        match coding {
+            ContentCoding::Gzip => {
+                let mut encoder =
+                    DeflateEncoder::new(Vec::new(), DeflateLevel::new(settings.gzip_level));
+                // A sync flush byte-aligns the output without ending the stream
+                let _ = encoder.write_all(plain).and_then(|_| encoder.flush());
+                let data = std::mem::take(encoder.get_mut());
+                let mut crc = Crc::new();
+                crc.update(plain);
+                Self {
+                    data: Bytes::from(data),
+                    crc: Some(crc),
+                }
+            }
+            ContentCoding::Brotli => {
+                let params = BrotliEncoderParams {
+                    quality: settings.brotli_quality as i32,
+                    lgwin: BROTLI_WINDOW as i32,
+                    catable: true,
+                    ..BrotliEncoderParams::default()
+                };
+                let mut data = Vec::new();
+                let _ = brotli::BrotliCompress(&mut &plain[..], &mut data, &params);
+                Self {
+                    data: Bytes::from(data),
+                    crc: None,
+                }
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// Pre-compressed forms of a pooled chunk
+#[derive(Default)]
+pub struct Variants {
+    gzip: Option<Member>,
+    brotli: Option<Member>,
+}
+
+impl Variants {
+    fn get(&self, coding: ContentCoding) -> Option<&Member> {
+        match coding {
+            ContentCoding::Gzip => self.gzip.as_ref(),
+            ContentCoding::Brotli => self.brotli.as_ref(),
+        }
+    }
+
+    /// Bytes held by the variants
+    pub fn memory_bytes(&self) -> usize {
+        self.gzip.as_ref().map_or(0, Member::len) + self.brotli.as_ref().map_or(0, Member::len)
+    }
+}
+
+/// A piece of a body on its way to being compressed
+pub enum Piece {
+    Plain(Bytes),
+    /// A pooled chunk, with its pre-compressed forms if the pool made them
+    Chunk {
+        plain: Bytes,
+        variants: Variants,
+    },
+}
+
+/// Stitches members into one compressed body
+struct Framer {
+    coding: ContentCoding,
+    settings: &'static Settings,
+    crc: Crc,
+    catli: BroCatli,
+    bytes_in: u64,
+    bytes_out: u64,
+    precompressed_bytes: u64,
+}
+
+impl Framer {
+    fn new(coding: ContentCoding, settings: &'static Settings) -> Self {
+        Self {
+            coding,
+            settings,
+            crc: Crc::new(),
+            catli: BroCatli::new_with_window_size(BROTLI_WINDOW),
+            bytes_in: 0,
+            bytes_out: 0,
+            precompressed_bytes: 0,
+        }
+    }
+
+    fn header(&mut self) -> Bytes {
+        match self.coding {
+            ContentCoding::Gzip => self.emit(GZIP_HEADER.to_vec()),
+            ContentCoding::Brotli => Bytes::new(),
+        }
+    }
+
+    fn push(&mut self, piece: Piece) -> Bytes {
+        match piece {
+            Piece::Plain(plain) => self.push_plain(&plain),
+            Piece::Chunk { plain, variants } => match variants.get(self.coding) {
+                Some(member) => {
+                    self.precompressed_bytes += plain.len() as u64;
+                    self.bytes_in += plain.len() as u64;
+                    self.push_member(member)
+                }
+                None => self.push_plain(&plain),
+            },
+        }
+    }
+
+    fn push_plain(&mut self, plain: &[u8]) -> Bytes {
+        if plain.is_empty() {
+            return Bytes::new();
+        }
+        self.bytes_in += plain.len() as u64;
+        let member = Member::compress(self.coding, plain, self.settings);
+        self.push_member(&member)
+    }
+
+    fn push_member(&mut self, member: &Member) -> Bytes {
+        match self.coding {
+            ContentCoding::Gzip => {
+                if let Some(crc) = &member.crc {
+                    self.crc.combine(crc);
+                }
+                self.bytes_out += member.len() as u64;
+                member.data.clone()
+            }
+            ContentCoding::Brotli => {
+                self.catli.new_brotli_file();
+                let mut out = vec![0; member.len() + 64];
+                let mut in_offset = 0;
+                let mut out_offset = 0;
+                loop {
+                    match self
+                        .catli
+                        .stream(&member.data, &mut in_offset, &mut out, &mut out_offset)
+                    {
+                        BroCatliResult::NeedsMoreOutput => out.resize(out.len() * 2, 0),
+                        BroCatliResult::NeedsMoreInput => break,
+                        failure => {
+                            tracing::warn!("Could not join brotli members: {:?}", failure);
+                            break;
+                        }
+                    }
+                }
+                out.truncate(out_offset);
+                self.emit(out)
+            }
+        }
+    }
+
+    fn finish(mut self) -> Bytes {
+        let tail = match self.coding {
+            ContentCoding::Gzip => {
+                let mut tail = DEFLATE_END.to_vec();
+                tail.extend_from_slice(&self.crc.sum().to_le_bytes());
+                tail.extend_from_slice(&self.crc.amount().to_le_bytes());
+                tail
+            }
+            ContentCoding::Brotli => {
+                let mut out = vec![0; 16];
+                let mut out_offset = 0;
+                while let BroCatliResult::NeedsMoreOutput =
+                    self.catli.finish(&mut out, &mut out_offset)
+                {
+                    out.resize(out.len() * 2, 0);
+                }
+                out.truncate(out_offset);
+                out
+            }
+        };
+        let tail = self.emit(tail);
+        STATS.record(
+            self.coding,
+            self.bytes_in,
+            self.bytes_out,
+            self.precompressed_bytes,
+        );
+        tail
+    }
+
+    fn emit(&mut self, data: Vec<u8>) -> Bytes {
+        self.bytes_out += data.len() as u64;
+        Bytes::from(data)
+    }
+}
+
+/// Compression settings, fixed at startup
+struct Settings {
+    gzip: bool,
+    brotli: bool,
+    precompress: bool,
+    gzip_level: u32,
+    brotli_quality: u32,
+}
+
+/// Turn on compressed responses, if configured; call once at startup
+pub fn configure(config: &CompressionConfig) -> anyhow::Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+    if config.gzip_level > 9 {
+        anyhow::bail!("compression.gzip_level must be between 0 and 9");
+    }
+    if config.brotli_quality > 11 {
+        anyhow::bail!("compression.brotli_quality must be between 0 and 11");
+    }
+    tracing::info!(
+        "Compressed responses enabled (gzip: {}, brotli: {}, pre-compressed pool: {})",
+        config.gzip,
+        config.brotli,
+        config.precompress
+    );
+    let _ = SETTINGS.set(Settings {
+        gzip: config.gzip,
+        brotli: config.brotli,
+        precompress: config.precompress,
+        gzip_level: config.gzip_level,
+        brotli_quality: config.brotli_quality,
+    });
+    Ok(())
+}
+
+/// Whether responses may be compressed at all
+pub fn is_enabled() -> bool {
+    SETTINGS.get().is_some()
+}
+
+/// The coding to answer a request with, from its `Accept-Encoding`. Brotli
+/// wins ties, and codings with `q=0` are never picked
+pub fn negotiate(headers: &HeaderMap) -> Option<ContentCoding> {
+    let settings = SETTINGS.get()?;
+    let accept = headers.get(header::ACCEPT_ENCODING)?.to_str().ok()?;
+    let mut best: Option<(ContentCoding, f32)> = None;
+    for entry in accept.split(',') {
+        let mut parts = entry.split(';');
+        let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let codings: &[ContentCoding] = match name.as_str() {
+            "br" => &[ContentCoding::Brotli],
+            "gzip" | "x-gzip" => &[ContentCoding::Gzip],
+            "*" => &[ContentCoding::Brotli, ContentCoding::Gzip],
+            _ => &[],
+        };
+        for &coding in codings {
+            let enabled = match coding {
+                ContentCoding::Gzip => settings.gzip,
+                ContentCoding::Brotli => settings.brotli,
+            };
+            if !enabled || quality <= 0.0 {
+                continue;
+            }
+            let better = match best {
+                None => true,
+                Some((current, current_quality)) => {
+                    quality > current_quality
+                        || (quality == current_quality
+                            && current == ContentCoding::Gzip
+                            && coding == ContentCoding::Brotli)
+                }
+            };
+            if better {
+                best = Some((coding, quality));
+            }
+        }
+    }
+    best.map(|(coding, _)| coding)
+}
+
+/// Pre-compressed forms of a freshly generated chunk, for every enabled coding
+pub fn precompress(plain: &str) -> Variants {
+    let Some(settings) = SETTINGS.get().filter(|settings| settings.precompress) else {
+        return Variants::default();
+    };
+    let variants = Variants {
+        gzip: settings
+            .gzip
+            .then(|| Member::compress(ContentCoding::Gzip, plain.as_bytes(), settings)),
+        brotli: settings
+            .brotli
+            .then(|| Member::compress(ContentCoding::Brotli, plain.as_bytes(), settings)),
+    };
+    STATS.precompressed_chunks.fetch_add(1, Ordering::Relaxed);
+    variants
+}
+
+/// Compress a whole body in one go
+pub fn compress_document(coding: ContentCoding, plain: &[u8]) -> Bytes {
+    let Some(settings) = SETTINGS.get() else {
+        return Bytes::copy_from_slice(plain);
+    };
+    let mut framer = Framer::new(coding, settings);
+    let mut body = framer.header().to_vec();
+    body.extend_from_slice(&framer.push_plain(plain));
+    body.extend_from_slice(&framer.finish());
+    Bytes::from(body)
+}
+
+/// Compress a body as it streams, using pre-compressed chunks where there are any
+pub fn compress_stream(
+    coding: ContentCoding,
+    pieces: impl Stream<Item = Result<Piece, std::io::Error>> + Send + 'static,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send {
+    async_stream::stream! {
+        let Some(settings) = SETTINGS.get() else {
+            return;
+        };
+        let mut framer = Framer::new(coding, settings);
+        yield Ok(framer.header());
+        futures::pin_mut!(pieces);
+        while let Some(piece) = pieces.next().await {
+            match piece {
+                Ok(piece) => {
+                    let compressed = framer.push(piece);
+                    if !compressed.is_empty() {
+                        yield Ok(compressed);
+                    }
+                }
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            }
+        }
+        yield Ok(framer.finish());
+    }
+}
+
+/// Compressed responses of one coding since startup
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CodingStats {
+    pub responses: u64,
+    /// Body bytes before compression
+    pub bytes_in: u64,
+    /// Body bytes sent
+    pub bytes_out: u64,
+    /// Bytes before compression that came pre-compressed from the pool
+    pub precompressed_bytes: u64,
+    /// Bytes before compression that were compressed while responding
+    pub on_demand_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CompressionStats {
+    pub gzip: CodingStats,
+    pub brotli: CodingStats,
+    /// Pool chunks compressed ahead of time
+    pub precompressed_chunks: u64,
+}
+
+#[derive(Default)]
+struct CodingCounters {
+    responses: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    precompressed_bytes: AtomicU64,
+}
+
+impl CodingCounters {
+    fn get_stats(&self) -> CodingStats {
+        let bytes_in = self.bytes_in.load(Ordering::Relaxed);
+        let precompressed_bytes = self.precompressed_bytes.load(Ordering::Relaxed);
+        CodingStats {
+            responses: self.responses.load(Ordering::Relaxed),
+            bytes_in,
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            precompressed_bytes,
+            on_demand_bytes: bytes_in.saturating_sub(precompressed_bytes),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    gzip: CodingCounters,
+    brotli: CodingCounters,
+    precompressed_chunks: AtomicU64,
+}
+
+impl Counters {
+    fn record(&self, coding: ContentCoding, bytes_in: u64, bytes_out: u64, precompressed: u64) {
+        let counters = match coding {
+            ContentCoding::Gzip => &self.gzip,
+            ContentCoding::Brotli => &self.brotli,
+        };
+        counters.responses.fetch_add(1, Ordering::Relaxed);
+        counters.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        counters.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+        counters
+            .precompressed_bytes
+            .fetch_add(precompressed, Ordering::Relaxed);
+    }
+}
+
+/// Compression counts, if compressed responses are enabled
+pub fn get_stats() -> Option<CompressionStats> {
+    is_enabled().then(|| CompressionStats {
+        gzip: STATS.gzip.get_stats(),
+        brotli: STATS.brotli.get_stats(),
+        precompressed_chunks: STATS.precompressed_chunks.load(Ordering::Relaxed),
+    })
+}
+
+// Global compression settings and counters
+static SETTINGS: OnceCell<Settings> = OnceCell::new();
+static STATS: Lazy<Counters> = Lazy::new(Counters::default);
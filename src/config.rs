@@ -2,21 +2,1579 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
     pub garble: GarbleConfig,
     pub performance: PerformanceConfig,
+    /// Tenants sharing this deployment, each with its own garble profile and stats bucket
+    #[serde(default)]
+    pub tenants: Vec<TenantConfig>,
+    #[serde(default)]
+    pub dictionary: DictionaryConfig,
+    #[serde(default)]
+    pub markov: MarkovConfig,
+    #[serde(default)]
+    pub string_lengths: StringLengthConfig,
+    #[serde(default)]
+    pub date_formats: DateFormatWeights,
+    #[serde(default)]
+    pub mirror: MirrorConfig,
+    /// Downstream services `/garble` can call before responding
+    #[serde(default)]
+    pub dependencies: Vec<DependencyConfig>,
+    #[serde(default)]
+    pub cluster: ClusterConfig,
+    #[serde(default)]
+    pub flags: FlagsConfig,
+    /// Custom endpoints returning fixed JSON skeletons with garbled parts
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    /// Scripted test cases selected by what the client sends, checked before routing
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+    /// Faults injected into every request to matching paths
+    #[serde(default)]
+    pub path_faults: Vec<PathFaultConfig>,
+    /// Upstream that unmatched requests are forwarded to, learning its response shapes
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+    /// Garble for every path no endpoint or route answers, with sizes and latency per path
+    #[serde(default)]
+    pub catch_all: CatchAllConfig,
+    /// Schema registry `/garble/avro` registers its schema with
+    #[serde(default)]
+    pub avro: AvroConfig,
+    /// Pre-generated files served from `/corpus/{name}`
+    #[serde(default)]
+    pub corpus: CorpusConfig,
+    /// Custom value generators, invoked by name from templates and mixed into garble
+    #[serde(default)]
+    pub generators: GeneratorsConfig,
+    /// Structure presets selectable with `?preset=`, added to or replacing the built-ins
+    #[serde(default)]
+    pub presets: BTreeMap<String, StructurePreset>,
+    /// Post-processing chains selectable by name with `?postProcess=`
+    #[serde(default)]
+    pub post_processors: BTreeMap<String, Vec<String>>,
+    /// Limits on requests held open by `?hang=true` and `?blackhole=true`
+    #[serde(default)]
+    pub hang: HangConfig,
+    /// Concurrency limit and bounded queue in front of garble generation
+    #[serde(default)]
+    pub admission: AdmissionConfig,
+    /// gzip and brotli responses, stitched from pre-compressed pool chunks
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    /// Fully assembled responses kept for reuse by exact size
+    #[serde(default)]
+    pub response_cache: ResponseCacheConfig,
+    /// Key for bodies encrypted with `?encrypt=`
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+    /// Layout of length-prefixed binary frames, and the raw TCP listener sending them
+    #[serde(default)]
+    pub frames: FramesConfig,
+    /// Flapping health checks and rising error rates for tuning outlier detection
+    #[serde(default)]
+    pub outlier: OutlierConfig,
+    /// Bandwidth shared by all requests on a connection, emulating last-mile links
+    #[serde(default)]
+    pub connection_shaping: ConnectionShapingConfig,
+    /// HTTP/2 flow control windows, kept small to test clients against backpressure
+    #[serde(default)]
+    pub http2: Http2Config,
+    /// Stats written to a file periodically and on shutdown
+    #[serde(default)]
+    pub stats_export: StatsExportConfig,
+    /// Error responses standing in for a failing upstream, for a share of garble requests
+    #[serde(default)]
+    pub faults: FaultsConfig,
+    /// Response headers that test how intermediaries sanitize them, off unless enabled
+    #[serde(default)]
+    pub header_hazards: HeaderHazardsConfig,
+}
+
+/// A custom endpoint mocking part of a real API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteConfig {
+    /// Path pattern; `:name` matches one segment and a trailing `*` matches the rest
+    pub path: String,
+    /// Method to answer, or `*` for any
+    #[serde(default = "default_route_method")]
+    pub method: String,
+    #[serde(default = "default_route_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Response skeleton, where `"$garble(100kb)"` strings are replaced by garble
+    #[serde(default)]
+    pub body: Option<serde_json::Value>,
+    /// File holding the skeleton, instead of `body`
+    #[serde(default)]
+    pub body_file: Option<String>,
+}
+
+fn default_route_method() -> String {
+    "GET".to_string()
+}
+
+fn default_route_status() -> u16 {
+    200
+}
+
+/// A scripted test case: the first rule whose conditions all hold decides how
+/// the request is answered
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleConfig {
+    /// Name reported in `/stats` (default: `rule-N`)
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub when: RuleConditions,
+    /// What to do on every match
+    #[serde(default)]
+    pub then: Option<RuleAction>,
+    /// Actions taken in turn on successive matches, instead of `then`
+    #[serde(default)]
+    pub sequence: Option<SequenceConfig>,
+}
+
+/// Scripted outcomes for successive matches of a rule, such as a 200, then
+/// a 500, then a 429
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SequenceConfig {
+    pub steps: Vec<RuleAction>,
+    #[serde(default)]
+    pub scope: SequenceScope,
+    /// Header identifying the client when `scope` is `client`
+    #[serde(default = "default_client_header")]
+    pub client_header: String,
+    /// Start over after the last step, rather than staying on it
+    #[serde(default = "default_repeat")]
+    pub repeat: bool,
+}
+
+fn default_client_header() -> String {
+    "x-client-id".to_string()
+}
+
+fn default_repeat() -> bool {
+    true
+}
+
+/// Who shares a sequence's position
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SequenceScope {
+    /// Every client advances the same position
+    #[default]
+    Global,
+    /// Each value of the client header has a position of its own
+    Client,
+}
+
+/// Conditions a request must meet for a rule to apply; unset ones always hold
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RuleConditions {
+    /// Path pattern, as for routes
+    pub path: Option<String>,
+    /// Method, or `*` for any
+    pub method: Option<String>,
+    /// Required header values, where `*` accepts any value
+    pub headers: BTreeMap<String, String>,
+    /// Required query parameter values, where `*` accepts any value
+    pub query: BTreeMap<String, String>,
+}
+
+/// What a matching rule does: answer with a fixed response, or pass the
+/// request on with a different garble profile. Either may be delayed
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RuleAction {
+    /// Pause before acting, such as a long one to simulate a hanging upstream
+    pub delay_ms: u64,
+    /// Profile replacing the tenant's `garble` section for the request
+    pub garble: Option<GarbleConfig>,
+    pub response: Option<ResponseConfig>,
+}
+
+impl RuleAction {
+    pub fn validate(&self) -> Result<()> {
+        if self.garble.is_some() && self.response.is_some() {
+            anyhow::bail!("a rule can set either garble or response, not both");
+        }
+        if let Some(garble) = &self.garble {
+            garble.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// A fixed response answered by a rule
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseConfig {
+    #[serde(default = "default_route_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Response skeleton, with the same placeholders as routes
+    #[serde(default)]
+    pub body: Option<serde_json::Value>,
+    /// File holding the skeleton, instead of `body`
+    #[serde(default)]
+    pub body_file: Option<String>,
+}
+
+/// Faults for the endpoints matching a path pattern, such as a slow search
+/// endpoint next to a healthy profile endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PathFaultConfig {
+    /// Path pattern, as for routes
+    pub path: String,
+    /// Method, or `*` for any
+    #[serde(default = "default_any_method")]
+    pub method: String,
+    /// Share of requests failed with `error_status`
+    #[serde(default)]
+    pub error_rate: f64,
+    #[serde(default = "default_path_fault_status")]
+    pub error_status: u16,
+    /// Delay added before the request is handled
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// Share of requests `latency_ms` is added to
+    #[serde(default = "default_latency_rate")]
+    pub latency_rate: f64,
+    /// Share of responses cut off partway
+    #[serde(default)]
+    pub truncation_rate: f64,
+}
+
+/// Garble answering any path no endpoint or route matches, when no proxy is set
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CatchAllConfig {
+    pub enabled: bool,
+    /// Size and latency defaults by path; the first match wins and unmatched
+    /// paths use the tenant's garble profile
+    pub paths: Vec<PathDefaultsConfig>,
+}
+
+/// Default size and latency ranges for the paths matching a pattern; unset
+/// values fall back to the garble profile
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PathDefaultsConfig {
+    /// Path pattern, as for routes
+    pub path: String,
+    #[serde(default)]
+    pub min_body_size: Option<usize>,
+    #[serde(default)]
+    pub max_body_size: Option<usize>,
+    #[serde(default)]
+    pub min_wait_duration_ms: Option<u64>,
+    #[serde(default)]
+    pub max_wait_duration_ms: Option<u64>,
+}
+
+impl PathDefaultsConfig {
+    /// Apply these defaults to the profile serving a matching path
+    pub fn apply(&self, garble: &mut GarbleConfig) {
+        if let Some(size) = self.min_body_size {
+            garble.min_body_size = size;
+        }
+        if let Some(size) = self.max_body_size {
+            garble.max_body_size = size;
+        }
+        if let Some(wait) = self.min_wait_duration_ms {
+            garble.min_wait_duration_ms = wait;
+        }
+        if let Some(wait) = self.max_wait_duration_ms {
+            garble.max_wait_duration_ms = wait;
+        }
+    }
+}
+
+fn default_any_method() -> String {
+    "*".to_string()
+}
+
+fn default_path_fault_status() -> u16 {
+    500
+}
+
+fn default_latency_rate() -> f64 {
+    1.0
+}
+
+impl PathFaultConfig {
+    pub fn validate(&self) -> Result<()> {
+        for (name, rate) in [
+            ("error_rate", self.error_rate),
+            ("latency_rate", self.latency_rate),
+            ("truncation_rate", self.truncation_rate),
+        ] {
+            if !(0.0..=1.0).contains(&rate) {
+                anyhow::bail!("{} must be between 0 and 1, got {}", name, rate);
+            }
+        }
+        if !(400..=599).contains(&self.error_status) {
+            anyhow::bail!(
+                "error_status must be between 400 and 599, got {}",
+                self.error_status
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Chaos behaviors injected into `/garble`, each switched on and off by its own flag
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FlagsConfig {
+    /// JSON object of flag states, such as `{"errors": true}`, reloaded when it changes
+    pub file: Option<String>,
+    /// How often the flags file is checked for changes
+    pub reload_interval_ms: u64,
+    pub errors: ErrorChaos,
+    pub latency_spikes: LatencyChaos,
+    pub truncation: TruncationChaos,
+}
+
+impl Default for FlagsConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            reload_interval_ms: 5000,
+            errors: ErrorChaos::default(),
+            latency_spikes: LatencyChaos::default(),
+            truncation: TruncationChaos::default(),
+        }
+    }
+}
+
+impl FlagsConfig {
+    pub fn validate(&self) -> Result<()> {
+        for (flag, probability) in [
+            ("errors", self.errors.probability),
+            ("latency_spikes", self.latency_spikes.probability),
+            ("truncation", self.truncation.probability),
+        ] {
+            if !(0.0..=1.0).contains(&probability) {
+                anyhow::bail!(
+                    "flags.{}.probability must be between 0 and 1, got {}",
+                    flag,
+                    probability
+                );
+            }
+        }
+        if !(400..=599).contains(&self.errors.status) {
+            anyhow::bail!(
+                "flags.errors.status must be between 400 and 599, got {}",
+                self.errors.status
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Fail a share of requests with an error status
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ErrorChaos {
+    /// Initial state of the `errors` flag
+    pub enabled: bool,
+    pub probability: f64,
+    pub status: u16,
+}
+
+impl Default for ErrorChaos {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            probability: 0.1,
+            status: 500,
+        }
+    }
+}
+
+/// Add a long delay to a share of requests
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LatencyChaos {
+    /// Initial state of the `latency_spikes` flag
+    pub enabled: bool,
+    pub probability: f64,
+    pub delay_ms: u64,
+}
+
+impl Default for LatencyChaos {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            probability: 0.1,
+            delay_ms: 2000,
+        }
+    }
+}
+
+/// Cut a share of response bodies off partway and drop the connection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TruncationChaos {
+    /// Initial state of the `truncation` flag
+    pub enabled: bool,
+    pub probability: f64,
+}
+
+impl Default for TruncationChaos {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            probability: 0.1,
+        }
+    }
+}
+
+/// Forwarding of requests no endpoint or route answers to a real upstream.
+/// The shapes of its JSON responses are learned per route, so schema-shaped
+/// garble can be served when it is down
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProxyConfig {
+    /// Base URL of the upstream; proxying is off when unset
+    pub upstream: Option<String>,
+    /// Forward to the upstream and learn from its responses; when off, every
+    /// request is answered from the learned schemas
+    pub record: bool,
+    /// File the learned schemas are loaded from and saved to
+    pub schema_file: Option<String>,
+    pub timeout_ms: u64,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            upstream: None,
+            record: true,
+            schema_file: None,
+            timeout_ms: 5000,
+        }
+    }
+}
+
+/// Confluent-compatible schema registry for `/garble/avro?format=confluent`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AvroConfig {
+    /// Base URL of the registry; Confluent wire-format output is off when unset
+    pub registry_url: Option<String>,
+    /// Subject the schema is registered under
+    pub subject: String,
+    pub timeout_ms: u64,
+}
+
+impl Default for AvroConfig {
+    fn default() -> Self {
+        Self {
+            registry_url: None,
+            subject: "daddle-value".to_string(),
+            timeout_ms: 5000,
+        }
+    }
+}
+
+/// Files `daddle corpus build` generates once and `/corpus/{name}` serves from disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CorpusConfig {
+    /// Directory holding the generated files and their manifest
+    pub dir: String,
+    pub files: Vec<CorpusFileConfig>,
+}
+
+impl Default for CorpusConfig {
+    fn default() -> Self {
+        Self {
+            dir: "corpus".to_string(),
+            files: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CorpusFileConfig {
+    /// File name, also the last segment of its URL
+    pub name: String,
+    pub size_bytes: usize,
+    /// Files built from the same seed are byte-identical
+    #[serde(default)]
+    pub seed: u64,
+    #[serde(default)]
+    pub format: CorpusFormat,
+}
+
+/// Layout of a corpus file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CorpusFormat {
+    /// One chunked document, like a streamed `/garble` response
+    #[default]
+    Json,
+    /// One garbled value per line
+    Ndjson,
+}
+
+impl CorpusFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            CorpusFormat::Json => "application/json",
+            CorpusFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+}
+
+impl CorpusFileConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let valid_name = !self.name.is_empty()
+            && !self.name.starts_with('.')
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+        if !valid_name {
+            anyhow::bail!(
+                "corpus file name {:?} must be letters, digits, '.', '-' or '_' and not start with '.'",
+                self.name
+            );
+        }
+        if self.name == "manifest.json" {
+            anyhow::bail!("corpus file name manifest.json is reserved for the manifest");
+        }
+        if self.size_bytes == 0 {
+            anyhow::bail!("corpus file {} must have a size_bytes above 0", self.name);
+        }
+        Ok(())
+    }
+}
+
+/// Identity of this instance within a fleet, and where it follows the shared scenario from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClusterConfig {
+    /// Reported in the `X-Daddle-Instance` header; the host name or a random id when unset
+    pub instance_id: Option<String>,
+    /// Scenario to follow, usually another instance's `/admin/scenario`; a leader when unset
+    pub leader_url: Option<String>,
+    pub poll_interval_ms: u64,
+    pub timeout_ms: u64,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            instance_id: None,
+            leader_url: None,
+            poll_interval_ms: 2000,
+            timeout_ms: 1000,
+        }
+    }
+}
+
+/// A downstream service called by `/garble?dependencies=...`, often another daddle instance
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DependencyConfig {
+    pub name: String,
+    pub url: String,
+    #[serde(default = "default_dependency_method")]
+    pub method: String,
+    #[serde(default = "default_dependency_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Whether a failed call fails the whole request; optional ones are only reported
+    #[serde(default = "default_required")]
+    pub required: bool,
+    /// Failure injected into every call, also switchable through `/admin/dependencies`
+    #[serde(default)]
+    pub fault: DependencyFault,
+}
+
+/// Failure injected into calls to a dependency
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum DependencyFault {
+    /// Call the dependency as configured
+    #[default]
+    None,
+    /// Hang until the call times out, without calling the dependency
+    Timeout,
+    /// Answer with a server error, without calling the dependency
+    Error {
+        #[serde(default = "default_fault_status")]
+        status: u16,
+    },
+    /// Delay the call, timing out when the delay exceeds the timeout
+    Slow { delay_ms: u64 },
+}
+
+impl DependencyFault {
+    pub fn validate(&self) -> Result<()> {
+        if let DependencyFault::Error { status } = self {
+            if !(500..=599).contains(status) {
+                anyhow::bail!("fault status must be between 500 and 599, got {}", status);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_fault_status() -> u16 {
+    503
+}
+
+fn default_dependency_method() -> String {
+    "GET".to_string()
+}
+
+fn default_dependency_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_required() -> bool {
+    true
+}
+
+/// Asynchronous copies of every received request, posted to a collector
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MirrorConfig {
+    /// Collector receiving a JSON summary of each request; mirroring is off when unset
+    pub url: Option<String>,
+    pub timeout_ms: u64,
+    /// How much of each request body is included in the summary
+    pub body_preview_bytes: usize,
+    /// Summaries waiting to be posted; further requests are not mirrored while it is full
+    pub queue_size: usize,
+    /// Headers whose values are replaced by `[redacted]`
+    pub redact_headers: Vec<String>,
+}
+
+impl Default for MirrorConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            timeout_ms: 2000,
+            body_preview_bytes: 1024,
+            queue_size: 1000,
+            redact_headers: vec![
+                "authorization".to_string(),
+                "cookie".to_string(),
+                "x-api-key".to_string(),
+            ],
+        }
+    }
+}
+
+/// Relative weights of the formats used by `dates=mixed`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DateFormatWeights {
+    /// Seconds since the Unix epoch, as a JSON number
+    pub epoch_seconds: u32,
+    /// Milliseconds since the Unix epoch, as a JSON number
+    pub epoch_millis: u32,
+    /// RFC 3339 with a random UTC offset
+    pub rfc3339: u32,
+    /// RFC 2822 with a random UTC offset
+    pub rfc2822: u32,
+    /// ISO week date such as `2025-W26-7`
+    pub iso_week: u32,
+    /// Ambiguous US style `MM/DD/YYYY`
+    pub us_date: u32,
+}
+
+impl Default for DateFormatWeights {
+    fn default() -> Self {
+        Self {
+            epoch_seconds: 1,
+            epoch_millis: 1,
+            rfc3339: 1,
+            rfc2822: 1,
+            iso_week: 1,
+            us_date: 1,
+        }
+    }
+}
+
+impl DateFormatWeights {
+    /// Weights in the order epoch seconds, epoch millis, RFC 3339, RFC 2822, ISO week, US date
+    pub fn as_array(&self) -> [u32; 6] {
+        [
+            self.epoch_seconds,
+            self.epoch_millis,
+            self.rfc3339,
+            self.rfc2822,
+            self.iso_week,
+            self.us_date,
+        ]
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.as_array().iter().all(|&weight| weight == 0) {
+            anyhow::bail!("date_formats needs at least one format with a non-zero weight");
+        }
+        Ok(())
+    }
+}
+
+/// Shape of the length distribution for generated string values
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LengthDistribution {
+    /// Every length between the bounds is equally likely
+    #[default]
+    Uniform,
+    /// Lengths cluster around `mean` with spread `std_dev`
+    Normal,
+    /// Pareto tail starting at `min_length`: mostly short strings, occasionally huge ones
+    Pareto,
+}
+
+/// Length distribution and bounds for generated string values
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StringLengthConfig {
+    pub distribution: LengthDistribution,
+    /// Shortest string generated
+    pub min_length: usize,
+    /// Longest string generated; samples beyond it are clamped
+    pub max_length: usize,
+    /// Mean of the normal distribution
+    pub mean: f64,
+    /// Standard deviation of the normal distribution
+    pub std_dev: f64,
+    /// Shape of the Pareto distribution; smaller values give a heavier tail
+    pub tail_index: f64,
+}
+
+impl Default for StringLengthConfig {
+    fn default() -> Self {
+        Self {
+            distribution: LengthDistribution::Uniform,
+            min_length: 1,
+            max_length: 50,
+            mean: 20.0,
+            std_dev: 10.0,
+            tail_index: 1.5,
+        }
+    }
+}
+
+impl StringLengthConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.min_length > self.max_length {
+            anyhow::bail!(
+                "string_lengths.min_length ({}) is greater than max_length ({})",
+                self.min_length,
+                self.max_length
+            );
+        }
+        if !self.std_dev.is_finite() || self.std_dev < 0.0 {
+            anyhow::bail!("string_lengths.std_dev must not be negative");
+        }
+        if !self.tail_index.is_finite() || self.tail_index <= 0.0 {
+            anyhow::bail!("string_lengths.tail_index must be positive");
+        }
+        Ok(())
+    }
+}
+
+/// Corpus and order of the Markov model behind `text=markov`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MarkovConfig {
+    /// Text files to train on; the built-in corpus is used when empty
+    pub corpus_files: Vec<String>,
+    /// Number of preceding words each next word depends on
+    pub order: usize,
+}
+
+impl Default for MarkovConfig {
+    fn default() -> Self {
+        Self {
+            corpus_files: Vec::new(),
+            order: 2,
+        }
+    }
+}
+
+/// Word lists mixed into generated keys and string values
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DictionaryConfig {
+    /// Files with one key per line
+    pub key_files: Vec<String>,
+    /// Files with one string value per line
+    pub value_files: Vec<String>,
+    /// Probability that a key or string value comes from the word lists
+    pub usage_ratio: f64,
+    /// How often the files are checked for changes
+    pub reload_interval_ms: u64,
+}
+
+impl Default for DictionaryConfig {
+    fn default() -> Self {
+        Self {
+            key_files: Vec::new(),
+            value_files: Vec::new(),
+            usage_ratio: 0.5,
+            reload_interval_ms: 5000,
+        }
+    }
+}
+
+impl DictionaryConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.key_files.is_empty() || !self.value_files.is_empty()
+    }
+}
+
+/// Custom value generators on top of the built-in ones
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GeneratorsConfig {
+    /// Generators producing strings that follow a pattern
+    pub patterns: Vec<PatternGeneratorConfig>,
+    /// Probability that a generated scalar comes from the weighted generators instead
+    pub usage_ratio: f64,
+    /// Generators mixed into garble by name, with their relative weights
+    pub weights: BTreeMap<String, u32>,
+}
+
+impl GeneratorsConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(0.0..=1.0).contains(&self.usage_ratio) {
+            anyhow::bail!("generators.usage_ratio must be between 0 and 1");
+        }
+        if self.usage_ratio > 0.0 && self.weights.values().all(|&weight| weight == 0) {
+            anyhow::bail!(
+                "generators.usage_ratio needs at least one generator with a non-zero weight"
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HangConfig {
+    /// How long a request is held before giving up on it
+    pub max_duration_ms: u64,
+    /// Most requests held at once; further ones are answered with a 503
+    pub max_concurrent: u64,
+    /// Pause between reads of a blackholed request body
+    pub blackhole_read_interval_ms: u64,
+}
+
+impl Default for HangConfig {
+    fn default() -> Self {
+        Self {
+            max_duration_ms: 300_000,
+            max_concurrent: 1000,
+            blackhole_read_interval_ms: 1000,
+        }
+    }
+}
+
+/// What happens to a request arriving while the admission queue is full
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Answer the newcomer with a 503
+    #[default]
+    Reject,
+    /// Answer the longest waiting request with a 503 and queue the newcomer
+    ShedOldest,
+    /// Serve the newcomer right away with a body of at most `degraded_body_size`
+    Degrade,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdmissionConfig {
+    /// Most garble responses generated at once; unset admits everything
+    pub max_concurrent: Option<usize>,
+    /// Most requests waiting for a slot before the overflow policy applies
+    pub queue_depth: usize,
+    pub overflow: OverflowPolicy,
+    /// Largest body served under the `degrade` policy
+    pub degraded_body_size: usize,
+    /// Longest a request waits in the queue before it is answered with a 503
+    pub queue_timeout_ms: u64,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: None,
+            queue_depth: 100,
+            overflow: OverflowPolicy::Reject,
+            degraded_body_size: 1024,
+            queue_timeout_ms: 30_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// Compress garble responses for clients that send `Accept-Encoding`
+    pub enabled: bool,
+    pub gzip: bool,
+    pub brotli: bool,
+    /// Keep compressed forms of pooled chunks so responses are stitched together
+    /// instead of compressed per request
+    pub precompress: bool,
+    /// Deflate level of gzip members, 0 to 9
+    pub gzip_level: u32,
+    /// Quality of brotli members, 0 to 11
+    pub brotli_quality: u32,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            gzip: true,
+            brotli: true,
+            precompress: true,
+            gzip_level: 6,
+            brotli_quality: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResponseCacheConfig {
+    /// Keep fully assembled garble responses and replay them for repeat requests
+    /// of the same exact size, format and profile
+    pub enabled: bool,
+    /// How long a cached response is served before it is assembled afresh
+    pub ttl_ms: u64,
+    /// Memory all cached responses may take together; least recently used go first
+    pub max_memory_mb: usize,
+    /// Largest target size that is cached
+    pub max_entry_mb: usize,
+}
+
+impl Default for ResponseCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl_ms: 60000,
+            max_memory_mb: 256,
+            max_entry_mb: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    /// AES key as hex: 32, 48 or 64 digits for AES-128, AES-192 or AES-256 GCM
+    pub key: Option<String>,
+    /// Identifier sent along with encrypted bodies, so the receiver can pick its key
+    pub key_id: Option<String>,
+}
+
+/// Order of the bytes in a frame's length field
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ByteOrder {
+    #[default]
+    Big,
+    Little,
+}
+
+/// What frame payloads are made of
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FramePayload {
+    /// A garbled JSON document per frame
+    #[default]
+    Json,
+    /// Random bytes
+    Binary,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FramesConfig {
+    /// Width of the length field: 1, 2, 4 or 8 bytes
+    pub length_bytes: usize,
+    pub byte_order: ByteOrder,
+    /// Count the header itself in the length, instead of the payload alone
+    pub length_includes_header: bool,
+    /// Values the type byte after the length is picked from; empty leaves it out
+    pub types: Vec<u8>,
+    pub payload: FramePayload,
+    pub min_payload_bytes: usize,
+    pub max_payload_bytes: usize,
+    /// Port of a raw TCP listener writing frames to every connection; off when unset
+    pub tcp_port: Option<u16>,
+    /// Frames written per TCP connection before it is closed; 0 keeps writing
+    /// until the client goes away
+    pub frames_per_connection: usize,
+    /// Pause between frames written over TCP
+    pub interval_ms: u64,
+}
+
+impl Default for FramesConfig {
+    fn default() -> Self {
+        Self {
+            length_bytes: 4,
+            byte_order: ByteOrder::Big,
+            length_includes_header: false,
+            types: vec![1],
+            payload: FramePayload::Json,
+            min_payload_bytes: 16,
+            max_payload_bytes: 1024,
+            tcp_port: None,
+            frames_per_connection: 100,
+            interval_ms: 0,
+        }
+    }
+}
+
+/// Health checks and failures shaped for service mesh health checking and
+/// outlier detection, such as Envoy's
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutlierConfig {
+    /// Path answering the simulated health check
+    pub health_path: String,
+    /// Phases the health check cycles through, healthy then degraded then
+    /// unhealthy; with all three zero it stays healthy
+    pub healthy_ms: u64,
+    pub degraded_ms: u64,
+    pub unhealthy_ms: u64,
+    /// Status of the health check while unhealthy
+    pub unhealthy_status: u16,
+    /// Start at a random point of the cycle, so a fleet does not flap in step
+    pub stagger: bool,
+    /// Share of `/garble` requests failed, rising over time
+    pub error_ramp: Option<ErrorRamp>,
+}
+
+impl Default for OutlierConfig {
+    fn default() -> Self {
+        Self {
+            health_path: "/healthz".to_string(),
+            healthy_ms: 0,
+            degraded_ms: 0,
+            unhealthy_ms: 0,
+            unhealthy_status: 503,
+            stagger: true,
+            error_ramp: None,
+        }
+    }
+}
+
+impl OutlierConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.health_path.starts_with('/') || self.health_path.contains([':', '*']) {
+            anyhow::bail!(
+                "outlier.health_path must be a plain path starting with /, got {}",
+                self.health_path
+            );
+        }
+        // Routes daddle serves itself cannot be taken over
+        if ["/health", "/stats", "/metrics", "/garble", "/corpus"]
+            .contains(&self.health_path.as_str())
+            || ["/admin/", "/debug/", "/stats/", "/garble/", "/cache/"]
+                .iter()
+                .any(|prefix| self.health_path.starts_with(prefix))
+        {
+            anyhow::bail!(
+                "outlier.health_path {} is already served by daddle",
+                self.health_path
+            );
+        }
+        if !(500..=599).contains(&self.unhealthy_status) {
+            anyhow::bail!(
+                "outlier.unhealthy_status must be between 500 and 599, got {}",
+                self.unhealthy_status
+            );
+        }
+        if let Some(ramp) = &self.error_ramp {
+            for (name, rate) in [("start_rate", ramp.start_rate), ("max_rate", ramp.max_rate)] {
+                if !(0.0..=1.0).contains(&rate) {
+                    anyhow::bail!(
+                        "outlier.error_ramp.{} must be between 0 and 1, got {}",
+                        name,
+                        rate
+                    );
+                }
+            }
+            if !(500..=599).contains(&ramp.status) {
+                anyhow::bail!(
+                    "outlier.error_ramp.status must be between 500 and 599, got {}",
+                    ramp.status
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Error rate growing linearly from `start_rate` to `max_rate`, then holding
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ErrorRamp {
+    /// Time before failures start
+    pub delay_ms: u64,
+    /// Time to climb from `start_rate` to `max_rate`
+    pub ramp_ms: u64,
+    pub start_rate: f64,
+    pub max_rate: f64,
+    /// Status of the failed requests
+    pub status: u16,
+}
+
+impl Default for ErrorRamp {
+    fn default() -> Self {
+        Self {
+            delay_ms: 0,
+            ramp_ms: 60_000,
+            start_rate: 0.0,
+            max_rate: 0.5,
+            status: 503,
+        }
+    }
+}
+
+/// Links connections are shaped like, one picked per connection by weight;
+/// without any, connections are not shaped
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectionShapingConfig {
+    pub links: Vec<LinkConfig>,
+}
+
+/// The bandwidth of a last-mile link, shared by every request on a connection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkConfig {
+    pub name: String,
+    /// Response bytes per second; unlimited when unset
+    #[serde(default)]
+    pub down_bytes_per_second: Option<u64>,
+    /// Request body bytes per second; unlimited when unset
+    #[serde(default)]
+    pub up_bytes_per_second: Option<u64>,
+    /// Bytes that may go out at once after the connection was idle
+    #[serde(default = "default_link_burst_bytes")]
+    pub burst_bytes: u64,
+    /// Relative share of connections on this link
+    #[serde(default = "default_link_weight")]
+    pub weight: u32,
+}
+
+fn default_link_burst_bytes() -> u64 {
+    16 * 1024
+}
+
+fn default_link_weight() -> u32 {
+    1
+}
+
+impl ConnectionShapingConfig {
+    pub fn validate(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        for link in &self.links {
+            if !names.insert(link.name.as_str()) {
+                anyhow::bail!("connection_shaping.links has more than one {}", link.name);
+            }
+            for (field, rate) in [
+                ("down_bytes_per_second", link.down_bytes_per_second),
+                ("up_bytes_per_second", link.up_bytes_per_second),
+            ] {
+                if rate == Some(0) {
+                    anyhow::bail!(
+                        "connection_shaping.links.{}.{} must be at least 1",
+                        link.name,
+                        field
+                    );
+                }
+            }
+            if link.burst_bytes == 0 {
+                anyhow::bail!(
+                    "connection_shaping.links.{}.burst_bytes must be at least 1",
+                    link.name
+                );
+            }
+        }
+        if !self.links.is_empty() && self.links.iter().all(|link| link.weight == 0) {
+            anyhow::bail!("connection_shaping needs at least one link with a non-zero weight");
+        }
+        Ok(())
+    }
+}
+
+/// Smallest connection window HTTP/2 allows, as every connection starts with it
+pub const MIN_HTTP2_CONNECTION_WINDOW: u32 = 65_535;
+/// Largest flow control window HTTP/2 allows
+pub const MAX_HTTP2_WINDOW: u32 = (1 << 31) - 1;
+
+/// Flow control windows advertised to HTTP/2 clients; hyper's defaults apply when unset
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Http2Config {
+    /// Request body bytes a client may send on one stream before it waits for a window update
+    pub initial_stream_window_bytes: Option<u32>,
+    /// Request body bytes a client may send across all streams of a connection
+    pub initial_connection_window_bytes: Option<u32>,
+}
+
+impl Http2Config {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(window) = self.initial_stream_window_bytes {
+            if !(1..=MAX_HTTP2_WINDOW).contains(&window) {
+                anyhow::bail!(
+                    "http2.initial_stream_window_bytes must be between 1 and {}",
+                    MAX_HTTP2_WINDOW
+                );
+            }
+        }
+        if let Some(window) = self.initial_connection_window_bytes {
+            if !(MIN_HTTP2_CONNECTION_WINDOW..=MAX_HTTP2_WINDOW).contains(&window) {
+                anyhow::bail!(
+                    "http2.initial_connection_window_bytes must be between {} and {}",
+                    MIN_HTTP2_CONNECTION_WINDOW,
+                    MAX_HTTP2_WINDOW
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FaultsConfig {
+    /// Share of garble requests answered with an error, from 0 to 1, unless
+    /// a request sets `errorRate`
+    pub error_rate: f64,
+    /// Error statuses picked from, by weight
+    pub statuses: BTreeMap<u16, u32>,
+    /// Approximate size of the garbled error body
+    pub body_bytes: usize,
+    /// Sent as `Retry-After` on `429` and `503` errors
+    pub retry_after_secs: Option<u64>,
+}
+
+impl Default for FaultsConfig {
+    fn default() -> Self {
+        Self {
+            error_rate: 0.0,
+            statuses: BTreeMap::from([(500, 1), (502, 1), (503, 1), (504, 1)]),
+            body_bytes: 256,
+            retry_after_secs: None,
+        }
+    }
+}
+
+impl FaultsConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(0.0..=1.0).contains(&self.error_rate) {
+            anyhow::bail!(
+                "faults.error_rate must be between 0 and 1, got {}",
+                self.error_rate
+            );
+        }
+        if let Some(status) = self
+            .statuses
+            .keys()
+            .find(|status| !(400..=599).contains(*status))
+        {
+            anyhow::bail!(
+                "faults.statuses must be between 400 and 599, got {}",
+                status
+            );
+        }
+        if self.statuses.values().all(|&weight| weight == 0) {
+            anyhow::bail!("faults.statuses needs at least one status with a non-zero weight");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeaderHazardsConfig {
+    /// Whether requests may ask for hazardous headers at all
+    pub enabled: bool,
+    /// Longest value of the `long` header
+    pub max_value_bytes: usize,
+}
+
+impl Default for HeaderHazardsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_value_bytes: 65_536,
+        }
+    }
+}
+
+impl HeaderHazardsConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(1..=MAX_HAZARD_VALUE_BYTES).contains(&self.max_value_bytes) {
+            anyhow::bail!(
+                "header_hazards.max_value_bytes must be between 1 and {}, got {}",
+                MAX_HAZARD_VALUE_BYTES,
+                self.max_value_bytes
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Upper bound for `header_hazards.max_value_bytes`
+const MAX_HAZARD_VALUE_BYTES: usize = 1_048_576;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatsExportConfig {
+    /// File the stats snapshot is written to; off when unset
+    pub path: Option<String>,
+    /// Pause between snapshots while running; unset writes only on shutdown
+    pub interval_ms: Option<u64>,
+}
+
+impl StatsExportConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.interval_ms == Some(0) {
+            anyhow::bail!("stats_export.interval_ms must be positive");
+        }
+        if self.path.as_deref() == Some("") {
+            anyhow::bail!("stats_export.path must not be empty");
+        }
+        Ok(())
+    }
+}
+
+/// Longest nesting a structure preset may ask for
+pub const MAX_PRESET_DEPTH: usize = 32;
+/// Most fields per object, or elements per array, a structure preset may ask for
+pub const MAX_PRESET_WIDTH: usize = 1000;
+
+/// A named shape for garble documents: how deep and wide they get and which
+/// kinds of values they are made of
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StructurePreset {
+    /// Deepest nesting of objects and arrays below the root
+    pub max_depth: usize,
+    /// Fewest fields in a generated object
+    pub min_fields: usize,
+    /// Most fields in a generated object
+    pub max_fields: usize,
+    /// Most elements in a generated array
+    pub max_array_length: usize,
+    /// Chance that an object field holds a nested object, on top of the type weights
+    pub nest_probability: f64,
+    pub type_weights: TypeWeights,
+    /// Length distribution for strings, replacing `string_lengths` for this preset
+    pub string_lengths: Option<StringLengthConfig>,
+}
+
+impl Default for StructurePreset {
+    fn default() -> Self {
+        Self {
+            max_depth: 3,
+            min_fields: 1,
+            max_fields: 14,
+            max_array_length: 10,
+            nest_probability: 0.3,
+            type_weights: TypeWeights::default(),
+            string_lengths: None,
+        }
+    }
+}
+
+impl StructurePreset {
+    pub fn validate(&self, name: &str) -> Result<()> {
+        if self.max_depth > MAX_PRESET_DEPTH {
+            anyhow::bail!(
+                "presets.{}.max_depth must be at most {}",
+                name,
+                MAX_PRESET_DEPTH
+            );
+        }
+        if self.min_fields == 0 || self.min_fields > self.max_fields {
+            anyhow::bail!(
+                "presets.{} needs 1 <= min_fields <= max_fields, got {} and {}",
+                name,
+                self.min_fields,
+                self.max_fields
+            );
+        }
+        if self.max_fields > MAX_PRESET_WIDTH || self.max_array_length > MAX_PRESET_WIDTH {
+            anyhow::bail!(
+                "presets.{} allows at most {} fields and array elements",
+                name,
+                MAX_PRESET_WIDTH
+            );
+        }
+        if !(0.0..=1.0).contains(&self.nest_probability) {
+            anyhow::bail!("presets.{}.nest_probability must be between 0 and 1", name);
+        }
+        if self
+            .type_weights
+            .scalars()
+            .iter()
+            .all(|&weight| weight == 0)
+        {
+            anyhow::bail!(
+                "presets.{}.type_weights needs at least one scalar type with a non-zero weight",
+                name
+            );
+        }
+        if let Some(lengths) = &self.string_lengths {
+            lengths
+                .validate()
+                .with_context(|| format!("invalid presets.{}.string_lengths", name))?;
+        }
+        Ok(())
+    }
+}
+
+/// Relative weights of the kinds of values a structure preset generates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TypeWeights {
+    /// Plain text: dictionary words, Markov sentences or random characters
+    pub text: u32,
+    pub integer: u32,
+    pub float: u32,
+    pub boolean: u32,
+    pub null: u32,
+    pub uuid: u32,
+    /// Strings of garbled characters
+    pub garbled: u32,
+    pub date: u32,
+    pub array: u32,
+    pub object: u32,
+    pub hex: u32,
+    pub base64: u32,
+}
+
+impl Default for TypeWeights {
+    fn default() -> Self {
+        Self {
+            text: 1,
+            integer: 1,
+            float: 1,
+            boolean: 1,
+            null: 1,
+            uuid: 1,
+            garbled: 1,
+            date: 1,
+            array: 1,
+            object: 1,
+            hex: 1,
+            base64: 1,
+        }
+    }
+}
+
+impl TypeWeights {
+    /// All weights zero, to build up presets made of a few kinds of values
+    pub fn none() -> Self {
+        Self {
+            text: 0,
+            integer: 0,
+            float: 0,
+            boolean: 0,
+            null: 0,
+            uuid: 0,
+            garbled: 0,
+            date: 0,
+            array: 0,
+            object: 0,
+            hex: 0,
+            base64: 0,
+        }
+    }
+
+    /// Weights in the order garble picks value kinds in: text, integer, float,
+    /// boolean, null, UUID, garbled, date, array, object, hex, base64
+    pub fn as_array(&self) -> [u32; 12] {
+        [
+            self.text,
+            self.integer,
+            self.float,
+            self.boolean,
+            self.null,
+            self.uuid,
+            self.garbled,
+            self.date,
+            self.array,
+            self.object,
+            self.hex,
+            self.base64,
+        ]
+    }
+
+    /// Weights in the same order with the containers zeroed, for values at the deepest level
+    pub fn scalars(&self) -> [u32; 12] {
+        Self {
+            array: 0,
+            object: 0,
+            ..self.clone()
+        }
+        .as_array()
+    }
+}
+
+/// A generator for identifiers in a fixed format: `#` is a digit, `A` an
+/// uppercase letter, `a` a lowercase letter, `X` a hex digit, `?` a letter or
+/// digit, and a backslash makes the next character literal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PatternGeneratorConfig {
+    pub name: String,
+    pub pattern: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TenantConfig {
+    pub name: String,
+    /// API keys identifying this tenant, sent as `X-Api-Key` or a bearer token
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    /// Profile replacing the global `garble` section for this tenant
+    pub garble: Option<GarbleConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
+    /// How long in-flight responses may keep running after a shutdown signal
+    #[serde(default = "default_drain_timeout_ms")]
+    pub drain_timeout_ms: u64,
+}
+
+fn default_drain_timeout_ms() -> u64 {
+    30_000
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,6 +1583,96 @@ pub struct GarbleConfig {
     pub max_body_size: usize,
     pub min_wait_duration_ms: u64,
     pub max_wait_duration_ms: u64,
+    /// Reject inconsistent or out-of-range query parameters with a 400 instead
+    /// of silently swapping or clamping them
+    #[serde(default)]
+    pub strict_validation: bool,
+    /// Safety cap on the requested body size
+    pub body_size_cap: Option<usize>,
+    /// What happens when a caller asks for a body beyond `body_size_cap`
+    #[serde(default)]
+    pub oversize: OversizeAction,
+    /// Safety cap on the requested wait duration
+    pub wait_duration_cap_ms: Option<u64>,
+    /// How wait durations are spread within the min/max range
+    #[serde(default)]
+    pub wait_jitter: WaitJitter,
+    /// Replace the `detail` of problem+json error responses with garbled text
+    #[serde(default)]
+    pub garble_error_details: bool,
+}
+
+impl GarbleConfig {
+    pub fn validate(&self) -> Result<()> {
+        self.wait_jitter.validate()
+    }
+}
+
+/// Shape of the wait duration distribution; every model stays within the
+/// resolved min/max range
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WaitJitter {
+    pub model: JitterModel,
+    /// Wait that the `percentage` model jitters around
+    pub base_ms: u64,
+    /// Largest deviation from the base or mode, as a percentage of it
+    pub jitter_percent: f64,
+    /// Typical wait of fast responses in the `bimodal` model, such as cache hits
+    pub fast_ms: u64,
+    /// Typical wait of slow responses in the `bimodal` model, such as cache misses
+    pub slow_ms: u64,
+    /// Share of slow responses in the `bimodal` model
+    pub slow_probability: f64,
+}
+
+impl Default for WaitJitter {
+    fn default() -> Self {
+        Self {
+            model: JitterModel::Uniform,
+            base_ms: 100,
+            jitter_percent: 20.0,
+            fast_ms: 20,
+            slow_ms: 500,
+            slow_probability: 0.1,
+        }
+    }
+}
+
+impl WaitJitter {
+    pub fn validate(&self) -> Result<()> {
+        if !self.jitter_percent.is_finite() || !(0.0..=100.0).contains(&self.jitter_percent) {
+            anyhow::bail!("wait_jitter.jitter_percent must be between 0 and 100");
+        }
+        if !(0.0..=1.0).contains(&self.slow_probability) {
+            anyhow::bail!("wait_jitter.slow_probability must be between 0 and 1");
+        }
+        Ok(())
+    }
+}
+
+/// How wait durations are drawn
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JitterModel {
+    /// Evenly across the min/max range
+    #[default]
+    Uniform,
+    /// Around `base_ms`, give or take `jitter_percent`
+    Percentage,
+    /// Mostly around `fast_ms`, sometimes around `slow_ms`
+    Bimodal,
+}
+
+/// How requests for bodies beyond the cap are handled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OversizeAction {
+    /// Answer 413 with the cap in the problem details
+    #[default]
+    Reject,
+    /// Generate a body of at most the cap
+    Clamp,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,6 +1685,13 @@ pub struct PerformanceConfig {
     pub background_generation_interval_ms: u64,
     pub memory_check_interval_ms: u64,
     pub enable_parallel_generation: bool,
+    /// Hard process memory ceiling; large requests are rejected and background
+    /// generation pauses as usage approaches it
+    pub memory_ceiling_mb: Option<usize>,
+    /// Where to persist the chunk pool on shutdown and restore it from on startup
+    pub pool_snapshot_path: Option<String>,
+    /// Longest time generating one response may take before it is abandoned
+    pub generation_budget_ms: Option<u64>,
 }
 
 impl Default for Config {
@@ -45,12 +1700,19 @@ impl Default for Config {
             server: ServerConfig {
                 host: "0.0.0.0".to_string(),
                 port: 3000,
+                drain_timeout_ms: default_drain_timeout_ms(),
             },
             garble: GarbleConfig {
                 min_body_size: 100,
                 max_body_size: 10000,
                 min_wait_duration_ms: 0,
                 max_wait_duration_ms: 1000,
+                strict_validation: false,
+                body_size_cap: None,
+                oversize: OversizeAction::default(),
+                wait_duration_cap_ms: None,
+                wait_jitter: WaitJitter::default(),
+                garble_error_details: false,
             },
             performance: PerformanceConfig {
                 chunk_pool_max_memory_mb: 8,
@@ -61,12 +1723,96 @@ impl Default for Config {
                 background_generation_interval_ms: 1000,
                 memory_check_interval_ms: 5000,
                 enable_parallel_generation: true,
+                memory_ceiling_mb: None,
+                pool_snapshot_path: None,
+                generation_budget_ms: None,
             },
+            tenants: Vec::new(),
+            dictionary: DictionaryConfig::default(),
+            markov: MarkovConfig::default(),
+            string_lengths: StringLengthConfig::default(),
+            date_formats: DateFormatWeights::default(),
+            mirror: MirrorConfig::default(),
+            dependencies: Vec::new(),
+            cluster: ClusterConfig::default(),
+            flags: FlagsConfig::default(),
+            routes: Vec::new(),
+            rules: Vec::new(),
+            path_faults: Vec::new(),
+            proxy: ProxyConfig::default(),
+            catch_all: CatchAllConfig::default(),
+            avro: AvroConfig::default(),
+            corpus: CorpusConfig::default(),
+            generators: GeneratorsConfig::default(),
+            presets: BTreeMap::new(),
+            post_processors: BTreeMap::new(),
+            hang: HangConfig::default(),
+            admission: AdmissionConfig::default(),
+            compression: CompressionConfig::default(),
+            response_cache: ResponseCacheConfig::default(),
+            encryption: EncryptionConfig::default(),
+            frames: FramesConfig::default(),
+            outlier: OutlierConfig::default(),
+            connection_shaping: ConnectionShapingConfig::default(),
+            http2: Http2Config::default(),
+            stats_export: StatsExportConfig::default(),
+            faults: FaultsConfig::default(),
+            header_hazards: HeaderHazardsConfig::default(),
         }
     }
 }
 
+/// Stands in for secrets in exported configurations
+pub const REDACTED: &str = "[redacted]";
+
 impl Config {
+    /// This configuration with tenant API keys and the encryption key replaced
+    /// by `[redacted]`, for handing out over the admin API
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        for tenant in &mut config.tenants {
+            for key in &mut tenant.api_keys {
+                *key = REDACTED.to_string();
+            }
+        }
+        if config.encryption.key.is_some() {
+            config.encryption.key = Some(REDACTED.to_string());
+        }
+        config
+    }
+
+    /// Put back the secrets of `current` where this configuration has them
+    /// redacted, matching tenants by name; redacted keys of a tenant `current`
+    /// does not have are dropped
+    pub fn restore_secrets(&mut self, current: &Config) {
+        for tenant in &mut self.tenants {
+            let before = tenant.api_keys.len();
+            tenant.api_keys.retain(|key| key != REDACTED);
+            if tenant.api_keys.len() == before {
+                continue;
+            }
+            if let Some(kept) = current.tenants.iter().find(|kept| kept.name == tenant.name) {
+                tenant.api_keys.extend(kept.api_keys.iter().cloned());
+            }
+        }
+        if self.encryption.key.as_deref() == Some(REDACTED) {
+            self.encryption.key = current.encryption.key.clone();
+        }
+    }
+
+    /// Check the global garble profile and every tenant's
+    pub fn validate_profiles(&self) -> Result<()> {
+        self.garble.validate()?;
+        for tenant in &self.tenants {
+            if let Some(garble) = &tenant.garble {
+                garble
+                    .validate()
+                    .with_context(|| format!("invalid garble profile of tenant {}", tenant.name))?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn load_from_file(path: &str) -> Result<Self> {
         match fs::read_to_string(path) {
             Ok(content) => {
@@ -80,3 +1826,32 @@ impl Config {
         }
     }
 }
+
+/// The configuration requests are served with, replaced when a remote source changes
+pub struct LiveConfig {
+    current: RwLock<Arc<Config>>,
+    generation: AtomicU64,
+}
+
+impl LiveConfig {
+    pub fn new(config: Config) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(config)),
+            generation: AtomicU64::new(1),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, config: Config) {
+        *self.current.write().unwrap() = Arc::new(config);
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Configurations served since startup, counting the initial one
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+}
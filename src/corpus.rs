@@ -0,0 +1,409 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A corpus of large garbled files, generated once by `daddle corpus build` and
+//! then served from disk by `/corpus/{name}`. Each file is generated from its
+//! seed, so rebuilding gives byte-identical content and repeated test runs
+//! download the same artifacts at disk and network speed.
+
+use anyhow::Context;
+use axum::{
+    body::Body,
+    extract::Path,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{BufWriter, Write};
+use std::path::{Path as FsPath, PathBuf};
+use tokio_util::io::ReaderStream;
+
+use crate::chunk_pool::ChunkSize;
+use crate::config::{CorpusConfig, CorpusFileConfig, CorpusFormat, StringLengthConfig};
+use crate::emit::{self, EmitOptions, CHUNK_DEPTH};
+use crate::error::ApiError;
+use crate::generator::{self, GeneratorOptions, RandomDataGenerator, GENERATOR_VERSION};
+
+const MANIFEST_FILE: &str = "manifest.json";
+/// Read size when streaming files from disk
+const READ_BUFFER_BYTES: usize = 256 * 1024;
+
+/// A built file as recorded in the manifest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CorpusEntry {
+    pub name: String,
+    #[serde(default)]
+    pub format: CorpusFormat,
+    pub size_bytes: usize,
+    pub seed: u64,
+    /// Actual length, which overshoots `size_bytes` by up to one chunk
+    pub bytes: u64,
+    pub sha256: String,
+}
+
+impl CorpusEntry {
+    fn built_from(&self, config: &CorpusFileConfig) -> bool {
+        self.name == config.name
+            && self.format == config.format
+            && self.size_bytes == config.size_bytes
+            && self.seed == config.seed
+    }
+
+    fn file_config(&self) -> CorpusFileConfig {
+        CorpusFileConfig {
+            name: self.name.clone(),
+            size_bytes: self.size_bytes,
+            seed: self.seed,
+            format: self.format,
+        }
+    }
+}
+
+/// Everything needed to regenerate a corpus byte for byte on another machine
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CorpusManifest {
+    /// Daddle release that built the corpus, for reference
+    #[serde(default)]
+    pub daddle_version: String,
+    /// Seeded output only repeats between builds with the same generator version
+    #[serde(default)]
+    pub generator_version: u32,
+    /// String length settings the corpus was generated with
+    #[serde(default)]
+    pub string_lengths: StringLengthConfig,
+    pub files: Vec<CorpusEntry>,
+}
+
+impl CorpusManifest {
+    fn read(path: &FsPath) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Ok(Some(serde_json::from_str(&json).with_context(|| {
+            format!("invalid corpus manifest {}", path.display())
+        })?))
+    }
+
+    fn write(&self, dir: &FsPath) -> anyhow::Result<()> {
+        let path = dir.join(MANIFEST_FILE);
+        let partial = path.with_extension("partial");
+        std::fs::write(&partial, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    /// Whether files in this manifest would be generated again as they are
+    fn reproducible_with(&self, string_lengths: &StringLengthConfig) -> bool {
+        self.generator_version == GENERATOR_VERSION && self.string_lengths == *string_lengths
+    }
+}
+
+/// Writer that hashes and counts everything passing through it
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    written: u64,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Write a chunked garble document, laid out like a streamed `/garble` response
+fn write_json(
+    out: &mut HashingWriter<impl Write>,
+    generator: &mut RandomDataGenerator,
+    size: usize,
+) -> std::io::Result<()> {
+    let options = EmitOptions::default();
+    let chunk_bytes = ChunkSize::Large.target_bytes();
+    out.write_all(emit::chunks_open(&options).as_bytes())?;
+    let mut count = 0;
+    while (out.written as usize) < size {
+        let remaining = size - out.written as usize;
+        let chunk = generator.generate_array_element(remaining.min(chunk_bytes));
+        out.write_all(emit::element_separator(count, &options, CHUNK_DEPTH).as_bytes())?;
+        out.write_all(emit::to_json_at(&chunk, &options, CHUNK_DEPTH).as_bytes())?;
+        count += 1;
+    }
+    out.write_all(emit::array_close(count, &options, CHUNK_DEPTH).as_bytes())?;
+    out.write_all(emit::chunks_close(&options).as_bytes())
+}
+
+/// Write one garbled value per line
+fn write_ndjson(
+    out: &mut HashingWriter<impl Write>,
+    generator: &mut RandomDataGenerator,
+    size: usize,
+) -> std::io::Result<()> {
+    let line_bytes = ChunkSize::Small.target_bytes();
+    while (out.written as usize) < size {
+        let remaining = size - out.written as usize;
+        let value = generator.generate_array_element(remaining.min(line_bytes));
+        out.write_all(serde_json::to_string(&value)?.as_bytes())?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+fn generate_file(dir: &FsPath, config: &CorpusFileConfig) -> anyhow::Result<CorpusEntry> {
+    let path = dir.join(&config.name);
+    let partial = path.with_extension("partial");
+    let file = std::fs::File::create(&partial)
+        .with_context(|| format!("failed to create {}", partial.display()))?;
+    let mut out = HashingWriter {
+        inner: BufWriter::new(file),
+        hasher: Sha256::new(),
+        written: 0,
+    };
+
+    let mut generator = RandomDataGenerator::seeded(GeneratorOptions::default(), config.seed);
+    match config.format {
+        CorpusFormat::Json => write_json(&mut out, &mut generator, config.size_bytes)?,
+        CorpusFormat::Ndjson => write_ndjson(&mut out, &mut generator, config.size_bytes)?,
+    }
+    out.flush()?;
+
+    let bytes = out.written;
+    let sha256 = out
+        .hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    drop(out.inner);
+    std::fs::rename(&partial, &path)?;
+
+    Ok(CorpusEntry {
+        name: config.name.clone(),
+        format: config.format,
+        size_bytes: config.size_bytes,
+        seed: config.seed,
+        bytes,
+        sha256,
+    })
+}
+
+/// Options of `daddle corpus build`
+#[derive(Debug, Default)]
+pub struct BuildOptions {
+    /// Regenerate the corpus described by this manifest instead of the configured one
+    pub manifest: Option<PathBuf>,
+    /// Write the corpus here instead of the configured directory
+    pub dir: Option<PathBuf>,
+}
+
+impl BuildOptions {
+    /// Parse `daddle corpus build` command line arguments
+    pub fn from_args(args: &[String]) -> anyhow::Result<Self> {
+        let mut options = BuildOptions::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("{} requires a value", arg))?;
+            match arg.as_str() {
+                "--manifest" | "-m" => options.manifest = Some(PathBuf::from(value)),
+                "--dir" | "-d" => options.dir = Some(PathBuf::from(value)),
+                other => anyhow::bail!("unknown corpus build argument: {}", other),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Generate the corpus and write its manifest, keeping files that were already
+/// built from the same settings. When regenerating from a manifest, every file
+/// must come out with the checksum the manifest records.
+pub async fn build(
+    config: &CorpusConfig,
+    string_lengths: &StringLengthConfig,
+    options: BuildOptions,
+) -> anyhow::Result<CorpusManifest> {
+    let dir = options.dir.unwrap_or_else(|| PathBuf::from(&config.dir));
+    let pinned = match &options.manifest {
+        Some(path) => Some(
+            CorpusManifest::read(path)?
+                .ok_or_else(|| anyhow::anyhow!("no corpus manifest at {}", path.display()))?,
+        ),
+        None => None,
+    };
+    let (files, string_lengths) = match &pinned {
+        Some(pinned) => {
+            if pinned.generator_version != GENERATOR_VERSION {
+                anyhow::bail!(
+                    "manifest was built with generator version {} (daddle {}), this is version {}; \
+                     build it with the matching daddle release",
+                    pinned.generator_version,
+                    pinned.daddle_version,
+                    GENERATOR_VERSION
+                );
+            }
+            let files = pinned.files.iter().map(CorpusEntry::file_config).collect();
+            (files, pinned.string_lengths.clone())
+        }
+        None => (config.files.clone(), string_lengths.clone()),
+    };
+    for file in &files {
+        file.validate()?;
+    }
+    generator::configure_string_lengths(&string_lengths)?;
+
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("failed to create corpus directory {}", dir.display()))?;
+    let previous = CorpusManifest::read(&dir.join(MANIFEST_FILE))?
+        .filter(|previous| previous.reproducible_with(&string_lengths))
+        .unwrap_or_default();
+
+    let mut manifest = CorpusManifest {
+        daddle_version: env!("CARGO_PKG_VERSION").to_string(),
+        generator_version: GENERATOR_VERSION,
+        string_lengths,
+        files: Vec::new(),
+    };
+    for file in files {
+        let existing = previous.files.iter().find(|entry| {
+            entry.built_from(&file)
+                && std::fs::metadata(dir.join(&entry.name))
+                    .is_ok_and(|metadata| metadata.len() == entry.bytes)
+        });
+        if let Some(entry) = existing {
+            tracing::info!("Keeping corpus file {}, already built", file.name);
+            manifest.files.push(entry.clone());
+            continue;
+        }
+
+        tracing::info!(
+            "Generating corpus file {} ({} bytes, seed {})",
+            file.name,
+            file.size_bytes,
+            file.seed
+        );
+        let dir = dir.clone();
+        let entry = tokio::task::spawn_blocking(move || generate_file(&dir, &file)).await??;
+        manifest.files.push(entry);
+    }
+    manifest.write(&dir)?;
+
+    if let Some(pinned) = pinned {
+        let mismatched: Vec<_> = pinned
+            .files
+            .iter()
+            .zip(&manifest.files)
+            .filter(|(expected, built)| expected.sha256 != built.sha256)
+            .map(|(expected, _)| expected.name.as_str())
+            .collect();
+        if !mismatched.is_empty() {
+            anyhow::bail!(
+                "regenerated files differ from the manifest: {}",
+                mismatched.join(", ")
+            );
+        }
+    }
+    Ok(manifest)
+}
+
+struct Corpus {
+    dir: PathBuf,
+    manifest: CorpusManifest,
+}
+
+/// Load the manifest of a built corpus; call once at startup
+pub fn load(config: &CorpusConfig) -> anyhow::Result<()> {
+    let dir = PathBuf::from(&config.dir);
+    let Some(manifest) = CorpusManifest::read(&dir.join(MANIFEST_FILE))? else {
+        if !config.files.is_empty() {
+            tracing::warn!(
+                "No corpus manifest in {}, run `daddle corpus build` to generate the corpus",
+                dir.display()
+            );
+        }
+        return Ok(());
+    };
+    tracing::info!(
+        "Serving {} corpus files from {}",
+        manifest.files.len(),
+        dir.display()
+    );
+    let _ = CORPUS.set(Corpus { dir, manifest });
+    Ok(())
+}
+
+/// The manifest of the served corpus, which `daddle corpus build --manifest` can regenerate it from
+pub async fn corpus_manifest_handler() -> Result<Json<&'static CorpusManifest>, ApiError> {
+    CORPUS
+        .get()
+        .map(|corpus| Json(&corpus.manifest))
+        .ok_or_else(|| ApiError::not_found("/corpus"))
+}
+
+/// Stream a corpus file from disk, answering revalidations by its hash
+pub async fn corpus_handler(
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let Some((corpus, entry)) = CORPUS.get().and_then(|corpus| {
+        corpus
+            .manifest
+            .files
+            .iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| (corpus, entry))
+    }) else {
+        return Err(ApiError::not_found(&format!("/corpus/{}", name)));
+    };
+
+    let etag = format!("\"{}\"", entry.sha256);
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes() == etag.as_bytes())
+    {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+
+    let path = corpus.dir.join(&entry.name);
+    let file = tokio::fs::File::open(&path)
+        .await
+        .map_err(|e| ApiError::internal(format!("corpus file {} unreadable: {}", name, e)))?;
+    let length = file
+        .metadata()
+        .await
+        .map(|metadata| metadata.len())
+        .unwrap_or_default();
+    if length != entry.bytes {
+        return Err(ApiError::internal(format!(
+            "corpus file {} changed since it was built, rerun `daddle corpus build`",
+            name
+        )));
+    }
+
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                entry.format.content_type().to_string(),
+            ),
+            (header::CONTENT_LENGTH, length.to_string()),
+            (header::ETAG, etag),
+        ],
+        Body::from_stream(ReaderStream::with_capacity(file, READ_BUFFER_BYTES)),
+    )
+        .into_response())
+}
+
+// Global corpus, loaded once at startup
+static CORPUS: OnceCell<Corpus> = OnceCell::new();
@@ -0,0 +1,347 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use axum::{
+    extract::rejection::JsonRejection,
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::Json,
+};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::{DependencyConfig, DependencyFault};
+use crate::error::ApiError;
+
+/// Carries the number of daddle hops a request has been through
+pub const HOP_HEADER: &str = "x-daddle-hop";
+
+/// Deepest chain of daddle instances calling each other before a request is refused,
+/// so a cyclic topology cannot call itself forever
+const MAX_HOPS: u32 = 16;
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+/// Whether the requested dependencies are called one after another or all at once
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DependencyOrder {
+    #[default]
+    Parallel,
+    Sequential,
+}
+
+/// A configured dependency with its current fault and call counts
+pub struct Dependency {
+    config: DependencyConfig,
+    fault: Mutex<DependencyFault>,
+    calls: AtomicU64,
+    failures: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DependencySummary {
+    pub name: String,
+    pub url: String,
+    pub required: bool,
+    pub timeout_ms: u64,
+    pub fault: DependencyFault,
+    pub calls: u64,
+    pub failures: u64,
+}
+
+impl Dependency {
+    fn fault(&self) -> DependencyFault {
+        *self.fault.lock().unwrap()
+    }
+
+    fn summary(&self) -> DependencySummary {
+        DependencySummary {
+            name: self.config.name.clone(),
+            url: self.config.url.clone(),
+            required: self.config.required,
+            timeout_ms: self.config.timeout_ms,
+            fault: self.fault(),
+            calls: self.calls.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What happened when a dependency was called
+#[derive(Debug, Clone, Serialize)]
+pub struct DependencyOutcome {
+    pub name: String,
+    /// HTTP status, when a response arrived
+    pub status: Option<u16>,
+    pub duration_ms: f64,
+    /// `timeout`, `connect`, `status` or `request` when the call failed
+    pub error: Option<&'static str>,
+    pub required: bool,
+    /// Fault injected into this call, if any
+    pub fault: Option<&'static str>,
+}
+
+impl DependencyOutcome {
+    pub fn failed(&self) -> bool {
+        self.error.is_some()
+    }
+}
+
+/// Register the dependencies requests may call
+pub fn configure(dependencies: &[DependencyConfig]) -> anyhow::Result<()> {
+    for dependency in dependencies {
+        dependency.fault.validate()?;
+    }
+    let registered = dependencies
+        .iter()
+        .map(|config| Dependency {
+            config: config.clone(),
+            fault: Mutex::new(config.fault),
+            calls: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+        })
+        .collect();
+    if DEPENDENCIES.set(registered).is_ok() && !dependencies.is_empty() {
+        tracing::info!("Configured {} downstream dependencies", dependencies.len());
+    }
+    Ok(())
+}
+
+fn configured() -> &'static [Dependency] {
+    DEPENDENCIES.get().map_or(&[], Vec::as_slice)
+}
+
+/// Current fault of every dependency, by name
+pub fn faults() -> BTreeMap<String, DependencyFault> {
+    configured()
+        .iter()
+        .map(|dependency| (dependency.config.name.clone(), dependency.fault()))
+        .collect()
+}
+
+/// Switch faults by dependency name, leaving dependencies that are not listed alone
+pub fn apply_faults(faults: &BTreeMap<String, DependencyFault>) {
+    for (name, fault) in faults {
+        match configured().iter().find(|d| &d.config.name == name) {
+            Some(dependency) if fault.validate().is_ok() => {
+                *dependency.fault.lock().unwrap() = *fault;
+            }
+            Some(_) => tracing::warn!("Ignoring invalid fault {:?} for {}", fault, name),
+            None => tracing::debug!("Ignoring fault for unknown dependency {}", name),
+        }
+    }
+}
+
+/// Dependencies named in a `dependencies` parameter: a comma separated list, or `all`
+pub fn select(names: &str) -> Result<Vec<&'static Dependency>, ApiError> {
+    let configured = configured();
+    if names == "all" {
+        return Ok(configured.iter().collect());
+    }
+    names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            configured
+                .iter()
+                .find(|dependency| dependency.config.name == name)
+                .ok_or_else(|| ApiError::bad_request(format!("unknown dependency: {}", name)))
+        })
+        .collect()
+}
+
+/// Hop count of an incoming request, refusing requests that went around too often
+pub fn incoming_hop(headers: &HeaderMap) -> Result<u32, ApiError> {
+    let hop = headers
+        .get(HOP_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+    if hop >= MAX_HOPS {
+        return Err(ApiError::dependency_loop(hop));
+    }
+    Ok(hop)
+}
+
+async fn call(dependency: &Dependency, hop: u32) -> DependencyOutcome {
+    let started = Instant::now();
+    let fault = dependency.fault();
+    let (status, error) = match fault {
+        DependencyFault::None => request(&dependency.config, Duration::ZERO, hop).await,
+        DependencyFault::Timeout => {
+            tokio::time::sleep(Duration::from_millis(dependency.config.timeout_ms)).await;
+            (None, Some("timeout"))
+        }
+        DependencyFault::Error { status } => (Some(status), Some("status")),
+        DependencyFault::Slow { delay_ms } => {
+            let delay = delay_ms.min(dependency.config.timeout_ms);
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            if delay_ms >= dependency.config.timeout_ms {
+                (None, Some("timeout"))
+            } else {
+                request(&dependency.config, Duration::from_millis(delay), hop).await
+            }
+        }
+    };
+
+    dependency.calls.fetch_add(1, Ordering::Relaxed);
+    if error.is_some() {
+        dependency.failures.fetch_add(1, Ordering::Relaxed);
+    }
+    DependencyOutcome {
+        name: dependency.config.name.clone(),
+        status,
+        duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+        error,
+        required: dependency.config.required,
+        fault: match fault {
+            DependencyFault::None => None,
+            DependencyFault::Timeout => Some("timeout"),
+            DependencyFault::Error { .. } => Some("error"),
+            DependencyFault::Slow { .. } => Some("slow"),
+        },
+    }
+}
+
+/// Make the actual call, with `elapsed` of its timeout already used up
+async fn request(
+    config: &DependencyConfig,
+    elapsed: Duration,
+    hop: u32,
+) -> (Option<u16>, Option<&'static str>) {
+    let method =
+        reqwest::Method::from_bytes(config.method.as_bytes()).unwrap_or(reqwest::Method::GET);
+    let result = CLIENT
+        .request(method, &config.url)
+        .timeout(Duration::from_millis(config.timeout_ms).saturating_sub(elapsed))
+        .header(HOP_HEADER, HeaderValue::from(hop + 1))
+        .send()
+        .await;
+
+    match result {
+        // Read the body so its transfer time counts towards the latency
+        Ok(response) => {
+            let status = response.status();
+            match response.bytes().await {
+                Ok(_) if status.is_server_error() => (Some(status.as_u16()), Some("status")),
+                Ok(_) => (Some(status.as_u16()), None),
+                Err(e) if e.is_timeout() => (Some(status.as_u16()), Some("timeout")),
+                Err(_) => (Some(status.as_u16()), Some("request")),
+            }
+        }
+        Err(e) if e.is_timeout() => (None, Some("timeout")),
+        Err(e) if e.is_connect() => (None, Some("connect")),
+        Err(_) => (None, Some("request")),
+    }
+}
+
+/// Call every dependency, in parallel or one after another
+pub async fn call_all(
+    dependencies: &[&Dependency],
+    order: DependencyOrder,
+    hop: u32,
+) -> Vec<DependencyOutcome> {
+    match order {
+        DependencyOrder::Parallel => {
+            futures::future::join_all(dependencies.iter().map(|dependency| call(dependency, hop)))
+                .await
+        }
+        DependencyOrder::Sequential => {
+            let mut outcomes = Vec::with_capacity(dependencies.len());
+            for dependency in dependencies {
+                outcomes.push(call(dependency, hop).await);
+            }
+            outcomes
+        }
+    }
+}
+
+/// Fail the request if a required dependency failed, as a gateway would
+pub fn check(outcomes: &[DependencyOutcome]) -> Result<(), ApiError> {
+    match outcomes.iter().find(|o| o.required && o.failed()) {
+        Some(failed) => Err(ApiError::dependency_failed(failed, outcomes)),
+        None => Ok(()),
+    }
+}
+
+/// `Server-Timing` header value listing each dependency call
+pub fn server_timing(outcomes: &[DependencyOutcome]) -> Option<HeaderValue> {
+    if outcomes.is_empty() {
+        return None;
+    }
+    let entries: Vec<String> = outcomes
+        .iter()
+        .map(|outcome| {
+            let description = match (outcome.error, outcome.status) {
+                (Some(error), _) => error.to_string(),
+                (None, Some(status)) => status.to_string(),
+                (None, None) => String::new(),
+            };
+            format!(
+                "dep-{};dur={:.1};desc=\"{}\"",
+                outcome.name, outcome.duration_ms, description
+            )
+        })
+        .collect();
+    HeaderValue::from_str(&entries.join(", ")).ok()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FaultUpdate {
+    /// Dependency to change; every dependency when unset
+    name: Option<String>,
+    fault: DependencyFault,
+}
+
+/// List configured dependencies with their current faults and call counts
+pub async fn dependencies_list_handler() -> Json<Vec<DependencySummary>> {
+    Json(configured().iter().map(Dependency::summary).collect())
+}
+
+/// Switch the fault injected into calls to a dependency while requests run
+pub async fn dependencies_update_handler(
+    update: Result<Json<FaultUpdate>, JsonRejection>,
+) -> Result<Json<Vec<DependencySummary>>, ApiError> {
+    let Json(update) = update?;
+    update
+        .fault
+        .validate()
+        .map_err(|e| ApiError::bad_request(e.to_string()))?;
+
+    let targets: Vec<&Dependency> = match &update.name {
+        Some(name) => {
+            let dependency = configured()
+                .iter()
+                .find(|dependency| &dependency.config.name == name)
+                .ok_or_else(|| {
+                    ApiError::new(
+                        StatusCode::NOT_FOUND,
+                        "not-found",
+                        "Not found",
+                        format!("no dependency named {}", name),
+                    )
+                })?;
+            vec![dependency]
+        }
+        None => configured().iter().collect(),
+    };
+
+    for dependency in &targets {
+        *dependency.fault.lock().unwrap() = update.fault;
+    }
+    tracing::info!(
+        "Set fault of {} dependencies to {:?}",
+        targets.len(),
+        update.fault
+    );
+    Ok(Json(targets.iter().map(|d| d.summary()).collect()))
+}
+
+// Global dependency list, set once at startup
+static DEPENDENCIES: OnceCell<Vec<Dependency>> = OnceCell::new();
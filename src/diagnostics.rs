@@ -0,0 +1,124 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use axum::{
+    extract::{rejection::QueryRejection, Query},
+    response::Json,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+
+use crate::error::ApiError;
+
+/// How long worker busy time is sampled for when computing utilization
+const DEFAULT_SAMPLE_WINDOW_MS: u64 = 250;
+const MAX_SAMPLE_WINDOW_MS: u64 = 10_000;
+
+/// Initialize tracing, adding the tokio-console layer when the `console` feature is enabled
+pub fn init_tracing() {
+    #[cfg(feature = "console")]
+    {
+        use tracing_subscriber::{filter::LevelFilter, prelude::*};
+
+        tracing_subscriber::registry()
+            .with(console_subscriber::spawn())
+            .with(tracing_subscriber::fmt::layer().with_filter(LevelFilter::INFO))
+            .init();
+        tracing::info!("tokio-console subscriber enabled");
+    }
+
+    #[cfg(not(feature = "console"))]
+    tracing_subscriber::fmt::init();
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RuntimeParams {
+    #[serde(rename = "windowMs")]
+    window_ms: Option<u64>,
+}
+
+/// Report Tokio runtime health, sampling worker utilization over a short window
+pub async fn runtime_handler(
+    params: Result<Query<RuntimeParams>, QueryRejection>,
+) -> Result<Json<Value>, ApiError> {
+    let Query(params) = params?;
+    let window = Duration::from_millis(
+        params
+            .window_ms
+            .unwrap_or(DEFAULT_SAMPLE_WINDOW_MS)
+            .min(MAX_SAMPLE_WINDOW_MS),
+    );
+
+    let handle = Handle::current();
+    let metrics = handle.metrics();
+    let workers = metrics.num_workers();
+
+    let busy_before: Vec<Duration> = (0..workers)
+        .map(|worker| metrics.worker_total_busy_duration(worker))
+        .collect();
+    let parks_before: Vec<u64> = (0..workers)
+        .map(|worker| metrics.worker_park_count(worker))
+        .collect();
+
+    let started = Instant::now();
+    tokio::time::sleep(window).await;
+    let elapsed = started.elapsed().as_secs_f64();
+
+    let utilizations: Vec<f64> = (0..workers)
+        .map(|worker| {
+            let busy = metrics.worker_total_busy_duration(worker) - busy_before[worker];
+            (busy.as_secs_f64() / elapsed).min(1.0)
+        })
+        .collect();
+
+    let worker_stats: Vec<Value> = (0..workers)
+        .map(|worker| {
+            serde_json::json!({
+                "worker": worker,
+                "utilization": utilizations[worker],
+                "busy_ms_total": metrics.worker_total_busy_duration(worker).as_millis() as u64,
+                "parks_in_window": metrics.worker_park_count(worker) - parks_before[worker],
+            })
+        })
+        .collect();
+
+    let mut body = serde_json::json!({
+        "runtime": {
+            "flavor": format!("{:?}", handle.runtime_flavor()),
+            "workers": workers,
+            "alive_tasks": metrics.num_alive_tasks(),
+            "global_queue_depth": metrics.global_queue_depth(),
+        },
+        "sample_window_ms": elapsed * 1000.0,
+        "mean_worker_utilization": utilizations.iter().sum::<f64>() / workers.max(1) as f64,
+        "workers": worker_stats,
+        "timestamp": chrono::Utc::now()
+    });
+
+    // Blocking pool metrics are only exposed by Tokio's unstable API
+    #[cfg(tokio_unstable)]
+    {
+        let threads = metrics.num_blocking_threads();
+        let idle = metrics.num_idle_blocking_threads();
+        body["blocking"] = serde_json::json!({
+            "available": true,
+            "threads": threads,
+            "idle_threads": idle,
+            "busy_threads": threads.saturating_sub(idle),
+            "queue_depth": metrics.blocking_queue_depth(),
+        });
+    }
+
+    #[cfg(not(tokio_unstable))]
+    {
+        body["blocking"] = serde_json::json!({
+            "available": false,
+            "reason": "build with RUSTFLAGS=\"--cfg tokio_unstable\" to expose blocking pool metrics",
+        });
+    }
+
+    Ok(Json(body))
+}
@@ -0,0 +1,111 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use once_cell::sync::Lazy;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::DictionaryConfig;
+
+/// Domain vocabulary mixed into generated keys and string values
+#[derive(Debug, Default)]
+pub struct WordLists {
+    pub keys: Vec<String>,
+    pub values: Vec<String>,
+    /// Probability that a key or string value is drawn from the lists
+    pub usage_ratio: f64,
+}
+
+impl WordLists {
+    fn load(config: &DictionaryConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            keys: read_words(&config.key_files)?,
+            values: read_words(&config.value_files)?,
+            usage_ratio: config.usage_ratio.clamp(0.0, 1.0),
+        })
+    }
+}
+
+/// One word or phrase per line; blank lines and `#` comments are skipped
+fn read_words(paths: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut words = Vec::new();
+    for path in paths {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read word list {}: {}", path, e))?;
+        words.extend(
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_string),
+        );
+    }
+    Ok(words)
+}
+
+/// Latest modification time across all word list files
+fn last_modified(config: &DictionaryConfig) -> Option<SystemTime> {
+    config
+        .key_files
+        .iter()
+        .chain(&config.value_files)
+        .filter_map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .max()
+}
+
+/// Holds the current word lists, swapped atomically on reload
+pub struct Dictionary {
+    words: RwLock<Arc<WordLists>>,
+}
+
+impl Dictionary {
+    fn new() -> Self {
+        Self {
+            words: RwLock::new(Arc::new(WordLists::default())),
+        }
+    }
+
+    /// Load the configured word lists, keeping the previous ones if loading fails
+    pub fn load(&self, config: &DictionaryConfig) -> anyhow::Result<()> {
+        let words = WordLists::load(config)?;
+        tracing::info!(
+            "Loaded dictionary with {} keys and {} values",
+            words.keys.len(),
+            words.values.len()
+        );
+        *self.words.write().unwrap() = Arc::new(words);
+        Ok(())
+    }
+
+    pub fn current(&self) -> Arc<WordLists> {
+        self.words.read().unwrap().clone()
+    }
+}
+
+/// Reload the word lists whenever one of the files changes on disk
+pub async fn watch_dictionary(config: DictionaryConfig, shutdown: CancellationToken) {
+    let mut last_seen = last_modified(&config);
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(config.reload_interval_ms)) => {}
+            _ = shutdown.cancelled() => break,
+        }
+
+        let modified = last_modified(&config);
+        if modified != last_seen {
+            last_seen = modified;
+            if let Err(e) = DICTIONARY.load(&config) {
+                tracing::warn!(
+                    "Failed to reload dictionary, keeping previous word lists: {}",
+                    e
+                );
+            }
+        }
+    }
+}
+
+// Global dictionary instance
+pub static DICTIONARY: Lazy<Dictionary> = Lazy::new(Dictionary::new);
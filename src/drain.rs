@@ -0,0 +1,50 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use axum::response::Json;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::memory::MEMORY_GUARD;
+
+/// Tracks whether the instance is draining ahead of a restart
+pub struct DrainController {
+    draining: AtomicBool,
+}
+
+impl DrainController {
+    fn new() -> Self {
+        Self {
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    /// Stop accepting new garble requests and report unhealthy to load balancers
+    pub fn start_draining(&self) {
+        if !self.draining.swap(true, Ordering::Relaxed) {
+            tracing::info!(
+                "Entering draining mode with {} responses in flight",
+                MEMORY_GUARD.get_stats().in_flight_responses
+            );
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
+
+pub async fn drain_handler() -> Json<Value> {
+    DRAIN.start_draining();
+
+    Json(serde_json::json!({
+        "draining": true,
+        "in_flight_responses": MEMORY_GUARD.get_stats().in_flight_responses,
+        "timestamp": chrono::Utc::now()
+    }))
+}
+
+// Global drain controller instance
+pub static DRAIN: Lazy<DrainController> = Lazy::new(DrainController::new);
@@ -0,0 +1,165 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use serde_json::Value;
+
+/// Depth of the elements of the `garbled_chunks` array in a chunked document
+pub const CHUNK_DEPTH: usize = 2;
+
+/// How generated values are written out as JSON text
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EmitOptions {
+    /// Write object keys in sorted order, whatever order the map holds them in
+    pub sorted_keys: bool,
+    /// Pretty-print with this many spaces per level; compact when unset
+    pub indent: Option<usize>,
+}
+
+impl EmitOptions {
+    /// Line break and indentation before an item at `depth`, when pretty-printing
+    fn newline(&self, depth: usize, out: &mut String) {
+        if let Some(indent) = self.indent {
+            out.push('\n');
+            out.extend(std::iter::repeat_n(' ', indent * depth));
+        }
+    }
+
+    fn key_separator(&self) -> &'static str {
+        if self.indent.is_some() {
+            ": "
+        } else {
+            ":"
+        }
+    }
+}
+
+/// Serialize `value` as a top-level document
+pub fn to_json(value: &Value, options: &EmitOptions) -> String {
+    to_json_at(value, options, 0)
+}
+
+/// Serialize `value` as it appears nested `depth` levels deep, so pretty-printed
+/// fragments line up once spliced into a larger document
+pub fn to_json_at(value: &Value, options: &EmitOptions, depth: usize) -> String {
+    if *options == EmitOptions::default() {
+        return serde_json::to_string(value).unwrap_or_else(|_| "{}".to_string());
+    }
+
+    let mut out = String::new();
+    write_value(value, options, depth, &mut out);
+    out
+}
+
+fn write_value(value: &Value, options: &EmitOptions, depth: usize, out: &mut String) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            let mut entries: Vec<_> = map.iter().collect();
+            if options.sorted_keys {
+                entries.sort_by_key(|(key, _)| *key);
+            }
+
+            out.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                options.newline(depth + 1, out);
+                out.push_str(&serde_json::to_string(key).unwrap_or_default());
+                out.push_str(options.key_separator());
+                write_value(value, options, depth + 1, out);
+            }
+            options.newline(depth, out);
+            out.push('}');
+        }
+        Value::Array(items) if !items.is_empty() => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                options.newline(depth + 1, out);
+                write_value(item, options, depth + 1, out);
+            }
+            options.newline(depth, out);
+            out.push(']');
+        }
+        other => out.push_str(&serde_json::to_string(other).unwrap_or_else(|_| "null".to_string())),
+    }
+}
+
+/// Bytes a member takes in an object nested `depth` levels deep, the comma
+/// separating it from the next one included
+pub fn member_len(key: &str, value: &Value, options: &EmitOptions, depth: usize) -> usize {
+    let mut newline = String::new();
+    options.newline(depth + 1, &mut newline);
+    newline.len()
+        + serde_json::to_string(key).map_or(0, |key| key.len())
+        + options.key_separator().len()
+        + to_json_at(value, options, depth + 1).len()
+        + 1
+}
+
+/// Render an object from pre-rendered member values nested `depth` levels deep,
+/// sorting the members by key when requested
+pub fn object(mut members: Vec<(&str, String)>, options: &EmitOptions, depth: usize) -> String {
+    if options.sorted_keys {
+        members.sort_by_key(|(key, _)| *key);
+    }
+
+    let mut out = String::from("{");
+    for (i, (key, value)) in members.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        options.newline(depth + 1, &mut out);
+        out.push_str(&format!(r#""{}"{}{}"#, key, options.key_separator(), value));
+    }
+    options.newline(depth, &mut out);
+    out.push('}');
+    out
+}
+
+/// Opening of a chunked document, up to and including the `[` of `garbled_chunks`
+pub fn chunks_open(options: &EmitOptions) -> String {
+    let mut out = String::from("{");
+    options.newline(1, &mut out);
+    out.push_str(&format!(r#""garbled_chunks"{}["#, options.key_separator()));
+    out
+}
+
+/// Text to put before the array element at `index`, nested `depth` levels deep
+pub fn element_separator(index: usize, options: &EmitOptions, depth: usize) -> String {
+    let mut out = String::new();
+    if index > 0 {
+        out.push(',');
+    }
+    options.newline(depth, &mut out);
+    out
+}
+
+/// Closing `]` of an array with `len` elements nested `depth` levels deep
+pub fn array_close(len: usize, options: &EmitOptions, depth: usize) -> String {
+    let mut out = String::new();
+    if len > 0 {
+        options.newline(depth - 1, &mut out);
+    }
+    out.push(']');
+    out
+}
+
+/// A `,"key":value` member following the chunk array of a chunked document
+pub fn trailing_member(key: &str, value: &str, options: &EmitOptions) -> String {
+    let mut out = String::from(",");
+    options.newline(1, &mut out);
+    out.push_str(&format!(r#""{}"{}{}"#, key, options.key_separator(), value));
+    out
+}
+
+/// Closing `}` of a chunked document
+pub fn chunks_close(options: &EmitOptions) -> String {
+    let mut out = String::new();
+    options.newline(0, &mut out);
+    out.push('}');
+    out
+}
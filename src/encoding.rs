@@ -0,0 +1,93 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use axum::body::Bytes;
+use serde::Deserialize;
+
+/// Character encoding of a response body
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+pub enum TextEncoding {
+    #[default]
+    #[serde(rename = "utf-8")]
+    Utf8,
+    #[serde(rename = "utf-16le")]
+    Utf16Le,
+    #[serde(rename = "utf-16be")]
+    Utf16Be,
+    /// ISO-8859-1; characters outside it are written as `?`
+    #[serde(rename = "latin1", alias = "iso-8859-1")]
+    Latin1,
+}
+
+impl TextEncoding {
+    /// Value of the `charset` parameter on the Content-Type
+    fn charset(&self) -> &'static str {
+        match self {
+            TextEncoding::Utf8 => "utf-8",
+            TextEncoding::Utf16Le => "utf-16le",
+            TextEncoding::Utf16Be => "utf-16be",
+            TextEncoding::Latin1 => "iso-8859-1",
+        }
+    }
+
+    /// Whether this encoding has a byte order mark
+    pub fn supports_bom(&self) -> bool {
+        !matches!(self, TextEncoding::Latin1)
+    }
+}
+
+/// How the response text is turned into bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct BodyEncoding {
+    pub encoding: TextEncoding,
+    /// Start the body with a byte order mark
+    pub bom: bool,
+}
+
+impl BodyEncoding {
+    /// Content type with a `charset` parameter, left untouched for plain UTF-8
+    pub fn content_type(&self, content_type: &str) -> String {
+        if *self == BodyEncoding::default() {
+            return content_type.to_string();
+        }
+        format!("{}; charset={}", content_type, self.encoding.charset())
+    }
+
+    /// Byte order mark to send before the body, if one was requested
+    pub fn bom(&self) -> &'static [u8] {
+        if !self.bom {
+            return &[];
+        }
+        match self.encoding {
+            TextEncoding::Utf8 => &[0xEF, 0xBB, 0xBF],
+            TextEncoding::Utf16Le => &[0xFF, 0xFE],
+            TextEncoding::Utf16Be => &[0xFE, 0xFF],
+            TextEncoding::Latin1 => &[],
+        }
+    }
+
+    /// Encode a piece of the body; pieces must not split characters
+    pub fn encode(&self, text: String) -> Bytes {
+        match self.encoding {
+            TextEncoding::Utf8 => Bytes::from(text),
+            TextEncoding::Utf16Le => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            TextEncoding::Utf16Be => text.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+            TextEncoding::Latin1 => text
+                .chars()
+                .map(|c| u8::try_from(u32::from(c)).unwrap_or(b'?'))
+                .collect(),
+        }
+    }
+
+    /// Encode a whole body, including the byte order mark
+    pub fn encode_document(&self, text: String) -> Bytes {
+        let bom = self.bom();
+        if bom.is_empty() {
+            return self.encode(text);
+        }
+        let mut bytes = bom.to_vec();
+        bytes.extend_from_slice(&self.encode(text));
+        Bytes::from(bytes)
+    }
+}
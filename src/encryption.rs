@@ -0,0 +1,205 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Encrypted garble bodies, for pipelines that decrypt what they receive. The
+//! body is assembled as usual and then sealed with AES-GCM under the key from
+//! the `encryption` config: `aes-gcm` sends nonce, ciphertext and tag back to
+//! back, `jwe` wraps them in a flattened JWE JSON object with direct key
+//! agreement, which JOSE libraries open as is. Either way the plaintext keeps
+//! the requested size.
+
+use anyhow::Context;
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, HeaderValue},
+    response::Response,
+};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use once_cell::sync::OnceCell;
+use openssl::symm::{encrypt_aead, Cipher};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config::EncryptionConfig;
+use crate::error::ApiError;
+
+/// GCM nonce length, the size every implementation supports
+const NONCE_LEN: usize = 12;
+
+/// GCM authentication tag length
+const TAG_LEN: usize = 16;
+
+/// How an encrypted body is laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum EncryptionMode {
+    /// Raw bytes: nonce, ciphertext, tag
+    #[serde(rename = "aes-gcm")]
+    AesGcm,
+    /// Flattened JWE JSON serialization
+    #[serde(rename = "jwe")]
+    Jwe,
+}
+
+struct Key {
+    bytes: Vec<u8>,
+    id: Option<String>,
+}
+
+impl Key {
+    fn cipher(&self) -> Cipher {
+        match self.bytes.len() {
+            16 => Cipher::aes_128_gcm(),
+            24 => Cipher::aes_192_gcm(),
+            _ => Cipher::aes_256_gcm(),
+        }
+    }
+
+    /// Value of the `X-Garble-Encryption` header, such as `aes-256-gcm`
+    fn scheme(&self) -> String {
+        format!("aes-{}-gcm", self.bytes.len() * 8)
+    }
+
+    /// JWE content encryption algorithm, such as `A256GCM`
+    fn jwe_enc(&self) -> String {
+        format!("A{}GCM", self.bytes.len() * 8)
+    }
+}
+
+/// Bytes of a hex string, or `None` if it is not one
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Load the key, if one is configured; call once at startup
+pub fn configure(config: &EncryptionConfig) -> anyhow::Result<()> {
+    let Some(hex) = &config.key else {
+        return Ok(());
+    };
+    let bytes = decode_hex(hex.trim()).context("encryption.key must be hex")?;
+    if ![16, 24, 32].contains(&bytes.len()) {
+        anyhow::bail!(
+            "encryption.key must be 32, 48 or 64 hex digits, got {}",
+            hex.trim().len()
+        );
+    }
+    let key = Key {
+        bytes,
+        id: config.key_id.clone(),
+    };
+    tracing::info!(
+        "Response encryption: {}, key id {}",
+        key.scheme(),
+        key.id.as_deref().unwrap_or("none")
+    );
+    let _ = KEY.set(key);
+    Ok(())
+}
+
+/// Whether a key is loaded, so `?encrypt=` can be served
+pub fn is_configured() -> bool {
+    KEY.get().is_some()
+}
+
+/// Replace the body of `response` with its encryption; the plaintext is
+/// buffered whole first, and its type and length move to `X-Garble-Plaintext-*`
+pub async fn seal(response: Response, mode: EncryptionMode) -> Result<Response, ApiError> {
+    let key = KEY
+        .get()
+        .expect("encryption is only requested when a key is configured");
+    let (mut parts, body) = response.into_parts();
+    let plaintext = to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to assemble response: {}", e)))?;
+    let plaintext_type = parts.headers.remove(header::CONTENT_TYPE);
+
+    let failed = |e: openssl::error::ErrorStack| {
+        ApiError::internal(format!("Failed to encrypt response: {}", e))
+    };
+    let mut nonce = [0u8; NONCE_LEN];
+    openssl::rand::rand_bytes(&mut nonce).map_err(failed)?;
+    let mut tag = [0u8; TAG_LEN];
+    let (sealed, content_type) = match mode {
+        EncryptionMode::AesGcm => {
+            let ciphertext = encrypt_aead(
+                key.cipher(),
+                &key.bytes,
+                Some(&nonce),
+                &[],
+                &plaintext,
+                &mut tag,
+            )
+            .map_err(failed)?;
+            let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len() + TAG_LEN);
+            sealed.extend_from_slice(&nonce);
+            sealed.extend_from_slice(&ciphertext);
+            sealed.extend_from_slice(&tag);
+            (sealed, "application/octet-stream")
+        }
+        EncryptionMode::Jwe => {
+            let mut protected = json!({ "alg": "dir", "enc": key.jwe_enc() });
+            if let Some(id) = &key.id {
+                protected["kid"] = json!(id);
+            }
+            if let Some(cty) = plaintext_type.as_ref().and_then(|t| t.to_str().ok()) {
+                protected["cty"] = json!(cty);
+            }
+            // The encoded protected header is the additional authenticated data
+            let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+            let ciphertext = encrypt_aead(
+                key.cipher(),
+                &key.bytes,
+                Some(&nonce),
+                protected.as_bytes(),
+                &plaintext,
+                &mut tag,
+            )
+            .map_err(failed)?;
+            let envelope = json!({
+                "protected": protected,
+                "iv": URL_SAFE_NO_PAD.encode(nonce),
+                "ciphertext": URL_SAFE_NO_PAD.encode(ciphertext),
+                "tag": URL_SAFE_NO_PAD.encode(tag),
+            });
+            (envelope.to_string().into_bytes(), "application/jose+json")
+        }
+    };
+
+    // The sealed body is complete, so it goes out with a length instead of chunked
+    parts.headers.remove(header::TRANSFER_ENCODING);
+    parts.headers.remove(header::TRAILER);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    if let Ok(scheme) = HeaderValue::from_str(&key.scheme()) {
+        parts.headers.insert("x-garble-encryption", scheme);
+    }
+    if let Some(id) = key
+        .id
+        .as_deref()
+        .and_then(|id| HeaderValue::from_str(id).ok())
+    {
+        parts.headers.insert("x-garble-key-id", id);
+    }
+    parts.headers.insert(
+        "x-garble-plaintext-bytes",
+        HeaderValue::from(plaintext.len()),
+    );
+    if let Some(plaintext_type) = plaintext_type {
+        parts
+            .headers
+            .insert("x-garble-plaintext-type", plaintext_type);
+    }
+    Ok(Response::from_parts(parts, Body::from(sealed)))
+}
+
+// Global encryption key, set once at startup when configured
+static KEY: OnceCell<Key> = OnceCell::new();
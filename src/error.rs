@@ -0,0 +1,382 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use axum::{
+    body::Body,
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        Request,
+    },
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::budget::BudgetOverrun;
+use crate::dependencies::DependencyOutcome;
+use crate::generator::RandomDataGenerator;
+use crate::validation::ValidationError;
+
+const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// When set, the `detail` of every problem is replaced with garbled text
+static GARBLE_DETAILS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_garble_details(enabled: bool) {
+    GARBLE_DETAILS.store(enabled, Ordering::Relaxed);
+    if enabled {
+        tracing::info!("Error details will be garbled");
+    }
+}
+
+/// An RFC 7807 problem returned as `application/problem+json`
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    status: StatusCode,
+    problem_type: &'static str,
+    title: &'static str,
+    detail: String,
+    extensions: Map<String, Value>,
+}
+
+impl ApiError {
+    pub fn new(
+        status: StatusCode,
+        problem_type: &'static str,
+        title: &'static str,
+        detail: impl Into<String>,
+    ) -> Self {
+        Self {
+            status,
+            problem_type,
+            title,
+            detail: detail.into(),
+            extensions: Map::new(),
+        }
+    }
+
+    /// Attach an extension member to the problem body
+    pub fn with_extension(mut self, key: &str, value: impl Serialize) -> Self {
+        self.extensions.insert(
+            key.to_string(),
+            serde_json::to_value(value).unwrap_or(Value::Null),
+        );
+        self
+    }
+
+    pub fn bad_request(detail: impl Into<String>) -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "bad-request",
+            "Bad request",
+            detail,
+        )
+    }
+
+    pub fn not_found(path: &str) -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            "not-found",
+            "Not found",
+            format!("no endpoint at {}", path),
+        )
+    }
+
+    pub fn internal(detail: impl Into<String>) -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal-error",
+            "Internal server error",
+            detail,
+        )
+    }
+
+    /// The instance is draining ahead of a restart
+    pub fn draining() -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "draining",
+            "Service draining",
+            "instance is draining ahead of a restart, retry against another instance",
+        )
+    }
+
+    /// A response was shed because it would push the process over its memory ceiling
+    pub fn memory_ceiling(target_size: usize) -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "memory-ceiling",
+            "Memory ceiling reached",
+            format!(
+                "a {}B response would exceed the configured memory ceiling",
+                target_size
+            ),
+        )
+        .with_extension("target_size", target_size)
+    }
+
+    /// The caller asked for a body beyond the configured cap
+    pub fn body_too_large(requested: usize, cap: usize) -> Self {
+        Self::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "body-too-large",
+            "Requested body too large",
+            format!(
+                "a body of {}B was requested, but bodies are capped at {}B",
+                requested, cap
+            ),
+        )
+        .with_extension("requested_body_size", requested)
+        .with_extension("body_size_cap", cap)
+    }
+
+    /// Too many requests are hung already to hang another
+    pub fn hang_limit(max_concurrent: u64) -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "hang-limit",
+            "Too many hung requests",
+            format!(
+                "{} requests are hung already, the configured maximum",
+                max_concurrent
+            ),
+        )
+    }
+
+    /// A hung request reached the longest it may be held
+    pub fn hang_expired(max_duration: Duration) -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "hang-expired",
+            "Hang expired",
+            format!(
+                "request was held for the configured maximum of {}ms",
+                max_duration.as_millis()
+            ),
+        )
+    }
+
+    /// Reading the request body was stopped on purpose with `uploadStallAfterBytes`
+    pub fn upload_stalled(read: usize, stall: Duration) -> Self {
+        Self::new(
+            StatusCode::REQUEST_TIMEOUT,
+            "upload-stalled",
+            "Upload stalled",
+            format!(
+                "stopped reading the request body after {} bytes and gave up {}ms later",
+                read,
+                stall.as_millis()
+            ),
+        )
+        .with_extension("body_bytes_read", read)
+    }
+
+    /// The admission queue was full when the request arrived
+    pub fn queue_full(queue_depth: usize) -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "queue-full",
+            "Admission queue full",
+            format!(
+                "{} requests are already waiting for a generation slot",
+                queue_depth
+            ),
+        )
+        .with_extension("queue_depth", queue_depth)
+    }
+
+    /// A queued request was dropped to make room for a newer one
+    pub fn queue_shed() -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "queue-shed",
+            "Shed from admission queue",
+            "request waited longest in a full admission queue and was shed for a newer one",
+        )
+    }
+
+    /// A queued request waited the longest it may for a generation slot
+    pub fn queue_timeout(queue_timeout: Duration) -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "queue-timeout",
+            "Admission queue timeout",
+            format!(
+                "no generation slot freed up within {}ms",
+                queue_timeout.as_millis()
+            ),
+        )
+    }
+
+    /// Generating the response took longer than the configured budget
+    pub fn generation_budget(overrun: &BudgetOverrun, budget: Duration) -> Self {
+        Self::new(
+            StatusCode::INSUFFICIENT_STORAGE,
+            "generation-budget",
+            "Generation budget exceeded",
+            format!(
+                "generating a {}B {} response was abandoned after {}ms, over the {}ms budget",
+                overrun.target_size,
+                overrun.strategy,
+                overrun.elapsed_ms,
+                budget.as_millis()
+            ),
+        )
+        .with_extension("budget_ms", budget.as_millis() as u64)
+        .with_extension("elapsed_ms", overrun.elapsed_ms)
+        .with_extension("strategy", overrun.strategy)
+        .with_extension("target_size", overrun.target_size)
+        .with_extension("generated_bytes", overrun.generated_bytes)
+    }
+
+    /// A required downstream dependency failed, so the request fails as a gateway would
+    pub fn dependency_failed(failed: &DependencyOutcome, outcomes: &[DependencyOutcome]) -> Self {
+        let (status, problem_type) = match failed.error {
+            Some("timeout") => (StatusCode::GATEWAY_TIMEOUT, "dependency-timeout"),
+            _ => (StatusCode::BAD_GATEWAY, "dependency-failed"),
+        };
+        Self::new(
+            status,
+            problem_type,
+            "Dependency failed",
+            format!(
+                "dependency {} failed: {}",
+                failed.name,
+                failed.error.unwrap_or("unknown")
+            ),
+        )
+        .with_extension("dependencies", outcomes)
+    }
+
+    /// A failure injected by the `errors` chaos flag
+    pub fn injected(status: StatusCode) -> Self {
+        Self::new(
+            status,
+            "injected-error",
+            "Injected error",
+            "failure injected by the errors chaos flag",
+        )
+        .with_extension("flag", crate::flags::ERRORS)
+    }
+
+    /// A failure picked by the `outlier.error_ramp` at its current rate
+    pub fn error_ramp(status: StatusCode, rate: f64) -> Self {
+        Self::new(
+            status,
+            "injected-error",
+            "Injected error",
+            "failure injected by the outlier error ramp",
+        )
+        .with_extension("error_rate", rate)
+    }
+
+    /// Failure injected by a fault configured for the request's path
+    pub fn path_fault(status: StatusCode, pattern: &str) -> Self {
+        Self::new(
+            status,
+            "injected-error",
+            "Injected error",
+            format!("failure injected by the fault on {}", pattern),
+        )
+        .with_extension("pattern", pattern)
+    }
+
+    /// A request passed through too many daddle instances calling each other
+    pub fn dependency_loop(hop: u32) -> Self {
+        Self::new(
+            StatusCode::LOOP_DETECTED,
+            "loop-detected",
+            "Dependency loop detected",
+            format!(
+                "request has already passed through {} daddle instances",
+                hop
+            ),
+        )
+        .with_extension("hop", hop)
+    }
+
+    fn body(&self, instance: Option<&str>) -> Value {
+        let detail = if GARBLE_DETAILS.load(Ordering::Relaxed) {
+            RandomDataGenerator::new().generate_garbled_string()
+        } else {
+            self.detail.clone()
+        };
+
+        let mut body = self.extensions.clone();
+        body.insert(
+            "type".to_string(),
+            Value::from(format!("urn:daddle:problem:{}", self.problem_type)),
+        );
+        body.insert("title".to_string(), Value::from(self.title));
+        body.insert("status".to_string(), Value::from(self.status.as_u16()));
+        body.insert("detail".to_string(), Value::from(detail));
+        if let Some(instance) = instance {
+            body.insert("instance".to_string(), Value::from(instance));
+        }
+
+        Value::Object(body)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = self.body(None).to_string();
+        let mut response = (
+            self.status,
+            [(header::CONTENT_TYPE, PROBLEM_CONTENT_TYPE)],
+            body,
+        )
+            .into_response();
+
+        // Keep the problem around so `problem_instance` can fill in the request path
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+impl From<ValidationError> for ApiError {
+    fn from(error: ValidationError) -> Self {
+        tracing::warn!(
+            "Rejecting request with {} invalid parameters",
+            error.problems.len()
+        );
+
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "invalid-parameters",
+            "Invalid request parameters",
+            format!("{} request parameters are invalid", error.problems.len()),
+        )
+        .with_extension("invalid_params", error.problems)
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::bad_request(rejection.body_text())
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::bad_request(rejection.body_text())
+    }
+}
+
+/// Middleware that sets the `instance` of problem responses to the request path
+pub async fn problem_instance(request: Request, next: Next) -> Response {
+    let path = request.uri().path().to_string();
+    let mut response = next.run(request).await;
+
+    if let Some(problem) = response.extensions_mut().remove::<ApiError>() {
+        response.headers_mut().remove(header::CONTENT_LENGTH);
+        *response.body_mut() = Body::from(problem.body(Some(&path)).to_string());
+    }
+
+    response
+}
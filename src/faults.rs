@@ -0,0 +1,118 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Error responses in place of a share of garble responses, so daddle can
+//! stand in for a flaky upstream when exercising retries and circuit
+//! breakers. The share comes from the `faults` config or a request's
+//! `errorRate`, the status is picked by weight, and the body is garbled JSON
+//! rather than a problem document, the way a misbehaving upstream answers.
+
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use once_cell::sync::{Lazy, OnceCell};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::config::FaultsConfig;
+use crate::generator::RandomDataGenerator;
+
+struct Faults {
+    config: FaultsConfig,
+    statuses: Vec<StatusCode>,
+    weights: WeightedIndex<u32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FaultStats {
+    /// Default share of requests failed
+    pub error_rate: f64,
+    /// Errors returned, by status
+    pub injected: BTreeMap<u16, u64>,
+}
+
+/// Check and keep the fault settings; call once at startup
+pub fn configure(config: &FaultsConfig) -> anyhow::Result<()> {
+    config.validate()?;
+    let statuses = config
+        .statuses
+        .keys()
+        .filter_map(|&status| StatusCode::from_u16(status).ok())
+        .collect();
+    let weights = WeightedIndex::new(config.statuses.values().copied())?;
+    if config.error_rate > 0.0 {
+        tracing::info!(
+            "Failing {:.1}% of garble requests with {:?}",
+            config.error_rate * 100.0,
+            config.statuses
+        );
+    }
+    let _ = FAULTS.set(Faults {
+        config: config.clone(),
+        statuses,
+        weights,
+    });
+    Ok(())
+}
+
+/// The error status to fail a request with, at `rate` when the request gives
+/// one and the configured rate otherwise, or `None` to serve it
+pub fn roll(rate: Option<f64>) -> Option<StatusCode> {
+    let faults = FAULTS.get()?;
+    let rate = rate.unwrap_or(faults.config.error_rate);
+    let mut rng = rand::thread_rng();
+    if rate <= 0.0 || !rng.gen_bool(rate.min(1.0)) {
+        return None;
+    }
+    Some(faults.statuses[faults.weights.sample(&mut rng)])
+}
+
+/// A garbled JSON error body with `status`, counted under the status
+pub fn respond(status: StatusCode) -> Response {
+    let faults = FAULTS
+        .get()
+        .expect("faults are rolled only once configured");
+    *INJECTED.lock().unwrap().entry(status.as_u16()).or_default() += 1;
+
+    let body = RandomDataGenerator::new().generate_sized_record(faults.config.body_bytes);
+    let mut response = (
+        status,
+        [
+            (header::CONTENT_TYPE, "application/json"),
+            (
+                header::HeaderName::from_static("x-garble-fault"),
+                "injected",
+            ),
+        ],
+        serde_json::to_string(&body).unwrap_or_default(),
+    )
+        .into_response();
+    let throttled = matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    );
+    if let Some(retry_after) = faults.config.retry_after_secs.filter(|_| throttled) {
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    }
+    response
+}
+
+pub fn get_stats() -> FaultStats {
+    FaultStats {
+        error_rate: FAULTS.get().map_or(0.0, |faults| faults.config.error_rate),
+        injected: INJECTED.lock().unwrap().clone(),
+    }
+}
+
+// Global fault settings, set once at startup
+static FAULTS: OnceCell<Faults> = OnceCell::new();
+
+// Errors returned so far, by status
+static INJECTED: Lazy<Mutex<BTreeMap<u16, u64>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
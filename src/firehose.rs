@@ -0,0 +1,296 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use async_stream::stream;
+use axum::{
+    body::{Body, Bytes},
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        Query, RawQuery,
+    },
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::drain::DRAIN;
+use crate::error::ApiError;
+use crate::generator::RandomDataGenerator;
+use crate::metrics::METRICS;
+use crate::tenants::Tenant;
+use crate::validation::strict_query;
+
+const DEFAULT_RPS: u64 = 100;
+const MAX_RPS: u64 = 100_000;
+
+const DEFAULT_RECORD_BYTES: usize = 512;
+const MAX_RECORD_BYTES: usize = 1_000_000;
+
+/// How often records are released; each tick emits the records that fell due since the last
+const TICK: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Deserialize)]
+pub struct FirehoseParams {
+    rps: Option<u64>,
+    #[serde(rename = "recordBytes")]
+    record_bytes: Option<usize>,
+}
+
+/// Live state of one firehose stream, shared with the admin API
+struct FirehoseStream {
+    tenant: String,
+    record_bytes: usize,
+    rps: AtomicU64,
+    records_sent: AtomicU64,
+    started: Instant,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FirehoseSummary {
+    id: u64,
+    tenant: String,
+    rps: u64,
+    record_bytes: usize,
+    records_sent: u64,
+    uptime_ms: u128,
+}
+
+impl FirehoseStream {
+    fn summary(&self, id: u64) -> FirehoseSummary {
+        FirehoseSummary {
+            id,
+            tenant: self.tenant.clone(),
+            rps: self.rps.load(Ordering::Relaxed),
+            record_bytes: self.record_bytes,
+            records_sent: self.records_sent.load(Ordering::Relaxed),
+            uptime_ms: self.started.elapsed().as_millis(),
+        }
+    }
+}
+
+/// Open firehose streams, so their rate can be changed while they run
+pub struct FirehoseRegistry {
+    next_id: AtomicU64,
+    streams: Mutex<BTreeMap<u64, Arc<FirehoseStream>>>,
+}
+
+/// Removes a stream from the registry once its response is dropped
+struct Registration {
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        FIREHOSE.streams.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl FirehoseRegistry {
+    fn new() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            streams: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn register(&self, stream: Arc<FirehoseStream>) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.streams.lock().unwrap().insert(id, stream);
+        Registration { id }
+    }
+
+    fn summaries(&self) -> Vec<FirehoseSummary> {
+        self.streams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, stream)| stream.summary(*id))
+            .collect()
+    }
+
+    /// Change the rate of one stream, or of every open stream when `id` is unset
+    fn set_rate(&self, id: Option<u64>, rps: u64) -> Result<Vec<FirehoseSummary>, ApiError> {
+        let streams = self.streams.lock().unwrap();
+        let targets: Vec<_> = match id {
+            Some(id) => {
+                let stream = streams.get(&id).ok_or_else(|| {
+                    ApiError::new(
+                        StatusCode::NOT_FOUND,
+                        "not-found",
+                        "Not found",
+                        format!("no open firehose with id {}", id),
+                    )
+                })?;
+                vec![(id, stream)]
+            }
+            None => streams.iter().map(|(id, stream)| (*id, stream)).collect(),
+        };
+
+        Ok(targets
+            .into_iter()
+            .map(|(id, stream)| {
+                stream.rps.store(rps, Ordering::Relaxed);
+                stream.summary(id)
+            })
+            .collect())
+    }
+}
+
+fn validate_rps(rps: u64) -> Result<u64, ApiError> {
+    if rps > MAX_RPS {
+        return Err(ApiError::bad_request(format!(
+            "rps must be at most {}, got {}",
+            MAX_RPS, rps
+        )));
+    }
+    Ok(rps)
+}
+
+/// NDJSON records of roughly `record_bytes` each, released at the stream's current rate
+/// until the client disconnects or the instance starts draining
+fn records(
+    state: Arc<FirehoseStream>,
+    registration: Registration,
+) -> impl futures::Stream<Item = Result<Bytes, std::io::Error>> + Send {
+    stream! {
+        let _registration = registration;
+        let mut ticker = tokio::time::interval(TICK);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut last_tick = Instant::now();
+        let mut due = 0.0f64;
+
+        loop {
+            ticker.tick().await;
+            if DRAIN.is_draining() {
+                break;
+            }
+
+            // Carry fractional records over so low rates are still honored
+            let now = Instant::now();
+            let rps = state.rps.load(Ordering::Relaxed);
+            due += rps as f64 * now.duration_since(last_tick).as_secs_f64();
+            last_tick = now;
+            let batch = due.floor() as usize;
+            if batch == 0 {
+                continue;
+            }
+            due -= batch as f64;
+
+            // Generation is CPU bound, keep it off the async workers
+            let record_bytes = state.record_bytes;
+            let Ok(lines) = tokio::task::spawn_blocking(move || {
+                let mut generator = RandomDataGenerator::new();
+                let mut lines = String::new();
+                for _ in 0..batch {
+                    let record = generator.generate_sized_record(record_bytes);
+                    lines.push_str(&serde_json::to_string(&record).unwrap_or_default());
+                    lines.push('\n');
+                }
+                lines
+            })
+            .await
+            else {
+                break;
+            };
+
+            state.records_sent.fetch_add(batch as u64, Ordering::Relaxed);
+            yield Ok(Bytes::from(lines));
+        }
+    }
+}
+
+pub async fn firehose_handler(
+    params: Result<Query<FirehoseParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    tenant: Tenant,
+) -> Result<Response, ApiError> {
+    if DRAIN.is_draining() {
+        return Err(ApiError::draining());
+    }
+
+    let params = strict_query(
+        params,
+        raw_query.as_deref(),
+        tenant.garble.strict_validation,
+    )?;
+    let rps = validate_rps(params.rps.unwrap_or(DEFAULT_RPS))?;
+    let record_bytes = params.record_bytes.unwrap_or(DEFAULT_RECORD_BYTES);
+    if record_bytes == 0 || record_bytes > MAX_RECORD_BYTES {
+        return Err(ApiError::bad_request(format!(
+            "recordBytes must be between 1 and {}, got {}",
+            MAX_RECORD_BYTES, record_bytes
+        )));
+    }
+
+    let state = Arc::new(FirehoseStream {
+        tenant: tenant.name.clone(),
+        record_bytes,
+        rps: AtomicU64::new(rps),
+        records_sent: AtomicU64::new(0),
+        started: Instant::now(),
+    });
+    let registration = FIREHOSE.register(state.clone());
+    let id = registration.id;
+
+    tracing::info!(
+        "Opened firehose {}: tenant={}, rps={}, record_bytes={}B",
+        id,
+        tenant.name,
+        rps,
+        record_bytes
+    );
+    METRICS.record_response(&tenant.segment(), "firehose", 0, 0);
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson".to_string()),
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+            (
+                header::HeaderName::from_static("x-garble-mode"),
+                "firehose".to_string(),
+            ),
+            (
+                header::HeaderName::from_static("x-firehose-id"),
+                id.to_string(),
+            ),
+        ],
+        Body::from_stream(records(state, registration)),
+    )
+        .into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FirehoseUpdate {
+    /// Stream to adjust; every open stream when unset
+    id: Option<u64>,
+    rps: u64,
+}
+
+/// List open firehose streams
+pub async fn firehose_list_handler() -> Json<Vec<FirehoseSummary>> {
+    Json(FIREHOSE.summaries())
+}
+
+/// Change the rate of open firehose streams while they run
+pub async fn firehose_update_handler(
+    update: Result<Json<FirehoseUpdate>, JsonRejection>,
+) -> Result<Json<Vec<FirehoseSummary>>, ApiError> {
+    let Json(update) = update?;
+    let rps = validate_rps(update.rps)?;
+    let updated = FIREHOSE.set_rate(update.id, rps)?;
+    tracing::info!(
+        "Set rate of {} firehose streams to {}/s",
+        updated.len(),
+        rps
+    );
+    Ok(Json(updated))
+}
+
+// Global firehose registry instance
+pub static FIREHOSE: Lazy<FirehoseRegistry> = Lazy::new(FirehoseRegistry::new);
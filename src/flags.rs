@@ -0,0 +1,267 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Flags switching individual chaos behaviors of `/garble` on and off. How a
+//! behavior misbehaves is configured under `flags`; whether it is active can be
+//! changed at runtime through a flags file, `/admin/flags` or the cluster scenario.
+
+use async_stream::stream;
+use axum::{
+    body::Body,
+    extract::rejection::JsonRejection,
+    http::StatusCode,
+    response::{Json, Response},
+};
+use futures::StreamExt;
+use once_cell::sync::{Lazy, OnceCell};
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::FlagsConfig;
+use crate::error::ApiError;
+
+pub const ERRORS: &str = "errors";
+pub const LATENCY_SPIKES: &str = "latency_spikes";
+pub const TRUNCATION: &str = "truncation";
+
+/// Pause between the last byte of a truncated body and dropping the connection
+const TRUNCATION_FLUSH_DELAY: Duration = Duration::from_millis(50);
+
+struct Flag {
+    enabled: AtomicBool,
+    /// Requests the behavior was applied to
+    triggered: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FlagStats {
+    pub enabled: bool,
+    pub triggered: u64,
+}
+
+/// Current state of every chaos flag
+pub struct Flags {
+    flags: BTreeMap<&'static str, Flag>,
+    settings: OnceCell<FlagsConfig>,
+}
+
+impl Flags {
+    fn new() -> Self {
+        let flags = [ERRORS, LATENCY_SPIKES, TRUNCATION]
+            .into_iter()
+            .map(|name| {
+                let flag = Flag {
+                    enabled: AtomicBool::new(false),
+                    triggered: AtomicU64::new(0),
+                };
+                (name, flag)
+            })
+            .collect();
+        Self {
+            flags,
+            settings: OnceCell::new(),
+        }
+    }
+
+    /// Switch a flag, returning `false` for unknown flags
+    pub fn set(&self, name: &str, enabled: bool) -> bool {
+        match self.flags.get(name) {
+            Some(flag) => {
+                if flag.enabled.swap(enabled, Ordering::Relaxed) != enabled {
+                    tracing::info!("Chaos flag {} {}", name, if enabled { "on" } else { "off" });
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Switch flags by name, ignoring names that are not flags
+    pub fn apply(&self, states: &BTreeMap<String, bool>) {
+        for (name, enabled) in states {
+            if !self.set(name, *enabled) {
+                tracing::debug!("Ignoring unknown chaos flag {}", name);
+            }
+        }
+    }
+
+    pub fn states(&self) -> BTreeMap<String, bool> {
+        self.flags
+            .iter()
+            .map(|(name, flag)| (name.to_string(), flag.enabled.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    pub fn get_stats(&self) -> BTreeMap<&'static str, FlagStats> {
+        self.flags
+            .iter()
+            .map(|(name, flag)| {
+                let stats = FlagStats {
+                    enabled: flag.enabled.load(Ordering::Relaxed),
+                    triggered: flag.triggered.load(Ordering::Relaxed),
+                };
+                (*name, stats)
+            })
+            .collect()
+    }
+
+    /// Whether the behavior behind `name` applies to this request
+    fn roll(&self, name: &str, probability: f64) -> bool {
+        let Some(flag) = self.flags.get(name) else {
+            return false;
+        };
+        if !flag.enabled.load(Ordering::Relaxed) || !thread_rng().gen_bool(probability) {
+            return false;
+        }
+        flag.triggered.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+}
+
+/// Chaos picked for a single `/garble` request
+#[derive(Debug, Default)]
+pub struct Chaos {
+    /// Extra delay on top of the requested wait
+    pub latency_spike: Option<Duration>,
+    /// Status to fail the request with
+    pub error: Option<StatusCode>,
+    /// Whether to cut the body off partway
+    pub truncate: bool,
+}
+
+/// Decide which chaos behaviors apply to a request
+pub fn roll() -> Chaos {
+    let Some(settings) = FLAGS.settings.get() else {
+        return Chaos::default();
+    };
+    Chaos {
+        latency_spike: FLAGS
+            .roll(LATENCY_SPIKES, settings.latency_spikes.probability)
+            .then(|| Duration::from_millis(settings.latency_spikes.delay_ms)),
+        error: FLAGS
+            .roll(ERRORS, settings.errors.probability)
+            .then(|| StatusCode::from_u16(settings.errors.status).ok())
+            .flatten(),
+        truncate: FLAGS.roll(TRUNCATION, settings.truncation.probability),
+    }
+}
+
+/// Stream the body up to a random point below `target_size`, then fail it so
+/// the connection is dropped without finishing the response
+pub fn truncate(response: Response, target_size: usize) -> Response {
+    let cutoff = thread_rng().gen_range(0..target_size.max(1));
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(axum::http::header::CONTENT_LENGTH);
+
+    let truncated = stream! {
+        let mut body = body.into_data_stream();
+        let mut sent = 0;
+        while let Some(data) = body.next().await {
+            let mut data = match data {
+                Ok(data) => data,
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            };
+            let room = cutoff - sent;
+            if data.len() >= room {
+                data.truncate(room);
+                yield Ok(data);
+                break;
+            }
+            sent += data.len();
+            yield Ok(data);
+        }
+        // Let the partial body reach the client before the connection drops
+        tokio::time::sleep(TRUNCATION_FLUSH_DELAY).await;
+        yield Err(axum::Error::new("response truncated by chaos flag"));
+    };
+    Response::from_parts(parts, Body::from_stream(truncated))
+}
+
+/// Set the chaos settings and initial flag states; call once at startup
+pub fn configure(config: &FlagsConfig) -> anyhow::Result<()> {
+    config.validate()?;
+    FLAGS.set(ERRORS, config.errors.enabled);
+    FLAGS.set(LATENCY_SPIKES, config.latency_spikes.enabled);
+    FLAGS.set(TRUNCATION, config.truncation.enabled);
+    if let Some(path) = &config.file {
+        load_file(Path::new(path))?;
+    }
+    let _ = FLAGS.settings.set(config.clone());
+    Ok(())
+}
+
+fn load_file(path: &Path) -> anyhow::Result<()> {
+    let states: BTreeMap<String, bool> = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    FLAGS.apply(&states);
+    tracing::info!("Loaded chaos flags from {}", path.display());
+    Ok(())
+}
+
+fn last_modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reload the flags file whenever it changes
+pub async fn watch_flags(config: FlagsConfig, shutdown: CancellationToken) {
+    let Some(path) = config.file.as_deref().map(Path::new) else {
+        return;
+    };
+    let mut last_seen = last_modified(path);
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(config.reload_interval_ms)) => {}
+            _ = shutdown.cancelled() => break,
+        }
+
+        let modified = last_modified(path);
+        if modified != last_seen {
+            last_seen = modified;
+            if let Err(e) = load_file(path) {
+                tracing::warn!(
+                    "Failed to reload chaos flags, keeping current states: {}",
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FlagUpdate {
+    flag: String,
+    enabled: bool,
+}
+
+/// List chaos flags with their states and how often they fired
+pub async fn flags_list_handler() -> Json<BTreeMap<&'static str, FlagStats>> {
+    Json(FLAGS.get_stats())
+}
+
+/// Switch a chaos flag on or off
+pub async fn flags_update_handler(
+    update: Result<Json<FlagUpdate>, JsonRejection>,
+) -> Result<Json<BTreeMap<&'static str, FlagStats>>, ApiError> {
+    let Json(update) = update?;
+    if !FLAGS.set(&update.flag, update.enabled) {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "not-found",
+            "Not found",
+            format!("no chaos flag named {}", update.flag),
+        ));
+    }
+    Ok(Json(FLAGS.get_stats()))
+}
+
+// Global chaos flags instance
+pub static FLAGS: Lazy<Flags> = Lazy::new(Flags::new);
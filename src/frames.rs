@@ -0,0 +1,353 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Length-prefixed binary frames for feeding custom protocol parsers. Each
+//! frame is a length field, an optional type byte and a random payload, laid
+//! out as the `frames` config describes (a big endian u32 length and a type
+//! byte by default). They are served over HTTP by `/garble/frames`, and over a
+//! raw TCP listener when `frames.tcp_port` is set.
+
+use async_stream::stream;
+use axum::{
+    body::{Body, Bytes},
+    extract::{rejection::QueryRejection, Query, RawQuery},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use once_cell::sync::OnceCell;
+use rand::{seq::SliceRandom, thread_rng, Rng, RngCore};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{ByteOrder, FramePayload, FramesConfig};
+use crate::drain::DRAIN;
+use crate::error::ApiError;
+use crate::generator::RandomDataGenerator;
+use crate::metrics::METRICS;
+use crate::tenants::Tenant;
+use crate::validation::strict_query;
+
+const DEFAULT_FRAME_COUNT: usize = 100;
+const MAX_FRAME_COUNT: usize = 1_000_000;
+
+/// Largest payload of a single frame
+const MAX_PAYLOAD_BYTES: usize = 16_000_000;
+
+/// Longest accepted pause between frames
+const MAX_INTERVAL_MS: u64 = 60_000;
+
+/// Frames generated together when they are not paced
+const BATCH_FRAMES: usize = 64;
+
+/// Furthest a corrupted length field is off from the truth
+const MAX_CORRUPTION: u64 = 64;
+
+#[derive(Debug, Deserialize)]
+pub struct FramesParams {
+    count: Option<usize>,
+    #[serde(rename = "minPayloadBytes")]
+    min_payload_bytes: Option<usize>,
+    #[serde(rename = "maxPayloadBytes")]
+    max_payload_bytes: Option<usize>,
+    payload: Option<FramePayload>,
+    #[serde(rename = "corruptRate")]
+    corrupt_rate: Option<f64>,
+    #[serde(rename = "intervalMs")]
+    interval_ms: Option<u64>,
+}
+
+/// What to send: how many frames, their payloads, and how often a length lies
+#[derive(Debug, Clone, Copy)]
+struct FrameSpec {
+    /// Frames to send; `None` keeps going until the receiver goes away
+    count: Option<usize>,
+    min_payload: usize,
+    max_payload: usize,
+    payload: FramePayload,
+    corrupt_rate: f64,
+    interval: Duration,
+}
+
+/// Frame header layout
+fn layout() -> &'static FramesConfig {
+    LAYOUT.get_or_init(FramesConfig::default)
+}
+
+fn header_len(layout: &FramesConfig) -> usize {
+    layout.length_bytes + usize::from(!layout.types.is_empty())
+}
+
+/// Largest payload the length field can describe
+fn max_payload(layout: &FramesConfig) -> usize {
+    let max_length = match layout.length_bytes {
+        8 => u64::MAX,
+        bytes => (1u64 << (bytes * 8)) - 1,
+    };
+    let header = if layout.length_includes_header {
+        header_len(layout) as u64
+    } else {
+        0
+    };
+    usize::try_from(max_length - header)
+        .unwrap_or(usize::MAX)
+        .min(MAX_PAYLOAD_BYTES)
+}
+
+/// Append one frame with a payload of `size` bytes
+fn write_frame(out: &mut Vec<u8>, layout: &FramesConfig, spec: &FrameSpec, size: usize) {
+    let mut rng = thread_rng();
+    let payload = match spec.payload {
+        FramePayload::Json => {
+            let record = RandomDataGenerator::new().generate_sized_record(size);
+            let mut payload = serde_json::to_vec(&record).unwrap_or_default();
+            // Records come out roughly sized; pad or cut them to the exact length
+            payload.resize(size, b' ');
+            payload
+        }
+        FramePayload::Binary => {
+            let mut payload = vec![0u8; size];
+            rng.fill_bytes(&mut payload);
+            payload
+        }
+    };
+
+    let mut length = payload.len() as u64;
+    if layout.length_includes_header {
+        length += header_len(layout) as u64;
+    }
+    if spec.corrupt_rate > 0.0 && rng.gen_bool(spec.corrupt_rate) {
+        let delta = rng.gen_range(1..=MAX_CORRUPTION);
+        length = if rng.gen_bool(0.5) {
+            length.wrapping_add(delta)
+        } else {
+            length.wrapping_sub(delta)
+        };
+    }
+    let field = match layout.byte_order {
+        ByteOrder::Big => length.to_be_bytes(),
+        ByteOrder::Little => length.to_le_bytes(),
+    };
+    match layout.byte_order {
+        ByteOrder::Big => out.extend_from_slice(&field[8 - layout.length_bytes..]),
+        ByteOrder::Little => out.extend_from_slice(&field[..layout.length_bytes]),
+    }
+    if let Some(frame_type) = layout.types.choose(&mut rng) {
+        out.push(*frame_type);
+    }
+    out.extend_from_slice(&payload);
+}
+
+/// `frames` consecutive frames with payload sizes picked from the spec's range
+fn frame_batch(spec: FrameSpec, frames: usize) -> Vec<u8> {
+    let layout = layout();
+    let mut rng = thread_rng();
+    let mut out = Vec::new();
+    for _ in 0..frames {
+        let size = rng.gen_range(spec.min_payload..=spec.max_payload);
+        write_frame(&mut out, layout, &spec, size);
+    }
+    out
+}
+
+/// Frames as they are due, until the spec's count is reached, the instance
+/// starts draining or `shutdown` fires
+fn frames(
+    spec: FrameSpec,
+    shutdown: Option<CancellationToken>,
+) -> impl futures::Stream<Item = Result<Bytes, std::io::Error>> + Send {
+    stream! {
+        let mut sent = 0;
+        loop {
+            let remaining = spec.count.map_or(usize::MAX, |count| count - sent);
+            if remaining == 0 || DRAIN.is_draining() {
+                break;
+            }
+            if shutdown.as_ref().is_some_and(CancellationToken::is_cancelled) {
+                break;
+            }
+            if sent > 0 && !spec.interval.is_zero() {
+                tokio::time::sleep(spec.interval).await;
+            }
+
+            let batch = if spec.interval.is_zero() {
+                remaining.min(BATCH_FRAMES)
+            } else {
+                1
+            };
+            // Generation is CPU bound, keep it off the async workers
+            let Ok(bytes) = tokio::task::spawn_blocking(move || frame_batch(spec, batch)).await
+            else {
+                break;
+            };
+            sent += batch;
+            yield Ok(Bytes::from(bytes));
+        }
+    }
+}
+
+fn validate_payload_range(min: usize, max: usize) -> Result<(), String> {
+    let limit = max_payload(layout());
+    if min > max {
+        return Err(format!(
+            "minimum payload of {} bytes is larger than the maximum of {}",
+            min, max
+        ));
+    }
+    if max > limit {
+        return Err(format!(
+            "payloads of up to {} bytes do not fit the frame layout, which allows {}",
+            max, limit
+        ));
+    }
+    Ok(())
+}
+
+/// Check and keep the frame layout, and bind the TCP listener if a port is
+/// configured; call once at startup
+pub fn start(
+    config: &FramesConfig,
+    shutdown: CancellationToken,
+) -> anyhow::Result<Option<JoinHandle<()>>> {
+    if ![1, 2, 4, 8].contains(&config.length_bytes) {
+        anyhow::bail!(
+            "frames.length_bytes must be 1, 2, 4 or 8, got {}",
+            config.length_bytes
+        );
+    }
+    let _ = LAYOUT.set(config.clone());
+    validate_payload_range(config.min_payload_bytes, config.max_payload_bytes)
+        .map_err(|e| anyhow::anyhow!("frames: {}", e))?;
+
+    let Some(port) = config.tcp_port else {
+        return Ok(None);
+    };
+    let listener = std::net::TcpListener::bind(("0.0.0.0", port))?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let spec = FrameSpec {
+        count: (config.frames_per_connection > 0).then_some(config.frames_per_connection),
+        min_payload: config.min_payload_bytes,
+        max_payload: config.max_payload_bytes,
+        payload: config.payload,
+        corrupt_rate: 0.0,
+        interval: Duration::from_millis(config.interval_ms),
+    };
+    tracing::info!("Serving binary frames over TCP on port {}", port);
+    Ok(Some(tokio::spawn(accept(listener, spec, shutdown))))
+}
+
+async fn accept(listener: TcpListener, spec: FrameSpec, shutdown: CancellationToken) {
+    loop {
+        let (socket, peer) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("Failed to accept frame connection: {}", e);
+                    continue;
+                }
+            },
+        };
+        tracing::debug!("Sending frames to {}", peer);
+        tokio::spawn(send(socket, spec, shutdown.clone()));
+    }
+}
+
+/// Write frames to a TCP client until they are all out or it goes away
+async fn send(mut socket: TcpStream, spec: FrameSpec, shutdown: CancellationToken) {
+    let frames = frames(spec, Some(shutdown));
+    futures::pin_mut!(frames);
+    while let Some(Ok(bytes)) = futures::StreamExt::next(&mut frames).await {
+        if socket.write_all(&bytes).await.is_err() {
+            return;
+        }
+    }
+    let _ = socket.shutdown().await;
+}
+
+pub async fn frames_handler(
+    params: Result<Query<FramesParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    tenant: Tenant,
+) -> Result<Response, ApiError> {
+    if DRAIN.is_draining() {
+        return Err(ApiError::draining());
+    }
+
+    let params = strict_query(
+        params,
+        raw_query.as_deref(),
+        tenant.garble.strict_validation,
+    )?;
+    let config = layout();
+    let count = params.count.unwrap_or(DEFAULT_FRAME_COUNT);
+    if count == 0 || count > MAX_FRAME_COUNT {
+        return Err(ApiError::bad_request(format!(
+            "count must be between 1 and {}, got {}",
+            MAX_FRAME_COUNT, count
+        )));
+    }
+    let min_payload = params.min_payload_bytes.unwrap_or(config.min_payload_bytes);
+    let max_payload = params.max_payload_bytes.unwrap_or(config.max_payload_bytes);
+    validate_payload_range(min_payload, max_payload).map_err(ApiError::bad_request)?;
+    let corrupt_rate = params.corrupt_rate.unwrap_or(0.0);
+    if !(0.0..=1.0).contains(&corrupt_rate) {
+        return Err(ApiError::bad_request(format!(
+            "corruptRate must be between 0 and 1, got {}",
+            corrupt_rate
+        )));
+    }
+    let interval_ms = params.interval_ms.unwrap_or(0);
+    if interval_ms > MAX_INTERVAL_MS {
+        return Err(ApiError::bad_request(format!(
+            "intervalMs must be at most {}, got {}",
+            MAX_INTERVAL_MS, interval_ms
+        )));
+    }
+    // The payloads alone are held to the same cap as garble bodies
+    let largest = count.saturating_mul(max_payload);
+    if let Some(cap) = tenant.garble.body_size_cap.filter(|&cap| largest > cap) {
+        return Err(ApiError::body_too_large(largest, cap));
+    }
+
+    let spec = FrameSpec {
+        count: Some(count),
+        min_payload,
+        max_payload,
+        payload: params.payload.unwrap_or(config.payload),
+        corrupt_rate,
+        interval: Duration::from_millis(interval_ms),
+    };
+    tracing::info!(
+        "Sending {} frames: tenant={}, payload={}-{}B",
+        count,
+        tenant.name,
+        min_payload,
+        max_payload
+    );
+    METRICS.record_response(&tenant.segment(), "frames", 0, 0);
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (
+                header::HeaderName::from_static("x-garble-mode"),
+                "frames".to_string(),
+            ),
+            (
+                header::HeaderName::from_static("x-garble-frame-count"),
+                count.to_string(),
+            ),
+        ],
+        Body::from_stream(frames(spec, None)),
+    )
+        .into_response())
+}
+
+// Global frame layout, set once at startup
+static LAYOUT: OnceCell<FramesConfig> = OnceCell::new();
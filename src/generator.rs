@@ -2,21 +2,282 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use chrono::Utc;
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use once_cell::sync::{Lazy, OnceCell};
+use rand::distributions::WeightedIndex;
 use rand::prelude::*;
+use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
+use std::sync::Arc;
+use std::time::Instant;
 use uuid::Uuid;
 
+use crate::config::{DateFormatWeights, LengthDistribution, StringLengthConfig, StructurePreset};
+use crate::dictionary::{WordLists, DICTIONARY};
+use crate::markov;
+use crate::plugins::{self, Plugin, PluginMix};
+
+/// How plain string values are produced
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextMode {
+    /// Random characters
+    #[default]
+    Random,
+    /// Semi-plausible sentences from the Markov model
+    Markov,
+}
+
+/// How temporal values are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DateMode {
+    /// The current time in chrono's default UTC format
+    #[default]
+    Standard,
+    /// Random times in a weighted mix of formats
+    Mixed,
+}
+
+/// Value type of a record column
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColumnKind {
+    Text,
+    Integer,
+    Float,
+    Boolean,
+    Uuid,
+    Timestamp,
+    Hex,
+}
+
+impl ColumnKind {
+    const ALL: &'static [ColumnKind] = &[
+        ColumnKind::Text,
+        ColumnKind::Integer,
+        ColumnKind::Float,
+        ColumnKind::Boolean,
+        ColumnKind::Uuid,
+        ColumnKind::Timestamp,
+        ColumnKind::Hex,
+    ];
+}
+
+#[derive(Debug, Clone)]
+struct RecordColumn {
+    name: String,
+    kind: ColumnKind,
+    nullable: bool,
+}
+
+/// Columns shared by every record of a `shape=records` response
+#[derive(Debug, Clone)]
+pub struct RecordSchema {
+    columns: Vec<RecordColumn>,
+}
+
+/// How often individual keys and values repeat
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueDistribution {
+    /// Every key and value is freshly generated
+    #[default]
+    Uniform,
+    /// Keys and values are drawn from a shared vocabulary by Zipf rank, so a
+    /// few appear very often and a long tail appears rarely
+    Zipf,
+}
+
+/// Values replaced by JSON documents serialized into strings, as in
+/// `"{\"id\":1}"`, which consumers have to decode twice
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StringifiedJson {
+    /// Chance of a value being replaced, from 0 to 1
+    pub probability: f64,
+    /// Rough serialized size of each embedded document
+    pub bytes: usize,
+}
+
+/// Per-request knobs for the generator
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GeneratorOptions {
+    pub text: TextMode,
+    /// Zipf exponent for skewed keys and values; uniform when unset
+    pub zipf_exponent: Option<f64>,
+    /// Emit numbers outside the i64/f64 range
+    pub big_numbers: bool,
+    pub dates: DateMode,
+    /// Shape from a structure preset, in place of garble's default shape
+    pub structure: Option<Arc<Structure>>,
+    pub stringified_json: Option<StringifiedJson>,
+}
+
+impl GeneratorOptions {
+    /// Pooled chunks are generated with default options, so only requests
+    /// that keep the defaults can be served from the pool
+    pub fn uses_pool(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A structure preset ready to generate from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Structure {
+    max_depth: usize,
+    min_fields: usize,
+    max_fields: usize,
+    max_array_length: usize,
+    nest_probability: f64,
+    types: WeightedIndex<u32>,
+    // Types for values at the deepest level, where containers are ruled out
+    scalars: WeightedIndex<u32>,
+    string_lengths: Option<StringLengthConfig>,
+}
+
+impl Structure {
+    /// Compile a preset that has passed validation
+    pub fn compile(preset: &StructurePreset) -> anyhow::Result<Self> {
+        Ok(Self {
+            max_depth: preset.max_depth,
+            min_fields: preset.min_fields,
+            max_fields: preset.max_fields,
+            max_array_length: preset.max_array_length,
+            nest_probability: preset.nest_probability,
+            types: WeightedIndex::new(preset.type_weights.as_array())?,
+            scalars: WeightedIndex::new(preset.type_weights.scalars())?,
+            string_lengths: preset.string_lengths.clone(),
+        })
+    }
+}
+
+static STRING_LENGTHS: OnceCell<StringLengthConfig> = OnceCell::new();
+static DATE_FORMATS: OnceCell<WeightedIndex<u32>> = OnceCell::new();
+
+/// UTC offsets in minutes used for formats that carry one, including the odd half and quarter hours
+const DATE_OFFSETS_MINUTES: &[i32] = &[
+    -720, -600, -480, -300, -210, 0, 60, 120, 330, 345, 540, 570, 765, 840,
+];
+
+/// Set the length distribution for generated string values; call once at startup
+pub fn configure_string_lengths(config: &StringLengthConfig) -> anyhow::Result<()> {
+    config.validate()?;
+    let _ = STRING_LENGTHS.set(config.clone());
+    Ok(())
+}
+
+/// Set the format weights used by `dates=mixed`; call once at startup
+pub fn configure_date_formats(weights: &DateFormatWeights) -> anyhow::Result<()> {
+    weights.validate()?;
+    let _ = DATE_FORMATS.set(WeightedIndex::new(weights.as_array())?);
+    Ok(())
+}
+
+/// Version of what seeded generators produce; bump it with any change, including
+/// a `rand` upgrade, that makes a seed generate different values
+pub const GENERATOR_VERSION: u32 = 1;
+
+/// Clock seeded generators generate dates around, so their output does not drift
+const SEEDED_CLOCK: DateTime<Utc> = DateTime::from_timestamp_nanos(1_704_067_200_000_000_000);
+
+/// Number of distinct keys and values that skewed generation draws from
+const SKEWED_VOCABULARY_SIZE: usize = 1000;
+
+/// Keys and scalar values shared by all skewed responses, ordered by rank
+struct SkewedVocabulary {
+    keys: Vec<String>,
+    values: Vec<Value>,
+}
+
+static SKEWED_VOCABULARY: Lazy<SkewedVocabulary> = Lazy::new(|| {
+    let mut generator = RandomDataGenerator::new();
+    let keys = (0..SKEWED_VOCABULARY_SIZE)
+        .map(|_| generator.generate_random_key())
+        .collect();
+    let mut values = Vec::with_capacity(SKEWED_VOCABULARY_SIZE);
+    while values.len() < SKEWED_VOCABULARY_SIZE {
+        values.push(generator.generate_scalar());
+    }
+    SkewedVocabulary { keys, values }
+});
+
 pub struct RandomDataGenerator {
-    rng: ThreadRng,
+    rng: StdRng,
+    /// Time dates are generated around; the current time when unset
+    clock: Option<DateTime<Utc>>,
+    /// Custom generators mixed into scalars, per the `generators` config
+    plugin_mix: Option<&'static PluginMix>,
+    words: Arc<WordLists>,
+    lengths: &'static StringLengthConfig,
+    options: GeneratorOptions,
+    // Rank sampler for skewed keys and values
+    zipf: Option<WeightedIndex<f64>>,
+    /// Time after which growing payloads stop short of their target size
+    deadline: Option<Instant>,
+}
+
+impl Default for RandomDataGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RandomDataGenerator {
     pub fn new() -> Self {
-        Self { rng: thread_rng() }
+        Self::with_options(GeneratorOptions::default())
+    }
+
+    pub fn with_options(options: GeneratorOptions) -> Self {
+        let zipf = options.zipf_exponent.and_then(|exponent| {
+            WeightedIndex::new(
+                (1..=SKEWED_VOCABULARY_SIZE).map(|rank| 1.0 / (rank as f64).powf(exponent)),
+            )
+            .ok()
+        });
+
+        Self {
+            rng: StdRng::from_rng(thread_rng()).expect("thread rng does not fail"),
+            clock: None,
+            plugin_mix: plugins::mix(),
+            words: DICTIONARY.current(),
+            lengths: STRING_LENGTHS.get_or_init(StringLengthConfig::default),
+            options,
+            zipf,
+            deadline: None,
+        }
+    }
+
+    /// Stop growing payloads towards their target size once `deadline` passes,
+    /// so callers with a time budget can check it and give up
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Whether the deadline, if any, has passed
+    pub fn out_of_time(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// A generator that produces the same values for the same seed and string
+    /// length settings; word lists and custom generators are left out as they
+    /// differ between machines
+    pub fn seeded(options: GeneratorOptions, seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            clock: Some(SEEDED_CLOCK),
+            plugin_mix: None,
+            words: Arc::new(WordLists::default()),
+            ..Self::with_options(options)
+        }
     }
 
     pub fn generate_payload(&mut self, target_size: usize) -> Value {
+        if let Some(structure) = self.options.structure.clone() {
+            return self.generate_structured_payload(&structure, target_size);
+        }
+
         // Start with completely random structure - no fixed fields
         let mut payload = self.generate_random_object(3); // Start with depth 3
 
@@ -24,47 +285,51 @@ impl RandomDataGenerator {
         let mut current_size = serde_json::to_string(&payload).unwrap().len();
         let mut iterations = 0;
 
-        while current_size < target_size && iterations < 1000 {
+        while current_size < target_size && iterations < 1000 && !self.out_of_time() {
             // Randomly decide what to add
-            match self.rng.gen_range(0..4) {
+            let (key, value) = match self.rng.gen_range(0..4) {
                 0 => {
                     // Add a completely random field to root
                     let key = self.generate_random_key();
                     let depth = self.rng.gen_range(1..6);
-                    let value = self.generate_random_value(depth);
-                    if let Value::Object(ref mut map) = payload {
-                        map.insert(key, value);
-                    }
+                    (key, self.generate_random_value(depth))
                 }
                 1 => {
                     // Add random array
                     let key = self.generate_random_key();
                     let length = self.rng.gen_range(1..20);
-                    let array = self.generate_random_array(length);
-                    if let Value::Object(ref mut map) = payload {
-                        map.insert(key, array);
-                    }
+                    (key, self.generate_random_array(length))
                 }
                 2 => {
                     // Add nested random object
                     let key = self.generate_random_key();
                     let depth = self.rng.gen_range(1..5);
-                    let obj = self.generate_random_object(depth);
-                    if let Value::Object(ref mut map) = payload {
-                        map.insert(key, obj);
-                    }
+                    (key, self.generate_random_object(depth))
                 }
                 _ => {
                     // Add random garbled data
                     let key = self.generate_random_key();
-                    let garbled = self.generate_garbled_data();
-                    if let Value::Object(ref mut map) = payload {
-                        map.insert(key, garbled);
-                    }
+                    (key, self.generate_garbled_data())
                 }
-            }
+            };
+            let Value::Object(ref mut map) = payload else {
+                break;
+            };
 
-            current_size = serde_json::to_string(&payload).unwrap().len();
+            // Count the member in rather than serializing the whole payload again:
+            // a quoted key, colon and value, and a comma unless the object was empty
+            let value_size = serde_json::to_string(&value).unwrap().len();
+            let member_size = serde_json::to_string(&key).unwrap().len()
+                + 1
+                + value_size
+                + usize::from(!map.is_empty());
+            match map.insert(key, value) {
+                None => current_size += member_size,
+                Some(replaced) => {
+                    current_size =
+                        current_size + value_size - serde_json::to_string(&replaced).unwrap().len()
+                }
+            }
             iterations += 1;
 
             // Safety check to prevent infinite loops
@@ -78,6 +343,11 @@ impl RandomDataGenerator {
 
     /// Generate a payload that's designed to be an array element (not a complete JSON object)
     pub fn generate_array_element(&mut self, target_size: usize) -> Value {
+        // A preset shapes every element like a whole payload
+        if self.options.structure.is_some() {
+            return self.generate_payload(target_size);
+        }
+
         // Generate various types of values that can go in an array
         let choice = self.rng.gen_range(0..6);
         match choice {
@@ -99,6 +369,115 @@ impl RandomDataGenerator {
         }
     }
 
+    /// A flat object of roughly `target_size` serialized bytes, for line-oriented formats
+    /// where every record should be about the same size
+    pub fn generate_sized_record(&mut self, target_size: usize) -> Value {
+        let mut record = Map::new();
+        // Braces, then a quoted key, colon and comma per field
+        let mut size = 2;
+
+        while size < target_size {
+            let key = self.generate_random_key();
+            // Arrays nest arbitrarily large values, keep to scalars
+            let value = self.generate_scalar();
+            let field_size = key.len() + serde_json::to_string(&value).unwrap().len() + 4;
+            if record.insert(key, value).is_none() {
+                size += field_size;
+            }
+        }
+
+        Value::Object(record)
+    }
+
+    /// A garbled variant of `value` that keeps its overall shape: some keys are renamed
+    /// and some scalars replaced, then top-level members are added or dropped to bring
+    /// it to roughly `target_size` serialized bytes
+    pub fn mutate(&mut self, value: &Value, target_size: usize) -> Value {
+        let mut mutated = self.mutate_value(value);
+        let mut size = serde_json::to_string(&mutated).unwrap().len();
+
+        match &mut mutated {
+            Value::Object(map) => {
+                if size < target_size {
+                    if let Value::Object(extra) = self.generate_sized_record(target_size - size) {
+                        map.extend(extra);
+                    }
+                }
+                while size > target_size && map.len() > 1 {
+                    let key = map.keys().choose(&mut self.rng).unwrap().clone();
+                    let removed = map.remove(&key).unwrap();
+                    size -= key.len() + serde_json::to_string(&removed).unwrap().len() + 4;
+                }
+            }
+            Value::Array(items) => {
+                while size < target_size {
+                    let element = self.generate_sized_record(target_size - size);
+                    size += serde_json::to_string(&element).unwrap().len() + 1;
+                    items.push(element);
+                }
+                while size > target_size && items.len() > 1 {
+                    let removed = items.pop().unwrap();
+                    size -= serde_json::to_string(&removed).unwrap().len() + 1;
+                }
+            }
+            Value::String(text) if size < target_size => {
+                let padding = self.garbled_string_of_length(target_size - size);
+                text.push_str(&padding);
+            }
+            _ => {}
+        }
+
+        mutated
+    }
+
+    fn mutate_value(&mut self, value: &Value) -> Value {
+        match value {
+            Value::Object(map) => Value::Object(
+                map.iter()
+                    .map(|(key, value)| {
+                        let key = if self.rng.gen_bool(0.2) {
+                            self.generate_random_key()
+                        } else {
+                            key.clone()
+                        };
+                        (key, self.mutate_value(value))
+                    })
+                    .collect(),
+            ),
+            Value::Array(items) => {
+                Value::Array(items.iter().map(|v| self.mutate_value(v)).collect())
+            }
+            Value::String(text) if self.rng.gen_bool(0.3) => {
+                Value::String(self.garble_characters(text))
+            }
+            _ if self.rng.gen_bool(0.3) => self.generate_scalar(),
+            scalar => scalar.clone(),
+        }
+    }
+
+    /// `text` with about a quarter of its characters swapped for garbage
+    pub fn garble_characters(&mut self, text: &str) -> String {
+        text.chars()
+            .map(|c| {
+                if self.rng.gen_bool(0.25) {
+                    self.garbled_string_of_length(1).chars().next().unwrap_or(c)
+                } else {
+                    c
+                }
+            })
+            .collect()
+    }
+
+    /// A random value that is neither an array nor an object
+    pub fn generate_scalar(&mut self) -> Value {
+        loop {
+            let value = self.generate_random_value(0);
+            if !value.is_array() && !value.is_object() {
+                return value;
+            }
+        }
+    }
+
     fn generate_random_object(&mut self, max_depth: usize) -> Value {
         let mut obj = Map::new();
         let field_count = self.rng.gen_range(1..15);
@@ -130,21 +509,10 @@ impl RandomDataGenerator {
     }
 
     fn generate_random_value(&mut self, max_depth: usize) -> Value {
+        if let Some(value) = self.stringified_json() {
+            return value;
+        }
         match self.rng.gen_range(0..12) {
-            0 => {
-                let length = self.rng.gen_range(1..50);
-                Value::String(self.generate_random_string(length))
-            }
-            1 => Value::Number(serde_json::Number::from(self.rng.gen::<i64>())),
-            2 => Value::Number(
-                serde_json::Number::from_f64(self.rng.gen::<f64>())
-                    .unwrap_or_else(|| serde_json::Number::from(0)),
-            ),
-            3 => Value::Bool(self.rng.gen_bool(0.5)),
-            4 => Value::Null,
-            5 => Value::String(Uuid::new_v4().to_string()),
-            6 => Value::String(self.generate_garbled_string()),
-            7 => Value::String(format!("{}", Utc::now())),
             8 => {
                 let length = self.rng.gen_range(1..10);
                 self.generate_random_array(length)
@@ -156,12 +524,213 @@ impl RandomDataGenerator {
                     Value::Null
                 }
             }
+            choice => self.generate_scalar_of_type(choice),
+        }
+    }
+
+    /// A scalar of the kind at `choice` in the order of `TypeWeights::as_array`
+    fn generate_scalar_of_type(&mut self, choice: usize) -> Value {
+        // Only scalars are drawn from the skewed vocabulary and custom generators
+        if let Some(rank) = self.zipf_rank() {
+            return SKEWED_VOCABULARY.values[rank].clone();
+        }
+        if let Some(value) = self.plugin_mix.and_then(|mix| mix.sample(&mut self.rng)) {
+            return value;
+        }
+
+        match choice {
+            0 => Value::String(self.generate_text()),
+            1 => self.generate_integer(),
+            2 => self.generate_float(),
+            3 => Value::Bool(self.rng.gen_bool(0.5)),
+            4 => Value::Null,
+            5 => Value::String(self.generate_uuid().to_string()),
+            6 if self.options.text == TextMode::Markov => {
+                Value::String(self.generate_markov_text())
+            }
+            6 => {
+                let length = self.string_length();
+                Value::String(self.garbled_string_of_length(length))
+            }
+            7 => self.generate_date(),
             10 => Value::String(self.generate_hex_string()),
             _ => Value::String(self.generate_base64_like_string()),
         }
     }
 
-    fn generate_random_key(&mut self) -> String {
+    /// A root object shaped by `structure`, grown with root fields until it reaches `target_size`
+    fn generate_structured_payload(&mut self, structure: &Structure, target_size: usize) -> Value {
+        let mut payload = Map::new();
+        let mut current_size = 2;
+
+        // Size is tracked per field rather than by reserializing, as wide presets add many
+        while payload.is_empty() || (current_size < target_size && !self.out_of_time()) {
+            let key = self.generate_random_key();
+            let value = self.generate_structured_field(structure, structure.max_depth);
+            current_size += key.len() + serde_json::to_string(&value).unwrap().len() + 4;
+            payload.insert(key, value);
+        }
+
+        Value::Object(payload)
+    }
+
+    fn generate_structured_object(&mut self, structure: &Structure, depth: usize) -> Value {
+        let field_count = self
+            .rng
+            .gen_range(structure.min_fields..=structure.max_fields);
+        let mut obj = Map::new();
+        for _ in 0..field_count {
+            let key = self.generate_random_key();
+            let value = self.generate_structured_field(structure, depth);
+            obj.insert(key, value);
+        }
+        Value::Object(obj)
+    }
+
+    /// The value of an object field with `depth` levels of nesting left
+    fn generate_structured_field(&mut self, structure: &Structure, depth: usize) -> Value {
+        if depth > 0 && self.rng.gen_bool(structure.nest_probability) {
+            return self.generate_structured_object(structure, depth - 1);
+        }
+        self.generate_structured_value(structure, depth)
+    }
+
+    fn generate_structured_value(&mut self, structure: &Structure, depth: usize) -> Value {
+        if let Some(value) = self.stringified_json() {
+            return value;
+        }
+        if depth == 0 {
+            let choice = structure.scalars.sample(&mut self.rng);
+            return self.generate_scalar_of_type(choice);
+        }
+        match structure.types.sample(&mut self.rng) {
+            8 => {
+                let length = self.rng.gen_range(0..=structure.max_array_length);
+                let array = (0..length)
+                    .map(|_| self.generate_structured_value(structure, depth - 1))
+                    .collect();
+                Value::Array(array)
+            }
+            9 => self.generate_structured_object(structure, depth - 1),
+            choice => self.generate_scalar_of_type(choice),
+        }
+    }
+
+    /// A JSON document serialized into a string, drawn at the `stringified_json`
+    /// probability in place of another value
+    fn stringified_json(&mut self) -> Option<Value> {
+        let stringified = self.options.stringified_json?;
+        if !self.rng.gen_bool(stringified.probability) {
+            return None;
+        }
+        // The embedded document is encoded once only, its own values are left plain
+        self.options.stringified_json = None;
+        let document = self.generate_sized_record(stringified.bytes);
+        self.options.stringified_json = Some(stringified);
+        Some(Value::String(
+            serde_json::to_string(&document).unwrap_or_default(),
+        ))
+    }
+
+    /// A plain string value: dictionary words, Markov sentences or random characters
+    fn generate_text(&mut self) -> String {
+        if let Some(phrase) = self.dictionary_phrase() {
+            return phrase;
+        }
+        if self.options.text == TextMode::Markov {
+            return self.generate_markov_text();
+        }
+        let length = self.string_length();
+        self.generate_random_string(length)
+    }
+
+    fn generate_integer(&mut self) -> Value {
+        if self.options.big_numbers {
+            return Value::Number(self.generate_big_number());
+        }
+        Value::Number(serde_json::Number::from(self.rng.gen::<i64>()))
+    }
+
+    fn generate_float(&mut self) -> Value {
+        if self.options.big_numbers {
+            return Value::Number(self.generate_big_number());
+        }
+        Value::Number(
+            serde_json::Number::from_f64(self.rng.gen::<f64>())
+                .unwrap_or_else(|| serde_json::Number::from(0)),
+        )
+    }
+
+    fn generate_date(&mut self) -> Value {
+        match self.options.dates {
+            DateMode::Standard => Value::String(format!("{}", self.now())),
+            DateMode::Mixed => self.generate_mixed_date(),
+        }
+    }
+
+    /// Pick a random schema for `shape=records`: unique column names, each with a fixed type
+    pub fn generate_schema(&mut self) -> RecordSchema {
+        let column_count = self.rng.gen_range(3..15);
+        let mut names = std::collections::HashSet::new();
+        let mut columns = Vec::with_capacity(column_count);
+
+        while columns.len() < column_count {
+            let name = self.generate_random_key();
+            if !names.insert(name.clone()) {
+                continue;
+            }
+            columns.push(RecordColumn {
+                name,
+                kind: *ColumnKind::ALL.choose(&mut self.rng).unwrap(),
+                nullable: self.rng.gen_bool(0.2),
+            });
+        }
+
+        RecordSchema { columns }
+    }
+
+    /// One record following `schema`
+    pub fn generate_record(&mut self, schema: &RecordSchema) -> Value {
+        let mut record = Map::new();
+        for column in &schema.columns {
+            let value = if column.nullable && self.rng.gen_bool(0.1) {
+                Value::Null
+            } else {
+                self.generate_column(column.kind)
+            };
+            record.insert(column.name.clone(), value);
+        }
+        Value::Object(record)
+    }
+
+    /// A value from a custom generator, drawn from this generator's rng
+    pub fn generate_plugin(&mut self, plugin: &Plugin) -> Value {
+        plugin.generate(&mut self.rng)
+    }
+
+    /// A random value of the given type
+    pub fn generate_column(&mut self, kind: ColumnKind) -> Value {
+        match kind {
+            ColumnKind::Text => Value::String(self.generate_text()),
+            ColumnKind::Integer => self.generate_integer(),
+            ColumnKind::Float => self.generate_float(),
+            ColumnKind::Boolean => Value::Bool(self.rng.gen_bool(0.5)),
+            ColumnKind::Uuid => Value::String(self.generate_uuid().to_string()),
+            ColumnKind::Timestamp => self.generate_date(),
+            ColumnKind::Hex => Value::String(self.generate_hex_string()),
+        }
+    }
+
+    /// A random object key, following the key skew and dictionary when set
+    pub fn generate_random_key(&mut self) -> String {
+        if let Some(rank) = self.zipf_rank() {
+            return SKEWED_VOCABULARY.keys[rank].clone();
+        }
+
+        if let Some(key) = self.dictionary_key() {
+            return key;
+        }
+
         match self.rng.gen_range(0..8) {
             0 => {
                 let length = self.rng.gen_range(3..20);
@@ -180,11 +749,152 @@ impl RandomDataGenerator {
                 self.generate_garbled_string(),
                 self.generate_random_string(3)
             ),
-            6 => Uuid::new_v4().to_string().replace("-", "_"),
+            6 => self.generate_uuid().to_string().replace("-", "_"),
             _ => format!("garbled_{}", self.generate_random_string(8)),
         }
     }
 
+    /// A number that does not fit in i64 or f64 without loss, emitted as a raw JSON token
+    fn generate_big_number(&mut self) -> serde_json::Number {
+        match self.rng.gen_range(0..5) {
+            0 => serde_json::Number::from(u64::MAX - self.rng.gen_range(0..1000)),
+            1 => serde_json::Number::from_i128(self.rng.gen::<i128>())
+                .unwrap_or_else(|| serde_json::Number::from(i64::MIN)),
+            2 => serde_json::Number::from_u128(self.rng.gen::<u128>())
+                .unwrap_or_else(|| serde_json::Number::from(u64::MAX)),
+            3 => {
+                // Decimal with more significant digits than an f64 can hold
+                let sign = if self.rng.gen_bool(0.5) { "-" } else { "" };
+                let integer_digits = self.rng.gen_range(1..40);
+                let fraction_digits = self.rng.gen_range(10..40);
+                serde_json::Number::from_string_unchecked(format!(
+                    "{}{}{}.{}",
+                    sign,
+                    self.rng.gen_range(1..10),
+                    self.generate_digits(integer_digits - 1),
+                    self.generate_digits(fraction_digits)
+                ))
+            }
+            _ => {
+                // Exponent beyond the f64 range
+                let mantissa_digits = self.rng.gen_range(1..20);
+                serde_json::Number::from_string_unchecked(format!(
+                    "{}.{}e{}",
+                    self.rng.gen_range(1..10),
+                    self.generate_digits(mantissa_digits),
+                    self.rng.gen_range(309..1000)
+                ))
+            }
+        }
+    }
+
+    fn generate_digits(&mut self, count: usize) -> String {
+        (0..count)
+            .map(|_| char::from(b'0' + self.rng.gen_range(0..10)))
+            .collect()
+    }
+
+    /// A random time within ten years of now, in one of the weighted formats
+    fn generate_mixed_date(&mut self) -> Value {
+        let formats = DATE_FORMATS.get_or_init(|| {
+            WeightedIndex::new(DateFormatWeights::default().as_array())
+                .expect("default date format weights are valid")
+        });
+        let seconds = self.rng.gen_range(-315_360_000..315_360_000);
+        let time = self.now() + Duration::seconds(seconds);
+
+        match formats.sample(&mut self.rng) {
+            0 => Value::from(time.timestamp()),
+            1 => Value::from(time.timestamp_millis()),
+            2 => Value::String(self.with_random_offset(time).to_rfc3339()),
+            3 => Value::String(self.with_random_offset(time).to_rfc2822()),
+            4 => Value::String(time.format("%G-W%V-%u").to_string()),
+            _ => Value::String(time.format("%m/%d/%Y").to_string()),
+        }
+    }
+
+    fn now(&self) -> DateTime<Utc> {
+        self.clock.unwrap_or_else(Utc::now)
+    }
+
+    /// A version 4 UUID drawn from this generator's rng, so seeded output repeats
+    fn generate_uuid(&mut self) -> Uuid {
+        uuid::Builder::from_random_bytes(self.rng.gen()).into_uuid()
+    }
+
+    fn with_random_offset(&mut self, time: DateTime<Utc>) -> DateTime<FixedOffset> {
+        let minutes = *DATE_OFFSETS_MINUTES.choose(&mut self.rng).unwrap_or(&0);
+        let offset = FixedOffset::east_opt(minutes * 60).expect("offsets are within a day");
+        time.with_timezone(&offset)
+    }
+
+    /// Length of a string value, sampled from the configured distribution
+    fn string_length(&mut self) -> usize {
+        let lengths = self
+            .options
+            .structure
+            .as_ref()
+            .and_then(|structure| structure.string_lengths.as_ref())
+            .unwrap_or(self.lengths);
+        let (min, max) = (lengths.min_length, lengths.max_length);
+        let sample = match lengths.distribution {
+            LengthDistribution::Uniform => return self.rng.gen_range(min..=max),
+            LengthDistribution::Normal => {
+                // Box-Muller transform
+                let u1: f64 = 1.0 - self.rng.gen::<f64>();
+                let u2: f64 = self.rng.gen();
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                lengths.mean + z * lengths.std_dev
+            }
+            LengthDistribution::Pareto => {
+                let u: f64 = 1.0 - self.rng.gen::<f64>();
+                (min.max(1) as f64) * u.powf(-1.0 / lengths.tail_index)
+            }
+        };
+        (sample.round().max(0.0) as usize).clamp(min, max)
+    }
+
+    /// Vocabulary rank for a skewed key or value, if skew is enabled
+    fn zipf_rank(&mut self) -> Option<usize> {
+        self.zipf.as_ref().map(|zipf| zipf.sample(&mut self.rng))
+    }
+
+    /// A key built from one or two dictionary words, if word lists are loaded
+    fn dictionary_key(&mut self) -> Option<String> {
+        if self.words.keys.is_empty() || !self.rng.gen_bool(self.words.usage_ratio) {
+            return None;
+        }
+
+        let count = self.rng.gen_range(1..=2);
+        let parts: Vec<&str> = (0..count)
+            .filter_map(|_| self.words.keys.choose(&mut self.rng).map(String::as_str))
+            .collect();
+        Some(parts.join("_"))
+    }
+
+    /// A phrase of a few dictionary values, if word lists are loaded
+    fn dictionary_phrase(&mut self) -> Option<String> {
+        if self.words.values.is_empty() || !self.rng.gen_bool(self.words.usage_ratio) {
+            return None;
+        }
+
+        let count = self.rng.gen_range(1..=4);
+        let parts: Vec<&str> = (0..count)
+            .filter_map(|_| self.words.values.choose(&mut self.rng).map(String::as_str))
+            .collect();
+        Some(parts.join(" "))
+    }
+
+    /// One to three sentences from the Markov model
+    fn generate_markov_text(&mut self) -> String {
+        let model = markov::model();
+        let sentences = self.rng.gen_range(1..=3);
+        (0..sentences)
+            .map(|_| model.sentence(&mut self.rng, 40))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
     fn generate_garbled_data(&mut self) -> Value {
         match self.rng.gen_range(0..6) {
             0 => {
@@ -243,7 +953,7 @@ impl RandomDataGenerator {
         }
     }
 
-    fn generate_random_string(&mut self, length: usize) -> String {
+    pub fn generate_random_string(&mut self, length: usize) -> String {
         const CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789_-";
         (0..length)
             .map(|_| {
@@ -253,10 +963,21 @@ impl RandomDataGenerator {
             .collect()
     }
 
-    fn generate_garbled_string(&mut self) -> String {
+    /// `length` random bytes, for binary payloads
+    pub fn generate_blob(&mut self, length: usize) -> Vec<u8> {
+        let mut blob = vec![0; length];
+        self.rng.fill_bytes(&mut blob);
+        blob
+    }
+
+    pub fn generate_garbled_string(&mut self) -> String {
+        let length = self.rng.gen_range(3..40);
+        self.garbled_string_of_length(length)
+    }
+
+    fn garbled_string_of_length(&mut self, length: usize) -> String {
         // Truly garbled - mix of everything
         const GARBLED_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789!@#$%^&*()_+-=[]{}|;:,.<>?~`";
-        let length = self.rng.gen_range(3..40);
         (0..length)
             .map(|_| {
                 let idx = self.rng.gen_range(0..GARBLED_CHARS.len());
@@ -265,7 +986,7 @@ impl RandomDataGenerator {
             .collect()
     }
 
-    fn generate_hex_string(&mut self) -> String {
+    pub fn generate_hex_string(&mut self) -> String {
         const HEX_CHARS: &[u8] = b"0123456789abcdef";
         let length = self.rng.gen_range(8..32);
         (0..length)
@@ -301,7 +1022,7 @@ impl RandomDataGenerator {
 
             // Sometimes add random data
             if self.rng.gen_bool(0.4) {
-                result.push_str(&format!("_UUID_{}_", Uuid::new_v4()));
+                result.push_str(&format!("_UUID_{}_", self.generate_uuid()));
             }
             if self.rng.gen_bool(0.3) {
                 result.push_str(&format!("_HEX_{}_", self.generate_hex_string()));
@@ -0,0 +1,614 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! GraphQL subscriptions over the `graphql-transport-ws` protocol (the protocol of
+//! the `graphql-ws` library). There is no schema: each event follows the shape of
+//! the operation's selection set, with random scalar values at the leaves.
+
+use axum::{
+    extract::{
+        rejection::QueryRejection,
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Query, RawQuery,
+    },
+    http::{header, HeaderMap},
+    response::Response,
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::drain::DRAIN;
+use crate::error::ApiError;
+use crate::generator::RandomDataGenerator;
+use crate::metrics::METRICS;
+use crate::tenants::Tenant;
+use crate::validation::strict_query;
+
+const SUBPROTOCOL: &str = "graphql-transport-ws";
+
+const DEFAULT_EVENT_INTERVAL_MS: u64 = 1000;
+const MAX_EVENT_INTERVAL_MS: u64 = 60_000;
+
+/// Deepest fragment expansion followed, so cyclic fragments cannot recurse forever
+const MAX_SELECTION_DEPTH: usize = 32;
+
+/// Close codes defined by the protocol
+const CLOSE_BAD_REQUEST: u16 = 4400;
+const CLOSE_UNAUTHORIZED: u16 = 4401;
+const CLOSE_DUPLICATE_SUBSCRIPTION: u16 = 4409;
+const CLOSE_TOO_MANY_INIT: u16 = 4429;
+
+#[derive(Debug, Deserialize)]
+pub struct GraphqlParams {
+    #[serde(rename = "eventIntervalMs")]
+    event_interval_ms: Option<u64>,
+    #[serde(rename = "maxEvents")]
+    max_events: Option<u64>,
+}
+
+/// How subscription events are paced
+#[derive(Debug, Clone, Copy)]
+struct EventPacing {
+    interval: Duration,
+    /// Complete the subscription after this many events
+    max_events: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Punct(char),
+    Spread,
+    /// Strings, numbers and anything else that only matters inside arguments
+    Other,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() || c == ',' || c == '\u{feff}' => {
+                chars.next();
+            }
+            '#' => while chars.next().is_some_and(|c| c != '\n') {},
+            '"' => {
+                chars.next();
+                let block = chars.peek() == Some(&'"') && {
+                    chars.next();
+                    if chars.peek() == Some(&'"') {
+                        chars.next();
+                        true
+                    } else {
+                        // An empty string
+                        tokens.push(Token::Other);
+                        continue;
+                    }
+                };
+                let mut quotes = 0;
+                loop {
+                    match chars.next() {
+                        None => return Err("unterminated string".to_string()),
+                        Some('\\') => {
+                            chars.next();
+                            quotes = 0;
+                        }
+                        Some('"') if !block => break,
+                        Some('"') => {
+                            quotes += 1;
+                            if quotes == 3 {
+                                break;
+                            }
+                        }
+                        Some(_) => quotes = 0,
+                    }
+                }
+                tokens.push(Token::Other);
+            }
+            '.' => {
+                let dots: String = (0..3).filter_map(|_| chars.next()).collect();
+                if dots != "..." {
+                    return Err("unexpected '.'".to_string());
+                }
+                tokens.push(Token::Spread);
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut name = String::new();
+                while let Some(&c) = chars
+                    .peek()
+                    .filter(|c| c.is_ascii_alphanumeric() || **c == '_')
+                {
+                    name.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Name(name));
+            }
+            '{' | '}' | '(' | ')' | '[' | ']' | ':' | '@' | '$' | '!' | '=' | '|' | '&' => {
+                chars.next();
+                tokens.push(Token::Punct(c));
+            }
+            _ => {
+                chars.next();
+                tokens.push(Token::Other);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone)]
+enum Selection {
+    Field {
+        /// Alias if given, otherwise the field name
+        key: String,
+        name: String,
+        selections: Option<Vec<Selection>>,
+    },
+    FragmentSpread(String),
+    InlineFragment(Vec<Selection>),
+}
+
+#[derive(Debug)]
+struct Operation {
+    kind: String,
+    name: Option<String>,
+    selections: Vec<Selection>,
+}
+
+/// The operations and named fragments of a GraphQL document
+#[derive(Debug, Default)]
+struct Document {
+    operations: Vec<Operation>,
+    fragments: HashMap<String, Vec<Selection>>,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn at_punct(&self, c: char) -> bool {
+        self.peek() == Some(&Token::Punct(c))
+    }
+
+    fn expect_punct(&mut self, c: char) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Punct(p)) if p == c => Ok(()),
+            other => Err(format!("expected '{}', found {:?}", c, other)),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Name(name)) => Ok(name),
+            other => Err(format!("expected a name, found {:?}", other)),
+        }
+    }
+
+    /// Skip a bracketed group such as arguments or variable definitions
+    fn skip_group(&mut self, open: char, close: char) -> Result<(), String> {
+        self.expect_punct(open)?;
+        let mut depth = 1;
+        while depth > 0 {
+            match self.next() {
+                Some(Token::Punct(c)) if c == open => depth += 1,
+                Some(Token::Punct(c)) if c == close => depth -= 1,
+                Some(_) => {}
+                None => return Err(format!("missing '{}'", close)),
+            }
+        }
+        Ok(())
+    }
+
+    fn skip_directives(&mut self) -> Result<(), String> {
+        while self.at_punct('@') {
+            self.next();
+            self.name()?;
+            if self.at_punct('(') {
+                self.skip_group('(', ')')?;
+            }
+        }
+        Ok(())
+    }
+
+    fn document(&mut self) -> Result<Document, String> {
+        let mut document = Document::default();
+        while let Some(token) = self.peek().cloned() {
+            match token {
+                Token::Punct('{') => document.operations.push(Operation {
+                    kind: "query".to_string(),
+                    name: None,
+                    selections: self.selection_set()?,
+                }),
+                Token::Name(keyword) if keyword == "fragment" => {
+                    self.next();
+                    let name = self.name()?;
+                    if self.name()? != "on" {
+                        return Err(format!("fragment {} is missing a type condition", name));
+                    }
+                    self.name()?;
+                    self.skip_directives()?;
+                    let selections = self.selection_set()?;
+                    document.fragments.insert(name, selections);
+                }
+                Token::Name(kind)
+                    if matches!(kind.as_str(), "query" | "mutation" | "subscription") =>
+                {
+                    self.next();
+                    let name = match self.peek() {
+                        Some(Token::Name(_)) => Some(self.name()?),
+                        _ => None,
+                    };
+                    if self.at_punct('(') {
+                        self.skip_group('(', ')')?;
+                    }
+                    self.skip_directives()?;
+                    document.operations.push(Operation {
+                        kind,
+                        name,
+                        selections: self.selection_set()?,
+                    });
+                }
+                other => return Err(format!("unexpected {:?} in document", other)),
+            }
+        }
+        Ok(document)
+    }
+
+    fn selection_set(&mut self) -> Result<Vec<Selection>, String> {
+        self.expect_punct('{')?;
+        let mut selections = Vec::new();
+        while !self.at_punct('}') {
+            if self.peek() == Some(&Token::Spread) {
+                self.next();
+                match self.peek() {
+                    Some(Token::Name(on)) if on == "on" => {
+                        self.next();
+                        self.name()?;
+                        self.skip_directives()?;
+                        selections.push(Selection::InlineFragment(self.selection_set()?));
+                    }
+                    Some(Token::Name(_)) => {
+                        let name = self.name()?;
+                        self.skip_directives()?;
+                        selections.push(Selection::FragmentSpread(name));
+                    }
+                    _ => {
+                        self.skip_directives()?;
+                        selections.push(Selection::InlineFragment(self.selection_set()?));
+                    }
+                }
+                continue;
+            }
+
+            let key = self.name()?;
+            let mut name = key.clone();
+            if self.at_punct(':') {
+                self.next();
+                name = self.name()?;
+            }
+            if self.at_punct('(') {
+                self.skip_group('(', ')')?;
+            }
+            self.skip_directives()?;
+            let selections_of_field = if self.at_punct('{') {
+                Some(self.selection_set()?)
+            } else {
+                None
+            };
+            selections.push(Selection::Field {
+                key,
+                name,
+                selections: selections_of_field,
+            });
+        }
+        self.expect_punct('}')?;
+        if selections.is_empty() {
+            return Err("empty selection set".to_string());
+        }
+        Ok(selections)
+    }
+}
+
+fn parse(source: &str) -> Result<Document, String> {
+    Parser {
+        tokens: tokenize(source)?,
+        pos: 0,
+    }
+    .document()
+}
+
+/// Random data shaped like `selections`, expanding fragments from `document`
+fn shaped_value(
+    generator: &mut RandomDataGenerator,
+    selections: &[Selection],
+    document: &Document,
+    depth: usize,
+) -> Value {
+    let mut object = Map::new();
+    fill(generator, selections, document, depth, &mut object);
+    Value::Object(object)
+}
+
+fn fill(
+    generator: &mut RandomDataGenerator,
+    selections: &[Selection],
+    document: &Document,
+    depth: usize,
+    object: &mut Map<String, Value>,
+) {
+    if depth > MAX_SELECTION_DEPTH {
+        return;
+    }
+    for selection in selections {
+        match selection {
+            Selection::Field {
+                key,
+                name,
+                selections,
+            } => {
+                let value = match selections {
+                    Some(selections) => shaped_value(generator, selections, document, depth + 1),
+                    None if name == "__typename" => {
+                        let type_name = generator.generate_random_string(8);
+                        Value::String(format!("Garbled{}", type_name))
+                    }
+                    None => generator.generate_scalar(),
+                };
+                object.insert(key.clone(), value);
+            }
+            Selection::FragmentSpread(name) => {
+                if let Some(selections) = document.fragments.get(name) {
+                    fill(generator, selections, document, depth + 1, object);
+                }
+            }
+            Selection::InlineFragment(selections) => {
+                fill(generator, selections, document, depth + 1, object);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    ConnectionInit,
+    Ping,
+    Pong,
+    Subscribe {
+        id: String,
+        payload: SubscribePayload,
+    },
+    Complete {
+        id: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct SubscribePayload {
+    query: String,
+    #[serde(rename = "operationName")]
+    operation_name: Option<String>,
+}
+
+/// The operation a subscribe message asks for, with its fragments
+fn resolve_operation(payload: &SubscribePayload) -> Result<(Document, usize), String> {
+    let document = parse(&payload.query)?;
+    let index = match &payload.operation_name {
+        Some(wanted) => document
+            .operations
+            .iter()
+            .position(|op| op.name.as_deref() == Some(wanted.as_str()))
+            .ok_or_else(|| format!("unknown operation {}", wanted))?,
+        None if document.operations.len() == 1 => 0,
+        None => {
+            return Err(
+                "operationName is required for documents with several operations".to_string(),
+            )
+        }
+    };
+    Ok((document, index))
+}
+
+/// Produce `next` messages for one operation: a stream of events for subscriptions,
+/// a single result for queries and mutations
+async fn run_operation(
+    id: String,
+    document: Document,
+    index: usize,
+    pacing: EventPacing,
+    outgoing: mpsc::Sender<Value>,
+) {
+    let operation = &document.operations[index];
+    let is_subscription = operation.kind == "subscription";
+    let mut ticker = tokio::time::interval(pacing.interval);
+    let mut sent = 0u64;
+
+    loop {
+        if is_subscription {
+            ticker.tick().await;
+        }
+        if DRAIN.is_draining() {
+            break;
+        }
+
+        let data = shaped_value(
+            &mut RandomDataGenerator::new(),
+            &operation.selections,
+            &document,
+            0,
+        );
+        let next = json!({ "id": id, "type": "next", "payload": { "data": data } });
+        if outgoing.send(next).await.is_err() {
+            return;
+        }
+        sent += 1;
+
+        if !is_subscription || pacing.max_events.is_some_and(|max| sent >= max) {
+            break;
+        }
+    }
+    let _ = outgoing.send(json!({ "id": id, "type": "complete" })).await;
+}
+
+async fn close(mut socket: WebSocket, code: u16, reason: &'static str) {
+    let _ = socket
+        .send(Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        })))
+        .await;
+}
+
+/// Serve one `graphql-transport-ws` connection
+async fn session(mut socket: WebSocket, pacing: EventPacing, tenant: Tenant) {
+    let (outgoing, mut pending) = mpsc::channel::<Value>(64);
+    let mut subscriptions: HashMap<String, JoinHandle<()>> = HashMap::new();
+    let mut acknowledged = false;
+
+    loop {
+        tokio::select! {
+            incoming = socket.recv() => {
+                let text = match incoming {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Binary(_))) => {
+                        return close(socket, CLOSE_BAD_REQUEST, "binary messages are not supported").await;
+                    }
+                    Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                };
+                let Ok(message) = serde_json::from_str::<ClientMessage>(&text) else {
+                    return close(socket, CLOSE_BAD_REQUEST, "invalid message").await;
+                };
+
+                let reply = match message {
+                    ClientMessage::ConnectionInit if acknowledged => {
+                        return close(socket, CLOSE_TOO_MANY_INIT, "too many initialisation requests").await;
+                    }
+                    ClientMessage::ConnectionInit => {
+                        acknowledged = true;
+                        Some(json!({ "type": "connection_ack" }))
+                    }
+                    ClientMessage::Ping => Some(json!({ "type": "pong" })),
+                    ClientMessage::Pong => None,
+                    ClientMessage::Subscribe { .. } if !acknowledged => {
+                        return close(socket, CLOSE_UNAUTHORIZED, "unauthorized").await;
+                    }
+                    ClientMessage::Subscribe { id, payload } => {
+                        subscriptions.retain(|_, task| !task.is_finished());
+                        if subscriptions.contains_key(&id) {
+                            return close(socket, CLOSE_DUPLICATE_SUBSCRIPTION, "subscriber already exists").await;
+                        }
+                        match resolve_operation(&payload) {
+                            Ok((document, index)) => {
+                                METRICS.record_response(&tenant.segment(), "graphql", 0, pacing.interval.as_millis() as u64);
+                                let task = tokio::spawn(run_operation(id.clone(), document, index, pacing, outgoing.clone()));
+                                subscriptions.insert(id, task);
+                                None
+                            }
+                            Err(message) => Some(json!({
+                                "id": id,
+                                "type": "error",
+                                "payload": [{ "message": message }]
+                            })),
+                        }
+                    }
+                    ClientMessage::Complete { id } => {
+                        if let Some(task) = subscriptions.remove(&id) {
+                            task.abort();
+                        }
+                        None
+                    }
+                };
+
+                if let Some(reply) = reply {
+                    if socket.send(Message::Text(reply.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            Some(message) = pending.recv() => {
+                if socket.send(Message::Text(message.to_string())).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    for task in subscriptions.into_values() {
+        task.abort();
+    }
+}
+
+pub async fn graphql_ws_handler(
+    upgrade: WebSocketUpgrade,
+    params: Result<Query<GraphqlParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    tenant: Tenant,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    if DRAIN.is_draining() {
+        return Err(ApiError::draining());
+    }
+
+    let params = strict_query(
+        params,
+        raw_query.as_deref(),
+        tenant.garble.strict_validation,
+    )?;
+    let interval_ms = params
+        .event_interval_ms
+        .unwrap_or(DEFAULT_EVENT_INTERVAL_MS);
+    if interval_ms == 0 || interval_ms > MAX_EVENT_INTERVAL_MS {
+        return Err(ApiError::bad_request(format!(
+            "eventIntervalMs must be between 1 and {}, got {}",
+            MAX_EVENT_INTERVAL_MS, interval_ms
+        )));
+    }
+    let pacing = EventPacing {
+        interval: Duration::from_millis(interval_ms),
+        max_events: params.max_events,
+    };
+
+    tracing::info!(
+        "Opening GraphQL WebSocket: tenant={}, event_interval={}ms",
+        tenant.name,
+        interval_ms
+    );
+
+    // Clients must ask for the protocol, anything else gets a plain close
+    let requested = headers
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim() == SUBPROTOCOL);
+    if !requested {
+        return Ok(upgrade.on_upgrade(|socket| {
+            close(
+                socket,
+                close_code::PROTOCOL,
+                "graphql-transport-ws subprotocol required",
+            )
+        }));
+    }
+    let upgrade = upgrade.protocols([SUBPROTOCOL]);
+    Ok(upgrade.on_upgrade(move |socket| session(socket, pacing, tenant)))
+}
@@ -0,0 +1,681 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Random but valid GraphQL SDL documents, for stress testing tooling that
+//! ingests schemas: code generators, gateways and linters. Types only refer to
+//! types defined before them, so the document never has input cycles, and
+//! objects copy the fields of the interfaces they implement. `unusual=true`
+//! reaches for the corners of the spec: block string descriptions, long names,
+//! deeply wrapped types, repeatable directives, interface hierarchies, type
+//! extensions and renamed root types.
+
+use axum::{
+    body::Body,
+    extract::{rejection::QueryRejection, Query, RawQuery},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use rand::prelude::*;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::time::Duration;
+use tokio::time::sleep;
+
+use crate::error::ApiError;
+use crate::handlers::{relabel_content_type, resolve_targets, GarbleParams};
+use crate::metrics::METRICS;
+use crate::streaming::ResponseDecision;
+use crate::tenants::Tenant;
+use crate::validation::strict_query;
+
+/// Scalars every schema has without defining them
+const BUILT_IN_SCALARS: &[&str] = &["Int", "Float", "String", "Boolean", "ID"];
+
+/// Syllables names are made of
+const SYLLABLES: &[&str] = &[
+    "ac", "bel", "cor", "dax", "el", "fen", "gar", "hul", "ix", "jor", "kal", "lum", "mer", "nox",
+    "or", "pel", "quin", "ros", "sil", "tor", "ul", "vex", "wyn", "yar", "zel",
+];
+
+/// Where a directive may be applied, among the locations of a type system document
+const DIRECTIVE_LOCATIONS: &[&str] = &[
+    "SCALAR",
+    "OBJECT",
+    "FIELD_DEFINITION",
+    "ARGUMENT_DEFINITION",
+    "INTERFACE",
+    "UNION",
+    "ENUM",
+    "ENUM_VALUE",
+    "INPUT_OBJECT",
+    "INPUT_FIELD_DEFINITION",
+];
+
+/// Text for descriptions of `unusual` schemas, including what parsers trip over
+const UNUSUAL_TEXT: &[&str] = &[
+    "Ünïcödé, 漢字 and 😀 in one line",
+    "A line with a \\\"\"\" escaped triple quote",
+    "    indented by four spaces",
+    "backslashes \\ stay \\n literal in block strings",
+    "",
+    "# not a comment inside a description",
+];
+
+/// Bytes set aside for the root types when filling the document up to size
+const ROOT_RESERVE: usize = 512;
+
+#[derive(Debug, Deserialize)]
+pub struct SchemaParams {
+    seed: Option<u64>,
+    unusual: Option<bool>,
+}
+
+#[derive(Clone, PartialEq)]
+struct Field {
+    name: String,
+    /// Arguments as written, such as `first: Int = 10`
+    args: Vec<String>,
+    ty: String,
+}
+
+struct Interface {
+    name: String,
+    fields: Vec<Field>,
+    /// Every interface this one implements, including those of its ancestors
+    implements: Vec<String>,
+}
+
+struct Directive {
+    name: String,
+    /// Argument names and their scalar types
+    args: Vec<(String, &'static str)>,
+    repeatable: bool,
+    locations: Vec<&'static str>,
+}
+
+struct SchemaGenerator {
+    rng: StdRng,
+    unusual: bool,
+    names: HashSet<String>,
+    scalars: Vec<String>,
+    enums: Vec<String>,
+    inputs: Vec<String>,
+    interfaces: Vec<Interface>,
+    objects: Vec<(String, HashSet<String>)>,
+    unions: Vec<String>,
+    directives: Vec<Directive>,
+    out: String,
+}
+
+impl SchemaGenerator {
+    fn new(seed: u64, unusual: bool) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            unusual,
+            names: BUILT_IN_SCALARS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            scalars: BUILT_IN_SCALARS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            enums: Vec::new(),
+            inputs: Vec::new(),
+            interfaces: Vec::new(),
+            objects: Vec::new(),
+            unions: Vec::new(),
+            directives: Vec::new(),
+            out: String::new(),
+        }
+    }
+
+    /// A document of about `target_size` bytes, padded with a comment when short
+    fn generate(mut self, target_size: usize) -> String {
+        // Directives come first so every later definition can use them
+        for _ in 0..self.rng.gen_range(0..=if self.unusual { 6 } else { 2 }) {
+            self.directive();
+        }
+        while self.out.len() + ROOT_RESERVE < target_size {
+            match self.rng.gen_range(0..20) {
+                0 => self.scalar(),
+                1..=2 => self.enumeration(),
+                3..=4 => self.input(),
+                5..=6 => self.interface(),
+                7 if self.objects.len() >= 2 => self.union(),
+                8 if self.unusual && !self.objects.is_empty() => self.extension(),
+                _ => self.object(),
+            }
+        }
+        self.roots();
+
+        // Whatever is left is filled with a comment, which every parser skips
+        let missing = target_size.saturating_sub(self.out.len());
+        if missing >= 2 {
+            self.out.push('#');
+            self.out.push_str(&" ".repeat(missing - 2));
+            self.out.push('\n');
+        }
+        self.out
+    }
+
+    fn word(&mut self, syllables: usize) -> String {
+        (0..syllables)
+            .map(|_| *SYLLABLES.choose(&mut self.rng).unwrap())
+            .collect()
+    }
+
+    fn syllable_count(&mut self) -> usize {
+        if self.unusual && self.rng.gen_bool(0.05) {
+            self.rng.gen_range(20..80)
+        } else {
+            self.rng.gen_range(1..4)
+        }
+    }
+
+    /// A PascalCase name no other type or directive has
+    fn type_name(&mut self) -> String {
+        let syllables = self.syllable_count();
+        let word = self.word(syllables);
+        let mut name = capitalize(&word);
+        if self.unusual && self.rng.gen_bool(0.1) {
+            name.insert(0, '_');
+        }
+        let mut unique = name.clone();
+        let mut n = 2;
+        while !self.names.insert(unique.clone()) {
+            unique = format!("{}{}", name, n);
+            n += 1;
+        }
+        unique
+    }
+
+    /// A camelCase name not in `taken`
+    fn member_name(&mut self, taken: &mut HashSet<String>) -> String {
+        let syllables = self.syllable_count();
+        let name = self.word(syllables);
+        let mut unique = name.clone();
+        let mut n = 2;
+        while !taken.insert(unique.clone()) {
+            unique = format!("{}{}", name, n);
+            n += 1;
+        }
+        unique
+    }
+
+    fn description(&mut self, indent: &str) {
+        if !self.rng.gen_bool(0.3) {
+            return;
+        }
+        if self.unusual && self.rng.gen_bool(0.5) {
+            let _ = writeln!(self.out, "{}\"\"\"", indent);
+            for _ in 0..self.rng.gen_range(1..4) {
+                let line = UNUSUAL_TEXT.choose(&mut self.rng).unwrap();
+                let _ = writeln!(self.out, "{}{}", indent, line);
+            }
+            let _ = writeln!(self.out, "{}\"\"\"", indent);
+        } else {
+            let words: Vec<String> = (0..self.rng.gen_range(2..8))
+                .map(|_| self.word(2))
+                .collect();
+            let _ = writeln!(self.out, "{}\"{}\"", indent, capitalize(&words.join(" ")));
+        }
+    }
+
+    /// Directive applications allowed at `location`, with a leading space each
+    fn directives_at(&mut self, location: &str) -> String {
+        let mut applied = String::new();
+        for directive in &self.directives {
+            if !directive.locations.contains(&location) || !self.rng.gen_bool(0.2) {
+                continue;
+            }
+            let times = if directive.repeatable {
+                self.rng.gen_range(1..4)
+            } else {
+                1
+            };
+            for _ in 0..times {
+                let _ = write!(applied, " @{}", directive.name);
+                let args: Vec<String> = directive
+                    .args
+                    .iter()
+                    .filter_map(|(name, ty)| {
+                        let value = literal(&mut self.rng, ty);
+                        self.rng
+                            .gen_bool(0.7)
+                            .then(|| format!("{}: {}", name, value))
+                    })
+                    .collect();
+                if !args.is_empty() {
+                    let _ = write!(applied, "({})", args.join(", "));
+                }
+            }
+        }
+        if location == "FIELD_DEFINITION" && self.rng.gen_bool(0.05) {
+            applied.push_str(" @deprecated(reason: \"Use something else\")");
+        }
+        applied
+    }
+
+    /// Wrap a named type in lists and non-null markers
+    fn wrap(&mut self, name: &str) -> String {
+        let mut ty = name.to_string();
+        if self.rng.gen_bool(0.4) {
+            ty.push('!');
+        }
+        let max_lists = if self.unusual { 5 } else { 1 };
+        for _ in 0..self.rng.gen_range(0..=max_lists) {
+            if !self.rng.gen_bool(0.3) {
+                break;
+            }
+            ty = format!("[{}]", ty);
+            if self.rng.gen_bool(0.5) {
+                ty.push('!');
+            }
+        }
+        ty
+    }
+
+    fn output_type(&mut self) -> String {
+        let mut named: Vec<&String> = self.scalars.iter().chain(&self.enums).collect();
+        named.extend(self.objects.iter().map(|(name, _)| name));
+        named.extend(self.interfaces.iter().map(|interface| &interface.name));
+        named.extend(&self.unions);
+        let name = named.choose(&mut self.rng).unwrap().to_string();
+        self.wrap(&name)
+    }
+
+    /// An input type, kept nullable so arguments and input fields can be left out
+    fn input_type(&mut self) -> String {
+        let named: Vec<&String> = self
+            .scalars
+            .iter()
+            .chain(&self.enums)
+            .chain(&self.inputs)
+            .collect();
+        let name = named.choose(&mut self.rng).unwrap().to_string();
+        let ty = self.wrap(&name);
+        ty.strip_suffix('!').map(str::to_string).unwrap_or(ty)
+    }
+
+    fn field(&mut self, taken: &mut HashSet<String>) -> Field {
+        let name = self.member_name(taken);
+        let mut arg_names = HashSet::new();
+        let args = (0..self.rng.gen_range(0..=if self.unusual { 5 } else { 2 }))
+            .map(|_| {
+                let arg = self.member_name(&mut arg_names);
+                let ty = self.input_type();
+                let default = if BUILT_IN_SCALARS.contains(&ty.as_str()) && self.rng.gen_bool(0.3) {
+                    format!(" = {}", literal(&mut self.rng, &ty))
+                } else {
+                    String::new()
+                };
+                let directives = self.directives_at("ARGUMENT_DEFINITION");
+                format!("{}: {}{}{}", arg, ty, default, directives)
+            })
+            .collect();
+        let ty = self.output_type();
+        Field { name, args, ty }
+    }
+
+    fn write_fields(&mut self, fields: &[Field]) {
+        self.out.push_str(" {\n");
+        for field in fields {
+            self.description("  ");
+            let args = if field.args.is_empty() {
+                String::new()
+            } else {
+                format!("({})", field.args.join(", "))
+            };
+            let directives = self.directives_at("FIELD_DEFINITION");
+            let _ = writeln!(
+                self.out,
+                "  {}{}: {}{}",
+                field.name, args, field.ty, directives
+            );
+        }
+        self.out.push_str("}\n\n");
+    }
+
+    fn field_count(&mut self) -> usize {
+        if self.unusual && self.rng.gen_bool(0.05) {
+            self.rng.gen_range(50..200)
+        } else {
+            self.rng.gen_range(1..9)
+        }
+    }
+
+    fn directive(&mut self) {
+        let name = self.type_name();
+        let name = name[..1].to_lowercase() + &name[1..];
+        let mut arg_names = HashSet::new();
+        let args = (0..self.rng.gen_range(0..3))
+            .map(|_| {
+                let arg = self.member_name(&mut arg_names);
+                let ty = ["String", "Int", "Float", "Boolean"]
+                    .choose(&mut self.rng)
+                    .unwrap();
+                (arg, *ty)
+            })
+            .collect::<Vec<_>>();
+        let repeatable = self.unusual && self.rng.gen_bool(0.5);
+        let count = self.rng.gen_range(1..4);
+        let locations: Vec<&'static str> = DIRECTIVE_LOCATIONS
+            .choose_multiple(&mut self.rng, count)
+            .copied()
+            .collect();
+
+        self.description("");
+        let _ = write!(self.out, "directive @{}", name);
+        if !args.is_empty() {
+            let args: Vec<String> = args
+                .iter()
+                .map(|(arg, ty)| format!("{}: {}", arg, ty))
+                .collect();
+            let _ = write!(self.out, "({})", args.join(", "));
+        }
+        if repeatable {
+            self.out.push_str(" repeatable");
+        }
+        let _ = writeln!(self.out, " on {}\n", locations.join(" | "));
+        self.directives.push(Directive {
+            name,
+            args,
+            repeatable,
+            locations,
+        });
+    }
+
+    fn scalar(&mut self) {
+        let name = self.type_name();
+        self.description("");
+        let directives = self.directives_at("SCALAR");
+        let specified_by = if self.rng.gen_bool(0.3) {
+            format!(
+                " @specifiedBy(url: \"https://example.com/scalars/{}\")",
+                name.to_lowercase()
+            )
+        } else {
+            String::new()
+        };
+        let _ = writeln!(self.out, "scalar {}{}{}\n", name, specified_by, directives);
+        self.scalars.push(name);
+    }
+
+    fn enumeration(&mut self) {
+        let name = self.type_name();
+        self.description("");
+        let directives = self.directives_at("ENUM");
+        let _ = writeln!(self.out, "enum {}{} {{", name, directives);
+        let mut values = HashSet::new();
+        for _ in 0..self.field_count() {
+            let value = self.member_name(&mut values).to_uppercase();
+            self.description("  ");
+            let directives = self.directives_at("ENUM_VALUE");
+            let _ = writeln!(self.out, "  {}{}", value, directives);
+        }
+        self.out.push_str("}\n\n");
+        self.enums.push(name);
+    }
+
+    fn input(&mut self) {
+        let name = self.type_name();
+        self.description("");
+        let directives = self.directives_at("INPUT_OBJECT");
+        let _ = writeln!(self.out, "input {}{} {{", name, directives);
+        let mut fields = HashSet::new();
+        for _ in 0..self.field_count() {
+            let field = self.member_name(&mut fields);
+            let ty = self.input_type();
+            self.description("  ");
+            let directives = self.directives_at("INPUT_FIELD_DEFINITION");
+            let _ = writeln!(self.out, "  {}: {}{}", field, ty, directives);
+        }
+        self.out.push_str("}\n\n");
+        self.inputs.push(name);
+    }
+
+    /// Interfaces to implement, with the fields that brings along
+    fn pick_interfaces(&mut self) -> (Vec<String>, Vec<Field>) {
+        let max = if self.unusual { 3 } else { 1 };
+        let count = self.rng.gen_range(0..=max).min(self.interfaces.len());
+        let mut implements = Vec::new();
+        let mut fields: Vec<Field> = Vec::new();
+        for interface in self.interfaces.choose_multiple(&mut self.rng, count) {
+            // A field both interfaces have has to be the same in both to implement them together
+            let clashes = interface
+                .fields
+                .iter()
+                .any(|field| fields.iter().any(|f| f.name == field.name && f != field));
+            if clashes {
+                continue;
+            }
+            for name in interface.implements.iter().chain([&interface.name]) {
+                if !implements.contains(name) {
+                    implements.push(name.clone());
+                }
+            }
+            for field in &interface.fields {
+                if !fields.iter().any(|f| f.name == field.name) {
+                    fields.push(field.clone());
+                }
+            }
+        }
+        (implements, fields)
+    }
+
+    /// Fields of a type implementing `inherited` fields, plus fresh ones
+    fn fields_with(&mut self, mut fields: Vec<Field>) -> (Vec<Field>, HashSet<String>) {
+        let mut taken: HashSet<String> = fields.iter().map(|f| f.name.clone()).collect();
+        // Types that pull in several interfaces may already have plenty of fields
+        let extra = if fields.is_empty() {
+            self.field_count()
+        } else {
+            self.rng.gen_range(0..4)
+        };
+        for _ in 0..extra {
+            let field = self.field(&mut taken);
+            fields.push(field);
+        }
+        (fields, taken)
+    }
+
+    fn interface(&mut self) {
+        let name = self.type_name();
+        // Only unusual schemas build interface hierarchies
+        let (implements, inherited) = if self.unusual {
+            self.pick_interfaces()
+        } else {
+            (Vec::new(), Vec::new())
+        };
+        let (fields, _) = self.fields_with(inherited);
+        self.description("");
+        let _ = write!(self.out, "interface {}", name);
+        if !implements.is_empty() {
+            let _ = write!(self.out, " implements {}", implements.join(" & "));
+        }
+        let directives = self.directives_at("INTERFACE");
+        self.out.push_str(&directives);
+        self.write_fields(&fields);
+        self.interfaces.push(Interface {
+            name,
+            fields,
+            implements,
+        });
+    }
+
+    fn object(&mut self) {
+        let name = self.type_name();
+        let (implements, inherited) = self.pick_interfaces();
+        let (fields, taken) = self.fields_with(inherited);
+        self.description("");
+        let _ = write!(self.out, "type {}", name);
+        if !implements.is_empty() {
+            let _ = write!(self.out, " implements {}", implements.join(" & "));
+        }
+        let directives = self.directives_at("OBJECT");
+        self.out.push_str(&directives);
+        self.write_fields(&fields);
+        self.objects.push((name, taken));
+    }
+
+    fn union(&mut self) {
+        let name = self.type_name();
+        let count = self.rng.gen_range(2..=self.objects.len().min(8));
+        let members: Vec<String> = self
+            .objects
+            .choose_multiple(&mut self.rng, count)
+            .map(|(name, _)| name.clone())
+            .collect();
+        self.description("");
+        let directives = self.directives_at("UNION");
+        let _ = writeln!(
+            self.out,
+            "union {}{} = {}\n",
+            name,
+            directives,
+            members.join(" | ")
+        );
+        self.unions.push(name);
+    }
+
+    /// More fields for an object defined earlier
+    fn extension(&mut self) {
+        let index = self.rng.gen_range(0..self.objects.len());
+        let mut taken = std::mem::take(&mut self.objects[index].1);
+        let fields: Vec<Field> = (0..self.rng.gen_range(1..4))
+            .map(|_| self.field(&mut taken))
+            .collect();
+        let name = self.objects[index].0.clone();
+        self.objects[index].1 = taken;
+        let _ = write!(self.out, "extend type {}", name);
+        self.write_fields(&fields);
+    }
+
+    /// Root operation types, renamed through a `schema` block in unusual schemas
+    fn roots(&mut self) {
+        let renamed = self.unusual && self.rng.gen_bool(0.5);
+        let mut roots = Vec::new();
+        for (operation, default, chance) in [
+            ("query", "Query", 1.0),
+            ("mutation", "Mutation", 0.6),
+            ("subscription", "Subscription", 0.3),
+        ] {
+            if !self.rng.gen_bool(chance) {
+                continue;
+            }
+            let name = if renamed || self.names.contains(default) {
+                self.type_name()
+            } else {
+                self.names.insert(default.to_string());
+                default.to_string()
+            };
+            let mut taken = HashSet::new();
+            let fields: Vec<Field> = (0..self.rng.gen_range(1..9))
+                .map(|_| self.field(&mut taken))
+                .collect();
+            self.description("");
+            let _ = write!(self.out, "type {}", name);
+            self.write_fields(&fields);
+            roots.push((operation, name));
+        }
+        if roots
+            .iter()
+            .any(|(_, name)| !["Query", "Mutation", "Subscription"].contains(&name.as_str()))
+        {
+            self.out.push_str("schema {\n");
+            for (operation, name) in roots {
+                let _ = writeln!(self.out, "  {}: {}", operation, name);
+            }
+            self.out.push_str("}\n\n");
+        }
+    }
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// A literal of a built-in scalar type
+fn literal(rng: &mut StdRng, ty: &str) -> String {
+    match ty {
+        "Int" => rng.gen_range(-1000..1000).to_string(),
+        "Float" => format!("{:.2}", rng.gen_range(-1000.0..1000.0)),
+        "Boolean" => rng.gen_bool(0.5).to_string(),
+        _ => format!("\"{}\"", SYLLABLES.choose(rng).unwrap()),
+    }
+}
+
+// Serve a random GraphQL schema of roughly the requested size
+pub async fn graphql_schema_handler(
+    garble_params: Result<Query<GarbleParams>, QueryRejection>,
+    schema_params: Result<Query<SchemaParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    tenant: Tenant,
+) -> Result<impl IntoResponse, ApiError> {
+    let strict = tenant.garble.strict_validation;
+    let garble_params = strict_query(garble_params, raw_query.as_deref(), strict)?;
+    let schema_params = strict_query(schema_params, raw_query.as_deref(), strict)?;
+
+    let (target_size, wait_duration_ms) = resolve_targets(&garble_params, &tenant.garble)?;
+    let content_type_label = garble_params.content_type_label()?;
+    // Without a seed one is picked, and reported so the schema can be had again
+    let seed = schema_params.seed.unwrap_or_else(|| thread_rng().gen());
+    let unusual = schema_params.unusual.unwrap_or(false);
+
+    if wait_duration_ms > 0 {
+        sleep(Duration::from_millis(wait_duration_ms)).await;
+    }
+
+    let body = tokio::task::spawn_blocking(move || {
+        SchemaGenerator::new(seed, unusual).generate(target_size)
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("GraphQL schema generation task failed: {}", e);
+        ApiError::internal("graphql schema generation task failed")
+    })?;
+
+    tracing::info!(
+        "Generated GARBLED graphql schema: tenant={}, seed={}, unusual={}, target_size={}B, actual_size={}B, wait={}ms",
+        tenant.name,
+        seed,
+        unusual,
+        target_size,
+        body.len(),
+        wait_duration_ms
+    );
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/graphql; charset=utf-8")
+        .header(
+            header::CONTENT_DISPOSITION,
+            r#"attachment; filename="garble.graphql""#,
+        )
+        .header("X-Garble-Mode", "graphql-schema")
+        .header("X-Garble-Seed", seed)
+        .body(Body::from(body))
+        .unwrap();
+    relabel_content_type(&mut response, content_type_label);
+    METRICS.record_response(
+        &tenant.segment(),
+        "graphql-schema",
+        target_size,
+        wait_duration_ms,
+    );
+    ResponseDecision {
+        strategy: "graphql-schema",
+        target_size,
+        wait_ms: wait_duration_ms,
+    }
+    .apply_headers(response.headers_mut());
+
+    Ok(response)
+}
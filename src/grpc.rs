@@ -0,0 +1,380 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A small gRPC service, `daddle.Garble`, spoken directly over HTTP/2 without
+//! generated code. Its messages are all `GarbleMessage { string json = 1; }`, see
+//! `proto/daddle.proto`. Options travel as request metadata, since gRPC has no
+//! query string.
+
+use async_stream::stream;
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap, HeaderValue},
+    response::Response,
+};
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{Stream, StreamExt};
+use http_body::Frame;
+use http_body_util::StreamBody;
+use rand::prelude::*;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::drain::DRAIN;
+use crate::generator::RandomDataGenerator;
+use crate::metrics::METRICS;
+use crate::tenants::Tenant;
+use crate::websocket::mutate_text;
+
+const GRPC_CONTENT_TYPE: &str = "application/grpc";
+
+const DEFAULT_MESSAGE_COUNT: u64 = 10;
+const MAX_MESSAGE_COUNT: u64 = 1_000_000;
+const DEFAULT_MESSAGE_BYTES: usize = 512;
+const MAX_MESSAGE_BYTES: usize = 4 * 1024 * 1024;
+const MAX_RATE: u64 = 100_000;
+
+/// gRPC status codes used by the service itself
+const OK: u8 = 0;
+const INVALID_ARGUMENT: u8 = 3;
+const UNAVAILABLE: u8 = 14;
+
+/// Highest defined gRPC status code (UNAUTHENTICATED)
+const MAX_STATUS: u8 = 16;
+
+/// Status the stream finishes with
+#[derive(Debug, Clone, Copy)]
+enum FinalStatus {
+    Code(u8),
+    /// A different random non-OK code for every call
+    Random,
+}
+
+impl FromStr for FinalStatus {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value == "random" {
+            return Ok(FinalStatus::Random);
+        }
+        match value.parse() {
+            Ok(code) if code <= MAX_STATUS => Ok(FinalStatus::Code(code)),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FinalStatus {
+    fn resolve(self) -> u8 {
+        match self {
+            FinalStatus::Code(code) => code,
+            FinalStatus::Random => thread_rng().gen_range(1..=MAX_STATUS),
+        }
+    }
+}
+
+/// Per-call options, read from `garble-*` request metadata
+#[derive(Debug, Clone)]
+struct CallOptions {
+    /// Messages sent by `Stream`
+    message_count: u64,
+    /// Messages per second; unpaced when unset
+    rate: Option<u64>,
+    /// Approximate size of each `Stream` message
+    message_bytes: usize,
+    /// Abort the HTTP/2 stream with RST_STREAM after this many messages
+    reset_after: Option<u64>,
+    /// Answer with headers only, carrying the final status and no messages
+    trailers_only: bool,
+    status: FinalStatus,
+}
+
+/// A status to end the call with straight away
+struct GrpcStatus {
+    code: u8,
+    message: String,
+}
+
+impl GrpcStatus {
+    fn invalid_argument(message: String) -> Self {
+        Self {
+            code: INVALID_ARGUMENT,
+            message,
+        }
+    }
+}
+
+fn metadata<T: FromStr>(headers: &HeaderMap, name: &str) -> Result<Option<T>, GrpcStatus> {
+    let Some(value) = headers.get(name) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .map(Some)
+        .ok_or_else(|| GrpcStatus::invalid_argument(format!("invalid {} metadata", name)))
+}
+
+impl CallOptions {
+    fn from_headers(headers: &HeaderMap) -> Result<Self, GrpcStatus> {
+        let message_count =
+            metadata(headers, "garble-message-count")?.unwrap_or(DEFAULT_MESSAGE_COUNT);
+        if message_count > MAX_MESSAGE_COUNT {
+            return Err(GrpcStatus::invalid_argument(format!(
+                "garble-message-count must be at most {}",
+                MAX_MESSAGE_COUNT
+            )));
+        }
+
+        let rate = metadata::<u64>(headers, "garble-rate")?.filter(|&rate| rate > 0);
+        if rate.is_some_and(|rate| rate > MAX_RATE) {
+            return Err(GrpcStatus::invalid_argument(format!(
+                "garble-rate must be at most {}",
+                MAX_RATE
+            )));
+        }
+
+        let message_bytes =
+            metadata(headers, "garble-message-bytes")?.unwrap_or(DEFAULT_MESSAGE_BYTES);
+        if message_bytes == 0 || message_bytes > MAX_MESSAGE_BYTES {
+            return Err(GrpcStatus::invalid_argument(format!(
+                "garble-message-bytes must be between 1 and {}",
+                MAX_MESSAGE_BYTES
+            )));
+        }
+
+        Ok(Self {
+            message_count,
+            rate,
+            message_bytes,
+            reset_after: metadata(headers, "garble-reset-after")?,
+            trailers_only: metadata(headers, "garble-trailers-only")?.unwrap_or(false),
+            status: metadata(headers, "garble-status")?.unwrap_or(FinalStatus::Code(OK)),
+        })
+    }
+}
+
+/// `GarbleMessage` holding `json`, with the gRPC length prefix
+fn encode_message(json: &str) -> Bytes {
+    let mut message = BytesMut::new();
+    // Field 1, length delimited
+    message.put_u8(0x0A);
+    put_varint(&mut message, json.len() as u64);
+    message.put_slice(json.as_bytes());
+
+    let mut framed = BytesMut::with_capacity(message.len() + 5);
+    framed.put_u8(0);
+    framed.put_u32(message.len() as u32);
+    framed.put_slice(&message);
+    framed.freeze()
+}
+
+fn put_varint(buf: &mut BytesMut, mut value: u64) {
+    while value >= 0x80 {
+        buf.put_u8((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+fn get_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        if !buf.has_remaining() {
+            return None;
+        }
+        let byte = buf.get_u8();
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// The `json` field of a `GarbleMessage`, skipping any other fields
+fn decode_message(mut buf: &[u8]) -> Option<String> {
+    let mut json = None;
+    while buf.has_remaining() {
+        let key = get_varint(&mut buf)?;
+        let skip = match key & 0x7 {
+            0 => {
+                get_varint(&mut buf)?;
+                0
+            }
+            1 => 8,
+            2 => {
+                let len = get_varint(&mut buf)? as usize;
+                if key >> 3 == 1 {
+                    let bytes = buf.get(..len)?;
+                    json = Some(String::from_utf8_lossy(bytes).into_owned());
+                }
+                len
+            }
+            5 => 4,
+            _ => return None,
+        };
+        if buf.remaining() < skip {
+            return None;
+        }
+        buf.advance(skip);
+    }
+    json
+}
+
+fn status_trailers(code: u8, message: &str) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from(u16::from(code)));
+    if let Ok(message) = HeaderValue::from_str(message) {
+        if code != OK {
+            trailers.insert("grpc-message", message);
+        }
+    }
+    trailers
+}
+
+fn status_message(code: u8) -> String {
+    if code == OK {
+        return String::new();
+    }
+    format!(
+        "garbled failure {}",
+        RandomDataGenerator::new().generate_random_string(12)
+    )
+}
+
+/// A response made only of headers, carrying the status where trailers would be
+fn trailers_only(code: u8, message: &str) -> Response {
+    let mut response = Response::new(Body::empty());
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(GRPC_CONTENT_TYPE),
+    );
+    headers.extend(status_trailers(code, message));
+    response
+}
+
+/// Send `messages`, then the final status, applying pacing and chaos options
+fn respond<S>(messages: S, options: CallOptions) -> Response
+where
+    S: Stream<Item = String> + Send + 'static,
+{
+    let status = options.status.resolve();
+    if options.trailers_only {
+        return trailers_only(status, &status_message(status));
+    }
+
+    let frames = stream! {
+        let mut messages = Box::pin(messages);
+        let mut ticker = options
+            .rate
+            .map(|rate| tokio::time::interval(Duration::from_secs_f64(1.0 / rate as f64)));
+        let mut sent = 0u64;
+
+        while let Some(json) = messages.next().await {
+            if options.reset_after == Some(sent) {
+                // Failing the body makes the server abort the stream with RST_STREAM
+                yield Err(std::io::Error::other("garbled stream reset"));
+                return;
+            }
+            if let Some(ticker) = ticker.as_mut() {
+                ticker.tick().await;
+            }
+            if DRAIN.is_draining() {
+                yield Ok(Frame::trailers(status_trailers(UNAVAILABLE, "instance is draining")));
+                return;
+            }
+            sent += 1;
+            yield Ok(Frame::data(encode_message(&json)));
+        }
+
+        if options.reset_after == Some(sent) {
+            yield Err(std::io::Error::other("garbled stream reset"));
+            return;
+        }
+        yield Ok(Frame::trailers(status_trailers(status, &status_message(status))));
+    };
+
+    let mut response = Response::new(Body::new(StreamBody::new(frames)));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(GRPC_CONTENT_TYPE),
+    );
+    response
+}
+
+fn call_options(headers: &HeaderMap) -> Result<CallOptions, GrpcStatus> {
+    if DRAIN.is_draining() {
+        return Err(GrpcStatus {
+            code: UNAVAILABLE,
+            message: "instance is draining".to_string(),
+        });
+    }
+    CallOptions::from_headers(headers)
+}
+
+/// `rpc Stream(GarbleMessage) returns (stream GarbleMessage)`: the request is ignored
+pub async fn stream_handler(tenant: Tenant, headers: HeaderMap) -> Response {
+    let options = match call_options(&headers) {
+        Ok(options) => options,
+        Err(status) => return trailers_only(status.code, &status.message),
+    };
+    tracing::info!(
+        "Opened gRPC stream: tenant={}, messages={}, message_bytes={}B, rate={:?}",
+        tenant.name,
+        options.message_count,
+        options.message_bytes,
+        options.rate
+    );
+    METRICS.record_response(
+        &tenant.segment(),
+        "grpc-stream",
+        options.message_bytes * options.message_count as usize,
+        0,
+    );
+
+    let (count, message_bytes) = (options.message_count, options.message_bytes);
+    let messages = futures::stream::iter(0..count).then(move |_| async move {
+        tokio::task::spawn_blocking(move || {
+            let record = RandomDataGenerator::new().generate_sized_record(message_bytes);
+            serde_json::to_string(&record).unwrap_or_default()
+        })
+        .await
+        .unwrap_or_default()
+    });
+    respond(messages, options)
+}
+
+/// `rpc Chat(stream GarbleMessage) returns (stream GarbleMessage)`: every request
+/// message is answered with a garbled mutation of its `json` field
+pub async fn chat_handler(tenant: Tenant, headers: HeaderMap, body: Body) -> Response {
+    let options = match call_options(&headers) {
+        Ok(options) => options,
+        Err(status) => return trailers_only(status.code, &status.message),
+    };
+    tracing::info!("Opened gRPC chat: tenant={}", tenant.name);
+    METRICS.record_response(&tenant.segment(), "grpc-chat", 0, 0);
+
+    let replies = stream! {
+        let mut body = body.into_data_stream();
+        let mut buffer = BytesMut::new();
+        while let Some(Ok(data)) = body.next().await {
+            buffer.extend_from_slice(&data);
+            // Answer every complete length-prefixed message received so far
+            while buffer.len() >= 5 {
+                let len = u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]) as usize;
+                if buffer.len() < len + 5 {
+                    break;
+                }
+                let frame = buffer.split_to(len + 5);
+                let json = decode_message(&frame[5..]).unwrap_or_default();
+                let target_size = json.len().max(1);
+                yield mutate_text(&json, target_size);
+            }
+        }
+    };
+    respond(replies, options)
+}
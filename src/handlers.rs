@@ -3,20 +3,70 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use axum::{
-    extract::{Query, State},
-    http::StatusCode,
-    response::{IntoResponse, Json},
+    body::Body,
+    extract::{rejection::QueryRejection, Query, RawQuery, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Json, Response,
+    },
+    Extension,
 };
+use futures::{Stream, StreamExt};
 use rand::prelude::*;
 use serde::Deserialize;
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 
+use std::convert::Infallible;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::sleep;
 
-use crate::config::Config;
-use crate::streaming::create_optimal_response;
+use crate::admission;
+use crate::budget;
+use crate::cache_origin;
+use crate::cluster::{self, CLUSTER};
+use crate::compression;
+use crate::config::{GarbleConfig, JitterModel, LiveConfig, OversizeAction, WaitJitter};
+use crate::dependencies::{self, DependencyOrder};
+use crate::drain::DRAIN;
+use crate::emit::EmitOptions;
+use crate::encoding::{BodyEncoding, TextEncoding};
+use crate::encryption::{self, EncryptionMode};
+use crate::error::ApiError;
+use crate::faults;
+use crate::flags::{self, FLAGS};
+use crate::generator::{DateMode, GeneratorOptions, StringifiedJson, TextMode, ValueDistribution};
+use crate::hang;
+use crate::header_hazards::{self, Hazards};
+use crate::hol_scenario;
+use crate::http2::{self, ResetCode, TransportFaults};
+use crate::interim::{self, Interim};
+use crate::memory::MEMORY_GUARD;
+use crate::metadata::{request_echo, MetadataLevel, MetadataOptions};
+use crate::metrics::{ClientAbortWatch, METRICS};
+use crate::mirror::MIRROR;
+use crate::outlier;
+use crate::pacing::{self, FlushPacing};
+use crate::path_faults;
+use crate::post_process;
+use crate::presets;
+use crate::proxy::PROXY;
+use crate::readiness;
+use crate::remote_config::REMOTE_CONFIG;
+use crate::response_cache;
+use crate::rules;
+use crate::server::ConnectionControl;
+use crate::shaping;
+use crate::streaming::{
+    self, create_optimal_response, ChunkDelay, ChunkLayout, ResponseOptions, ResponseShape,
+    ResponseStrategy,
+};
+use crate::tenants::Tenant;
+use crate::test_runs;
+use crate::upload::{self, ReadPacing, Stall, WaitFrom};
+use crate::validation::{strict_query, ValidationError};
 
 #[derive(Debug, Deserialize)]
 pub struct GarbleParams {
@@ -28,28 +78,981 @@ pub struct GarbleParams {
     max_wait_duration: Option<u64>,
     #[serde(rename = "minWaitDuration")]
     min_wait_duration: Option<u64>,
+    #[serde(rename = "contentType")]
+    content_type: Option<String>,
+    mislabel: Option<bool>,
+    metadata: Option<MetadataLevel>,
+    echo: Option<bool>,
+    text: Option<TextMode>,
+    distribution: Option<ValueDistribution>,
+    #[serde(rename = "zipfExponent")]
+    zipf_exponent: Option<f64>,
+    #[serde(rename = "bigNumbers")]
+    big_numbers: Option<bool>,
+    #[serde(rename = "stringifiedJson")]
+    stringified_json: Option<f64>,
+    #[serde(rename = "stringifiedJsonBytes")]
+    stringified_json_bytes: Option<usize>,
+    dates: Option<DateMode>,
+    preset: Option<String>,
+    shape: Option<ShapeParam>,
+    strategy: Option<StrategyParam>,
+    #[serde(rename = "recordCount")]
+    record_count: Option<usize>,
+    #[serde(rename = "sortedKeys")]
+    sorted_keys: Option<bool>,
+    pretty: Option<bool>,
+    indent: Option<usize>,
+    callback: Option<String>,
+    encoding: Option<TextEncoding>,
+    bom: Option<bool>,
+    #[serde(rename = "flushIntervalMs")]
+    flush_interval_ms: Option<u64>,
+    #[serde(rename = "flushBytes")]
+    flush_bytes: Option<usize>,
+    #[serde(rename = "durationMs")]
+    duration_ms: Option<u64>,
+    #[serde(rename = "bytesPerSecond")]
+    bytes_per_second: Option<u64>,
+    #[serde(rename = "chunkDelayMs")]
+    chunk_delay_ms: Option<String>,
+    #[serde(rename = "numChunks")]
+    num_chunks: Option<usize>,
+    #[serde(rename = "chunkBytes")]
+    chunk_bytes: Option<usize>,
+    dependencies: Option<String>,
+    #[serde(rename = "dependencyOrder")]
+    dependency_order: Option<DependencyOrder>,
+    encrypt: Option<EncryptionMode>,
+    #[serde(rename = "postProcess")]
+    post_process: Option<String>,
+    envelope: Option<usize>,
+    #[serde(rename = "envelopeKey")]
+    envelope_key: Option<String>,
+    #[serde(rename = "envelopeMode")]
+    envelope_mode: Option<EnvelopeMode>,
+    #[serde(rename = "waitFrom")]
+    wait_from: Option<WaitFrom>,
+    #[serde(rename = "uploadBytesPerSecond")]
+    upload_bytes_per_second: Option<u64>,
+    #[serde(rename = "uploadStallAfterBytes")]
+    upload_stall_after_bytes: Option<usize>,
+    #[serde(rename = "uploadStallMs")]
+    upload_stall_ms: Option<u64>,
+    seed: Option<u64>,
+    #[serde(rename = "stableByPath")]
+    stable_by_path: Option<bool>,
+    #[serde(rename = "h2ResetAfterBytes")]
+    h2_reset_after_bytes: Option<usize>,
+    #[serde(rename = "h2ResetCode")]
+    h2_reset_code: Option<ResetCode>,
+    #[serde(rename = "h2GoawayAfterBytes")]
+    h2_goaway_after_bytes: Option<usize>,
+    #[serde(rename = "h2ContinuationFrames")]
+    h2_continuation_frames: Option<usize>,
+    #[serde(rename = "headerHazards")]
+    header_hazards: Option<String>,
+    #[serde(rename = "headerDelay")]
+    header_delay: Option<u64>,
+    #[serde(rename = "bodyDelay")]
+    body_delay: Option<u64>,
+    #[serde(rename = "bodyDelayAfterBytes")]
+    body_delay_after_bytes: Option<usize>,
+    #[serde(rename = "headerHazardBytes")]
+    header_hazard_bytes: Option<usize>,
+    interim: Option<String>,
+    #[serde(rename = "exactBodySize")]
+    exact_body_size: Option<usize>,
+    #[serde(rename = "errorRate")]
+    error_rate: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ShapeParam {
+    Garble,
+    Records,
+    Base64,
+}
+
+/// How the layers of `envelope` hold the layer within
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum EnvelopeMode {
+    /// As a nested object
+    #[default]
+    Object,
+    /// JSON-encoded as a string, the way some gateways pass payloads on
+    String,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum StrategyParam {
+    Direct,
+    Fast,
+    Streaming,
+}
+
+/// Upper bound for `recordCount`, since record responses are built in memory
+const MAX_RECORD_COUNT: usize = 100_000;
+
+/// Longest accepted pause between paced writes
+const MAX_FLUSH_INTERVAL_MS: u64 = 60_000;
+
+/// Longest accepted `durationMs`
+const MAX_DURATION_MS: u64 = 600_000;
+
+/// Largest body `durationMs` and `bytesPerSecond` may add up to
+const MAX_DURATION_BODY_BYTES: usize = 1_000_000_000;
+
+/// Pause between writes of a body spread over `durationMs`, unless the body
+/// is too small to write that often
+const DURATION_FLUSH_INTERVAL_MS: u64 = 100;
+
+/// Longest accepted delay between streamed chunks
+const MAX_CHUNK_DELAY_MS: u64 = 60_000;
+
+/// How long a stalled upload stops reading before failing, unless `uploadStallMs` says
+const DEFAULT_UPLOAD_STALL_MS: u64 = 30_000;
+
+/// Longest accepted `uploadStallMs`
+const MAX_UPLOAD_STALL_MS: u64 = 600_000;
+
+/// Upper bound for `numChunks`
+const MAX_NUM_CHUNKS: usize = 100_000;
+
+/// Upper bound for `chunkBytes`
+const MAX_CHUNK_BYTES: usize = 10_000_000;
+
+/// Longest accepted JSONP callback name
+const MAX_CALLBACK_LENGTH: usize = 128;
+
+/// Indentation used by `pretty=true` when no `indent` is given
+const DEFAULT_INDENT: usize = 2;
+
+/// Upper bound for `indent`, beyond which whitespace would dwarf the data
+const MAX_INDENT: usize = 16;
+
+/// Size of the documents `stringifiedJson` embeds when no `stringifiedJsonBytes` is given
+const DEFAULT_STRINGIFIED_JSON_BYTES: usize = 128;
+
+/// Upper bound for `stringifiedJsonBytes`
+const MAX_STRINGIFIED_JSON_BYTES: usize = 65_536;
+
+/// Upper bound for `bodyDelay`
+const MAX_BODY_DELAY_MS: u64 = 600_000;
+
+/// Upper bound for `envelope` with nested objects
+const MAX_ENVELOPE_LAYERS: usize = 64;
+
+/// Upper bound for `envelope` with string layers, whose escaping grows the
+/// body with every layer
+const MAX_STRING_ENVELOPE_LAYERS: usize = 8;
+
+/// Field each `envelope` layer holds the next in when no `envelopeKey` is given
+const DEFAULT_ENVELOPE_KEY: &str = "data";
+
+/// Content types a response may be deliberately mislabeled with
+const MISLABEL_CONTENT_TYPES: &[&str] = &[
+    "text/html; charset=utf-8",
+    "text/plain",
+    "text/csv",
+    "application/xml",
+    "application/octet-stream",
+    "application/x-www-form-urlencoded",
+    "image/png",
+];
+
+impl GarbleParams {
+    /// Content type the response should be labeled with instead of its real one,
+    /// checked up front so a bad value fails before any waiting
+    pub fn content_type_label(&self) -> Result<Option<HeaderValue>, ApiError> {
+        if let Some(content_type) = &self.content_type {
+            let label = HeaderValue::from_str(content_type).map_err(|_| {
+                ApiError::bad_request(format!("invalid contentType: {}", content_type))
+            })?;
+            return Ok(Some(label));
+        }
+
+        if self.mislabel.unwrap_or(false) {
+            let mut rng = thread_rng();
+            let label = MISLABEL_CONTENT_TYPES.choose(&mut rng).copied();
+            return Ok(label.map(HeaderValue::from_static));
+        }
+
+        Ok(None)
+    }
+
+    /// Generator knobs requested by the caller
+    fn generator_options(&self) -> Result<GeneratorOptions, ApiError> {
+        let zipf_exponent = match self.distribution.unwrap_or_default() {
+            ValueDistribution::Uniform => None,
+            ValueDistribution::Zipf => {
+                let exponent = self.zipf_exponent.unwrap_or(1.0);
+                if !exponent.is_finite() || exponent <= 0.0 {
+                    return Err(ApiError::bad_request(format!(
+                        "zipfExponent must be a positive number, got {}",
+                        exponent
+                    )));
+                }
+                Some(exponent)
+            }
+        };
+
+        let structure = match &self.preset {
+            None => None,
+            Some(name) => Some(presets::get(name).ok_or_else(|| {
+                ApiError::bad_request(format!(
+                    "unknown preset {}, expected one of: {}",
+                    name,
+                    presets::names().join(", ")
+                ))
+            })?),
+        };
+
+        Ok(GeneratorOptions {
+            text: self.text.unwrap_or_default(),
+            zipf_exponent,
+            big_numbers: self.big_numbers.unwrap_or(false),
+            dates: self.dates.unwrap_or_default(),
+            structure,
+            stringified_json: self.stringified_json()?,
+        })
+    }
+
+    /// Values double-encoded as JSON strings, if asked for with `stringifiedJson`
+    fn stringified_json(&self) -> Result<Option<StringifiedJson>, ApiError> {
+        let Some(probability) = self.stringified_json else {
+            if self.stringified_json_bytes.is_some() {
+                return Err(ApiError::bad_request(
+                    "stringifiedJsonBytes needs stringifiedJson",
+                ));
+            }
+            return Ok(None);
+        };
+        if !(0.0..=1.0).contains(&probability) {
+            return Err(ApiError::bad_request(format!(
+                "stringifiedJson must be between 0 and 1, got {}",
+                probability
+            )));
+        }
+        let bytes = self
+            .stringified_json_bytes
+            .unwrap_or(DEFAULT_STRINGIFIED_JSON_BYTES);
+        if bytes > MAX_STRINGIFIED_JSON_BYTES {
+            return Err(ApiError::bad_request(format!(
+                "stringifiedJsonBytes must be at most {}, got {}",
+                MAX_STRINGIFIED_JSON_BYTES, bytes
+            )));
+        }
+        Ok((probability > 0.0).then_some(StringifiedJson { probability, bytes }))
+    }
+
+    /// Requested body layout
+    fn response_shape(&self) -> Result<ResponseShape, ApiError> {
+        match (self.shape, self.record_count) {
+            (Some(ShapeParam::Records), Some(count)) if count > MAX_RECORD_COUNT => {
+                Err(ApiError::bad_request(format!(
+                    "recordCount must be at most {}, got {}",
+                    MAX_RECORD_COUNT, count
+                )))
+            }
+            (Some(ShapeParam::Records), count) => Ok(ResponseShape::Records { count }),
+            (_, Some(_)) => Err(ApiError::bad_request("recordCount requires shape=records")),
+            (Some(ShapeParam::Base64), None) => Ok(ResponseShape::Base64),
+            (_, None) => Ok(ResponseShape::Garble),
+        }
+    }
+
+    /// Strategy the caller forces instead of the size thresholds
+    fn response_strategy(&self) -> Result<Option<ResponseStrategy>, ApiError> {
+        let Some(strategy) = self.strategy else {
+            return Ok(None);
+        };
+        if let Some(shape @ (ShapeParam::Records | ShapeParam::Base64)) = self.shape {
+            return Err(ApiError::bad_request(format!(
+                "strategy cannot be combined with shape={}",
+                match shape {
+                    ShapeParam::Records => "records",
+                    _ => "base64",
+                }
+            )));
+        }
+        let strategy = match strategy {
+            StrategyParam::Direct => ResponseStrategy::Direct,
+            StrategyParam::Fast => ResponseStrategy::Fast,
+            StrategyParam::Streaming => ResponseStrategy::Streaming,
+        };
+        let streams_anyway = self.chunk_delay_ms.is_some()
+            || self.num_chunks.is_some()
+            || self.chunk_bytes.is_some();
+        if streams_anyway && strategy != ResponseStrategy::Streaming {
+            return Err(ApiError::bad_request(
+                "chunkDelayMs, numChunks and chunkBytes need strategy=streaming",
+            ));
+        }
+        Ok(Some(strategy))
+    }
+
+    /// How the body is written out
+    fn emit_options(&self) -> Result<EmitOptions, ApiError> {
+        let indent = match (self.pretty.unwrap_or(false), self.indent) {
+            (_, Some(indent)) if indent > MAX_INDENT => {
+                return Err(ApiError::bad_request(format!(
+                    "indent must be at most {}, got {}",
+                    MAX_INDENT, indent
+                )));
+            }
+            (true, indent) => Some(indent.unwrap_or(DEFAULT_INDENT)),
+            (false, _) => None,
+        };
+
+        Ok(EmitOptions {
+            sorted_keys: self.sorted_keys.unwrap_or(false),
+            indent,
+        })
+    }
+
+    /// JSONP function name, restricted to dotted JavaScript identifiers so it
+    /// cannot inject script
+    fn jsonp_callback(&self) -> Result<Option<String>, ApiError> {
+        let Some(callback) = &self.callback else {
+            return Ok(None);
+        };
+
+        let valid_identifier = |part: &str| {
+            let mut chars = part.chars();
+            chars
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+        };
+        if callback.len() > MAX_CALLBACK_LENGTH || !callback.split('.').all(valid_identifier) {
+            return Err(ApiError::bad_request(format!(
+                "invalid callback: {}",
+                callback
+            )));
+        }
+
+        Ok(Some(callback.clone()))
+    }
+
+    /// How the body text is turned into bytes
+    fn body_encoding(&self) -> Result<BodyEncoding, ApiError> {
+        let encoding = self.encoding.unwrap_or_default();
+        let bom = self.bom.unwrap_or(false);
+        if bom && !encoding.supports_bom() {
+            return Err(ApiError::bad_request(
+                "bom is only supported for utf-8 and utf-16 encodings",
+            ));
+        }
+        Ok(BodyEncoding { encoding, bom })
+    }
+
+    /// Size of the JSON document for `exactBodySize`, which counts every byte
+    /// of the body, the JSONP call and byte order mark around the document included
+    fn exact_json_size(&self) -> Result<Option<usize>, ApiError> {
+        let Some(size) = self.exact_body_size else {
+            return Ok(None);
+        };
+        let conflicts = [
+            ("minBodySize", self.min_body_size.is_some()),
+            ("maxBodySize", self.max_body_size.is_some()),
+            ("durationMs", self.duration_ms.is_some()),
+            ("numChunks", self.num_chunks.is_some()),
+            ("chunkBytes", self.chunk_bytes.is_some()),
+            ("recordCount", self.record_count.is_some()),
+            ("encrypt", self.encrypt.is_some()),
+            ("postProcess", self.post_process.is_some()),
+            ("envelope", self.envelope.is_some()),
+        ];
+        if let Some((name, _)) = conflicts.iter().find(|(_, given)| *given) {
+            return Err(ApiError::bad_request(format!(
+                "exactBodySize cannot be combined with {}",
+                name
+            )));
+        }
+        let encoding = self.body_encoding()?;
+        if encoding.encoding != TextEncoding::Utf8 {
+            return Err(ApiError::bad_request(
+                "exactBodySize is only supported for utf-8 bodies, as other encodings change the size with the text",
+            ));
+        }
+        let wrapper = self
+            .jsonp_callback()?
+            .map_or(0, |callback| callback.len() + 3)
+            + encoding.bom().len();
+        // The smallest document is an empty object
+        match size
+            .checked_sub(wrapper)
+            .filter(|&json_size| json_size >= 2)
+        {
+            Some(json_size) => Ok(Some(json_size)),
+            None => Err(ApiError::bad_request(format!(
+                "exactBodySize must be at least {}, got {}",
+                wrapper + 2,
+                size
+            ))),
+        }
+    }
+
+    /// Body size and pacing that spread a response over `durationMs` at
+    /// `bytesPerSecond`, for callers describing a transfer instead of a byte count
+    fn duration_target(&self) -> Result<Option<(usize, FlushPacing)>, ApiError> {
+        let (duration_ms, bytes_per_second) = match (self.duration_ms, self.bytes_per_second) {
+            (None, None) => return Ok(None),
+            (Some(duration_ms), Some(bytes_per_second)) => (duration_ms, bytes_per_second),
+            _ => {
+                return Err(ApiError::bad_request(
+                    "durationMs and bytesPerSecond must be given together",
+                ))
+            }
+        };
+        if duration_ms > MAX_DURATION_MS {
+            return Err(ApiError::bad_request(format!(
+                "durationMs must be at most {}, got {}",
+                MAX_DURATION_MS, duration_ms
+            )));
+        }
+        if bytes_per_second == 0 {
+            return Err(ApiError::bad_request("bytesPerSecond must be at least 1"));
+        }
+        if self.min_body_size.is_some() || self.max_body_size.is_some() {
+            return Err(ApiError::bad_request(
+                "durationMs and bytesPerSecond set the body size, leave out minBodySize and maxBodySize",
+            ));
+        }
+        if self.flush_interval_ms.is_some() || self.flush_bytes.is_some() {
+            return Err(ApiError::bad_request(
+                "durationMs and bytesPerSecond set the pacing, leave out flushIntervalMs and flushBytes",
+            ));
+        }
+
+        let size = (bytes_per_second as u128)
+            .checked_mul(duration_ms as u128)
+            .map(|product| product / 1000)
+            .and_then(|size| usize::try_from(size).ok())
+            .filter(|&size| size <= MAX_DURATION_BODY_BYTES)
+            .ok_or_else(|| {
+                ApiError::bad_request(format!(
+                    "durationMs times bytesPerSecond must come to at most {} bytes",
+                    MAX_DURATION_BODY_BYTES
+                ))
+            })?
+            .max(1);
+        // The first write goes out at once, so the pauses in between make up the duration
+        let writes = (duration_ms / DURATION_FLUSH_INTERVAL_MS + 1).min(size as u64);
+        let interval_ms = if writes > 1 {
+            duration_ms / (writes - 1)
+        } else {
+            0
+        };
+        let pacing = FlushPacing {
+            interval_ms: Some(interval_ms),
+            bytes: Some(size.div_ceil(writes as usize)),
+        };
+        Ok(Some((size, pacing)))
+    }
+
+    /// How the body is handed to the socket
+    fn flush_pacing(&self) -> Result<FlushPacing, ApiError> {
+        if let Some((_, pacing)) = self.duration_target()? {
+            return Ok(pacing);
+        }
+        if self.flush_bytes == Some(0) {
+            return Err(ApiError::bad_request("flushBytes must be at least 1"));
+        }
+        if let Some(interval) = self
+            .flush_interval_ms
+            .filter(|&i| i > MAX_FLUSH_INTERVAL_MS)
+        {
+            return Err(ApiError::bad_request(format!(
+                "flushIntervalMs must be at most {}, got {}",
+                MAX_FLUSH_INTERVAL_MS, interval
+            )));
+        }
+        Ok(FlushPacing {
+            interval_ms: self.flush_interval_ms,
+            bytes: self.flush_bytes,
+        })
+    }
+
+    /// Delay between streamed chunks, given as `N` or `MIN-MAX` milliseconds
+    fn chunk_delay(&self) -> Result<Option<ChunkDelay>, ApiError> {
+        let Some(raw) = &self.chunk_delay_ms else {
+            return Ok(None);
+        };
+
+        let invalid = || {
+            ApiError::bad_request(format!(
+                "chunkDelayMs must be N or MIN-MAX milliseconds up to {}, got {}",
+                MAX_CHUNK_DELAY_MS, raw
+            ))
+        };
+        let parse = |value: &str| value.trim().parse::<u64>().map_err(|_| invalid());
+        let (min_ms, max_ms) = match raw.split_once('-') {
+            Some((min, max)) => (parse(min)?, parse(max)?),
+            None => {
+                let delay = parse(raw)?;
+                (delay, delay)
+            }
+        };
+        if min_ms > max_ms || max_ms > MAX_CHUNK_DELAY_MS {
+            return Err(invalid());
+        }
+
+        Ok(Some(ChunkDelay { min_ms, max_ms }))
+    }
+
+    /// Requested partitioning of the chunk array
+    fn chunk_layout(&self) -> Result<ChunkLayout, ApiError> {
+        if let Some(count) = self.num_chunks.filter(|&n| n == 0 || n > MAX_NUM_CHUNKS) {
+            return Err(ApiError::bad_request(format!(
+                "numChunks must be between 1 and {}, got {}",
+                MAX_NUM_CHUNKS, count
+            )));
+        }
+        if let Some(bytes) = self.chunk_bytes.filter(|&b| b == 0 || b > MAX_CHUNK_BYTES) {
+            return Err(ApiError::bad_request(format!(
+                "chunkBytes must be between 1 and {}, got {}",
+                MAX_CHUNK_BYTES, bytes
+            )));
+        }
+        Ok(ChunkLayout {
+            count: self.num_chunks,
+            bytes: self.chunk_bytes,
+        })
+    }
+
+    /// How the body is encrypted, if at all. It is assembled whole before it is
+    /// sealed, so options pacing or chunking its delivery do not combine
+    fn encryption(&self) -> Result<Option<EncryptionMode>, ApiError> {
+        let Some(mode) = self.encrypt else {
+            return Ok(None);
+        };
+        if !encryption::is_configured() {
+            return Err(ApiError::bad_request(
+                "encrypt needs a key under encryption.key in the config",
+            ));
+        }
+        if self.paced() {
+            return Err(ApiError::bad_request(
+                "encrypt cannot be combined with flushIntervalMs, flushBytes, durationMs, chunkDelayMs, numChunks or chunkBytes",
+            ));
+        }
+        Ok(Some(mode))
+    }
+
+    /// The chain the body is put through, if any. Like encryption, it works on
+    /// the whole body, so options pacing or chunking its delivery do not combine
+    /// `envelope` layers come first, so the `postProcess` steps work on the
+    /// wrapped body
+    fn post_processing(&self) -> Result<Option<post_process::Chain>, ApiError> {
+        let envelopes = self.envelopes()?;
+        let chain = match &self.post_process {
+            Some(param) => Some(post_process::resolve(param)?),
+            None => None,
+        };
+        let chain = match (envelopes, chain) {
+            (Some(envelopes), Some(chain)) => Some(envelopes.then(chain)),
+            (envelopes, chain) => envelopes.or(chain),
+        };
+        if chain.is_some() && self.paced() {
+            let name = match self.post_process {
+                Some(_) => "postProcess",
+                None => "envelope",
+            };
+            return Err(ApiError::bad_request(format!(
+                "{} cannot be combined with flushIntervalMs, flushBytes, durationMs, chunkDelayMs, numChunks or chunkBytes",
+                name
+            )));
+        }
+        Ok(chain)
+    }
+
+    /// The nested envelopes asked for with `envelope`. Every string layer
+    /// escapes the quotes of the layers within, roughly doubling them, so
+    /// fewer of those are allowed
+    fn envelopes(&self) -> Result<Option<post_process::Chain>, ApiError> {
+        let Some(layers) = self.envelope else {
+            if self.envelope_key.is_some() || self.envelope_mode.is_some() {
+                return Err(ApiError::bad_request(
+                    "envelopeKey and envelopeMode need envelope",
+                ));
+            }
+            return Ok(None);
+        };
+        let mode = self.envelope_mode.unwrap_or_default();
+        let max = match mode {
+            EnvelopeMode::Object => MAX_ENVELOPE_LAYERS,
+            EnvelopeMode::String => MAX_STRING_ENVELOPE_LAYERS,
+        };
+        if layers == 0 || layers > max {
+            return Err(ApiError::bad_request(format!(
+                "envelope must be between 1 and {} with envelopeMode={}, got {}",
+                max,
+                match mode {
+                    EnvelopeMode::Object => "object",
+                    EnvelopeMode::String => "string",
+                },
+                layers
+            )));
+        }
+        let key = self.envelope_key.as_deref().unwrap_or(DEFAULT_ENVELOPE_KEY);
+        post_process::Chain::nested(layers, key, mode == EnvelopeMode::String)
+            .map(Some)
+            .map_err(ApiError::bad_request)
+    }
+
+    /// Whether delivery of the body is paced or laid out in chunks
+    fn paced(&self) -> bool {
+        self.flush_interval_ms.is_some()
+            || self.flush_bytes.is_some()
+            || self.duration_ms.is_some()
+            || self.chunk_delay_ms.is_some()
+            || self.num_chunks.is_some()
+            || self.chunk_bytes.is_some()
+    }
+
+    /// How the request body is read: at a limited speed, and maybe stalling partway
+    fn read_pacing(&self) -> Result<ReadPacing, ApiError> {
+        if self.upload_bytes_per_second == Some(0) {
+            return Err(ApiError::bad_request(
+                "uploadBytesPerSecond must be at least 1",
+            ));
+        }
+        let stall = match (self.upload_stall_after_bytes, self.upload_stall_ms) {
+            (None, None) => None,
+            (None, Some(_)) => {
+                return Err(ApiError::bad_request(
+                    "uploadStallMs needs uploadStallAfterBytes",
+                ))
+            }
+            (Some(after_bytes), stall_ms) => {
+                let stall_ms = stall_ms.unwrap_or(DEFAULT_UPLOAD_STALL_MS);
+                if stall_ms > MAX_UPLOAD_STALL_MS {
+                    return Err(ApiError::bad_request(format!(
+                        "uploadStallMs must be at most {}",
+                        MAX_UPLOAD_STALL_MS
+                    )));
+                }
+                Some(Stall {
+                    after_bytes,
+                    duration: Duration::from_millis(stall_ms),
+                })
+            }
+        };
+        Ok(ReadPacing {
+            bytes_per_second: self.upload_bytes_per_second,
+            stall,
+        })
+    }
+
+    /// Share of requests answered with an injected error instead, overriding `faults.error_rate`
+    fn error_rate(&self) -> Result<Option<f64>, ApiError> {
+        match self.error_rate {
+            Some(rate) if !(0.0..=1.0).contains(&rate) => Err(ApiError::bad_request(format!(
+                "errorRate must be between 0 and 1, got {}",
+                rate
+            ))),
+            rate => Ok(rate),
+        }
+    }
+
+    /// Faults below the body: stream resets, GOAWAYs and oversized header blocks
+    fn transport_faults(&self) -> Result<TransportFaults, ApiError> {
+        let reset = match (self.h2_reset_after_bytes, self.h2_reset_code) {
+            (None, None) => None,
+            (None, Some(_)) => {
+                return Err(ApiError::bad_request("h2ResetCode needs h2ResetAfterBytes"))
+            }
+            (Some(after_bytes), code) => Some((after_bytes, code.unwrap_or_default())),
+        };
+        if let Some(frames) = self.h2_continuation_frames {
+            if !(1..=http2::MAX_CONTINUATION_FRAMES).contains(&frames) {
+                return Err(ApiError::bad_request(format!(
+                    "h2ContinuationFrames must be between 1 and {}",
+                    http2::MAX_CONTINUATION_FRAMES
+                )));
+            }
+        }
+        Ok(TransportFaults {
+            reset,
+            goaway_after: self.h2_goaway_after_bytes,
+            continuation_frames: self.h2_continuation_frames,
+        })
+    }
+
+    /// Hazardous headers to send. A folded header means writing the response
+    /// whole, so options pacing or chunking its delivery do not combine
+    fn header_hazards(&self) -> Result<Hazards, ApiError> {
+        let Some(spec) = self.header_hazards.as_deref() else {
+            if self.header_hazard_bytes.is_some() {
+                return Err(ApiError::bad_request(
+                    "headerHazardBytes needs headerHazards",
+                ));
+            }
+            return Ok(Hazards::default());
+        };
+        let hazards =
+            header_hazards::parse(spec, self.header_hazard_bytes).map_err(ApiError::bad_request)?;
+        if hazards.folds() && self.paced() {
+            return Err(ApiError::bad_request(
+                "headerHazards=fold cannot be combined with flushIntervalMs, flushBytes, durationMs, chunkDelayMs, numChunks or chunkBytes",
+            ));
+        }
+        if hazards.folds() && self.body_delay.is_some() {
+            return Err(ApiError::bad_request(
+                "headerHazards=fold cannot be combined with bodyDelay",
+            ));
+        }
+        Ok(hazards)
+    }
+
+    /// Fixed wait before the headers, in place of the random one between
+    /// `minWaitDuration` and `maxWaitDuration`
+    fn header_delay(&self) -> Result<Option<u64>, ApiError> {
+        let Some(delay) = self.header_delay else {
+            return Ok(None);
+        };
+        if self.min_wait_duration.is_some() || self.max_wait_duration.is_some() {
+            return Err(ApiError::bad_request(
+                "headerDelay sets the wait, leave out minWaitDuration and maxWaitDuration",
+            ));
+        }
+        Ok(Some(delay))
+    }
+
+    /// Stall in the body once the headers are out: its length, and the body
+    /// bytes sent before it
+    fn body_delay(&self) -> Result<Option<(Duration, usize)>, ApiError> {
+        let Some(delay_ms) = self.body_delay else {
+            if self.body_delay_after_bytes.is_some() {
+                return Err(ApiError::bad_request("bodyDelayAfterBytes needs bodyDelay"));
+            }
+            return Ok(None);
+        };
+        if delay_ms > MAX_BODY_DELAY_MS {
+            return Err(ApiError::bad_request(format!(
+                "bodyDelay must be at most {}, got {}",
+                MAX_BODY_DELAY_MS, delay_ms
+            )));
+        }
+        Ok(Some((
+            Duration::from_millis(delay_ms),
+            self.body_delay_after_bytes.unwrap_or(0),
+        )))
+    }
+
+    /// Interim responses to send before the final one
+    fn interim_responses(&self) -> Result<Vec<Interim>, ApiError> {
+        match self.interim.as_deref() {
+            Some(spec) => interim::parse(spec).map_err(ApiError::bad_request),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Options controlling how the response body is assembled, checked up front
+    /// so a bad value fails before any waiting; `request_echo` is only embedded
+    /// when the caller asked for it
+    pub fn response_options(
+        &self,
+        request_echo: impl FnOnce() -> Value,
+    ) -> Result<ResponseOptions, ApiError> {
+        Ok(ResponseOptions {
+            metadata: MetadataOptions {
+                level: self.metadata.unwrap_or_default(),
+                request_echo: self.echo.unwrap_or(false).then(request_echo),
+                dependencies: None,
+            },
+            generator: self.generator_options()?,
+            shape: self.response_shape()?,
+            emit: self.emit_options()?,
+            jsonp_callback: self.jsonp_callback()?,
+            encoding: self.body_encoding()?,
+            pacing: self.flush_pacing()?,
+            chunk_delay: self.chunk_delay()?,
+            chunking: self.chunk_layout()?,
+            coding: None,
+            strategy: self.response_strategy()?,
+            deadline: None,
+            segment: None,
+            seed: None,
+        })
+    }
+
+    /// Seed the body is generated from: the one given with `seed`, or for
+    /// `stableByPath` one the same for every request to the same path and
+    /// query, so the body can be had again without keeping any state
+    fn body_seed(&self, uri: &Uri) -> Result<Option<u64>, ApiError> {
+        let stable_by_path = self.stable_by_path.unwrap_or(false);
+        match (self.seed, stable_by_path) {
+            (None, false) => return Ok(None),
+            (Some(_), true) => {
+                return Err(ApiError::bad_request(
+                    "seed cannot be combined with stableByPath, which picks its own seed",
+                ))
+            }
+            (Some(seed), false) => return Ok(Some(seed)),
+            (None, true) => {}
+        }
+        let target = uri
+            .path_and_query()
+            .map_or(uri.path(), |path_and_query| path_and_query.as_str());
+        let digest = Sha256::digest(target.as_bytes());
+        Ok(Some(u64::from_be_bytes(
+            digest[..8].try_into().expect("digest is 32 bytes"),
+        )))
+    }
+}
+
+/// Hold the body of `response` back for `delay` once `after_bytes` of it went
+/// out, so the headers arrive quickly and the body stalls
+fn stall_body(response: Response, delay: Duration, after_bytes: usize) -> Response {
+    let (parts, body) = response.into_parts();
+    let body = body
+        .into_data_stream()
+        .map(|piece| piece.map_err(std::io::Error::other));
+    Response::from_parts(
+        parts,
+        Body::from_stream(pacing::stalled(body, after_bytes, delay)),
+    )
+}
+
+/// Replace the Content-Type of a generated response, leaving the body untouched
+pub fn relabel_content_type(response: &mut Response, label: Option<HeaderValue>) {
+    if let Some(label) = label {
+        response.headers_mut().insert(header::CONTENT_TYPE, label);
+    }
 }
 
 // No fixed response structure - everything is garbled!
 
-#[axum::debug_handler]
-pub async fn garble_handler(
-    Query(garble_params): Query<GarbleParams>,
-    State(config): State<Arc<Config>>,
-) -> Result<impl IntoResponse, StatusCode> {
-    // Determine effective configuration (query params override config file)
-    let min_body_size = garble_params
-        .min_body_size
-        .unwrap_or(config.garble.min_body_size);
-    let max_body_size = garble_params
-        .max_body_size
-        .unwrap_or(config.garble.max_body_size);
-    let min_wait_duration_ms = garble_params
-        .min_wait_duration
-        .unwrap_or(config.garble.min_wait_duration_ms);
-    let max_wait_duration_ms = garble_params
-        .max_wait_duration
-        .unwrap_or(config.garble.max_wait_duration_ms);
+/// Resolve the effective body size and wait duration for a request, picking
+/// random values within the configured ranges (query params override config file).
+/// Requested bodies beyond the cap are rejected with a 413 unless the config
+/// clamps them. In strict mode inverted ranges and values beyond the safety caps
+/// are reported instead of being swapped or clamped.
+pub fn resolve_targets(
+    garble_params: &GarbleParams,
+    garble: &GarbleConfig,
+) -> Result<(usize, u64), ApiError> {
+    pick_targets(garble_params, garble, None).map(|targets| (targets.size, targets.wait_ms))
+}
+
+/// Body size and wait picked for a request
+struct Targets {
+    size: usize,
+    /// Largest body size the request allows
+    max_size: usize,
+    wait_ms: u64,
+}
+
+/// Resolve the targets as `resolve_targets` does, with the body size picked
+/// from `size_seed` when given so it comes out the same every time
+fn pick_targets(
+    garble_params: &GarbleParams,
+    garble: &GarbleConfig,
+    size_seed: Option<u64>,
+) -> Result<Targets, ApiError> {
+    let duration_size = garble_params.duration_target()?.map(|(size, _)| size);
+    // The size follows from the duration and rate, so clamping it would break the pacing
+    if let (Some(size), Some(cap)) = (duration_size, garble.body_size_cap) {
+        if size > cap {
+            return Err(ApiError::bad_request(format!(
+                "durationMs times bytesPerSecond comes to {} bytes, more than the body size cap of {}",
+                size, cap
+            )));
+        }
+    }
+    let exact_size = garble_params.exact_json_size()?;
+    let min_body_size = exact_size
+        .or(garble_params.min_body_size)
+        .or(duration_size)
+        .unwrap_or(garble.min_body_size);
+    let max_body_size = exact_size
+        .or(garble_params.max_body_size)
+        .or(duration_size)
+        .unwrap_or(garble.max_body_size);
+    let header_delay = garble_params.header_delay()?;
+    let min_wait_duration_ms = header_delay
+        .or(garble_params.min_wait_duration)
+        .unwrap_or(garble.min_wait_duration_ms);
+    let max_wait_duration_ms = header_delay
+        .or(garble_params.max_wait_duration)
+        .unwrap_or(garble.max_wait_duration_ms);
+
+    // A clamped body would not be the size asked for, so exact sizes are never clamped
+    if let (Some(size), Some(cap)) = (exact_size, garble.body_size_cap) {
+        if size > cap {
+            METRICS.record_oversize(OversizeAction::Reject);
+            return Err(ApiError::body_too_large(size, cap));
+        }
+    }
+
+    if let (Some(cap), OversizeAction::Reject) = (garble.body_size_cap, garble.oversize) {
+        // Only sizes the caller asked for are rejected; configured defaults are still clamped
+        let requested = garble_params
+            .min_body_size
+            .into_iter()
+            .chain(garble_params.max_body_size)
+            .chain(duration_size)
+            .max();
+        if let Some(requested) = requested.filter(|&requested| requested > cap) {
+            METRICS.record_oversize(OversizeAction::Reject);
+            return Err(ApiError::body_too_large(requested, cap));
+        }
+    }
+
+    if garble.strict_validation {
+        let mut error = ValidationError::default();
+        if min_body_size > max_body_size {
+            error.push(
+                "minBodySize",
+                Some(min_body_size.to_string()),
+                format!("greater than maxBodySize ({})", max_body_size),
+            );
+        }
+        if min_wait_duration_ms > max_wait_duration_ms {
+            error.push(
+                "minWaitDuration",
+                Some(min_wait_duration_ms.to_string()),
+                format!("greater than maxWaitDuration ({})", max_wait_duration_ms),
+            );
+        }
+        if let Some(cap) = garble.body_size_cap {
+            for (name, value) in [
+                ("minBodySize", min_body_size),
+                ("maxBodySize", max_body_size),
+            ] {
+                if value > cap {
+                    error.push(
+                        name,
+                        Some(value.to_string()),
+                        format!("exceeds cap of {}", cap),
+                    );
+                }
+            }
+        }
+        if let Some(cap) = garble.wait_duration_cap_ms {
+            for (name, value) in [
+                ("minWaitDuration", min_wait_duration_ms),
+                ("maxWaitDuration", max_wait_duration_ms),
+            ] {
+                if value > cap {
+                    error.push(
+                        name,
+                        Some(value.to_string()),
+                        format!("exceeds cap of {}ms", cap),
+                    );
+                }
+            }
+        }
+        if !error.is_empty() {
+            return Err(error.into());
+        }
+    }
 
     // Validate parameters
     if min_body_size > max_body_size {
@@ -67,71 +1070,383 @@ pub async fn garble_handler(
         );
     }
 
-    let effective_min_body = min_body_size.min(max_body_size);
-    let effective_max_body = min_body_size.max(max_body_size);
-    let effective_min_wait = min_wait_duration_ms.min(max_wait_duration_ms);
-    let effective_max_wait = min_wait_duration_ms.max(max_wait_duration_ms);
+    let body_cap = garble.body_size_cap.unwrap_or(usize::MAX);
+    let wait_cap = garble.wait_duration_cap_ms.unwrap_or(u64::MAX);
+    if min_body_size.max(max_body_size) > body_cap {
+        tracing::warn!("Requested body size exceeds cap of {}, clamping", body_cap);
+        METRICS.record_oversize(OversizeAction::Clamp);
+    }
+    if min_wait_duration_ms.max(max_wait_duration_ms) > wait_cap {
+        tracing::warn!(
+            "Requested wait duration exceeds cap of {}ms, clamping",
+            wait_cap
+        );
+    }
+
+    let effective_min_body = min_body_size.min(max_body_size).min(body_cap);
+    let effective_max_body = min_body_size.max(max_body_size).min(body_cap);
+    let effective_min_wait = min_wait_duration_ms.min(max_wait_duration_ms).min(wait_cap);
+    let effective_max_wait = min_wait_duration_ms.max(max_wait_duration_ms).min(wait_cap);
 
     // Generate random values within the specified ranges
-    let (target_size, wait_duration_ms) = {
-        let mut rng = thread_rng();
-        let target_size = if effective_min_body == effective_max_body {
-            effective_min_body
-        } else {
-            rng.gen_range(effective_min_body..=effective_max_body)
-        };
+    let mut rng = thread_rng();
+    let target_size = match size_seed {
+        _ if effective_min_body == effective_max_body => effective_min_body,
+        Some(seed) => {
+            StdRng::seed_from_u64(seed).gen_range(effective_min_body..=effective_max_body)
+        }
+        None => rng.gen_range(effective_min_body..=effective_max_body),
+    };
 
-        let wait_duration_ms = if effective_min_wait == effective_max_wait {
-            effective_min_wait
-        } else {
-            rng.gen_range(effective_min_wait..=effective_max_wait)
-        };
+    let wait_duration_ms = if effective_min_wait == effective_max_wait {
+        effective_min_wait
+    } else {
+        sample_wait(
+            &garble.wait_jitter,
+            &mut rng,
+            effective_min_wait,
+            effective_max_wait,
+        )
+    };
+
+    Ok(Targets {
+        size: target_size,
+        max_size: effective_max_body,
+        wait_ms: wait_duration_ms,
+    })
+}
+
+/// `target_size`, or the smallest size at or above it that a document of the
+/// chosen strategy fits in, as long as the request allows for it
+fn fit_body_size(
+    target_size: usize,
+    max_size: usize,
+    options: &ResponseOptions,
+) -> Result<usize, ApiError> {
+    let mut size = target_size;
+    // The metadata block records the size, so one more digit can take one more byte
+    loop {
+        let smallest = streaming::smallest_body(size, options);
+        if smallest <= size {
+            return Ok(size);
+        }
+        if smallest > max_size {
+            return Err(ApiError::bad_request(format!(
+                "a body of at most {} bytes is too small for the {} bytes the smallest {} document takes",
+                max_size,
+                smallest,
+                ResponseStrategy::choose(size, options).name()
+            )));
+        }
+        size = smallest;
+    }
+}
 
-        (target_size, wait_duration_ms)
+/// A wait duration between `min` and `max` following the profile's jitter model
+fn sample_wait(jitter: &WaitJitter, rng: &mut impl Rng, min: u64, max: u64) -> u64 {
+    let center = match jitter.model {
+        JitterModel::Uniform => return rng.gen_range(min..=max),
+        JitterModel::Percentage => jitter.base_ms,
+        JitterModel::Bimodal if rng.gen_bool(jitter.slow_probability) => jitter.slow_ms,
+        JitterModel::Bimodal => jitter.fast_ms,
     };
+    let spread = center as f64 * jitter.jitter_percent / 100.0;
+    let wait = center as f64 + rng.gen_range(-1.0..=1.0) * spread;
+    (wait.round().max(0.0) as u64).clamp(min, max)
+}
 
-    // Wait for the specified duration
-    if wait_duration_ms > 0 {
-        sleep(Duration::from_millis(wait_duration_ms)).await;
+#[axum::debug_handler(state = Arc<LiveConfig>)]
+#[allow(clippy::too_many_arguments)]
+pub async fn garble_handler(
+    garble_params: Result<Query<GarbleParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    tenant: Tenant,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    connection: Option<Extension<Arc<ConnectionControl>>>,
+    body: Body,
+) -> Result<impl IntoResponse, ApiError> {
+    // Turn new work away while draining so clients move to another instance
+    if DRAIN.is_draining() {
+        return Err(ApiError::draining());
     }
 
-    // Use optimal response strategy based on size and configuration
-    let response = create_optimal_response(target_size);
+    let garble_params = strict_query(
+        garble_params,
+        raw_query.as_deref(),
+        tenant.garble.strict_validation,
+    )?;
+    let body_seed = garble_params.body_seed(&uri)?;
+    let targets = pick_targets(&garble_params, &tenant.garble, body_seed)?;
+    let (target_size, wait_duration_ms) = (targets.size, targets.wait_ms);
+    let content_type_label = garble_params.content_type_label()?;
+    // The echo needs the length of the body, so it is filled in once the body is read
+    let mut options = garble_params.response_options(|| Value::Null)?;
+    let read_pacing = garble_params.read_pacing()?;
+    let transport_faults = garble_params.transport_faults()?;
+    let interim_responses = garble_params.interim_responses()?;
+    let header_hazards = garble_params.header_hazards()?;
+    let body_delay = garble_params.body_delay()?;
+    let error_rate = garble_params.error_rate()?;
+    options.segment = Some(tenant.segment());
+    options.seed = body_seed;
+    let encryption = garble_params.encryption()?;
+    let post_processing = garble_params.post_processing()?;
+    // Ciphertext does not compress, so encrypted bodies never are; exact sizes
+    // count the body as generated, so those are not compressed either, and
+    // post-processed bodies are compressed by their chain if at all
+    let exact_size = garble_params.exact_body_size;
+    if encryption.is_none() && exact_size.is_none() && post_processing.is_none() {
+        options.coding = compression::negotiate(&headers);
+    }
+    let hop = dependencies::incoming_hop(&headers)?;
+    let called = match garble_params.dependencies.as_deref() {
+        Some(names) => dependencies::select(names)?,
+        None => Vec::new(),
+    };
 
-    // Log the response strategy used
-    let strategy = if target_size < config.performance.fast_response_threshold_bytes {
-        "direct"
-    } else if target_size < config.performance.streaming_threshold_bytes {
-        "fast_pool"
-    } else {
-        "streaming"
+    // Refuse large responses up front when close to the memory ceiling
+    let strategy = ResponseStrategy::choose(target_size, &options);
+    let in_flight = MEMORY_GUARD
+        .try_reserve(target_size, strategy)
+        .ok_or_else(|| ApiError::memory_ceiling(target_size))?;
+
+    // Read the request body and wait for the specified duration, alongside the body or
+    // after it; a client hanging up drops this future, which cancels the wait and
+    // whatever would have followed it
+    let wait_from = garble_params.wait_from.unwrap_or_default();
+    let mut client = ClientAbortWatch::new(match wait_from {
+        WaitFrom::Headers => "wait",
+        WaitFrom::Body => "upload",
+    });
+    let upload = upload::consume(body, read_pacing);
+    let wait = async {
+        if wait_duration_ms > 0 {
+            sleep(Duration::from_millis(wait_duration_ms)).await;
+        }
+    };
+    let upload_and_wait = async {
+        match wait_from {
+            WaitFrom::Headers => tokio::join!(upload, wait).0,
+            WaitFrom::Body => {
+                let body_size = upload.await?;
+                client.set_phase("wait");
+                wait.await;
+                Ok(body_size)
+            }
+        }
+    };
+    // Interim responses go out meanwhile, and all of them before the final response
+    let interim = interim::send(
+        connection
+            .as_ref()
+            .map(|Extension(connection)| connection.clone()),
+        interim_responses,
+    );
+    let body_size = tokio::join!(upload_and_wait, interim).0?;
+    if options.metadata.request_echo.is_some() {
+        options.metadata.request_echo = Some(request_echo(&method, &uri, &headers, body_size));
+    }
+
+    // Misbehave as the chaos flags that are on dictate
+    let chaos = flags::roll();
+    if let Some(spike) = chaos.latency_spike {
+        sleep(spike).await;
+    }
+    if let Some(status) = chaos.error {
+        return Err(ApiError::injected(status));
+    }
+    if let Some((status, rate)) = outlier::roll_error() {
+        return Err(ApiError::error_ramp(status, rate));
+    }
+    if let Some(status) = faults::roll(error_rate) {
+        return Ok(faults::respond(status));
+    }
+
+    // Call downstream dependencies, failing like a gateway when a required one does
+    client.set_phase("dependencies");
+    let outcomes = dependencies::call_all(
+        &called,
+        garble_params.dependency_order.unwrap_or_default(),
+        hop,
+    )
+    .await;
+    dependencies::check(&outcomes)?;
+    if !outcomes.is_empty() {
+        options.metadata.dependencies = serde_json::to_value(&outcomes).ok();
+    }
+
+    // The metadata block can only be left out of a streamed body so far, so a
+    // body too small for its document grows to fit within the requested range
+    let smallest = streaming::smallest_body(target_size, &options);
+    if smallest > target_size && exact_size.is_some() {
+        return Err(ApiError::bad_request(format!(
+            "exactBodySize leaves {} bytes for the document, fewer than the {} it takes with its metadata",
+            target_size, smallest
+        )));
+    }
+    let target_size = fit_body_size(target_size, targets.max_size, &options)?;
+
+    // Wait for a generation slot, settling for a smaller body if the queue says
+    // so, which a body of an exact size cannot
+    client.set_phase("admission");
+    let (admission, target_size) = admission::admit(target_size, exact_size.is_none()).await?;
+    client.set_phase("generation");
+
+    // Replay a cached response when one is kept for this size, format and profile
+    let cache_key = response_cache::key_for(&tenant.name, target_size, &options);
+    let build = move || {
+        // Use optimal response strategy based on size and configuration
+        create_optimal_response(target_size, options).map(|response| {
+            response
+                .with_in_flight(in_flight)
+                .with_admission(admission)
+                .with_wait_ms(wait_duration_ms)
+        })
+    };
+    let (mut response, strategy) = match cache_key {
+        Some(key) => response_cache::serve(key, wait_duration_ms, build).await?,
+        None => {
+            let garble_response = build()?;
+            let strategy = garble_response.decision.strategy;
+            (garble_response.into_response(), strategy)
+        }
     };
+    if let Some(mode) = encryption {
+        response = encryption::seal(response, mode).await?;
+    }
+    if let Some(chain) = post_processing {
+        response = post_process::apply(response, chain).await?;
+    }
+    // A streamed body keeps its own watch from here on
+    client.finish();
 
+    // Log the response strategy used
     tracing::info!(
-        "Generated GARBLED response: strategy={}, target_size={}B, wait={}ms",
+        "Generated GARBLED response: tenant={}, strategy={}, target_size={}B, wait={}ms",
+        tenant.name,
         strategy,
         target_size,
         wait_duration_ms
     );
+    METRICS.record_response(&tenant.segment(), strategy, target_size, wait_duration_ms);
+
+    relabel_content_type(&mut response, content_type_label);
+    if let Some(size) = exact_size {
+        // Streamed bodies are announced at their size too, leaving no room
+        // for the chunked coding their trailer needs
+        let headers = response.headers_mut();
+        headers.remove(header::TRANSFER_ENCODING);
+        headers.remove(header::TRAILER);
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(size));
+    }
+    if let Some(seed) = body_seed {
+        response
+            .headers_mut()
+            .insert("x-garble-seed", HeaderValue::from(seed));
+    }
+    if let Some(timing) = dependencies::server_timing(&outcomes) {
+        response.headers_mut().insert("server-timing", timing);
+    }
+    if chaos.truncate {
+        response = flags::truncate(response, target_size);
+    }
+    if let Some((delay, after_bytes)) = body_delay {
+        response = stall_body(response, delay, after_bytes);
+    }
+    let connection = connection.map(|Extension(connection)| connection);
+    response = header_hazards::apply(response, header_hazards, connection.clone()).await?;
+    response = http2::apply(response, transport_faults, connection);
 
     Ok(response)
 }
 
-pub async fn health_handler() -> Json<Value> {
+pub async fn health_handler(State(config): State<Arc<LiveConfig>>) -> Response {
+    if DRAIN.is_draining() {
+        // Fail health checks so load balancers stop routing here before the restart
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::CONNECTION, "close")],
+            Json(serde_json::json!({
+                "status": "draining",
+                "service": "daddle",
+                "instance": cluster::instance_id(),
+                "version": "0.1.0",
+                "timestamp": chrono::Utc::now()
+            })),
+        )
+            .into_response();
+    }
+
+    // Degraded components are reported without failing the check, as a cold pool or a
+    // stale remote config still serves traffic
+    let components = readiness::report(&config);
     Json(serde_json::json!({
-        "status": "healthy",
+        "status": if components.is_ok() { "healthy" } else { "degraded" },
         "service": "daddle",
+        "instance": cluster::instance_id(),
         "version": "0.1.0",
-        "timestamp": chrono::Utc::now()
+        "timestamp": chrono::Utc::now(),
+        "components": components
     }))
+    .into_response()
+}
+
+/// Interval between snapshots pushed by `/stats/stream`
+const STATS_STREAM_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Push a stats snapshot every second as a server-sent event, until the instance drains
+pub async fn stats_stream_handler() -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let mut interval = tokio::time::interval(STATS_STREAM_INTERVAL);
+    let snapshots = async_stream::stream! {
+        loop {
+            interval.tick().await;
+            if DRAIN.is_draining() {
+                break;
+            }
+            yield Ok(Event::default().event("stats").json_data(stats_snapshot()).unwrap_or_default());
+        }
+    };
+    Sse::new(snapshots).keep_alive(KeepAlive::default())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatsParams {
+    #[serde(rename = "runId")]
+    run_id: Option<String>,
+}
+
+/// Everything `/stats` reports, or with `runId` only what that test run's requests added up to
+pub async fn stats_handler(Query(params): Query<StatsParams>) -> Result<Json<Value>, ApiError> {
+    let Some(run_id) = params.run_id else {
+        return Ok(Json(stats_snapshot()));
+    };
+    let stats = test_runs::run_stats(&run_id).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "unknown-test-run",
+            "Unknown test run",
+            format!("no requests were seen for test run {}", run_id),
+        )
+    })?;
+    Ok(Json(serde_json::json!({
+        "run_id": run_id,
+        "run": stats,
+        "service": "daddle",
+        "version": "0.1.0",
+        "timestamp": chrono::Utc::now()
+    })))
 }
 
-pub async fn stats_handler() -> Json<Value> {
+/// Everything `/stats` reports, as of now
+pub fn stats_snapshot() -> Value {
     use crate::chunk_pool::CHUNK_POOL;
 
     let stats = CHUNK_POOL.get_stats();
+    let memory = MEMORY_GUARD.get_stats();
 
-    Json(serde_json::json!({
+    serde_json::json!({
         "chunk_pool": {
             "total_chunks": stats.total_chunks,
             "memory_usage_bytes": stats.memory_usage_bytes,
@@ -145,8 +1460,71 @@ pub async fn stats_handler() -> Json<Value> {
             },
             "background_generations": stats.background_generations
         },
+        "memory": {
+            "ceiling_mb": memory.ceiling_bytes.map(|bytes| bytes / (1024 * 1024)),
+            "rss_bytes": memory.rss_bytes,
+            "in_flight_bytes": memory.in_flight_bytes,
+            "in_flight_responses": memory.in_flight_responses,
+            "rejected_requests": memory.rejected_requests,
+            "background_paused": memory.background_paused,
+            "pressure": memory.pressure,
+            "pool_scale": memory.pool_scale
+        },
+        "tenants": METRICS.tenant_stats(),
+        "latency": METRICS.latency_stats(),
+        "oversize": METRICS.oversize_stats(),
+        "aborted_by_client": METRICS.client_abort_stats(),
+        "strategies": METRICS.strategy_stats(),
+        "generation_budget": budget::get_stats(),
+        "hang": hang::get_stats(),
+        "faults": faults::get_stats(),
+        "header_hazards": header_hazards::get_stats(),
+        "outlier": outlier::get_stats(),
+        "connection_shaping": shaping::get_stats(),
+        "http2": http2::get_stats(),
+        "interim": interim::get_stats(),
+        "admission": admission::get_stats(),
+        "compression": compression::get_stats(),
+        "post_process": post_process::get_stats(),
+        "response_cache": response_cache::get_stats(),
+        "cache_origin": cache_origin::get_stats(),
+        "hol_scenario": hol_scenario::get_stats(),
+        "test_runs": test_runs::get_stats(),
+        "mirror": MIRROR.get().map(|mirror| mirror.get_stats()),
+        "cluster": CLUSTER.get().map(|cluster| cluster.get_stats()),
+        "flags": FLAGS.get_stats(),
+        "rules": rules::get_stats(),
+        "path_faults": path_faults::get_stats(),
+        "proxy": PROXY.get().map(|proxy| proxy.get_stats()),
+        "remote_config": REMOTE_CONFIG.get().map(|remote| remote.get_stats()),
         "service": "daddle",
         "version": "0.1.0",
         "timestamp": chrono::Utc::now()
-    }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn targets(query: &str) -> Result<(usize, u64), ApiError> {
+        let params: GarbleParams = serde_urlencoded::from_str(query).unwrap();
+        resolve_targets(&params, &Config::default().garble)
+    }
+
+    #[test]
+    fn duration_products_past_the_limit_are_refused() {
+        for query in [
+            "durationMs=600000&bytesPerSecond=18446744073709551615",
+            "durationMs=600000&bytesPerSecond=10000000",
+        ] {
+            let refused = targets(query).unwrap_err();
+            assert_eq!(refused.into_response().status(), StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(
+            targets("durationMs=1000&bytesPerSecond=5000").unwrap().0,
+            5000
+        );
+    }
 }
@@ -0,0 +1,202 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Worst-case peers for testing timeout enforcement. `?hang=true` accepts a
+//! request and never finishes the response, `?blackhole=true` reads the request
+//! body as slowly as possible without answering. Both give up after the `hang`
+//! config's `max_duration_ms` so stuck clients cannot pin connections forever.
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body_util::BodyExt;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+use crate::config::HangConfig;
+use crate::error::ApiError;
+use crate::rules::EXEMPT_PREFIXES;
+
+#[derive(Debug, Default, Deserialize)]
+struct HangParams {
+    #[serde(default)]
+    hang: bool,
+    /// Hang before the status line and headers instead of after them
+    #[serde(default, rename = "hangBeforeHeaders")]
+    hang_before_headers: bool,
+    #[serde(default)]
+    blackhole: bool,
+}
+
+/// Hung requests right now and since startup
+#[derive(Debug, Clone, Serialize)]
+pub struct HangStats {
+    pub active: u64,
+    pub total: u64,
+    pub rejected: u64,
+    pub expired: u64,
+}
+
+struct Hangs {
+    max_duration: Duration,
+    max_concurrent: u64,
+    read_interval: Duration,
+    active: AtomicU64,
+    total: AtomicU64,
+    rejected: AtomicU64,
+    expired: AtomicU64,
+}
+
+impl Hangs {
+    /// Claim a slot for a hung request, unless too many are hung already
+    fn enter(&'static self) -> Option<HangSlot> {
+        let claimed = self
+            .active
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |active| {
+                (active < self.max_concurrent).then_some(active + 1)
+            });
+        if claimed.is_err() {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.total.fetch_add(1, Ordering::Relaxed);
+        Some(HangSlot { hangs: self })
+    }
+
+    fn expire(&self) {
+        self.expired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get_stats(&self) -> HangStats {
+        HangStats {
+            active: self.active.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A hung request, released when the request ends or the client goes away
+struct HangSlot {
+    hangs: &'static Hangs,
+}
+
+impl Drop for HangSlot {
+    fn drop(&mut self) {
+        self.hangs.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Set the limits hung requests are held to; call once at startup
+pub fn configure(config: &HangConfig) {
+    let _ = HANGS.set(Hangs {
+        max_duration: Duration::from_millis(config.max_duration_ms),
+        max_concurrent: config.max_concurrent,
+        read_interval: Duration::from_millis(config.blackhole_read_interval_ms),
+        active: AtomicU64::new(0),
+        total: AtomicU64::new(0),
+        rejected: AtomicU64::new(0),
+        expired: AtomicU64::new(0),
+    });
+}
+
+/// Hung request counts, if hanging is configured
+pub fn get_stats() -> Option<HangStats> {
+    HANGS.get().map(Hangs::get_stats)
+}
+
+/// Middleware holding requests that ask for `hang` or `blackhole`
+pub async fn hang_requests(request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    if EXEMPT_PREFIXES
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+    {
+        return next.run(request).await;
+    }
+    let params: HangParams =
+        serde_urlencoded::from_str(request.uri().query().unwrap_or_default()).unwrap_or_default();
+    if !params.hang && !params.blackhole {
+        return next.run(request).await;
+    }
+    let Some(hangs) = HANGS.get() else {
+        return next.run(request).await;
+    };
+    let Some(slot) = hangs.enter() else {
+        return ApiError::hang_limit(hangs.max_concurrent).into_response();
+    };
+
+    if params.blackhole {
+        tracing::info!("Blackholing {} {}", request.method(), request.uri());
+        blackhole(hangs, request.into_body()).await;
+    } else if params.hang_before_headers {
+        tracing::info!(
+            "Hanging {} {} before headers",
+            request.method(),
+            request.uri()
+        );
+        sleep(hangs.max_duration).await;
+    } else {
+        tracing::info!(
+            "Hanging {} {} after headers",
+            request.method(),
+            request.uri()
+        );
+        return hang_after_headers(hangs, slot);
+    }
+
+    hangs.expire();
+    drop(slot);
+    ApiError::hang_expired(hangs.max_duration).into_response()
+}
+
+/// Read one frame of the body per interval until the hang expires, so the
+/// client's send window fills and its writes stall
+async fn blackhole(hangs: &Hangs, mut body: Body) {
+    let deadline = Instant::now() + hangs.max_duration;
+    while Instant::now() < deadline {
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => break,
+            frame = body.frame() => {
+                if frame.is_none() {
+                    // Everything was sent; keep the client waiting for an answer
+                    tokio::time::sleep_until(deadline).await;
+                    break;
+                }
+            }
+        }
+        tokio::time::sleep_until(deadline.min(Instant::now() + hangs.read_interval)).await;
+    }
+}
+
+/// A 200 whose body never arrives, aborted once the hang expires
+fn hang_after_headers(hangs: &'static Hangs, slot: HangSlot) -> Response {
+    let body = futures::stream::once(async move {
+        sleep(hangs.max_duration).await;
+        hangs.expire();
+        drop(slot);
+        Err::<bytes::Bytes, _>(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "hang reached the configured maximum",
+        ))
+    });
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/json")],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
+
+// Global hang limits and counters, set once at startup
+static HANGS: OnceCell<Hangs> = OnceCell::new();
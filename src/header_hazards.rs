@@ -0,0 +1,245 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Response headers naive intermediaries mishandle, for checking that proxies
+//! and gateways sanitize what they pass on: CR and LF smuggled in encoded
+//! forms, overlong values, and values folded across lines (obs-fold). No bare
+//! CR or LF ever ends a header, so nothing is actually split unless an
+//! intermediary decodes or unfolds a value into one. Off unless the
+//! `header_hazards` config enables it, and every value stays within its bounds.
+//!
+//! hyper refuses to write folded values, so a folding response is written to
+//! the connection directly, which works over HTTP/1.1 only; the connection
+//! closes after it. On HTTP/2 connections, which have no folding, it is skipped.
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, HeaderName, HeaderValue},
+    response::Response,
+};
+use once_cell::sync::{Lazy, OnceCell};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::config::HeaderHazardsConfig;
+use crate::error::ApiError;
+use crate::server::ConnectionControl;
+
+/// Length of the `long` header when a request gives none
+pub const DEFAULT_LONG_BYTES: usize = 8 * 1024;
+
+/// Header a folded value is sent in
+const FOLD_HEADER: &str = "x-garble-hazard-fold";
+
+/// CR and LF in the encodings intermediaries are known to decode into header
+/// values: percent-encoded once and twice, backslash escapes, CJK characters
+/// whose low bytes are LF and CR, Unicode line separators, and an encoded
+/// blank line followed by markup
+const CRLF_VALUES: &[(&str, &str)] = &[
+    (
+        "x-garble-hazard-crlf-percent",
+        "garble%0d%0aSet-Cookie:%20garble-injected=1",
+    ),
+    (
+        "x-garble-hazard-crlf-double-percent",
+        "garble%250d%250aX-Garble-Injected:%201",
+    ),
+    (
+        "x-garble-hazard-crlf-escaped",
+        "garble\\r\\nX-Garble-Injected: 1",
+    ),
+    (
+        "x-garble-hazard-crlf-unicode",
+        "garble\u{560a}\u{560d}X-Garble-Injected: 1",
+    ),
+    (
+        "x-garble-hazard-crlf-separator",
+        "garble\u{2028}X-Garble-Injected: 1\u{0085}",
+    ),
+    (
+        "x-garble-hazard-crlf-body",
+        "garble%0d%0a%0d%0a<script>garble()</script>",
+    ),
+];
+
+/// Continuation lines of the folded value; the last one reads like a header
+/// of its own to a proxy that does not unfold
+const FOLD_LINES: &[&str] = &["garble", " folded=1", "\tX-Garble-Injected: 1"];
+
+/// Hazardous headers a response asked for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Hazards {
+    crlf: bool,
+    /// Length of the overlong header, if any
+    long: Option<usize>,
+    fold: bool,
+}
+
+impl Hazards {
+    /// Whether the response is written to the connection whole, its body buffered first
+    pub fn folds(&self) -> bool {
+        self.fold
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    responses: AtomicU64,
+    folded: AtomicU64,
+    fold_skipped: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HeaderHazardStats {
+    pub enabled: bool,
+    /// Responses sent with hazardous headers
+    pub responses: u64,
+    /// Responses with a folded header, written to HTTP/1.1 connections
+    pub folded: u64,
+    /// Folded headers asked for on HTTP/2 connections, which cannot carry them
+    pub fold_skipped: u64,
+}
+
+/// Check and keep the header hazard settings; call once at startup
+pub fn configure(config: &HeaderHazardsConfig) -> anyhow::Result<()> {
+    config.validate()?;
+    if config.enabled {
+        tracing::info!("Hazardous response headers can be asked for with headerHazards");
+    }
+    let _ = CONFIG.set(config.clone());
+    Ok(())
+}
+
+/// Parse a comma separated list of `crlf`, `long` and `fold`, with the
+/// `long` header `long_bytes` long
+pub fn parse(spec: &str, long_bytes: Option<usize>) -> Result<Hazards, String> {
+    let config = CONFIG.get().filter(|config| config.enabled).ok_or(
+        "headerHazards needs header_hazards.enabled set to true in the config".to_string(),
+    )?;
+    let mut hazards = Hazards::default();
+    for kind in spec.split(',').map(str::trim) {
+        match kind {
+            "crlf" => hazards.crlf = true,
+            "long" => hazards.long = Some(DEFAULT_LONG_BYTES),
+            "fold" => hazards.fold = true,
+            _ => {
+                return Err(format!(
+                    "unknown header hazard {:?}, expected crlf, long or fold",
+                    kind
+                ))
+            }
+        }
+    }
+    match (hazards.long, long_bytes) {
+        (None, Some(_)) => return Err("headerHazardBytes needs the long hazard".to_string()),
+        (Some(_), Some(bytes)) => hazards.long = Some(bytes),
+        _ => {}
+    }
+    if let Some(bytes) = hazards.long {
+        let max = config.max_value_bytes;
+        if bytes == 0 || bytes > max {
+            return Err(format!(
+                "headerHazardBytes must be between 1 and {}, got {}",
+                max, bytes
+            ));
+        }
+    }
+    Ok(hazards)
+}
+
+/// Add the headers `hazards` ask for to `response`. A folded header means
+/// writing the response to `connection` here, and handing hyper one it cannot
+/// send anymore
+pub async fn apply(
+    response: Response,
+    hazards: Hazards,
+    connection: Option<Arc<ConnectionControl>>,
+) -> Result<Response, ApiError> {
+    if hazards == Hazards::default() {
+        return Ok(response);
+    }
+    STATS.responses.fetch_add(1, Ordering::Relaxed);
+    let (mut parts, body) = response.into_parts();
+    if hazards.crlf {
+        for (name, value) in CRLF_VALUES {
+            parts.headers.insert(
+                HeaderName::from_static(name),
+                // Bytes past ASCII are allowed in values as obs-text
+                HeaderValue::from_bytes(value.as_bytes()).expect("valid hazard header value"),
+            );
+        }
+    }
+    if let Some(bytes) = hazards.long {
+        let value: String = rand::thread_rng()
+            .sample_iter(Alphanumeric)
+            .take(bytes)
+            .map(char::from)
+            .collect();
+        parts.headers.insert(
+            "x-garble-hazard-long",
+            HeaderValue::from_str(&value).expect("alphanumeric header value"),
+        );
+    }
+    if !hazards.fold {
+        return Ok(Response::from_parts(parts, body));
+    }
+    let Some(connection) = connection.filter(|connection| !connection.is_http2()) else {
+        STATS.fold_skipped.fetch_add(1, Ordering::Relaxed);
+        return Ok(Response::from_parts(parts, body));
+    };
+
+    let body = to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to assemble response: {}", e)))?;
+    parts.headers.remove(header::TRANSFER_ENCODING);
+    parts.headers.remove(header::TRAILER);
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+    parts
+        .headers
+        .insert(header::CONNECTION, HeaderValue::from_static("close"));
+
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        parts.status.as_u16(),
+        parts.status.canonical_reason().unwrap_or("Unknown")
+    )
+    .into_bytes();
+    for (name, value) in &parts.headers {
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(FOLD_HEADER.as_bytes());
+    head.extend_from_slice(b": ");
+    head.extend_from_slice(FOLD_LINES.join("\r\n").as_bytes());
+    head.extend_from_slice(b"\r\n\r\n");
+    head.extend_from_slice(&body);
+
+    // Whatever hyper makes of the response handed back fails to go out on the closed connection
+    if let Err(e) = connection.write_raw(&head).await {
+        tracing::debug!("Failed to send folded response: {}", e);
+    }
+    connection.close().await;
+    STATS.folded.fetch_add(1, Ordering::Relaxed);
+    Ok(Response::from_parts(parts, Body::empty()))
+}
+
+pub fn get_stats() -> HeaderHazardStats {
+    HeaderHazardStats {
+        enabled: CONFIG.get().is_some_and(|config| config.enabled),
+        responses: STATS.responses.load(Ordering::Relaxed),
+        folded: STATS.folded.load(Ordering::Relaxed),
+        fold_skipped: STATS.fold_skipped.load(Ordering::Relaxed),
+    }
+}
+
+// Global header hazard settings, set once at startup
+static CONFIG: OnceCell<HeaderHazardsConfig> = OnceCell::new();
+
+static STATS: Lazy<Counters> = Lazy::new(Counters::default);
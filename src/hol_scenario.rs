@@ -0,0 +1,431 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A head-of-line blocking scenario. `POST /scenario/hol` starts a run of one
+//! slow response and many fast ones, which a client sends on the same
+//! connection: over HTTP/1.1 the fast responses queue up behind the slow one,
+//! while HTTP/2 multiplexes them past it. Every request of the run is
+//! recorded with the connection and protocol it came over and when it arrived,
+//! and `/scenario/hol/{run}/report` sets the fast requests against the slow
+//! one, so the blocking a client suffers can be measured from both ends.
+
+use async_stream::stream;
+use axum::{
+    body::{Body, Bytes},
+    extract::{rejection::QueryRejection, Path, Query, RawQuery},
+    http::{header, HeaderMap, HeaderValue, Version},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::error::ApiError;
+use crate::generator::RandomDataGenerator;
+use crate::pacing::{paced, FlushPacing};
+use crate::server::ConnectionControl;
+use crate::tenants::Tenant;
+use crate::validation::strict_query;
+
+const DEFAULT_FAST_REQUESTS: usize = 20;
+const DEFAULT_SLOW_MS: u64 = 5_000;
+const DEFAULT_SLOW_BYTES: usize = 100_000;
+const DEFAULT_FAST_BYTES: usize = 1024;
+
+/// Most fast requests a run may have
+const MAX_FAST_REQUESTS: usize = 1_000;
+
+/// Longest accepted `slowMs`
+const MAX_SLOW_MS: u64 = 600_000;
+
+/// Pause between writes of the slow body
+const SLOW_WRITE_INTERVAL_MS: u64 = 100;
+
+/// Runs kept for their reports; starting another drops the oldest
+const MAX_RUNS: usize = 100;
+
+/// Requests recorded per run, beyond which repeats are served but not recorded
+const MAX_RECORDS: usize = 10_000;
+
+#[derive(Debug, Deserialize)]
+pub struct HolParams {
+    #[serde(rename = "fastRequests")]
+    fast_requests: Option<usize>,
+    #[serde(rename = "slowMs")]
+    slow_ms: Option<u64>,
+    #[serde(rename = "slowBytes")]
+    slow_bytes: Option<usize>,
+    #[serde(rename = "fastBytes")]
+    fast_bytes: Option<usize>,
+}
+
+/// The shape of a run, as given when it was started
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct RunSettings {
+    pub fast_requests: usize,
+    pub slow_ms: u64,
+    pub slow_bytes: usize,
+    pub fast_bytes: usize,
+}
+
+/// Which of the run's responses a request asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RequestKind {
+    Slow,
+    Fast,
+}
+
+/// A request of a run as it reached daddle
+#[derive(Debug, Clone, Serialize)]
+struct RequestRecord {
+    kind: RequestKind,
+    /// Position among the fast requests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<usize>,
+    /// Number of the connection it came over, when served by daddle's own listener
+    connection: Option<u64>,
+    protocol: &'static str,
+    /// Milliseconds from the start of the run until the request arrived
+    received_ms: f64,
+    /// Milliseconds from the start of the run until its body was written
+    completed_ms: Option<f64>,
+}
+
+struct Run {
+    id: u64,
+    settings: RunSettings,
+    started: Instant,
+    started_at: DateTime<Utc>,
+    records: Mutex<Vec<RequestRecord>>,
+}
+
+impl Run {
+    /// Milliseconds since the run started, to the microsecond
+    fn elapsed_ms(&self) -> f64 {
+        (self.started.elapsed().as_secs_f64() * 1_000_000.0).round() / 1000.0
+    }
+
+    /// Record a request, returning its position so its completion can be noted
+    fn record(
+        &self,
+        kind: RequestKind,
+        index: Option<usize>,
+        connection: Option<u64>,
+        version: Version,
+    ) -> Option<usize> {
+        let mut records = self.records.lock().unwrap();
+        if records.len() >= MAX_RECORDS {
+            return None;
+        }
+        records.push(RequestRecord {
+            kind,
+            index,
+            connection,
+            protocol: protocol(version),
+            received_ms: self.elapsed_ms(),
+            completed_ms: None,
+        });
+        HOL.requests.fetch_add(1, Ordering::Relaxed);
+        Some(records.len() - 1)
+    }
+
+    fn complete(&self, position: Option<usize>) {
+        if let Some(position) = position {
+            let completed_ms = self.elapsed_ms();
+            if let Some(record) = self.records.lock().unwrap().get_mut(position) {
+                record.completed_ms = Some(completed_ms);
+            }
+        }
+    }
+
+    fn paths(&self) -> RunPaths {
+        let base = format!("/scenario/hol/{}", self.id);
+        RunPaths {
+            slow: format!("{}/slow", base),
+            fast: (0..self.settings.fast_requests)
+                .map(|index| format!("{}/fast/{}", base, index))
+                .collect(),
+            report: format!("{}/report", base),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RunPaths {
+    slow: String,
+    fast: Vec<String>,
+    report: String,
+}
+
+/// How the fast requests fared against the slow one
+#[derive(Debug, Default, Serialize)]
+struct Summary {
+    /// Distinct connections the run's requests came over
+    connections: usize,
+    protocols: Vec<&'static str>,
+    slow_received_ms: Option<f64>,
+    slow_completed_ms: Option<f64>,
+    fast_received: usize,
+    /// Fast requests that arrived before the slow one
+    fast_before_slow: usize,
+    /// Fast requests that arrived while the slow body was being written,
+    /// which a multiplexing client gets answered alongside it
+    fast_during_slow: usize,
+    /// Fast requests that only arrived once the slow body was done, held back
+    /// behind it by the client or its connection
+    fast_after_slow: usize,
+    /// Arrival of the last fast request
+    last_fast_received_ms: Option<f64>,
+}
+
+fn summarize(records: &[RequestRecord]) -> Summary {
+    let mut connections: Vec<u64> = records.iter().filter_map(|r| r.connection).collect();
+    connections.sort_unstable();
+    connections.dedup();
+    let mut protocols: Vec<&'static str> = records.iter().map(|r| r.protocol).collect();
+    protocols.sort_unstable();
+    protocols.dedup();
+
+    let slow = records.iter().find(|r| r.kind == RequestKind::Slow);
+    let mut summary = Summary {
+        connections: connections.len(),
+        protocols,
+        slow_received_ms: slow.map(|r| r.received_ms),
+        slow_completed_ms: slow.and_then(|r| r.completed_ms),
+        ..Summary::default()
+    };
+    for fast in records.iter().filter(|r| r.kind == RequestKind::Fast) {
+        summary.fast_received += 1;
+        summary.last_fast_received_ms = Some(
+            summary
+                .last_fast_received_ms
+                .map_or(fast.received_ms, |last| last.max(fast.received_ms)),
+        );
+        match (summary.slow_received_ms, summary.slow_completed_ms) {
+            (Some(received), _) if fast.received_ms < received => summary.fast_before_slow += 1,
+            (Some(_), Some(completed)) if fast.received_ms >= completed => {
+                summary.fast_after_slow += 1
+            }
+            (Some(_), _) => summary.fast_during_slow += 1,
+            (None, _) => summary.fast_before_slow += 1,
+        }
+    }
+    summary
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HolScenarioStats {
+    pub runs: u64,
+    /// Requests recorded across all runs
+    pub requests: u64,
+}
+
+struct HolScenario {
+    runs: Mutex<VecDeque<Arc<Run>>>,
+    next_id: AtomicU64,
+    started: AtomicU64,
+    requests: AtomicU64,
+}
+
+impl HolScenario {
+    fn start(&self, settings: RunSettings) -> Arc<Run> {
+        let run = Arc::new(Run {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            settings,
+            started: Instant::now(),
+            started_at: Utc::now(),
+            records: Mutex::new(Vec::new()),
+        });
+        let mut runs = self.runs.lock().unwrap();
+        if runs.len() >= MAX_RUNS {
+            runs.pop_front();
+        }
+        runs.push_back(run.clone());
+        self.started.fetch_add(1, Ordering::Relaxed);
+        run
+    }
+
+    fn find(&self, id: u64) -> Option<Arc<Run>> {
+        self.runs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|run| run.id == id)
+            .cloned()
+    }
+}
+
+fn protocol(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "HTTP/0.9",
+        Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_2 => "HTTP/2",
+        Version::HTTP_3 => "HTTP/3",
+        _ => "HTTP/1.1",
+    }
+}
+
+fn find_run(id: u64, path: &str) -> Result<Arc<Run>, ApiError> {
+    HOL.find(id).ok_or_else(|| ApiError::not_found(path))
+}
+
+/// Headers telling a client how daddle saw the request, for matching its own timings
+fn record_headers(run: &Run, connection: Option<u64>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    headers.insert(
+        "x-hol-received-ms",
+        HeaderValue::from_str(&format!("{:.3}", run.elapsed_ms())).expect("formatted number"),
+    );
+    if let Some(connection) = connection {
+        headers.insert("x-hol-connection", HeaderValue::from(connection));
+    }
+    headers
+}
+
+fn garble_body(size: usize) -> Result<Vec<u8>, ApiError> {
+    serde_json::to_vec(&RandomDataGenerator::new().generate_sized_record(size))
+        .map_err(|e| ApiError::internal(format!("Failed to serialize response: {}", e)))
+}
+
+/// Start a run, answering with the paths its requests go to
+pub async fn start_handler(
+    params: Result<Query<HolParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    tenant: Tenant,
+) -> Result<Response, ApiError> {
+    let params = strict_query(
+        params,
+        raw_query.as_deref(),
+        tenant.garble.strict_validation,
+    )?;
+    let settings = RunSettings {
+        fast_requests: params.fast_requests.unwrap_or(DEFAULT_FAST_REQUESTS),
+        slow_ms: params.slow_ms.unwrap_or(DEFAULT_SLOW_MS),
+        slow_bytes: params.slow_bytes.unwrap_or(DEFAULT_SLOW_BYTES),
+        fast_bytes: params.fast_bytes.unwrap_or(DEFAULT_FAST_BYTES),
+    };
+    if settings.fast_requests == 0 || settings.fast_requests > MAX_FAST_REQUESTS {
+        return Err(ApiError::bad_request(format!(
+            "fastRequests must be between 1 and {}, got {}",
+            MAX_FAST_REQUESTS, settings.fast_requests
+        )));
+    }
+    if settings.slow_ms > MAX_SLOW_MS {
+        return Err(ApiError::bad_request(format!(
+            "slowMs must be at most {}, got {}",
+            MAX_SLOW_MS, settings.slow_ms
+        )));
+    }
+    if let Some(cap) = tenant.garble.body_size_cap {
+        let largest = settings.slow_bytes.max(settings.fast_bytes);
+        if largest > cap {
+            return Err(ApiError::body_too_large(largest, cap));
+        }
+    }
+
+    let run = HOL.start(settings);
+    tracing::info!(
+        "Started HOL scenario run {}: {} fast requests beside a {}ms slow one",
+        run.id,
+        settings.fast_requests,
+        settings.slow_ms
+    );
+    Ok(Json(serde_json::json!({
+        "run": run.id,
+        "started_at": run.started_at,
+        "settings": settings,
+        "paths": run.paths(),
+    }))
+    .into_response())
+}
+
+/// The slow response, its garble body written a piece at a time over `slowMs`
+pub async fn slow_handler(
+    Path(id): Path<u64>,
+    version: Version,
+    connection: Option<Extension<Arc<ConnectionControl>>>,
+) -> Result<Response, ApiError> {
+    let run = find_run(id, &format!("/scenario/hol/{}/slow", id))?;
+    let connection = connection.map(|Extension(connection)| connection.id());
+    let position = run.record(RequestKind::Slow, None, connection, version);
+    let headers = record_headers(&run, connection);
+
+    let body = garble_body(run.settings.slow_bytes)?;
+    let writes = (run.settings.slow_ms / SLOW_WRITE_INTERVAL_MS).max(1) as usize;
+    let pacing = FlushPacing {
+        interval_ms: Some(SLOW_WRITE_INTERVAL_MS),
+        bytes: Some(body.len().div_ceil(writes).max(1)),
+    };
+    let pieces = paced(
+        futures::stream::iter([Ok::<_, std::io::Error>(Bytes::from(body))]),
+        pacing,
+    );
+    let body = stream! {
+        let mut pieces = std::pin::pin!(pieces);
+        while let Some(piece) = pieces.next().await {
+            yield piece;
+        }
+        run.complete(position);
+    };
+    Ok((headers, Body::from_stream(body)).into_response())
+}
+
+/// One of the fast responses, written at once
+pub async fn fast_handler(
+    Path((id, index)): Path<(u64, usize)>,
+    version: Version,
+    connection: Option<Extension<Arc<ConnectionControl>>>,
+) -> Result<Response, ApiError> {
+    let path = format!("/scenario/hol/{}/fast/{}", id, index);
+    let run = find_run(id, &path)?;
+    if index >= run.settings.fast_requests {
+        return Err(ApiError::not_found(&path));
+    }
+    let connection = connection.map(|Extension(connection)| connection.id());
+    let position = run.record(RequestKind::Fast, Some(index), connection, version);
+    let headers = record_headers(&run, connection);
+    let body = garble_body(run.settings.fast_bytes)?;
+    run.complete(position);
+    Ok((headers, body).into_response())
+}
+
+/// Every request of a run so far, and how the fast ones fared against the slow one
+pub async fn report_handler(Path(id): Path<u64>) -> Result<Response, ApiError> {
+    let run = find_run(id, &format!("/scenario/hol/{}/report", id))?;
+    let records = run.records.lock().unwrap().clone();
+    Ok(Json(serde_json::json!({
+        "run": run.id,
+        "started_at": run.started_at,
+        "settings": run.settings,
+        "summary": summarize(&records),
+        "requests": records,
+    }))
+    .into_response())
+}
+
+pub fn get_stats() -> HolScenarioStats {
+    HolScenarioStats {
+        runs: HOL.started.load(Ordering::Relaxed),
+        requests: HOL.requests.load(Ordering::Relaxed),
+    }
+}
+
+// Global runs, kept until newer ones push them out
+static HOL: Lazy<HolScenario> = Lazy::new(|| HolScenario {
+    runs: Mutex::new(VecDeque::new()),
+    next_id: AtomicU64::new(1),
+    started: AtomicU64::new(0),
+    requests: AtomicU64::new(0),
+});
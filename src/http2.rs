@@ -0,0 +1,222 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! HTTP/2 faults below the body, for the failure modes of HTTP/2 clients and
+//! proxies that body-level chaos cannot reach: streams reset with a chosen
+//! error code, a GOAWAY sent while a response is still streaming, response
+//! headers spilling over many CONTINUATION frames, and flow control windows
+//! kept small. Over HTTP/1.1 a reset drops the connection and a GOAWAY closes
+//! it once the response is done.
+
+use async_stream::stream;
+use axum::{
+    body::Body,
+    http::{header, HeaderName, HeaderValue},
+    response::Response,
+};
+use futures::StreamExt;
+use h2::Reason;
+use hyper_util::{rt::TokioExecutor, server::conn::auto::Builder};
+use once_cell::sync::{Lazy, OnceCell};
+use rand::{seq::SliceRandom, thread_rng};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::Http2Config;
+use crate::server::ConnectionControl;
+
+/// Most CONTINUATION frames a response may ask for, about 1MB of headers
+pub const MAX_CONTINUATION_FRAMES: usize = 64;
+
+/// Size of each filler header value
+const FILLER_VALUE_BYTES: usize = 4096;
+
+/// Header blocks are cut into frames of the client's frame size, 16KB unless it asks for more
+const DEFAULT_FRAME_BYTES: usize = 16 * 1024;
+
+/// Characters whose HPACK Huffman codes are 8 bits long, so filler values
+/// take as many bytes in the header block as they have characters
+const FILLER_CHARS: &[u8] = b"&*,;XZ";
+
+/// Pause between the last byte before a reset and the reset itself
+const RESET_FLUSH_DELAY: Duration = Duration::from_millis(50);
+
+/// Error code sent with a stream reset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResetCode {
+    NoError,
+    ProtocolError,
+    #[default]
+    InternalError,
+    FlowControlError,
+    StreamClosed,
+    RefusedStream,
+    Cancel,
+    EnhanceYourCalm,
+    Http11Required,
+}
+
+impl ResetCode {
+    fn reason(self) -> Reason {
+        match self {
+            Self::NoError => Reason::NO_ERROR,
+            Self::ProtocolError => Reason::PROTOCOL_ERROR,
+            Self::InternalError => Reason::INTERNAL_ERROR,
+            Self::FlowControlError => Reason::FLOW_CONTROL_ERROR,
+            Self::StreamClosed => Reason::STREAM_CLOSED,
+            Self::RefusedStream => Reason::REFUSED_STREAM,
+            Self::Cancel => Reason::CANCEL,
+            Self::EnhanceYourCalm => Reason::ENHANCE_YOUR_CALM,
+            Self::Http11Required => Reason::HTTP_1_1_REQUIRED,
+        }
+    }
+}
+
+/// Transport faults picked for a single `/garble` request
+#[derive(Debug, Default)]
+pub struct TransportFaults {
+    /// Reset the stream once this many body bytes went out
+    pub reset: Option<(usize, ResetCode)>,
+    /// Send a GOAWAY once this many body bytes went out
+    pub goaway_after: Option<usize>,
+    /// CONTINUATION frames the response headers should span
+    pub continuation_frames: Option<usize>,
+}
+
+impl TransportFaults {
+    fn is_empty(&self) -> bool {
+        self.reset.is_none() && self.goaway_after.is_none() && self.continuation_frames.is_none()
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    resets: AtomicU64,
+    goaways: AtomicU64,
+    continuation_responses: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Http2Stats {
+    pub streams_reset: u64,
+    pub goaways_sent: u64,
+    pub continuation_responses: u64,
+}
+
+/// Load the flow control windows; call once at startup
+pub fn configure(config: &Http2Config) -> anyhow::Result<()> {
+    config.validate()?;
+    let _ = SETTINGS.set(config.clone());
+    Ok(())
+}
+
+/// Apply the configured flow control windows to a connection builder
+pub fn apply_settings(builder: &mut Builder<TokioExecutor>) {
+    let Some(settings) = SETTINGS.get() else {
+        return;
+    };
+    let mut http2 = builder.http2();
+    if let Some(window) = settings.initial_stream_window_bytes {
+        http2.initial_stream_window_size(window);
+    }
+    if let Some(window) = settings.initial_connection_window_bytes {
+        http2.initial_connection_window_size(window);
+    }
+}
+
+pub fn get_stats() -> Http2Stats {
+    Http2Stats {
+        streams_reset: STATS.resets.load(Ordering::Relaxed),
+        goaways_sent: STATS.goaways.load(Ordering::Relaxed),
+        continuation_responses: STATS.continuation_responses.load(Ordering::Relaxed),
+    }
+}
+
+/// Misbehave below the body of `response` as `faults` ask
+pub fn apply(
+    response: Response,
+    faults: TransportFaults,
+    connection: Option<Arc<ConnectionControl>>,
+) -> Response {
+    if faults.is_empty() {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    if let Some(frames) = faults.continuation_frames {
+        STATS.continuation_responses.fetch_add(1, Ordering::Relaxed);
+        add_filler_headers(&mut parts.headers, frames);
+    }
+    if faults.reset.is_none() && faults.goaway_after.is_none() {
+        return Response::from_parts(parts, body);
+    }
+    if faults.reset.is_some() {
+        parts.headers.remove(header::CONTENT_LENGTH);
+    }
+
+    let goaway = move |sent: usize| {
+        if let (Some(after), Some(connection)) = (faults.goaway_after, &connection) {
+            if sent >= after && connection.goaway() {
+                STATS.goaways.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    };
+    let faulted = stream! {
+        let mut body = body.into_data_stream();
+        let mut sent = 0;
+        goaway(sent);
+        while let Some(data) = body.next().await {
+            let mut data = match data {
+                Ok(data) => data,
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            };
+            if let Some((after, code)) = faults.reset {
+                let room = after - sent;
+                if data.len() >= room {
+                    data.truncate(room);
+                    sent += data.len();
+                    goaway(sent);
+                    yield Ok(data);
+                    // Let the partial body reach the client before the stream is reset
+                    tokio::time::sleep(RESET_FLUSH_DELAY).await;
+                    STATS.resets.fetch_add(1, Ordering::Relaxed);
+                    yield Err(axum::Error::new(h2::Error::from(code.reason())));
+                    return;
+                }
+            }
+            sent += data.len();
+            goaway(sent);
+            yield Ok(data);
+        }
+        // A body shorter than asked for still goes out with its GOAWAY
+        goaway(usize::MAX);
+    };
+    Response::from_parts(parts, Body::from_stream(faulted))
+}
+
+/// Add headers large enough for the header block to need `frames`
+/// CONTINUATION frames after its HEADERS frame, ending halfway into the last one
+fn add_filler_headers(headers: &mut axum::http::HeaderMap, frames: usize) {
+    let block_bytes = frames * DEFAULT_FRAME_BYTES + DEFAULT_FRAME_BYTES / 2;
+    let mut rng = thread_rng();
+    for i in 0..block_bytes.div_ceil(FILLER_VALUE_BYTES) {
+        let value: Vec<u8> = (0..FILLER_VALUE_BYTES)
+            .map(|_| *FILLER_CHARS.choose(&mut rng).expect("filler characters"))
+            .collect();
+        headers.insert(
+            HeaderName::try_from(format!("x-garble-filler-{}", i)).expect("valid header name"),
+            HeaderValue::from_bytes(&value).expect("valid header value"),
+        );
+    }
+}
+
+// Global windows, set at startup
+static SETTINGS: OnceCell<Http2Config> = OnceCell::new();
+
+static STATS: Lazy<Counters> = Lazy::new(Counters::default);
@@ -0,0 +1,168 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Interim responses, such as `103 Early Hints`, sent before the final
+//! response while the request is still being worked on. hyper cannot send
+//! them, so they are written to the connection directly, which works over
+//! HTTP/1.1 only; on HTTP/2 connections they are skipped.
+
+use axum::http::StatusCode;
+use once_cell::sync::Lazy;
+use rand::{distributions::Alphanumeric, seq::SliceRandom, Rng};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::server::ConnectionControl;
+
+/// Most interim responses one request may ask for
+pub const MAX_INTERIM_RESPONSES: usize = 20;
+/// Longest pause before an interim response
+pub const MAX_INTERIM_DELAY_MS: u64 = 60_000;
+
+/// Status of early hints, which carry `Link` headers
+const EARLY_HINTS: u16 = 103;
+
+/// Kinds of resources an early hint preloads: extension and `Link` parameters
+const PRELOADS: &[(&str, &str)] = &[
+    ("css", "as=style"),
+    ("js", "as=script"),
+    ("woff2", "as=font; crossorigin"),
+    ("webp", "as=image"),
+];
+
+/// An interim response and the pause before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interim {
+    pub status: StatusCode,
+    pub delay: Duration,
+}
+
+#[derive(Default)]
+struct Counters {
+    sent: AtomicU64,
+    skipped: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InterimStats {
+    /// Interim responses written to HTTP/1.1 connections
+    pub sent: u64,
+    /// Interim responses asked for on HTTP/2 connections, which cannot carry them
+    pub skipped: u64,
+}
+
+/// Parse a comma separated list of `STATUS` or `STATUS:DELAY_MS` entries,
+/// each delay counted from the previous interim response
+pub fn parse(spec: &str) -> Result<Vec<Interim>, String> {
+    let interims = spec
+        .split(',')
+        .map(|entry| {
+            let (status, delay_ms) = match entry.split_once(':') {
+                Some((status, delay_ms)) => (status, Some(delay_ms)),
+                None => (entry, None),
+            };
+            let status = status
+                .trim()
+                .parse::<u16>()
+                .ok()
+                .and_then(|status| StatusCode::from_u16(status).ok())
+                .filter(|status| {
+                    status.is_informational() && *status != StatusCode::SWITCHING_PROTOCOLS
+                })
+                .ok_or_else(|| {
+                    format!(
+                        "interim status {} must be between 100 and 199, other than 101",
+                        status.trim()
+                    )
+                })?;
+            let delay_ms = match delay_ms {
+                Some(delay_ms) => delay_ms
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|&delay_ms| delay_ms <= MAX_INTERIM_DELAY_MS)
+                    .ok_or_else(|| {
+                        format!(
+                            "interim delay {} must be a number of milliseconds up to {}",
+                            delay_ms.trim(),
+                            MAX_INTERIM_DELAY_MS
+                        )
+                    })?,
+                None => 0,
+            };
+            Ok(Interim {
+                status,
+                delay: Duration::from_millis(delay_ms),
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    if interims.len() > MAX_INTERIM_RESPONSES {
+        return Err(format!(
+            "interim takes at most {} responses",
+            MAX_INTERIM_RESPONSES
+        ));
+    }
+    Ok(interims)
+}
+
+/// Send `interims` on `connection` one after another, each after its delay.
+/// A client that hung up ends the sequence early
+pub async fn send(connection: Option<Arc<ConnectionControl>>, interims: Vec<Interim>) {
+    if interims.is_empty() {
+        return;
+    }
+    let Some(connection) = connection.filter(|connection| !connection.is_http2()) else {
+        STATS
+            .skipped
+            .fetch_add(interims.len() as u64, Ordering::Relaxed);
+        return;
+    };
+    for interim in interims {
+        tokio::time::sleep(interim.delay).await;
+        if let Err(e) = connection.write_raw(&head(interim.status)).await {
+            tracing::debug!("Failed to send interim response: {}", e);
+            return;
+        }
+        STATS.sent.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn get_stats() -> InterimStats {
+    InterimStats {
+        sent: STATS.sent.load(Ordering::Relaxed),
+        skipped: STATS.skipped.load(Ordering::Relaxed),
+    }
+}
+
+/// The status line and headers of an interim response; early hints preload
+/// a few made-up resources
+fn head(status: StatusCode) -> Vec<u8> {
+    let reason = match status.as_u16() {
+        EARLY_HINTS => "Early Hints",
+        _ => status.canonical_reason().unwrap_or("Informational"),
+    };
+    let mut head = format!("HTTP/1.1 {} {}\r\n", status.as_u16(), reason);
+    if status.as_u16() == EARLY_HINTS {
+        let mut rng = rand::thread_rng();
+        for _ in 0..rng.gen_range(1..=3) {
+            let (extension, parameters) = PRELOADS.choose(&mut rng).expect("preload kinds");
+            let length = rng.gen_range(4..=12);
+            let name: String = (&mut rng)
+                .sample_iter(Alphanumeric)
+                .take(length)
+                .map(|c| char::from(c).to_ascii_lowercase())
+                .collect();
+            head.push_str(&format!(
+                "link: </assets/{}.{}>; rel=preload; {}\r\n",
+                name, extension, parameters
+            ));
+        }
+    }
+    head.push_str("\r\n");
+    head.into_bytes()
+}
+
+static STATS: Lazy<Counters> = Lazy::new(Counters::default);
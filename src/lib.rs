@@ -0,0 +1,148 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Daddle's garble generator, for embedding in other programs. Build documents
+//! with [`RandomDataGenerator`], and blend domain-specific values into them by
+//! registering closures with [`register_generator`] and mixing them in by
+//! weight with [`configure_generators`].
+//!
+//! Modules hidden from these docs make up the daddle server and are public only
+//! so the `daddle` binary can use them; they carry no stability promise.
+
+#[doc(hidden)]
+pub mod admin_ui;
+#[doc(hidden)]
+pub mod admission;
+#[doc(hidden)]
+pub mod avro_file;
+#[doc(hidden)]
+pub mod bench;
+#[doc(hidden)]
+pub mod budget;
+#[doc(hidden)]
+pub mod cache_origin;
+#[doc(hidden)]
+pub mod catch_all;
+#[doc(hidden)]
+pub mod chunk_pool;
+#[doc(hidden)]
+pub mod cluster;
+#[doc(hidden)]
+pub mod compression;
+pub mod config;
+#[doc(hidden)]
+pub mod corpus;
+#[doc(hidden)]
+pub mod dependencies;
+#[doc(hidden)]
+pub mod diagnostics;
+#[doc(hidden)]
+pub mod dictionary;
+#[doc(hidden)]
+pub mod drain;
+#[doc(hidden)]
+pub mod emit;
+#[doc(hidden)]
+pub mod encoding;
+#[doc(hidden)]
+pub mod encryption;
+#[doc(hidden)]
+pub mod error;
+#[doc(hidden)]
+pub mod faults;
+#[doc(hidden)]
+pub mod firehose;
+#[doc(hidden)]
+pub mod flags;
+#[doc(hidden)]
+pub mod frames;
+pub mod generator;
+#[doc(hidden)]
+pub mod graphql;
+#[doc(hidden)]
+pub mod graphql_schema;
+#[doc(hidden)]
+pub mod grpc;
+#[doc(hidden)]
+pub mod handlers;
+#[doc(hidden)]
+pub mod hang;
+#[doc(hidden)]
+pub mod header_hazards;
+#[doc(hidden)]
+pub mod hol_scenario;
+#[doc(hidden)]
+pub mod http2;
+#[doc(hidden)]
+pub mod interim;
+#[doc(hidden)]
+pub mod markov;
+#[doc(hidden)]
+pub mod memory;
+#[doc(hidden)]
+pub mod metadata;
+#[doc(hidden)]
+pub mod metrics;
+#[doc(hidden)]
+pub mod mirror;
+#[doc(hidden)]
+pub mod ndjson;
+#[doc(hidden)]
+pub mod openapi;
+#[doc(hidden)]
+pub mod outlier;
+#[doc(hidden)]
+pub mod pacing;
+#[doc(hidden)]
+pub mod parquet_file;
+#[doc(hidden)]
+pub mod patches;
+#[doc(hidden)]
+pub mod path_faults;
+pub mod plugins;
+#[doc(hidden)]
+pub mod post_process;
+#[doc(hidden)]
+pub mod presets;
+#[cfg(feature = "pprof")]
+#[doc(hidden)]
+pub mod profiling;
+#[doc(hidden)]
+pub mod proxy;
+#[doc(hidden)]
+pub mod readiness;
+#[doc(hidden)]
+pub mod remote_config;
+#[doc(hidden)]
+pub mod response_cache;
+#[doc(hidden)]
+pub mod routes;
+#[doc(hidden)]
+pub mod rules;
+#[doc(hidden)]
+pub mod server;
+#[doc(hidden)]
+pub mod shaping;
+#[doc(hidden)]
+pub mod sink;
+#[doc(hidden)]
+pub mod stats_export;
+#[doc(hidden)]
+pub mod streaming;
+#[doc(hidden)]
+pub mod template;
+#[doc(hidden)]
+pub mod tenants;
+#[doc(hidden)]
+pub mod test_runs;
+#[doc(hidden)]
+pub mod upload;
+#[doc(hidden)]
+pub mod validation;
+#[doc(hidden)]
+pub mod websocket;
+
+pub use config::GeneratorsConfig;
+pub use generator::{GeneratorOptions, RandomDataGenerator};
+pub use plugins::{configure as configure_generators, register_generator, ValueGenerator};
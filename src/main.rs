@@ -2,20 +2,46 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-mod chunk_pool;
-mod config;
-mod generator;
-mod handlers;
-mod streaming;
-
-use axum::{routing::get, Router};
+use axum::{
+    middleware,
+    routing::{get, post},
+    Router,
+};
+use futures::future::OptionFuture;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::signal;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
 use tower::ServiceBuilder;
 use tower_http::cors::CorsLayer;
 
-use config::Config;
-use handlers::{garble_handler, health_handler, stats_handler};
+#[cfg(feature = "pprof")]
+use daddle::profiling;
+use daddle::{
+    admin_ui, admission, avro_file, bench, budget, cache_origin, catch_all, chunk_pool, cluster,
+    compression, config, corpus, dependencies, diagnostics, dictionary, drain, encryption, error,
+    faults, firehose, flags, frames, generator, graphql, graphql_schema, grpc, handlers, hang,
+    header_hazards, hol_scenario, http2, markov, memory, metrics, mirror, ndjson, openapi, outlier,
+    parquet_file, patches, path_faults, plugins, post_process, presets, proxy, remote_config,
+    response_cache, routes, rules, server, shaping, sink, stats_export, streaming, test_runs,
+    websocket,
+};
+
+use avro_file::avro_handler;
+use bench::bench_handler;
+use config::{Config, LiveConfig};
+use diagnostics::runtime_handler;
+use drain::drain_handler;
+use firehose::{firehose_handler, firehose_list_handler, firehose_update_handler};
+use handlers::{garble_handler, health_handler, stats_handler, stats_stream_handler};
+use parquet_file::parquet_handler;
+use websocket::websocket_handler;
+
+#[cfg(feature = "bench")]
+#[global_allocator]
+static ALLOCATOR: bench::CountingAllocator = bench::CountingAllocator;
 
 /// Wait for a shutdown signal (SIGTERM or SIGINT)
 async fn shutdown_signal() {
@@ -49,29 +75,250 @@ async fn shutdown_signal() {
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Initialize tracing
-    tracing_subscriber::fmt::init();
+    diagnostics::init_tracing();
+
+    // `daddle bench` runs the self-benchmark and exits without starting the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("bench") {
+        let options = bench::BenchOptions::from_args(&args[1..])?;
+        let report = bench::run(options).await;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    // `daddle sink` writes generated objects to object storage and exits
+    if args.first().map(String::as_str) == Some("sink") {
+        let options = sink::SinkOptions::from_args(&args[1..])?;
+        let report = sink::run(options).await?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        if report.failed > 0 {
+            anyhow::bail!(
+                "{} of {} objects failed to upload",
+                report.failed,
+                report.failed + report.objects
+            );
+        }
+        return Ok(());
+    }
 
-    // Load configuration
-    let config = Config::load_from_file("config.json")?;
+    // Load configuration, from a URL when one is given
+    let config = match std::env::var(remote_config::CONFIG_URL_VAR) {
+        Ok(url) if !url.is_empty() => remote_config::load(&url).await?,
+        _ => Config::load_from_file("config.json")?,
+    };
     tracing::info!("Loaded configuration: {:?}", config);
 
+    // `daddle corpus build` generates the corpus files and exits
+    if args.first().map(String::as_str) == Some("corpus") {
+        if args.get(1).map(String::as_str) != Some("build") {
+            anyhow::bail!("usage: daddle corpus build [--manifest FILE] [--dir DIR]");
+        }
+        let options = corpus::BuildOptions::from_args(&args[2..])?;
+        let manifest = corpus::build(&config.corpus, &config.string_lengths, options).await?;
+        println!("{}", serde_json::to_string_pretty(&manifest)?);
+        return Ok(());
+    }
+
     // Create shared state
-    let shared_config = Arc::new(config.clone());
+    let shared_config = Arc::new(LiveConfig::new(config.clone()));
+
+    // Background tasks observe this token and wind down cooperatively on shutdown
+    let shutdown = CancellationToken::new();
+
+    // Pooled chunks are compressed as they enter the pool, so compression comes first
+    compression::configure(&config.compression)?;
+
+    // Restore the chunk pool persisted by a previous run
+    let snapshot_path = config
+        .performance
+        .pool_snapshot_path
+        .as_ref()
+        .map(PathBuf::from);
+    if let Some(path) = &snapshot_path {
+        if let Err(e) = chunk_pool::CHUNK_POOL.load_snapshot(path) {
+            tracing::warn!("Failed to restore chunk pool snapshot: {}", e);
+        }
+    }
 
     // Start background chunk generation task (this will initialize the pool lazily)
     tracing::info!("Starting background chunk generation task...");
-    let background_task = tokio::spawn(async move {
-        tracing::info!("Background chunk generation task started");
-        let chunk_pool = chunk_pool::CHUNK_POOL.clone();
-        chunk_pool.background_maintenance().await;
+    let background_task = tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            tracing::info!("Background chunk generation task started");
+            let chunk_pool = chunk_pool::CHUNK_POOL.clone();
+            chunk_pool.background_maintenance(shutdown).await;
+
+            if let Some(path) = snapshot_path {
+                if let Err(e) = chunk_pool.save_snapshot(&path).await {
+                    tracing::warn!("Failed to save chunk pool snapshot: {}", e);
+                }
+            }
+        }
     });
 
+    config.validate_profiles()?;
+    error::set_garble_details(config.garble.garble_error_details);
+
+    streaming::configure_thresholds(&config.performance)?;
+    budget::configure(&config.performance)?;
+    generator::configure_string_lengths(&config.string_lengths)?;
+    generator::configure_date_formats(&config.date_formats)?;
+    plugins::configure(&config.generators)?;
+    presets::load(&config.presets)?;
+    post_process::load(&config.post_processors)?;
+    routes::load(&config.routes)?;
+    rules::load(&config.rules)?;
+    path_faults::load(&config.path_faults)?;
+    proxy::configure(&config.proxy)?;
+    catch_all::load(&config.catch_all)?;
+    avro_file::configure(&config.avro);
+    corpus::load(&config.corpus)?;
+    hang::configure(&config.hang);
+    admission::configure(&config.admission)?;
+    faults::configure(&config.faults)?;
+    header_hazards::configure(&config.header_hazards)?;
+    response_cache::configure(&config.response_cache);
+    encryption::configure(&config.encryption)?;
+    outlier::configure(&config.outlier)?;
+    shaping::configure(&config.connection_shaping)?;
+    http2::configure(&config.http2)?;
+    markov::train(&config.markov)?;
+
+    // Load word lists and watch them for changes
+    let dictionary_task = if config.dictionary.is_enabled() {
+        dictionary::DICTIONARY.load(&config.dictionary)?;
+        Some(tokio::spawn(dictionary::watch_dictionary(
+            config.dictionary.clone(),
+            shutdown.clone(),
+        )))
+    } else {
+        None
+    };
+
+    dependencies::configure(&config.dependencies)?;
+
+    // Chaos flags, switched by the flags file or the admin API
+    flags::configure(&config.flags)?;
+    let flags_task = config
+        .flags
+        .file
+        .is_some()
+        .then(|| tokio::spawn(flags::watch_flags(config.flags.clone(), shutdown.clone())));
+
+    // Pick up changes to a remote configuration
+    let config_task = remote_config::start(shared_config.clone(), shutdown.clone());
+
+    // Follow the leader's chaos scenario when part of a fleet
+    let cluster_task = cluster::start(&config.cluster, shutdown.clone());
+
+    // Serve binary frames over raw TCP, if a port is configured
+    let frames_task = frames::start(&config.frames, shutdown.clone())?;
+
+    // Copy every request to the collector, if one is configured
+    let mirror_task = mirror::start(&config.mirror, shutdown.clone());
+
+    // Leave stats behind in a file, if a path is configured
+    let stats_export_task = stats_export::start(&config.stats_export, shutdown.clone())?;
+
+    // Start memory monitoring so the ceiling sees current RSS
+    memory::MEMORY_GUARD.configure(config.performance.memory_ceiling_mb);
+    let memory_task = tokio::spawn(memory::monitor_memory(
+        config.performance.memory_check_interval_ms,
+        shutdown.clone(),
+    ));
+
     // Build the application with routes
     let app = Router::new()
-        .route("/garble", get(garble_handler))
+        .route(
+            "/garble",
+            get(garble_handler).post(garble_handler).put(garble_handler),
+        )
+        .route("/garble/parquet", get(parquet_handler))
+        .route("/garble/avro", get(avro_handler))
+        .route("/garble/firehose", get(firehose_handler))
+        .route("/garble/ndjson", get(ndjson::ndjson_handler))
+        .route("/garble/sse", get(streaming::sse_handler))
+        .route("/garble/ws", get(websocket_handler))
+        .route("/garble/frames", get(frames::frames_handler))
+        .route(
+            "/garble/graphql-schema",
+            get(graphql_schema::graphql_schema_handler),
+        )
+        .route("/garble/openapi", get(openapi::openapi_handler))
+        .route("/garble/patches", get(patches::patches_handler))
+        .route("/graphql/ws", get(graphql::graphql_ws_handler))
+        .route("/corpus", get(corpus::corpus_manifest_handler))
+        .route("/corpus/:name", get(corpus::corpus_handler))
+        .route("/daddle.Garble/Stream", post(grpc::stream_handler))
+        .route("/daddle.Garble/Chat", post(grpc::chat_handler))
+        .route("/cache/stable/:key", get(cache_origin::stable_handler))
+        .route("/cache/rotating/:key", get(cache_origin::rotating_handler))
+        .route("/scenario/hol", post(hol_scenario::start_handler))
+        .route("/scenario/hol/:run/slow", get(hol_scenario::slow_handler))
+        .route(
+            "/scenario/hol/:run/fast/:index",
+            get(hol_scenario::fast_handler),
+        )
+        .route(
+            "/scenario/hol/:run/report",
+            get(hol_scenario::report_handler),
+        )
         .route("/health", get(health_handler))
+        .route(&config.outlier.health_path, get(outlier::health_handler))
         .route("/stats", get(stats_handler))
-        .layer(ServiceBuilder::new().layer(CorsLayer::permissive()))
+        .route("/stats/stream", get(stats_stream_handler))
+        .route("/metrics", get(metrics::prometheus_handler))
+        .route("/admin/ui", get(admin_ui::index_handler))
+        .route("/admin/ui/dashboard.js", get(admin_ui::script_handler))
+        .route("/admin/ui/dashboard.css", get(admin_ui::stylesheet_handler))
+        .route("/admin/bench", post(bench_handler))
+        .route("/admin/drain", post(drain_handler))
+        .route(
+            "/admin/firehose",
+            get(firehose_list_handler).post(firehose_update_handler),
+        )
+        .route(
+            "/admin/dependencies",
+            get(dependencies::dependencies_list_handler)
+                .post(dependencies::dependencies_update_handler),
+        )
+        .route(
+            "/admin/flags",
+            get(flags::flags_list_handler).post(flags::flags_update_handler),
+        )
+        .route(
+            "/admin/scenario",
+            get(cluster::scenario_handler).post(cluster::scenario_import_handler),
+        )
+        .route(
+            "/admin/outlier",
+            get(outlier::outlier_state_handler).post(outlier::outlier_restart_handler),
+        )
+        .route("/admin/schemas", get(proxy::schemas_handler))
+        .route("/debug/runtime", get(runtime_handler));
+
+    #[cfg(feature = "pprof")]
+    let app = app.route("/debug/pprof", get(profiling::pprof_handler));
+
+    let app = app
+        .fallback(routes::route_handler)
+        .layer(
+            ServiceBuilder::new()
+                .layer(middleware::from_fn(shaping::shape_connection))
+                .layer(middleware::from_fn(error::problem_instance))
+                .layer(middleware::from_fn(test_runs::tag_run))
+                .layer(middleware::from_fn(mirror::mirror_request))
+                .layer(middleware::from_fn(metrics::track_latency))
+                .layer(middleware::from_fn(cluster::instance_header))
+                .layer(CorsLayer::permissive())
+                .layer(middleware::from_fn(path_faults::inject_path_faults))
+                .layer(middleware::from_fn(hang::hang_requests))
+                .layer(middleware::from_fn_with_state(
+                    shared_config.clone(),
+                    rules::apply_rules,
+                )),
+        )
         .with_state(shared_config);
 
     // Start the server
@@ -85,8 +332,42 @@ async fn main() -> anyhow::Result<()> {
     tracing::info!(
         "  GET /garble - Generate random JSON payload (with smart performance optimization)"
     );
+    tracing::info!("  GET /garble/parquet - Generate random Parquet file");
+    tracing::info!("  GET /garble/avro - Generate random Avro file or wire-format record");
+    tracing::info!("  GET /garble/firehose - Stream NDJSON records at a controlled rate");
+    tracing::info!("  GET /garble/ndjson - Stream a fixed number of NDJSON objects");
+    tracing::info!("  GET /garble/sse - Server-sent events with random JSON payloads");
+    tracing::info!("  GET /garble/frames - Length-prefixed binary frames with random payloads");
+    tracing::info!("  GET /garble/ws - WebSocket pushing garbled messages or echoing mutations");
+    tracing::info!("  GET /garble/graphql-schema - Random but valid GraphQL SDL document");
+    tracing::info!("  GET /garble/openapi - Random but valid OpenAPI 3.1 document");
+    tracing::info!("  GET /garble/patches - Seeded document followed by a JSON Patch stream");
+    tracing::info!("  GET /graphql/ws - GraphQL subscriptions with garbled events");
+    tracing::info!("  GET /corpus/:name - Pre-generated corpus file, byte-identical across runs");
+    tracing::info!(
+        "  POST /daddle.Garble/Stream, /daddle.Garble/Chat - gRPC streaming over HTTP/2"
+    );
+    tracing::info!("  GET /cache/stable/:key, /cache/rotating/:key - Origin for cache testing");
     tracing::info!("  GET /health - Health check endpoint");
+    tracing::info!(
+        "  GET {} - Flapping health check for service mesh health checking",
+        config.outlier.health_path
+    );
     tracing::info!("  GET /stats  - Chunk pool and performance statistics");
+    tracing::info!("  GET /stats/stream - Statistics pushed every second over SSE");
+    tracing::info!("  GET /metrics - Per-tenant and per-profile counters for Prometheus");
+    tracing::info!("  GET /admin/ui - Dashboard with live stats and chaos controls");
+    tracing::info!("  POST /admin/bench - Measure local generation throughput");
+    tracing::info!("  POST /admin/drain - Enter draining mode ahead of a restart");
+    tracing::info!("  GET|POST /admin/firehose - List firehose streams or change their rate");
+    tracing::info!("  GET|POST /admin/dependencies - List dependencies or inject failures");
+    tracing::info!("  GET|POST /admin/flags - List chaos flags or switch them");
+    tracing::info!("  GET /admin/scenario - Chaos scenario followed by the rest of the fleet");
+    tracing::info!("  GET|POST /admin/outlier - Outlier simulation state, or restart its cycle");
+    tracing::info!("  GET /admin/schemas - Response schemas learned by the proxy");
+    tracing::info!("  GET /debug/runtime - Tokio runtime metrics");
+    #[cfg(feature = "pprof")]
+    tracing::info!("  GET /debug/pprof - CPU profile capture");
     tracing::info!("");
     tracing::info!("Performance features:");
     tracing::info!("  - Chunk pool for fast responses");
@@ -102,22 +383,69 @@ async fn main() -> anyhow::Result<()> {
     // Start the server with graceful shutdown
     tracing::info!("Server starting with graceful shutdown support...");
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    let shutdown_started = Arc::new(Notify::new());
+    let server = server::serve(listener, app, {
+        let shutdown_started = shutdown_started.clone();
+        async move {
+            shutdown_signal().await;
+            drain::DRAIN.start_draining();
+            shutdown_started.notify_one();
+        }
+    });
+
+    // Once shutdown starts, in-flight responses get up to the drain timeout to finish
+    let drain_timeout = Duration::from_millis(config.server.drain_timeout_ms);
+    let drain_deadline = async {
+        shutdown_started.notified().await;
+        tokio::time::sleep(drain_timeout).await;
+    };
+
+    tokio::select! {
+        result = server => result?,
+        _ = drain_deadline => {
+            tracing::warn!(
+                "Drain timeout of {}ms elapsed with {} responses still in flight, forcing shutdown",
+                config.server.drain_timeout_ms,
+                memory::MEMORY_GUARD.get_stats().in_flight_responses
+            );
+        }
+    }
 
     tracing::info!("Server has shut down gracefully, stopping background tasks...");
 
-    // Abort the background task since it runs in an infinite loop
-    background_task.abort();
+    // Ask the background tasks to stop; a generation round in progress is allowed to finish
+    shutdown.cancel();
 
-    // Wait a moment for the task to clean up
-    match tokio::time::timeout(std::time::Duration::from_secs(5), background_task).await {
+    // Give the tasks a moment to flush stats and persist the pool
+    match tokio::time::timeout(Duration::from_secs(5), background_task).await {
         Ok(Ok(())) => tracing::info!("Background task completed gracefully"),
-        Ok(Err(e)) if e.is_cancelled() => tracing::info!("Background task was cancelled"),
         Ok(Err(e)) => tracing::warn!("Background task error: {}", e),
         Err(_) => tracing::warn!("Background task did not complete within timeout"),
     }
+    if let Err(e) = memory_task.await {
+        tracing::warn!("Memory monitor task error: {}", e);
+    }
+    if let Some(Err(e)) = OptionFuture::from(dictionary_task).await {
+        tracing::warn!("Dictionary watcher task error: {}", e);
+    }
+    if let Some(Err(e)) = OptionFuture::from(frames_task).await {
+        tracing::warn!("Frame listener task error: {}", e);
+    }
+    if let Some(Err(e)) = OptionFuture::from(mirror_task).await {
+        tracing::warn!("Mirror task error: {}", e);
+    }
+    if let Some(Err(e)) = OptionFuture::from(flags_task).await {
+        tracing::warn!("Flags watcher task error: {}", e);
+    }
+    if let Some(Err(e)) = OptionFuture::from(config_task).await {
+        tracing::warn!("Configuration refresh task error: {}", e);
+    }
+    if let Some(Err(e)) = OptionFuture::from(cluster_task).await {
+        tracing::warn!("Cluster task error: {}", e);
+    }
+    if let Some(Err(e)) = OptionFuture::from(stats_export_task).await {
+        tracing::warn!("Stats export task error: {}", e);
+    }
 
     tracing::info!("All tasks completed, application shutdown complete");
     Ok(())
@@ -0,0 +1,138 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use once_cell::sync::OnceCell;
+use rand::prelude::*;
+use std::collections::HashMap;
+
+use crate::config::MarkovConfig;
+
+/// Corpus the model is trained on when no corpus files are configured
+const BUILTIN_CORPUS: &str = "\
+The quarterly report shows that revenue grew faster than expected in the northern region. \
+Our support team resolved most customer tickets within one business day. \
+The new release improves search performance and fixes several issues with the checkout page. \
+Please update your billing address before the end of the month to avoid service interruptions. \
+The warehouse in the east shipped more orders this week than in the whole of last month. \
+Customers who signed up during the spring campaign are more likely to renew their subscription. \
+The engineering team moved the payment service to a new cluster without any downtime. \
+Several users reported that the mobile app logs them out after a few minutes. \
+The marketing team plans to launch a new campaign for small businesses next quarter. \
+Delivery times improved after the logistics partner opened a second distribution center. \
+The security review found no critical issues but recommended rotating the access keys. \
+Most orders placed over the weekend were delivered on Tuesday morning. \
+The product team is collecting feedback on the redesigned dashboard before the next release. \
+Our records show that the invoice was paid in full on the first of the month. \
+The customer asked whether the order could be delivered to a different address. \
+Search traffic increased after the team improved the product descriptions. \
+The new pricing plan is available to all customers who renew before the end of the year. \
+The data team found that most users open the app in the morning and again in the evening. \
+Please contact the support team if the problem continues after the update. \
+The finance team will review the budget for the new office before the end of the quarter.";
+
+/// Word-level Markov chain producing semi-plausible sentences
+pub struct MarkovChain {
+    order: usize,
+    starts: Vec<Vec<String>>,
+    transitions: HashMap<Vec<String>, Vec<String>>,
+}
+
+impl MarkovChain {
+    /// Train a chain of the given order on whitespace-separated text, treating
+    /// words ending in `.`, `!` or `?` as sentence boundaries
+    pub fn train(corpus: &str, order: usize) -> Self {
+        let order = order.max(1);
+        let mut starts = Vec::new();
+        let mut transitions: HashMap<Vec<String>, Vec<String>> = HashMap::new();
+
+        let mut sentence: Vec<String> = Vec::new();
+        for word in corpus.split_whitespace() {
+            sentence.push(word.to_string());
+            if word.ends_with(['.', '!', '?']) {
+                Self::add_sentence(&sentence, order, &mut starts, &mut transitions);
+                sentence.clear();
+            }
+        }
+        Self::add_sentence(&sentence, order, &mut starts, &mut transitions);
+
+        Self {
+            order,
+            starts,
+            transitions,
+        }
+    }
+
+    fn add_sentence(
+        sentence: &[String],
+        order: usize,
+        starts: &mut Vec<Vec<String>>,
+        transitions: &mut HashMap<Vec<String>, Vec<String>>,
+    ) {
+        if sentence.len() <= order {
+            return;
+        }
+
+        starts.push(sentence[..order].to_vec());
+        for window in sentence.windows(order + 1) {
+            transitions
+                .entry(window[..order].to_vec())
+                .or_default()
+                .push(window[order].clone());
+        }
+    }
+
+    /// Generate one sentence of at most `max_words` words
+    pub fn sentence<R: Rng>(&self, rng: &mut R, max_words: usize) -> String {
+        let Some(start) = self.starts.choose(rng) else {
+            return String::new();
+        };
+
+        let mut words = start.clone();
+        while words.len() < max_words {
+            let state = &words[words.len() - self.order..];
+            let Some(next) = self.transitions.get(state).and_then(|n| n.choose(rng)) else {
+                break;
+            };
+            let ends_sentence = next.ends_with(['.', '!', '?']);
+            words.push(next.clone());
+            if ends_sentence {
+                break;
+            }
+        }
+
+        words.join(" ")
+    }
+}
+
+static MARKOV: OnceCell<MarkovChain> = OnceCell::new();
+
+/// Train the model from the configured corpus files, or the built-in corpus if none are set
+pub fn train(config: &MarkovConfig) -> anyhow::Result<()> {
+    let corpus = if config.corpus_files.is_empty() {
+        BUILTIN_CORPUS.to_string()
+    } else {
+        let mut corpus = String::new();
+        for path in &config.corpus_files {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("failed to read Markov corpus {}: {}", path, e))?;
+            corpus.push_str(&content);
+            corpus.push('\n');
+        }
+        corpus
+    };
+
+    let chain = MarkovChain::train(&corpus, config.order);
+    tracing::info!(
+        "Trained Markov model of order {} with {} states",
+        chain.order,
+        chain.transitions.len()
+    );
+    let _ = MARKOV.set(chain);
+    Ok(())
+}
+
+/// The trained model, falling back to the built-in corpus if training never ran
+pub fn model() -> &'static MarkovChain {
+    MARKOV.get_or_init(|| MarkovChain::train(BUILTIN_CORPUS, MarkovConfig::default().order))
+}
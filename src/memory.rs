@@ -0,0 +1,319 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::time::Duration;
+use sysinfo::{ProcessRefreshKind, System};
+use tokio_util::sync::CancellationToken;
+
+use crate::chunk_pool::ChunkSize;
+use crate::readiness::MEMORY_HEARTBEAT;
+use crate::streaming::ResponseStrategy;
+
+/// Fraction of the ceiling at which we start shedding large requests and pause background work
+const HIGH_WATER_RATIO: f64 = 0.9;
+
+/// Memory pressure at which the chunk pool halves its targets
+const POOL_SHRINK_RATIO: f64 = 0.8;
+
+/// Memory pressure at which the chunk pool is emptied
+const POOL_EVICT_RATIO: f64 = 0.9;
+
+/// How far pressure has to fall below a threshold before the pool grows back, so it does not flap
+const POOL_RELIEF_MARGIN: f64 = 0.05;
+
+/// cgroup v2 memory files, as mounted inside a container
+const CGROUP_V2_DIR: &str = "/sys/fs/cgroup";
+
+/// cgroup v1 memory controller files
+const CGROUP_V1_DIR: &str = "/sys/fs/cgroup/memory";
+
+/// cgroup v1 reports "no limit" as a huge number rather than `max`
+const CGROUP_V1_UNLIMITED: u64 = 1 << 60;
+
+/// Tracks process memory against a configured hard ceiling
+pub struct MemoryGuard {
+    ceiling_bytes: AtomicUsize,
+    rss_bytes: AtomicUsize,
+    in_flight_bytes: AtomicUsize,
+    in_flight_responses: AtomicUsize,
+    rejected_requests: AtomicU64,
+    background_paused: AtomicBool,
+    /// Highest of the cgroup, host and ceiling usage ratios, in thousandths
+    pressure_permille: AtomicUsize,
+    /// 0 for full chunk pool targets, 1 for halved, 2 for an empty pool
+    pool_level: AtomicU8,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryStats {
+    pub ceiling_bytes: Option<usize>,
+    pub rss_bytes: usize,
+    pub in_flight_bytes: usize,
+    pub in_flight_responses: usize,
+    pub rejected_requests: u64,
+    pub background_paused: bool,
+    /// Memory in use as a fraction of the tightest limit: cgroup, host or ceiling
+    pub pressure: f64,
+    /// Share of its usual targets the chunk pool keeps while under pressure
+    pub pool_scale: f64,
+}
+
+/// Reservation for an in-flight response, released when dropped
+pub struct InFlightGuard {
+    bytes: usize,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        MEMORY_GUARD
+            .in_flight_bytes
+            .fetch_sub(self.bytes, Ordering::Relaxed);
+        MEMORY_GUARD
+            .in_flight_responses
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl MemoryGuard {
+    fn new() -> Self {
+        Self {
+            ceiling_bytes: AtomicUsize::new(0),
+            rss_bytes: AtomicUsize::new(0),
+            in_flight_bytes: AtomicUsize::new(0),
+            in_flight_responses: AtomicUsize::new(0),
+            rejected_requests: AtomicU64::new(0),
+            background_paused: AtomicBool::new(false),
+            pressure_permille: AtomicUsize::new(0),
+            pool_level: AtomicU8::new(0),
+        }
+    }
+
+    /// Set the hard ceiling; `None` disables enforcement
+    pub fn configure(&self, ceiling_mb: Option<usize>) {
+        let ceiling_bytes = ceiling_mb.map(|mb| mb * 1024 * 1024).unwrap_or(0);
+        self.ceiling_bytes.store(ceiling_bytes, Ordering::Relaxed);
+        if ceiling_bytes > 0 {
+            tracing::info!(
+                "Memory ceiling enforcement enabled at {}MB",
+                ceiling_bytes / (1024 * 1024)
+            );
+        }
+    }
+
+    fn ceiling(&self) -> Option<usize> {
+        match self.ceiling_bytes.load(Ordering::Relaxed) {
+            0 => None,
+            bytes => Some(bytes),
+        }
+    }
+
+    fn projected_usage(&self, additional: usize) -> usize {
+        self.rss_bytes.load(Ordering::Relaxed)
+            + self.in_flight_bytes.load(Ordering::Relaxed)
+            + additional
+    }
+
+    fn high_water_mark(ceiling: usize) -> usize {
+        (ceiling as f64 * HIGH_WATER_RATIO) as usize
+    }
+
+    /// Whether usage is close enough to the ceiling that background work should pause
+    pub fn under_pressure(&self) -> bool {
+        let pressured = self
+            .ceiling()
+            .is_some_and(|ceiling| self.projected_usage(0) >= Self::high_water_mark(ceiling));
+
+        if pressured != self.background_paused.swap(pressured, Ordering::Relaxed) {
+            if pressured {
+                tracing::warn!("Memory usage near ceiling, pausing background chunk generation");
+            } else {
+                tracing::info!("Memory pressure relieved, resuming background chunk generation");
+            }
+        }
+
+        pressured
+    }
+
+    /// Reserve memory for a response built with `strategy`, refusing large
+    /// responses that would push us over the ceiling
+    pub fn try_reserve(
+        &self,
+        target_size: usize,
+        strategy: ResponseStrategy,
+    ) -> Option<InFlightGuard> {
+        let estimate = estimate_response_memory(target_size, &strategy);
+
+        // Small bodies are never refused, whatever strategy builds them
+        let small = ResponseStrategy::for_size(target_size) == ResponseStrategy::Direct;
+        if let (Some(ceiling), false) = (self.ceiling(), small) {
+            if self.projected_usage(estimate) >= Self::high_water_mark(ceiling) {
+                self.rejected_requests.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(
+                    "Rejecting {}B response: projected memory {}B exceeds ceiling {}B",
+                    target_size,
+                    self.projected_usage(estimate),
+                    ceiling
+                );
+                return None;
+            }
+        }
+
+        self.in_flight_bytes.fetch_add(estimate, Ordering::Relaxed);
+        self.in_flight_responses.fetch_add(1, Ordering::Relaxed);
+        Some(InFlightGuard { bytes: estimate })
+    }
+
+    fn refresh_rss(&self, system: &mut System) {
+        let Ok(pid) = sysinfo::get_current_pid() else {
+            return;
+        };
+
+        system.refresh_process_specifics(pid, ProcessRefreshKind::new().with_memory());
+        if let Some(process) = system.process(pid) {
+            self.rss_bytes
+                .store(process.memory() as usize, Ordering::Relaxed);
+        }
+    }
+
+    /// Record the latest pressure and move the chunk pool targets up or down with it
+    fn update_pressure(&self, node_pressure: f64) {
+        let ceiling_pressure = self.ceiling().map_or(0.0, |ceiling| {
+            self.projected_usage(0) as f64 / ceiling as f64
+        });
+        let pressure = node_pressure.max(ceiling_pressure);
+        self.pressure_permille
+            .store((pressure * 1000.0) as usize, Ordering::Relaxed);
+
+        let wanted = if pressure >= POOL_EVICT_RATIO {
+            2
+        } else if pressure >= POOL_SHRINK_RATIO {
+            1
+        } else {
+            0
+        };
+        let current = self.pool_level.load(Ordering::Relaxed);
+        let relieved = match current {
+            2 => pressure < POOL_EVICT_RATIO - POOL_RELIEF_MARGIN,
+            1 => pressure < POOL_SHRINK_RATIO - POOL_RELIEF_MARGIN,
+            _ => true,
+        };
+        if wanted > current || (wanted < current && relieved) {
+            self.pool_level.store(wanted, Ordering::Relaxed);
+            tracing::info!(
+                "Memory pressure at {:.0}%, chunk pool now keeps {:.0}% of its targets",
+                pressure * 100.0,
+                self.pool_scale() * 100.0
+            );
+        }
+    }
+
+    /// Share of its usual targets the chunk pool should keep
+    pub fn pool_scale(&self) -> f64 {
+        match self.pool_level.load(Ordering::Relaxed) {
+            0 => 1.0,
+            1 => 0.5,
+            _ => 0.0,
+        }
+    }
+
+    pub fn get_stats(&self) -> MemoryStats {
+        MemoryStats {
+            ceiling_bytes: self.ceiling(),
+            rss_bytes: self.rss_bytes.load(Ordering::Relaxed),
+            in_flight_bytes: self.in_flight_bytes.load(Ordering::Relaxed),
+            in_flight_responses: self.in_flight_responses.load(Ordering::Relaxed),
+            rejected_requests: self.rejected_requests.load(Ordering::Relaxed),
+            background_paused: self.background_paused.load(Ordering::Relaxed),
+            pressure: self.pressure_permille.load(Ordering::Relaxed) as f64 / 1000.0,
+            pool_scale: self.pool_scale(),
+        }
+    }
+}
+
+/// Rough peak memory needed to produce a response: buffered strategies hold both the
+/// generated values and the serialized body, streaming only holds a chunk at a time
+fn estimate_response_memory(target_size: usize, strategy: &ResponseStrategy) -> usize {
+    match strategy {
+        ResponseStrategy::Streaming => ChunkSize::XLarge.target_bytes() * 2,
+        _ => target_size * 2,
+    }
+}
+
+/// Memory used against the limit of the cgroup we run in, v2 or v1; page cache
+/// the kernel can reclaim is not counted, as for the kubelet's working set
+fn cgroup_usage() -> Option<(u64, u64)> {
+    let read = |path: String| std::fs::read_to_string(path).ok();
+    let inactive_file = |stat: Option<String>, key: &str| -> u64 {
+        stat.and_then(|stat| {
+            stat.lines().find_map(|line| {
+                let (name, value) = line.split_once(' ')?;
+                (name == key).then(|| value.trim().parse().ok())?
+            })
+        })
+        .unwrap_or(0)
+    };
+
+    if let Some(max) = read(format!("{}/memory.max", CGROUP_V2_DIR)) {
+        let limit: u64 = max.trim().parse().ok()?;
+        let current: u64 = read(format!("{}/memory.current", CGROUP_V2_DIR))?
+            .trim()
+            .parse()
+            .ok()?;
+        let cache = inactive_file(
+            read(format!("{}/memory.stat", CGROUP_V2_DIR)),
+            "inactive_file",
+        );
+        return Some((current.saturating_sub(cache), limit));
+    }
+
+    let limit: u64 = read(format!("{}/memory.limit_in_bytes", CGROUP_V1_DIR))?
+        .trim()
+        .parse()
+        .ok()?;
+    if limit >= CGROUP_V1_UNLIMITED {
+        return None;
+    }
+    let usage: u64 = read(format!("{}/memory.usage_in_bytes", CGROUP_V1_DIR))?
+        .trim()
+        .parse()
+        .ok()?;
+    let cache = inactive_file(
+        read(format!("{}/memory.stat", CGROUP_V1_DIR)),
+        "total_inactive_file",
+    );
+    Some((usage.saturating_sub(cache), limit))
+}
+
+/// Memory in use as a fraction of the cgroup limit or of the host's memory, whichever is tighter
+fn node_pressure(system: &mut System) -> f64 {
+    let cgroup = cgroup_usage()
+        .filter(|&(_, limit)| limit > 0)
+        .map(|(used, limit)| used as f64 / limit as f64);
+    system.refresh_memory();
+    let total = system.total_memory();
+    let host = (total > 0).then(|| 1.0 - system.available_memory() as f64 / total as f64);
+    cgroup.into_iter().chain(host).fold(0.0, f64::max)
+}
+
+/// Periodically sample process RSS so reservations see up-to-date usage
+pub async fn monitor_memory(interval_ms: u64, shutdown: CancellationToken) {
+    let mut system = System::new();
+
+    loop {
+        MEMORY_GUARD.refresh_rss(&mut system);
+        MEMORY_GUARD.under_pressure();
+        MEMORY_GUARD.update_pressure(node_pressure(&mut system));
+        MEMORY_HEARTBEAT.beat(Duration::from_millis(interval_ms));
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(interval_ms)) => {}
+            _ = shutdown.cancelled() => break,
+        }
+    }
+}
+
+// Global memory guard instance
+pub static MEMORY_GUARD: Lazy<MemoryGuard> = Lazy::new(MemoryGuard::new);
@@ -0,0 +1,320 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use axum::http::{HeaderMap, Method, Uri};
+use rand::distributions::Alphanumeric;
+use rand::prelude::*;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::time::Instant;
+
+use crate::emit::{self, EmitOptions};
+
+/// Headers worth echoing back when debugging what intermediaries changed
+const ECHO_HEADERS: &[&str] = &[
+    "host",
+    "user-agent",
+    "accept",
+    "accept-encoding",
+    "content-type",
+    "content-length",
+    "forwarded",
+    "via",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+    "x-real-ip",
+    "x-request-id",
+];
+
+/// Alphanumeric filler of `length` characters, the same every time for the same seed
+pub fn padding(length: usize, seed: Option<u64>) -> String {
+    let filler = |rng: &mut dyn RngCore| -> String {
+        rng.sample_iter(&Alphanumeric)
+            .take(length)
+            .map(char::from)
+            .collect()
+    };
+    match seed {
+        Some(seed) => filler(&mut StdRng::seed_from_u64(seed)),
+        None => filler(&mut thread_rng()),
+    }
+}
+
+/// How much of the `metadata` block to include in chunked responses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataLevel {
+    /// Omit the block entirely, for consumers that reject unknown fields
+    None,
+    /// Generator, sizes and chunk count
+    #[default]
+    Basic,
+    /// Basic plus timing and chunk pool hit/miss counts
+    Full,
+}
+
+/// What the caller asked to see in the `metadata` block
+#[derive(Debug, Clone, Default)]
+pub struct MetadataOptions {
+    pub level: MetadataLevel,
+    /// Summary of the received request, embedded when the caller asks for an echo
+    pub request_echo: Option<Value>,
+    /// Outcomes of the downstream dependency calls made before responding
+    pub dependencies: Option<Value>,
+}
+
+impl MetadataOptions {
+    /// Metadata for direct responses, which only carry a block when a request echo
+    /// or dependency outcomes are wanted
+    pub fn direct_block(&self) -> Option<Value> {
+        if self.level == MetadataLevel::None {
+            return None;
+        }
+        let mut block = Map::new();
+        if let Some(echo) = &self.request_echo {
+            block.insert("request".to_string(), echo.clone());
+        }
+        if let Some(dependencies) = &self.dependencies {
+            block.insert("dependencies".to_string(), dependencies.clone());
+        }
+        (!block.is_empty()).then_some(Value::Object(block))
+    }
+}
+
+/// Summarize a received request as seen by daddle
+pub fn request_echo(method: &Method, uri: &Uri, headers: &HeaderMap, body_size: usize) -> Value {
+    let query: Map<String, Value> =
+        serde_urlencoded::from_str::<Vec<(String, String)>>(uri.query().unwrap_or_default())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, value)| (name, Value::from(value)))
+            .collect();
+
+    let echoed_headers: Map<String, Value> = ECHO_HEADERS
+        .iter()
+        .filter_map(|&name| {
+            let values: Vec<Value> = headers
+                .get_all(name)
+                .iter()
+                .map(|value| Value::from(String::from_utf8_lossy(value.as_bytes())))
+                .collect();
+            match values.len() {
+                0 => None,
+                1 => Some((name.to_string(), values[0].clone())),
+                _ => Some((name.to_string(), Value::from(values))),
+            }
+        })
+        .collect();
+
+    serde_json::json!({
+        "method": method.as_str(),
+        "path": uri.path(),
+        "query": query,
+        "headers": echoed_headers,
+        "body_size": body_size,
+    })
+}
+
+/// Bookkeeping for a single response, rendered as its `metadata` block
+pub struct ResponseMetadata {
+    level: MetadataLevel,
+    request_echo: Option<Value>,
+    dependencies: Option<Value>,
+    generated_by: &'static str,
+    target_size: usize,
+    started: Instant,
+    /// Seed stable content is generated from, reported at the full level
+    seed: Option<u64>,
+    pub chunk_count: usize,
+    pub chunk_hits: usize,
+    pub chunk_misses: usize,
+}
+
+impl ResponseMetadata {
+    pub fn new(options: &MetadataOptions, generated_by: &'static str, target_size: usize) -> Self {
+        Self {
+            level: options.level,
+            request_echo: options.request_echo.clone(),
+            dependencies: options.dependencies.clone(),
+            generated_by,
+            target_size,
+            started: Instant::now(),
+            seed: None,
+            chunk_count: 0,
+            chunk_hits: 0,
+            chunk_misses: 0,
+        }
+    }
+
+    /// Pad the body the same way every time for the same seed
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Record a chunk taken from the pool (`true`) or generated on demand (`false`)
+    pub fn record_chunk(&mut self, pooled: bool) {
+        self.chunk_count += 1;
+        if pooled {
+            self.chunk_hits += 1;
+        } else {
+            self.chunk_misses += 1;
+        }
+    }
+
+    /// Bytes to hold back for closing the document, so chunks are only added
+    /// while they leave room for it
+    pub fn closing_reserve(&self, emit_options: &EmitOptions) -> usize {
+        // Slack for the numbers in the block growing by a digit or two
+        const SLACK: usize = 16;
+        emit::array_close(1, emit_options, emit::CHUNK_DEPTH).len()
+            + self.render(self.target_size, emit_options).len()
+            + emit::chunks_close(emit_options).len()
+            + SLACK
+    }
+
+    /// Bytes of chunks, separators included, that still fit after `written` bytes
+    pub fn room(&self, written: usize, emit_options: &EmitOptions) -> usize {
+        self.target_size
+            .saturating_sub(written + self.closing_reserve(emit_options))
+    }
+
+    /// Close the chunk array and the document after `written` bytes. The
+    /// shortfall to the target size is filled with a padding string element, or
+    /// with whitespace when too little is missing for one, so the body comes out
+    /// exactly at the target; bodies already at or past it are closed as they are.
+    /// The padding element is not counted as a chunk
+    pub fn close(&self, written: usize, emit_options: &EmitOptions) -> String {
+        let separator = emit::element_separator(self.chunk_count, emit_options, emit::CHUNK_DEPTH);
+        let padded_close = emit::array_close(self.chunk_count + 1, emit_options, emit::CHUNK_DEPTH);
+        let plain_close = emit::array_close(self.chunk_count, emit_options, emit::CHUNK_DEPTH);
+        let chunks_close = emit::chunks_close(emit_options);
+
+        // The block reports the size written before it, which depends on the
+        // padding and so on the block's own length; settle both in a few rounds
+        let mut before_metadata = self.target_size;
+        for _ in 0..4 {
+            let mut tail = format!(
+                "{}{}",
+                self.render(before_metadata, emit_options),
+                chunks_close
+            );
+            let Some(settled) = self.target_size.checked_sub(tail.len()) else {
+                break;
+            };
+            if settled < before_metadata {
+                before_metadata = settled;
+                continue;
+            }
+            if settled > before_metadata {
+                // The size lost a digit on the way, and would gain it back with
+                // the block reporting it; keep the shorter block and space it out
+                let spaces = " ".repeat(settled - before_metadata);
+                tail = format!(
+                    "{}{}{}",
+                    self.render(before_metadata, emit_options),
+                    spaces,
+                    chunks_close
+                );
+            }
+
+            let Some(shortfall) = before_metadata.checked_sub(written) else {
+                break;
+            };
+            let element_overhead = separator.len() + 2 + padded_close.len();
+            if shortfall >= element_overhead {
+                let padding = padding(shortfall - element_overhead, self.seed);
+                return format!(r#"{}"{}"{}{}"#, separator, padding, padded_close, tail);
+            }
+            if shortfall >= plain_close.len() {
+                let spaces = " ".repeat(shortfall - plain_close.len());
+                return format!("{}{}{}", spaces, plain_close, tail);
+            }
+            break;
+        }
+
+        format!(
+            "{}{}{}",
+            plain_close,
+            self.render(written + plain_close.len(), emit_options),
+            chunks_close
+        )
+    }
+
+    /// Length of the shortest close after `written` bytes, with nothing padded
+    pub fn smallest_close(&self, written: usize, emit_options: &EmitOptions) -> usize {
+        let plain_close = emit::array_close(self.chunk_count, emit_options, emit::CHUNK_DEPTH);
+        plain_close.len()
+            + self.render(written + plain_close.len(), emit_options).len()
+            + emit::chunks_close(emit_options).len()
+    }
+
+    /// Render the `,"metadata":{...}` member that follows the chunk array,
+    /// or nothing at all when metadata is disabled
+    pub fn render(&self, actual_size: usize, emit_options: &EmitOptions) -> String {
+        if self.level == MetadataLevel::None {
+            return String::new();
+        }
+
+        let mut members = vec![
+            ("generated_by", format!(r#""{}""#, self.generated_by)),
+            ("target_size", self.target_size.to_string()),
+            ("actual_size", actual_size.to_string()),
+            ("chunk_count", self.chunk_count.to_string()),
+        ];
+
+        if self.generated_by == "streaming" {
+            members.push(("streaming", "true".to_string()));
+        }
+
+        if self.level == MetadataLevel::Full {
+            members.push(("chunk_hits", self.chunk_hits.to_string()));
+            members.push(("chunk_misses", self.chunk_misses.to_string()));
+            members.push((
+                "generation_ms",
+                format!("{:.3}", self.started.elapsed().as_secs_f64() * 1000.0),
+            ));
+            if let Some(seed) = self.seed {
+                members.push(("seed", seed.to_string()));
+            }
+        }
+
+        if let Some(echo) = &self.request_echo {
+            members.push(("request", emit::to_json_at(echo, emit_options, 2)));
+        }
+
+        if let Some(dependencies) = &self.dependencies {
+            members.push((
+                "dependencies",
+                emit::to_json_at(dependencies, emit_options, 2),
+            ));
+        }
+
+        let block = emit::object(members, emit_options, 1);
+        emit::trailing_member("metadata", &block, emit_options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendered(level: MetadataLevel, seed: Option<u64>) -> String {
+        let options = MetadataOptions {
+            level,
+            ..Default::default()
+        };
+        ResponseMetadata::new(&options, "streaming", 1000)
+            .with_seed(seed)
+            .render(1000, &EmitOptions::default())
+    }
+
+    #[test]
+    fn full_metadata_reports_the_seed() {
+        assert!(rendered(MetadataLevel::Full, Some(42)).contains(r#""seed":42"#));
+        assert!(!rendered(MetadataLevel::Full, None).contains("seed"));
+        assert!(!rendered(MetadataLevel::Basic, Some(42)).contains("seed"));
+    }
+}
@@ -0,0 +1,497 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use axum::{
+    extract::Request,
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::config::OversizeAction;
+use crate::test_runs;
+
+/// Upper bounds of the latency histogram buckets, in milliseconds
+const LATENCY_BUCKETS_MS: [u64; 12] =
+    [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 30000];
+
+/// Paths that serve operators rather than traffic under test, left out of the latency histogram
+const UNTRACKED_PREFIXES: &[&str] = &["/admin", "/stats", "/metrics", "/health", "/debug"];
+
+/// Who a request was served for: its tenant, and the garble profile it ran with
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Segment {
+    pub tenant: String,
+    pub profile: String,
+}
+
+/// Where the tenant extractor leaves the request's segment for `track_latency`
+#[derive(Clone, Default)]
+pub struct SegmentSlot(Arc<OnceLock<Segment>>);
+
+impl SegmentSlot {
+    pub fn fill(&self, segment: Segment) {
+        let _ = self.0.set(segment);
+    }
+}
+
+/// Request counters for a single stats bucket
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct BucketStats {
+    pub requests: u64,
+    pub target_bytes: u64,
+    pub wait_ms_total: u64,
+    pub strategies: BTreeMap<&'static str, u64>,
+    /// Chunks served from the pool
+    pub pool_hits: u64,
+    pub latency: LatencyStats,
+    /// The same counters per garble profile, on tenant buckets only
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, BucketStats>,
+}
+
+impl BucketStats {
+    fn add(&mut self, other: &BucketStats) {
+        self.requests += other.requests;
+        self.target_bytes = self.target_bytes.saturating_add(other.target_bytes);
+        self.wait_ms_total = self.wait_ms_total.saturating_add(other.wait_ms_total);
+        for (strategy, count) in &other.strategies {
+            *self.strategies.entry(strategy).or_default() += count;
+        }
+        self.pool_hits = self.pool_hits.saturating_add(other.pool_hits);
+        self.latency.add(&other.latency);
+    }
+}
+
+/// Time until response headers, with one more bucket for anything slower than the last bound
+struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+    sum_ms: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyBucket {
+    /// Upper bound in milliseconds; `None` for the overflow bucket
+    pub le_ms: Option<u64>,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyStats {
+    pub count: u64,
+    pub sum_ms: u64,
+    pub buckets: Vec<LatencyBucket>,
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            sum_ms: 0,
+            buckets: (0..=LATENCY_BUCKETS_MS.len())
+                .map(|i| LatencyBucket {
+                    le_ms: LATENCY_BUCKETS_MS.get(i).copied(),
+                    count: 0,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl LatencyStats {
+    fn record(&mut self, ms: u64) {
+        self.buckets[latency_bucket(ms)].count += 1;
+        self.count += 1;
+        self.sum_ms = self.sum_ms.saturating_add(ms);
+    }
+
+    fn add(&mut self, other: &LatencyStats) {
+        for (bucket, other) in self.buckets.iter_mut().zip(&other.buckets) {
+            bucket.count += other.count;
+        }
+        self.count += other.count;
+        self.sum_ms = self.sum_ms.saturating_add(other.sum_ms);
+    }
+}
+
+/// Index of the histogram bucket a latency falls in
+fn latency_bucket(ms: u64) -> usize {
+    LATENCY_BUCKETS_MS
+        .iter()
+        .position(|&bound| ms <= bound)
+        .unwrap_or(LATENCY_BUCKETS_MS.len())
+}
+
+/// Requests for bodies beyond the cap, by how they were handled
+#[derive(Debug, Clone, Serialize)]
+pub struct OversizeStats {
+    pub rejected: u64,
+    pub clamped: u64,
+}
+
+/// Responses built with one strategy and how fast their bodies were generated
+#[derive(Debug, Clone, Serialize)]
+pub struct StrategyStats {
+    pub requests: u64,
+    /// Bodies finished so far; streams count once they are sent in full
+    pub completed: u64,
+    pub bytes: u64,
+    pub generation_ms: f64,
+    /// Average generation throughput in MB/s
+    pub throughput_mb_per_s: f64,
+}
+
+/// Notes a client that goes away before its response is complete: dropped
+/// while still armed, it counts an abort in the phase the request was in
+pub struct ClientAbortWatch {
+    phase: Option<&'static str>,
+}
+
+impl ClientAbortWatch {
+    pub fn new(phase: &'static str) -> Self {
+        Self { phase: Some(phase) }
+    }
+
+    /// Move on to the next phase of the request
+    pub fn set_phase(&mut self, phase: &'static str) {
+        self.phase = Some(phase);
+    }
+
+    /// The response got where it was going; nothing to count
+    pub fn finish(&mut self) {
+        self.phase = None;
+    }
+}
+
+impl Drop for ClientAbortWatch {
+    fn drop(&mut self) {
+        if let Some(phase) = self.phase {
+            tracing::debug!("Client went away during {}", phase);
+            METRICS.record_client_abort(phase);
+        }
+    }
+}
+
+#[derive(Default)]
+struct StrategyCounters {
+    requests: u64,
+    completed: u64,
+    bytes: u64,
+    generation: Duration,
+}
+
+/// Request metrics kept in separate buckets per tenant and garble profile
+pub struct Metrics {
+    segments: Mutex<HashMap<Segment, BucketStats>>,
+    strategies: Mutex<BTreeMap<&'static str, StrategyCounters>>,
+    latency: LatencyHistogram,
+    oversize_rejected: AtomicU64,
+    oversize_clamped: AtomicU64,
+    aborted_by_client: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            segments: Mutex::new(HashMap::new()),
+            strategies: Mutex::new(BTreeMap::new()),
+            latency: LatencyHistogram {
+                buckets: Default::default(),
+                sum_ms: AtomicU64::new(0),
+            },
+            oversize_rejected: AtomicU64::new(0),
+            oversize_clamped: AtomicU64::new(0),
+            aborted_by_client: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record the time to response headers, also against the request's segment if known
+    pub fn record_latency(&self, latency: Duration, segment: Option<&Segment>) {
+        let ms = latency.as_millis() as u64;
+        self.latency.buckets[latency_bucket(ms)].fetch_add(1, Ordering::Relaxed);
+        self.latency.sum_ms.fetch_add(ms, Ordering::Relaxed);
+        if let Some(segment) = segment {
+            self.bucket(segment, |bucket| bucket.latency.record(ms));
+        }
+    }
+
+    /// Update the segment's bucket, and the bucket of the request's test run if it has one
+    fn bucket(&self, segment: &Segment, update: impl Fn(&mut BucketStats)) {
+        {
+            let mut segments = self.segments.lock().unwrap_or_else(|e| e.into_inner());
+            match segments.get_mut(segment) {
+                Some(bucket) => update(bucket),
+                None => update(segments.entry(segment.clone()).or_default()),
+            }
+        }
+        test_runs::record(update);
+    }
+
+    pub fn latency_stats(&self) -> LatencyStats {
+        let buckets: Vec<LatencyBucket> = self
+            .latency
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(i, count)| LatencyBucket {
+                le_ms: LATENCY_BUCKETS_MS.get(i).copied(),
+                count: count.load(Ordering::Relaxed),
+            })
+            .collect();
+        LatencyStats {
+            count: buckets.iter().map(|bucket| bucket.count).sum(),
+            sum_ms: self.latency.sum_ms.load(Ordering::Relaxed),
+            buckets,
+        }
+    }
+
+    /// Record a request for a body beyond the cap
+    pub fn record_oversize(&self, action: OversizeAction) {
+        let counter = match action {
+            OversizeAction::Reject => &self.oversize_rejected,
+            OversizeAction::Clamp => &self.oversize_clamped,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn oversize_stats(&self) -> OversizeStats {
+        OversizeStats {
+            rejected: self.oversize_rejected.load(Ordering::Relaxed),
+            clamped: self.oversize_clamped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Record a client that went away during `phase` of its request
+    pub fn record_client_abort(&self, phase: &'static str) {
+        *self
+            .aborted_by_client
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(phase)
+            .or_default() += 1;
+    }
+
+    /// Requests whose client went away before the response was complete, by phase
+    pub fn client_abort_stats(&self) -> BTreeMap<&'static str, u64> {
+        self.aborted_by_client
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Record a generated response against the segment's bucket
+    pub fn record_response(
+        &self,
+        segment: &Segment,
+        strategy: &'static str,
+        target_size: usize,
+        wait_ms: u64,
+    ) {
+        self.bucket(segment, |bucket| {
+            bucket.requests += 1;
+            bucket.target_bytes = bucket.target_bytes.saturating_add(target_size as u64);
+            bucket.wait_ms_total = bucket.wait_ms_total.saturating_add(wait_ms);
+            *bucket.strategies.entry(strategy).or_default() += 1;
+        });
+    }
+
+    /// Attribute chunks taken from the pool to the segment they were served for
+    pub fn record_pool_hits(&self, segment: &Segment, hits: usize) {
+        if hits > 0 {
+            self.bucket(segment, |bucket| {
+                bucket.pool_hits = bucket.pool_hits.saturating_add(hits as u64)
+            });
+        }
+    }
+
+    /// Record a response about to be built with `strategy`
+    pub fn record_strategy(&self, strategy: &'static str) {
+        let mut strategies = self.strategies.lock().unwrap_or_else(|e| e.into_inner());
+        strategies.entry(strategy).or_default().requests += 1;
+    }
+
+    /// Record a finished body and how long generating it took
+    pub fn record_generation(&self, strategy: &'static str, bytes: usize, elapsed: Duration) {
+        let mut strategies = self.strategies.lock().unwrap_or_else(|e| e.into_inner());
+        let counters = strategies.entry(strategy).or_default();
+        counters.completed += 1;
+        counters.bytes = counters.bytes.saturating_add(bytes as u64);
+        counters.generation = counters.generation.saturating_add(elapsed);
+    }
+
+    /// Counts and generation throughput of every strategy used so far
+    pub fn strategy_stats(&self) -> BTreeMap<&'static str, StrategyStats> {
+        self.strategies
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(name, counters)| {
+                let seconds = counters.generation.as_secs_f64();
+                let throughput_mb_per_s = if seconds > 0.0 {
+                    counters.bytes as f64 / 1_000_000.0 / seconds
+                } else {
+                    0.0
+                };
+                let stats = StrategyStats {
+                    requests: counters.requests,
+                    completed: counters.completed,
+                    bytes: counters.bytes,
+                    generation_ms: seconds * 1000.0,
+                    throughput_mb_per_s,
+                };
+                (*name, stats)
+            })
+            .collect()
+    }
+
+    /// Totals of every tenant, ordered by tenant name, each broken down by profile
+    pub fn tenant_stats(&self) -> BTreeMap<String, BucketStats> {
+        let mut tenants: BTreeMap<String, BucketStats> = BTreeMap::new();
+        for (segment, stats) in self
+            .segments
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+        {
+            let tenant = tenants.entry(segment.tenant.clone()).or_default();
+            tenant.add(stats);
+            tenant
+                .profiles
+                .insert(segment.profile.clone(), stats.clone());
+        }
+        tenants
+    }
+
+    /// Segmented counters in the Prometheus text format, labeled by tenant and profile
+    pub fn prometheus(&self) -> String {
+        let mut segments: Vec<_> = self
+            .segments
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(segment, stats)| (segment.clone(), stats.clone()))
+            .collect();
+        segments.sort_by(|(a, _), (b, _)| (&a.tenant, &a.profile).cmp(&(&b.tenant, &b.profile)));
+
+        let mut out = String::new();
+        let labels = |segment: &Segment| {
+            format!(
+                "tenant=\"{}\",profile=\"{}\"",
+                escape_label(&segment.tenant),
+                escape_label(&segment.profile)
+            )
+        };
+        let mut counter = |name: &str, help: &str, value: &dyn Fn(&BucketStats) -> u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (segment, stats) in &segments {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels(segment), value(stats));
+            }
+        };
+        counter(
+            "daddle_target_bytes_total",
+            "Body bytes requested",
+            &|stats| stats.target_bytes,
+        );
+        counter(
+            "daddle_wait_milliseconds_total",
+            "Time spent in requested waits",
+            &|stats| stats.wait_ms_total,
+        );
+        counter(
+            "daddle_pool_hits_total",
+            "Chunks served from the chunk pool",
+            &|stats| stats.pool_hits,
+        );
+
+        out.push_str("# HELP daddle_responses_total Responses served, by strategy\n");
+        out.push_str("# TYPE daddle_responses_total counter\n");
+        for (segment, stats) in &segments {
+            for (strategy, count) in &stats.strategies {
+                let _ = writeln!(
+                    out,
+                    "daddle_responses_total{{{},strategy=\"{}\"}} {}",
+                    labels(segment),
+                    strategy,
+                    count
+                );
+            }
+        }
+
+        out.push_str("# HELP daddle_request_duration_seconds Time until response headers\n");
+        out.push_str("# TYPE daddle_request_duration_seconds histogram\n");
+        for (segment, stats) in &segments {
+            let labels = labels(segment);
+            let mut cumulative = 0;
+            for bucket in &stats.latency.buckets {
+                cumulative += bucket.count;
+                let le = bucket
+                    .le_ms
+                    .map_or("+Inf".to_string(), |ms| (ms as f64 / 1000.0).to_string());
+                let _ = writeln!(
+                    out,
+                    "daddle_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, le, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "daddle_request_duration_seconds_sum{{{}}} {}",
+                labels,
+                stats.latency.sum_ms as f64 / 1000.0
+            );
+            let _ = writeln!(
+                out,
+                "daddle_request_duration_seconds_count{{{}}} {}",
+                labels, stats.latency.count
+            );
+        }
+        out
+    }
+}
+
+/// Escape a label value for the Prometheus text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve the segmented counters for Prometheus to scrape
+pub async fn prometheus_handler() -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.prometheus(),
+    )
+        .into_response()
+}
+
+/// Middleware recording how long traffic endpoints take to answer
+pub async fn track_latency(request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    if UNTRACKED_PREFIXES
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+    {
+        return next.run(request).await;
+    }
+    let slot = SegmentSlot::default();
+    let mut request = request;
+    request.extensions_mut().insert(slot.clone());
+    let started = Instant::now();
+    let response = next.run(request).await;
+    METRICS.record_latency(started.elapsed(), slot.0.get());
+    response
+}
+
+// Global request metrics instance
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
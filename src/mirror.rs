@@ -0,0 +1,275 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use axum::{
+    body::{Body, BodyDataStream, Bytes},
+    extract::Request,
+    middleware::Next,
+    response::Response,
+};
+use futures::Stream;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::MirrorConfig;
+use crate::test_runs::TestRun;
+
+/// Records posted to the collector at the same time
+const MAX_CONCURRENT_POSTS: usize = 8;
+
+/// Mirrored requests waiting to be posted, and what happened to earlier ones
+pub struct Mirror {
+    queue: mpsc::Sender<MirrorRecord>,
+    body_preview_bytes: usize,
+    redact_headers: Vec<String>,
+    sent: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MirrorStats {
+    pub sent: u64,
+    pub failed: u64,
+    pub dropped: u64,
+}
+
+impl Mirror {
+    pub fn get_stats(&self) -> MirrorStats {
+        MirrorStats {
+            sent: self.sent.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    fn enqueue(&self, record: MirrorRecord) {
+        if self.queue.try_send(record).is_err() {
+            // Never hold up the request for a slow collector
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Summary of one received request, as posted to the collector
+#[derive(Debug, Serialize)]
+struct MirrorRecord {
+    id: String,
+    /// Test run the request was tagged with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    run_id: Option<String>,
+    received_at: chrono::DateTime<chrono::Utc>,
+    method: String,
+    path: String,
+    query: Map<String, Value>,
+    version: String,
+    headers: Map<String, Value>,
+    body: BodySummary,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct BodySummary {
+    /// Bytes of the body read by daddle; bodies a handler ignores count as 0
+    size: usize,
+    /// The first `body_preview_bytes` bytes, lossily decoded as UTF-8
+    preview: String,
+    truncated: bool,
+}
+
+/// Request body passed through to the handler, summarizing what it reads and
+/// enqueueing the record once the body is finished or dropped
+struct MirroredBody {
+    inner: BodyDataStream,
+    preview: Vec<u8>,
+    record: Option<MirrorRecord>,
+}
+
+impl Stream for MirroredBody {
+    type Item = Result<Bytes, axum::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(data))) = &poll {
+            let limit = MIRROR.get().map_or(0, |mirror| mirror.body_preview_bytes);
+            let room = limit.saturating_sub(self.preview.len());
+            let data = data.clone();
+            self.preview
+                .extend_from_slice(&data[..room.min(data.len())]);
+            if let Some(record) = self.record.as_mut() {
+                record.body.size += data.len();
+                record.body.truncated = record.body.size > limit;
+            }
+        }
+        poll
+    }
+}
+
+impl Drop for MirroredBody {
+    fn drop(&mut self) {
+        if let (Some(mut record), Some(mirror)) = (self.record.take(), MIRROR.get()) {
+            record.body.preview = String::from_utf8_lossy(&self.preview).into_owned();
+            mirror.enqueue(record);
+        }
+    }
+}
+
+fn header_map(request: &Request, redact: &[String]) -> Map<String, Value> {
+    let mut headers = Map::new();
+    for name in request.headers().keys() {
+        let values: Vec<Value> = request
+            .headers()
+            .get_all(name)
+            .iter()
+            .map(|value| {
+                if redact.iter().any(|r| r.eq_ignore_ascii_case(name.as_str())) {
+                    Value::from("[redacted]")
+                } else {
+                    Value::from(String::from_utf8_lossy(value.as_bytes()))
+                }
+            })
+            .collect();
+        let value = match values.len() {
+            1 => values.into_iter().next().unwrap(),
+            _ => Value::from(values),
+        };
+        headers.insert(name.to_string(), value);
+    }
+    headers
+}
+
+/// Middleware mirroring every request to the collector when mirroring is configured
+pub async fn mirror_request(request: Request, next: Next) -> Response {
+    let Some(mirror) = MIRROR.get() else {
+        return next.run(request).await;
+    };
+
+    let query = serde_urlencoded::from_str::<Vec<(String, String)>>(
+        request.uri().query().unwrap_or_default(),
+    )
+    .unwrap_or_default()
+    .into_iter()
+    .map(|(name, value)| (name, Value::from(value)))
+    .collect();
+
+    let record = MirrorRecord {
+        id: uuid::Uuid::new_v4().to_string(),
+        run_id: request
+            .extensions()
+            .get::<TestRun>()
+            .map(|TestRun(run)| run.to_string()),
+        received_at: chrono::Utc::now(),
+        method: request.method().to_string(),
+        path: request.uri().path().to_string(),
+        query,
+        version: format!("{:?}", request.version()),
+        headers: header_map(&request, &mirror.redact_headers),
+        body: BodySummary::default(),
+    };
+
+    let (parts, body) = request.into_parts();
+    let body = MirroredBody {
+        inner: body.into_data_stream(),
+        preview: Vec::new(),
+        record: Some(record),
+    };
+    next.run(Request::from_parts(parts, Body::from_stream(body)))
+        .await
+}
+
+/// Post queued records to the collector until shutdown, then flush what is left
+async fn forward(
+    config: MirrorConfig,
+    url: String,
+    mut queue: mpsc::Receiver<MirrorRecord>,
+    shutdown: CancellationToken,
+) {
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_millis(config.timeout_ms))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("Failed to create mirror client, mirroring disabled: {}", e);
+            return;
+        }
+    };
+    let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_POSTS));
+
+    let post = |record: MirrorRecord, permit: OwnedSemaphorePermit| {
+        let request = client.post(&url).json(&record);
+        async move {
+            let result = request.send().await;
+            drop(permit);
+            let Some(mirror) = MIRROR.get() else {
+                return;
+            };
+            match result.and_then(|response| response.error_for_status()) {
+                Ok(_) => mirror.sent.fetch_add(1, Ordering::Relaxed),
+                Err(e) => {
+                    tracing::debug!("Failed to mirror request {}: {}", record.id, e);
+                    mirror.failed.fetch_add(1, Ordering::Relaxed)
+                }
+            };
+        }
+    };
+
+    loop {
+        tokio::select! {
+            record = queue.recv() => {
+                let Some(record) = record else { break };
+                // Wait for a free slot, so records back up in the queue rather than in tasks
+                let Ok(permit) = permits.clone().acquire_owned().await else { break };
+                tokio::spawn(post(record, permit));
+            }
+            _ = shutdown.cancelled() => break,
+        }
+    }
+
+    // Requests have stopped by now, so post whatever is still queued
+    queue.close();
+    while let Some(record) = queue.recv().await {
+        let Ok(permit) = permits.clone().acquire_owned().await else {
+            break;
+        };
+        tokio::spawn(post(record, permit));
+    }
+    let _ = permits.acquire_many(MAX_CONCURRENT_POSTS as u32).await;
+}
+
+/// Start mirroring when a collector URL is configured
+pub fn start(config: &MirrorConfig, shutdown: CancellationToken) -> Option<JoinHandle<()>> {
+    let url = config.url.clone()?;
+    let (queue, receiver) = mpsc::channel(config.queue_size.max(1));
+    let mirror = Mirror {
+        queue,
+        body_preview_bytes: config.body_preview_bytes,
+        redact_headers: config.redact_headers.clone(),
+        sent: AtomicU64::new(0),
+        failed: AtomicU64::new(0),
+        dropped: AtomicU64::new(0),
+    };
+    if MIRROR.set(mirror).is_err() {
+        return None;
+    }
+
+    tracing::info!("Mirroring requests to {}", url);
+    Some(tokio::spawn(forward(
+        config.clone(),
+        url,
+        receiver,
+        shutdown,
+    )))
+}
+
+// Global mirror, set once at startup when mirroring is configured
+pub static MIRROR: OnceCell<Mirror> = OnceCell::new();
@@ -0,0 +1,176 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A fixed number of newline-delimited JSON objects, streamed as fast as the
+//! client reads them, for feeding log-ingestion pipelines that expect JSON
+//! Lines. Each object numbers itself and carries a garbled `data` value,
+//! taken from the chunk pool when a pooled chunk fits the record size, and is
+//! padded out to exactly the record size.
+
+use async_stream::stream;
+use axum::{
+    body::{Body, Bytes},
+    extract::{rejection::QueryRejection, Query, RawQuery},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::chunk_pool::{ChunkSize, CHUNK_POOL};
+use crate::drain::DRAIN;
+use crate::error::ApiError;
+use crate::generator::RandomDataGenerator;
+use crate::metadata;
+use crate::metrics::{ClientAbortWatch, Segment, METRICS};
+use crate::tenants::Tenant;
+use crate::validation::strict_query;
+
+const DEFAULT_COUNT: usize = 100;
+const MAX_COUNT: usize = 10_000_000;
+
+const DEFAULT_RECORD_SIZE: usize = 512;
+const MAX_RECORD_SIZE: usize = 1_000_000;
+
+/// Bytes of records generated at a time before being written
+const BATCH_BYTES: usize = 256 * 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct NdjsonParams {
+    count: Option<usize>,
+    #[serde(rename = "recordSize")]
+    record_size: Option<usize>,
+}
+
+/// The object numbered `seq`, `record_size` bytes long unless its data
+/// alone is longer, followed by a newline; returns whether the data was pooled
+fn write_record(line: &mut String, seq: usize, record_size: usize) -> bool {
+    let opening = format!(r#"{{"seq":{},"data":"#, seq);
+    let closing = r#","padding":""}"#;
+    let room = record_size.saturating_sub(opening.len() + closing.len());
+
+    // The largest pooled chunks that can still fit, which vary in size around their target
+    let pooled = ChunkSize::all()
+        .iter()
+        .rev()
+        .find(|size| size.target_bytes() <= room)
+        .and_then(|&size| CHUNK_POOL.get_chunk(size))
+        .filter(|chunk| chunk.len() <= room);
+    let hit = pooled.is_some();
+    let data = pooled.unwrap_or_else(|| generated_data(room));
+
+    line.push_str(&opening);
+    line.push_str(&data);
+    line.push_str(r#","padding":""#);
+    line.push_str(&metadata::padding(room.saturating_sub(data.len()), None));
+    line.push_str("\"}\n");
+    hit
+}
+
+/// A generated object of at most `room` bytes where possible; the generator
+/// overshoots by up to a field, which is dropped again
+fn generated_data(room: usize) -> String {
+    let mut record = RandomDataGenerator::new().generate_sized_record(room);
+    loop {
+        let data = serde_json::to_string(&record).unwrap_or_else(|_| "{}".to_string());
+        let Value::Object(map) = &mut record else {
+            return data;
+        };
+        if data.len() <= room || map.is_empty() {
+            return data;
+        }
+        let last = map.keys().next_back().cloned().expect("a member");
+        map.remove(&last);
+    }
+}
+
+/// `count` records, generated a batch at a time off the async workers
+fn records(
+    count: usize,
+    record_size: usize,
+    segment: Segment,
+) -> impl futures::Stream<Item = Result<Bytes, std::io::Error>> + Send {
+    stream! {
+        // Dropping the stream mid-way means the client went away
+        let mut client = ClientAbortWatch::new("streaming");
+        let batch = (BATCH_BYTES / record_size.max(1)).max(1);
+        let mut seq = 0;
+        while seq < count {
+            if DRAIN.is_draining() {
+                break;
+            }
+            let end = (seq + batch).min(count);
+            let start = seq;
+            let Ok((lines, pool_hits)) = tokio::task::spawn_blocking(move || {
+                let mut lines = String::with_capacity((end - start) * (record_size + 1));
+                let pool_hits = (start..end)
+                    .filter(|&seq| write_record(&mut lines, seq, record_size))
+                    .count();
+                (lines, pool_hits)
+            })
+            .await
+            else {
+                break;
+            };
+            METRICS.record_pool_hits(&segment, pool_hits);
+            seq = end;
+            yield Ok(Bytes::from(lines));
+        }
+        client.finish();
+    }
+}
+
+/// Stream `count` NDJSON objects of `recordSize` bytes each
+pub async fn ndjson_handler(
+    params: Result<Query<NdjsonParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    tenant: Tenant,
+) -> Result<Response, ApiError> {
+    if DRAIN.is_draining() {
+        return Err(ApiError::draining());
+    }
+
+    let params = strict_query(
+        params,
+        raw_query.as_deref(),
+        tenant.garble.strict_validation,
+    )?;
+    let count = params.count.unwrap_or(DEFAULT_COUNT);
+    if count > MAX_COUNT {
+        return Err(ApiError::bad_request(format!(
+            "count must be at most {}, got {}",
+            MAX_COUNT, count
+        )));
+    }
+    let record_size = params.record_size.unwrap_or(DEFAULT_RECORD_SIZE);
+    if record_size == 0 || record_size > MAX_RECORD_SIZE {
+        return Err(ApiError::bad_request(format!(
+            "recordSize must be between 1 and {}, got {}",
+            MAX_RECORD_SIZE, record_size
+        )));
+    }
+    let total = count.saturating_mul(record_size + 1);
+    if let Some(cap) = tenant.garble.body_size_cap.filter(|&cap| total > cap) {
+        return Err(ApiError::body_too_large(total, cap));
+    }
+
+    tracing::info!(
+        "Streaming NDJSON: tenant={}, count={}, record_size={}B",
+        tenant.name,
+        count,
+        record_size
+    );
+    let segment = tenant.segment();
+    METRICS.record_response(&segment, "ndjson", total, 0);
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson"),
+            (header::CACHE_CONTROL, "no-cache"),
+            (header::HeaderName::from_static("x-garble-mode"), "ndjson"),
+        ],
+        Body::from_stream(records(count, record_size, segment)),
+    )
+        .into_response())
+}
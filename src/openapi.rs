@@ -0,0 +1,483 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Random but valid OpenAPI 3.1 documents, for stress testing API portal
+//! importers, client generators and gateway config compilers. Component
+//! schemas only refer to schemas defined before them, every path parameter is
+//! declared, and operation IDs are unique across the document.
+
+use axum::{
+    body::Body,
+    extract::{rejection::QueryRejection, Query, RawQuery},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use rand::prelude::*;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::time::sleep;
+
+use crate::error::ApiError;
+use crate::handlers::{relabel_content_type, resolve_targets, GarbleParams};
+use crate::metrics::METRICS;
+use crate::streaming::ResponseDecision;
+use crate::tenants::Tenant;
+use crate::validation::strict_query;
+
+/// Upper bound for `paths`
+const MAX_PATHS: usize = 10_000;
+
+/// Upper bound for `schemas`
+const MAX_SCHEMAS: usize = 10_000;
+
+/// Room kept for the document around the paths and schemas, and for the
+/// last one added, which usually ends up past the target
+const RESERVE: usize = 1024;
+
+/// Nouns resources are named after
+const NOUNS: &[&str] = &[
+    "account", "address", "invoice", "order", "payment", "product", "review", "shipment",
+    "customer", "ticket", "device", "report", "session", "webhook", "token", "plan", "coupon",
+    "region", "team", "member", "project", "task", "comment", "label", "asset",
+];
+
+/// Methods operations are picked from
+const METHODS: &[&str] = &["get", "put", "post", "delete", "patch"];
+
+/// Primitive schemas properties are made of
+const PRIMITIVES: &[(&str, Option<&str>)] = &[
+    ("string", None),
+    ("string", Some("date-time")),
+    ("string", Some("uuid")),
+    ("string", Some("email")),
+    ("string", Some("uri")),
+    ("integer", Some("int32")),
+    ("integer", Some("int64")),
+    ("number", Some("double")),
+    ("boolean", None),
+];
+
+/// Name of the schema every error response uses
+const ERROR_SCHEMA: &str = "Problem";
+
+#[derive(Debug, Deserialize)]
+pub struct OpenApiParams {
+    paths: Option<usize>,
+    schemas: Option<usize>,
+    seed: Option<u64>,
+}
+
+struct OpenApiGenerator {
+    rng: StdRng,
+    schemas: Map<String, Value>,
+    paths: Map<String, Value>,
+    /// Paths with parameter names blanked, as two paths may not differ only in those
+    path_shapes: HashSet<String>,
+    operation_ids: HashSet<String>,
+    tags: HashSet<String>,
+    /// Serialized size of everything added so far
+    size: usize,
+}
+
+impl OpenApiGenerator {
+    fn new(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            schemas: Map::new(),
+            paths: Map::new(),
+            path_shapes: HashSet::new(),
+            operation_ids: HashSet::new(),
+            tags: HashSet::new(),
+            size: 0,
+        }
+    }
+
+    /// A document with the given numbers of paths and schemas, or as many as
+    /// fit in `target_size`, padded up to it with an `x-padding` extension
+    fn generate(
+        mut self,
+        target_size: usize,
+        paths: Option<usize>,
+        schemas: Option<usize>,
+    ) -> String {
+        // Every error response refers to the problem schema, and it does not count
+        // toward `schemas`
+        self.error_schema();
+        loop {
+            let room = self.size + RESERVE < target_size;
+            let want_schema = schemas.map_or(room, |n| self.schemas.len() <= n);
+            let want_path = paths.map_or(room, |n| self.paths.len() < n);
+            match (want_schema, want_path) {
+                (false, false) => break,
+                (true, false) => self.schema(),
+                (false, true) => self.path(),
+                (true, true) => {
+                    if self.rng.gen_bool(0.5) {
+                        self.schema()
+                    } else {
+                        self.path()
+                    }
+                }
+            }
+        }
+
+        let mut tags: Vec<&String> = self.tags.iter().collect();
+        tags.sort();
+        let tags: Vec<Value> = tags
+            .into_iter()
+            .map(|tag| json!({ "name": tag, "description": format!("Operations on {}s", tag) }))
+            .collect();
+        let mut document = json!({
+            "openapi": "3.1.0",
+            "info": {
+                "title": "Garbled API",
+                "version": format!("{}.{}.{}", self.rng.gen_range(0..10), self.rng.gen_range(0..20), self.rng.gen_range(0..100)),
+                "description": "A random API description generated by daddle",
+            },
+            "servers": [{ "url": "https://api.example.com/v1" }],
+            "tags": tags,
+            "paths": self.paths,
+            "components": {
+                "schemas": self.schemas,
+                "securitySchemes": {
+                    "bearerAuth": { "type": "http", "scheme": "bearer", "bearerFormat": "JWT" },
+                },
+            },
+            "security": [{ "bearerAuth": [] }],
+        });
+
+        let body = document.to_string();
+        // `,"x-padding":""` is what an empty padding member costs
+        let overhead = r#","x-padding":"""#.len();
+        if target_size >= body.len() + overhead {
+            let padding = " ".repeat(target_size - body.len() - overhead);
+            document["x-padding"] = Value::String(padding);
+            return document.to_string();
+        }
+        body
+    }
+
+    fn noun(&mut self) -> &'static str {
+        NOUNS.choose(&mut self.rng).unwrap()
+    }
+
+    /// A PascalCase schema name no other schema has
+    fn schema_name(&mut self) -> String {
+        let noun = self.noun();
+        let suffix = [
+            "", "Summary", "Detail", "Request", "Page", "Settings", "Event",
+        ]
+        .choose(&mut self.rng)
+        .unwrap();
+        let name = format!("{}{}", capitalize(noun), suffix);
+        let mut unique = name.clone();
+        let mut n = 2;
+        while self.schemas.contains_key(&unique) {
+            unique = format!("{}{}", name, n);
+            n += 1;
+        }
+        unique
+    }
+
+    /// A reference to a schema defined so far, the problem schema if there is no other
+    fn schema_ref(&mut self) -> Value {
+        let name = self
+            .schemas
+            .keys()
+            .filter(|name| *name != ERROR_SCHEMA)
+            .choose(&mut self.rng)
+            .cloned()
+            .unwrap_or_else(|| ERROR_SCHEMA.to_string());
+        json!({ "$ref": format!("#/components/schemas/{}", name) })
+    }
+
+    fn primitive(&mut self) -> Value {
+        let (ty, format) = *PRIMITIVES.choose(&mut self.rng).unwrap();
+        let mut schema = json!({ "type": ty });
+        if let Some(format) = format {
+            schema["format"] = json!(format);
+        }
+        if ty == "string" && format.is_none() && self.rng.gen_bool(0.3) {
+            let values: Vec<String> = (0..self.rng.gen_range(2..6))
+                .map(|i| format!("{}_{}", self.noun(), i))
+                .collect();
+            schema["enum"] = json!(values);
+        }
+        // 3.1 writes nullable types as a type array
+        if self.rng.gen_bool(0.2) {
+            schema["type"] = json!([ty, "null"]);
+        }
+        schema
+    }
+
+    /// A property: a primitive, a reference to an earlier schema, or an array of either
+    fn property(&mut self) -> Value {
+        let item = if !self.schemas.is_empty() && self.rng.gen_bool(0.3) {
+            self.schema_ref()
+        } else {
+            self.primitive()
+        };
+        let mut property = if self.rng.gen_bool(0.2) {
+            json!({ "type": "array", "items": item })
+        } else {
+            item
+        };
+        if self.rng.gen_bool(0.3) && property.get("$ref").is_none() {
+            property["description"] = json!(format!("The {} of this {}", self.noun(), self.noun()));
+        }
+        property
+    }
+
+    fn error_schema(&mut self) {
+        let schema = json!({
+            "type": "object",
+            "description": "Problem details as in RFC 9457",
+            "required": ["type", "title", "status"],
+            "properties": {
+                "type": { "type": "string", "format": "uri" },
+                "title": { "type": "string" },
+                "status": { "type": "integer", "format": "int32" },
+                "detail": { "type": "string" },
+            },
+        });
+        self.size += schema.to_string().len();
+        self.schemas.insert(ERROR_SCHEMA.to_string(), schema);
+    }
+
+    fn schema(&mut self) {
+        let name = self.schema_name();
+        let schema = if self.schemas.len() > 2 && self.rng.gen_bool(0.1) {
+            // Polymorphism over earlier schemas
+            let count = self.rng.gen_range(2..4);
+            let variants: Vec<Value> = (0..count).map(|_| self.schema_ref()).collect();
+            json!({ "oneOf": variants })
+        } else {
+            let mut properties = Map::new();
+            for _ in 0..self.rng.gen_range(1..12) {
+                let property = self.property();
+                let name = format!("{}{}", self.noun(), capitalize(self.noun()));
+                properties.insert(name, property);
+            }
+            let required: Vec<&String> = properties
+                .keys()
+                .filter(|_| self.rng.gen_bool(0.4))
+                .collect();
+            let mut schema = json!({
+                "type": "object",
+                "properties": properties,
+                "required": required,
+            });
+            if self.rng.gen_bool(0.3) {
+                schema["additionalProperties"] = json!(false);
+            }
+            schema
+        };
+        self.size += name.len() + schema.to_string().len();
+        self.schemas.insert(name, schema);
+    }
+
+    fn operation_id(&mut self, method: &str, noun: &str) -> String {
+        let id = format!("{}{}", method, capitalize(noun));
+        let mut unique = id.clone();
+        let mut n = 2;
+        while !self.operation_ids.insert(unique.clone()) {
+            unique = format!("{}{}", id, n);
+            n += 1;
+        }
+        unique
+    }
+
+    fn path(&mut self) {
+        // Collections nest up to three deep, each level but the last addressed by id
+        let depth = self.rng.gen_range(1..4);
+        let mut path = String::new();
+        let mut shape = String::new();
+        let mut parameters = Vec::new();
+        let mut noun = "";
+        for level in 0..depth {
+            noun = self.noun();
+            path.push_str(&format!("/{}s", noun));
+            shape.push_str(&format!("/{}s", noun));
+            if level + 1 < depth || self.rng.gen_bool(0.5) {
+                let name = format!("{}Id", noun);
+                // The same noun twice would name two parameters alike
+                if parameters.iter().any(|p: &Value| p["name"] == json!(name)) {
+                    continue;
+                }
+                path.push_str(&format!("/{{{}}}", name));
+                shape.push_str("/{}");
+                parameters.push(json!({
+                    "name": name,
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" },
+                }));
+            }
+        }
+        if !self.path_shapes.insert(shape) {
+            return;
+        }
+        if self.tags.insert(noun.to_string()) {
+            self.size += 2 * noun.len() + 40;
+        }
+
+        let mut item = Map::new();
+        if !parameters.is_empty() {
+            item.insert("parameters".to_string(), json!(parameters));
+        }
+        let count = self.rng.gen_range(1..4);
+        let methods: Vec<&str> = METHODS
+            .choose_multiple(&mut self.rng, count)
+            .copied()
+            .collect();
+        for method in methods {
+            let operation = self.operation(method, noun);
+            item.insert(method.to_string(), operation);
+        }
+        let item = Value::Object(item);
+        self.size += path.len() + item.to_string().len();
+        self.paths.insert(path, item);
+    }
+
+    fn operation(&mut self, method: &str, noun: &str) -> Value {
+        let mut operation = json!({
+            "operationId": self.operation_id(method, noun),
+            "summary": format!("{} {}", capitalize(method), noun),
+            "tags": [noun],
+        });
+        if method == "get" && self.rng.gen_bool(0.5) {
+            let parameters: Vec<Value> = (0..self.rng.gen_range(1..4))
+                .map(|i| {
+                    json!({
+                        "name": format!("{}{}", self.noun(), i),
+                        "in": "query",
+                        "required": false,
+                        "schema": self.primitive(),
+                    })
+                })
+                .collect();
+            operation["parameters"] = json!(parameters);
+        }
+        if matches!(method, "post" | "put" | "patch") {
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": { "application/json": { "schema": self.schema_ref() } },
+            });
+        }
+        let success = if method == "delete" {
+            json!({ "description": "Deleted" })
+        } else {
+            json!({
+                "description": "Success",
+                "content": { "application/json": { "schema": self.schema_ref() } },
+            })
+        };
+        let problem = json!({
+            "description": "Failure",
+            "content": {
+                "application/problem+json": {
+                    "schema": { "$ref": format!("#/components/schemas/{}", ERROR_SCHEMA) }
+                }
+            },
+        });
+        let status = match method {
+            "post" => "201",
+            "delete" => "204",
+            _ => "200",
+        };
+        let mut responses = Map::new();
+        responses.insert(status.to_string(), success);
+        responses.insert("4XX".to_string(), problem.clone());
+        responses.insert("default".to_string(), problem);
+        operation["responses"] = Value::Object(responses);
+        operation
+    }
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+// Serve a random OpenAPI document of roughly the requested size
+pub async fn openapi_handler(
+    garble_params: Result<Query<GarbleParams>, QueryRejection>,
+    openapi_params: Result<Query<OpenApiParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    tenant: Tenant,
+) -> Result<impl IntoResponse, ApiError> {
+    let strict = tenant.garble.strict_validation;
+    let garble_params = strict_query(garble_params, raw_query.as_deref(), strict)?;
+    let openapi_params = strict_query(openapi_params, raw_query.as_deref(), strict)?;
+
+    let (target_size, wait_duration_ms) = resolve_targets(&garble_params, &tenant.garble)?;
+    let content_type_label = garble_params.content_type_label()?;
+    if openapi_params.paths.is_some_and(|paths| paths > MAX_PATHS) {
+        return Err(ApiError::bad_request(format!(
+            "paths must be at most {}",
+            MAX_PATHS
+        )));
+    }
+    if openapi_params
+        .schemas
+        .is_some_and(|schemas| schemas > MAX_SCHEMAS)
+    {
+        return Err(ApiError::bad_request(format!(
+            "schemas must be at most {}",
+            MAX_SCHEMAS
+        )));
+    }
+    // Without a seed one is picked, and reported so the document can be had again
+    let seed = openapi_params.seed.unwrap_or_else(|| thread_rng().gen());
+
+    if wait_duration_ms > 0 {
+        sleep(Duration::from_millis(wait_duration_ms)).await;
+    }
+
+    let (paths, schemas) = (openapi_params.paths, openapi_params.schemas);
+    let body = tokio::task::spawn_blocking(move || {
+        OpenApiGenerator::new(seed).generate(target_size, paths, schemas)
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("OpenAPI generation task failed: {}", e);
+        ApiError::internal("openapi generation task failed")
+    })?;
+
+    tracing::info!(
+        "Generated GARBLED openapi document: tenant={}, seed={}, target_size={}B, actual_size={}B, wait={}ms",
+        tenant.name,
+        seed,
+        target_size,
+        body.len(),
+        wait_duration_ms
+    );
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/vnd.oai.openapi+json")
+        .header(
+            header::CONTENT_DISPOSITION,
+            r#"attachment; filename="openapi.json""#,
+        )
+        .header("X-Garble-Mode", "openapi")
+        .header("X-Garble-Seed", seed)
+        .body(Body::from(body))
+        .unwrap();
+    relabel_content_type(&mut response, content_type_label);
+    METRICS.record_response(&tenant.segment(), "openapi", target_size, wait_duration_ms);
+    ResponseDecision {
+        strategy: "openapi",
+        target_size,
+        wait_ms: wait_duration_ms,
+    }
+    .apply_headers(response.headers_mut());
+
+    Ok(response)
+}
@@ -0,0 +1,258 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Targets for tuning service mesh health checking and outlier detection. The
+//! health check at `outlier.health_path` cycles through healthy, degraded and
+//! unhealthy phases in the terms Envoy's active health checker understands,
+//! and an error ramp fails a growing share of `/garble` requests so passive
+//! outlier detection and panic thresholds have hosts to eject.
+
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use once_cell::sync::OnceCell;
+use rand::Rng;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::cluster;
+use crate::config::OutlierConfig;
+use crate::drain::DRAIN;
+
+/// Marks a host degraded while its health check still passes
+const DEGRADED_HEADER: &str = "x-envoy-degraded";
+
+/// Fails the host's health check right away, without waiting for the unhealthy threshold
+const IMMEDIATE_FAIL_HEADER: &str = "x-envoy-immediate-health-check-fail";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Healthy,
+    Degraded,
+    Unhealthy,
+}
+
+/// Health checks answered per phase, and failures injected by the error ramp
+#[derive(Debug, Clone, Serialize)]
+pub struct OutlierStats {
+    pub phase: Phase,
+    /// Time left in the current phase, unless the health check does not flap
+    pub next_phase_in_ms: Option<u64>,
+    /// Share of `/garble` requests failed right now
+    pub error_rate: f64,
+    pub health_checks: HealthCheckCounts,
+    pub injected_errors: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthCheckCounts {
+    pub healthy: u64,
+    pub degraded: u64,
+    pub unhealthy: u64,
+    pub draining: u64,
+}
+
+struct Outlier {
+    config: OutlierConfig,
+    /// How far into the cycle this instance started
+    offset: Duration,
+    /// When the cycle and the error ramp started, moved by a restart
+    started: Mutex<Instant>,
+    healthy: AtomicU64,
+    degraded: AtomicU64,
+    unhealthy: AtomicU64,
+    draining: AtomicU64,
+    injected: AtomicU64,
+}
+
+impl Outlier {
+    fn elapsed(&self) -> Duration {
+        self.started.lock().unwrap().elapsed()
+    }
+
+    /// The current phase and the time left in it, if the health check flaps
+    fn phase(&self) -> (Phase, Option<Duration>) {
+        let phases = [
+            (Phase::Healthy, self.config.healthy_ms),
+            (Phase::Degraded, self.config.degraded_ms),
+            (Phase::Unhealthy, self.config.unhealthy_ms),
+        ];
+        let cycle: u64 = phases.iter().map(|(_, ms)| ms).sum();
+        if cycle == 0 {
+            return (Phase::Healthy, None);
+        }
+        let mut at = ((self.elapsed() + self.offset).as_millis() as u64) % cycle;
+        for (phase, ms) in phases {
+            if at < ms {
+                return (phase, Some(Duration::from_millis(ms - at)));
+            }
+            at -= ms;
+        }
+        unreachable!("the position is within the cycle")
+    }
+
+    fn error_rate(&self) -> f64 {
+        let Some(ramp) = &self.config.error_ramp else {
+            return 0.0;
+        };
+        let elapsed = self.elapsed().as_millis() as u64;
+        if elapsed < ramp.delay_ms {
+            return 0.0;
+        }
+        let progress = if ramp.ramp_ms == 0 {
+            1.0
+        } else {
+            ((elapsed - ramp.delay_ms) as f64 / ramp.ramp_ms as f64).min(1.0)
+        };
+        ramp.start_rate + (ramp.max_rate - ramp.start_rate) * progress
+    }
+
+    fn get_stats(&self) -> OutlierStats {
+        let (phase, remaining) = self.phase();
+        OutlierStats {
+            phase,
+            next_phase_in_ms: remaining.map(|remaining| remaining.as_millis() as u64),
+            error_rate: self.error_rate(),
+            health_checks: HealthCheckCounts {
+                healthy: self.healthy.load(Ordering::Relaxed),
+                degraded: self.degraded.load(Ordering::Relaxed),
+                unhealthy: self.unhealthy.load(Ordering::Relaxed),
+                draining: self.draining.load(Ordering::Relaxed),
+            },
+            injected_errors: self.injected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Start the health check cycle and the error ramp; call once at startup
+pub fn configure(config: &OutlierConfig) -> anyhow::Result<()> {
+    config.validate()?;
+    let cycle = config.healthy_ms + config.degraded_ms + config.unhealthy_ms;
+    let offset = if config.stagger && cycle > 0 {
+        Duration::from_millis(rand::thread_rng().gen_range(0..cycle))
+    } else {
+        Duration::ZERO
+    };
+    if cycle > 0 {
+        tracing::info!(
+            "Health check at {} flaps every {}ms, starting {}ms into the cycle",
+            config.health_path,
+            cycle,
+            offset.as_millis()
+        );
+    }
+    if let Some(ramp) = &config.error_ramp {
+        tracing::info!(
+            "Failing /garble with {} at a rate rising from {} to {} over {}ms, after {}ms",
+            ramp.status,
+            ramp.start_rate,
+            ramp.max_rate,
+            ramp.ramp_ms,
+            ramp.delay_ms
+        );
+    }
+    let _ = OUTLIER.set(Outlier {
+        config: config.clone(),
+        offset,
+        started: Mutex::new(Instant::now()),
+        healthy: AtomicU64::new(0),
+        degraded: AtomicU64::new(0),
+        unhealthy: AtomicU64::new(0),
+        draining: AtomicU64::new(0),
+        injected: AtomicU64::new(0),
+    });
+    Ok(())
+}
+
+/// Status to fail a `/garble` request with, and the error rate that picked it
+pub fn roll_error() -> Option<(StatusCode, f64)> {
+    let outlier = OUTLIER.get()?;
+    let ramp = outlier.config.error_ramp.as_ref()?;
+    let rate = outlier.error_rate();
+    if rate <= 0.0 || !rand::thread_rng().gen_bool(rate) {
+        return None;
+    }
+    outlier.injected.fetch_add(1, Ordering::Relaxed);
+    Some((StatusCode::from_u16(ramp.status).ok()?, rate))
+}
+
+/// Health check state and counts, if configured
+pub fn get_stats() -> Option<OutlierStats> {
+    OUTLIER.get().map(Outlier::get_stats)
+}
+
+/// Answer the simulated health check for the current phase
+pub async fn health_handler() -> Response {
+    let Some(outlier) = OUTLIER.get() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let (phase, remaining) = outlier.phase();
+    let body = |status: &str| {
+        Json(serde_json::json!({
+            "status": status,
+            "instance": cluster::instance_id(),
+            "next_phase_in_ms": remaining.map(|remaining| remaining.as_millis() as u64),
+        }))
+    };
+
+    // A draining instance asks to be taken out of rotation at once
+    if DRAIN.is_draining() {
+        outlier.draining.fetch_add(1, Ordering::Relaxed);
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [
+                (IMMEDIATE_FAIL_HEADER, HeaderValue::from_static("true")),
+                (
+                    header::CONNECTION.as_str(),
+                    HeaderValue::from_static("close"),
+                ),
+            ],
+            body("draining"),
+        )
+            .into_response();
+    }
+
+    match phase {
+        Phase::Healthy => {
+            outlier.healthy.fetch_add(1, Ordering::Relaxed);
+            body("healthy").into_response()
+        }
+        Phase::Degraded => {
+            outlier.degraded.fetch_add(1, Ordering::Relaxed);
+            (
+                [(DEGRADED_HEADER, HeaderValue::from_static("true"))],
+                body("degraded"),
+            )
+                .into_response()
+        }
+        Phase::Unhealthy => {
+            outlier.unhealthy.fetch_add(1, Ordering::Relaxed);
+            let status = StatusCode::from_u16(outlier.config.unhealthy_status)
+                .unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+            (status, body("unhealthy")).into_response()
+        }
+    }
+}
+
+/// Current phase, error rate and counts of the outlier simulation
+pub async fn outlier_state_handler() -> Json<Option<OutlierStats>> {
+    Json(get_stats())
+}
+
+/// Restart the health check cycle and the error ramp from now, keeping this
+/// instance's stagger
+pub async fn outlier_restart_handler() -> Json<Option<OutlierStats>> {
+    if let Some(outlier) = OUTLIER.get() {
+        *outlier.started.lock().unwrap() = Instant::now();
+        tracing::info!("Restarted the health check cycle and error ramp");
+    }
+    Json(get_stats())
+}
+
+// Global outlier simulation, set once at startup
+static OUTLIER: OnceCell<Outlier> = OnceCell::new();
@@ -0,0 +1,101 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use async_stream::try_stream;
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use std::time::Duration;
+
+/// How a body is handed to the socket, independent of how it was generated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlushPacing {
+    /// Pause between writes
+    pub interval_ms: Option<u64>,
+    /// Size of each write; the last one may be shorter
+    pub bytes: Option<usize>,
+}
+
+impl FlushPacing {
+    pub fn is_active(&self) -> bool {
+        self.interval_ms.is_some() || self.bytes.is_some()
+    }
+}
+
+/// Re-slice `body` into writes of `pacing.bytes` bytes, pausing `pacing.interval_ms`
+/// between them
+pub fn paced<S>(
+    body: S,
+    pacing: FlushPacing,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send
+where
+    S: Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static,
+{
+    try_stream! {
+        let mut body = Box::pin(body);
+        let mut buffer = BytesMut::new();
+        let mut writes = 0usize;
+
+        while let Some(piece) = body.next().await {
+            let piece = piece?;
+            let Some(size) = pacing.bytes else {
+                pause(writes, pacing).await;
+                writes += 1;
+                yield piece;
+                continue;
+            };
+
+            buffer.extend_from_slice(&piece);
+            while buffer.len() >= size {
+                pause(writes, pacing).await;
+                writes += 1;
+                yield buffer.split_to(size).freeze();
+            }
+        }
+
+        if !buffer.is_empty() {
+            pause(writes, pacing).await;
+            yield buffer.freeze();
+        }
+    }
+}
+
+async fn pause(writes: usize, pacing: FlushPacing) {
+    if let (true, Some(interval_ms)) = (writes > 0, pacing.interval_ms) {
+        tokio::time::sleep(Duration::from_millis(interval_ms)).await;
+    }
+}
+
+/// Pass `body` through, holding it back for `delay` once `after_bytes` bytes
+/// of it have gone out; the response headers are sent before any of it
+pub fn stalled<S>(
+    body: S,
+    after_bytes: usize,
+    delay: Duration,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send
+where
+    S: Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static,
+{
+    try_stream! {
+        let mut body = Box::pin(body);
+        let mut room = Some(after_bytes);
+
+        while let Some(piece) = body.next().await {
+            let mut piece = piece?;
+            if let Some(left) = room {
+                if piece.len() >= left {
+                    if left > 0 {
+                        yield piece.split_to(left);
+                    }
+                    tokio::time::sleep(delay).await;
+                    room = None;
+                } else {
+                    room = Some(left - piece.len());
+                }
+            }
+            if !piece.is_empty() {
+                yield piece;
+            }
+        }
+    }
+}
@@ -0,0 +1,330 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use axum::{
+    body::Body,
+    extract::{rejection::QueryRejection, Query, RawQuery},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use parquet::basic::{ConvertedType, Repetition, Type as PhysicalType};
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DoubleType, Int64Type};
+use parquet::errors::Result as ParquetResult;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedColumnWriter, SerializedFileWriter};
+use parquet::schema::types::Type;
+use rand::prelude::*;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+
+use crate::error::ApiError;
+use crate::generator::RandomDataGenerator;
+use crate::handlers::{relabel_content_type, resolve_targets, GarbleParams};
+use crate::metrics::METRICS;
+use crate::streaming::ResponseDecision;
+use crate::tenants::Tenant;
+use crate::validation::strict_query;
+
+/// Upper bound on the number of columns a single file may have
+const MAX_COLUMNS: usize = 256;
+
+/// Approximate fixed cost of the file header/footer and per-column metadata
+const FILE_OVERHEAD_BYTES: usize = 256;
+const COLUMN_OVERHEAD_BYTES: usize = 96;
+
+/// Percentage of values left null in nullable columns
+const NULL_PERCENT: u32 = 10;
+
+#[derive(Debug, Deserialize)]
+pub struct ParquetParams {
+    columns: Option<usize>,
+    #[serde(rename = "rowGroups")]
+    row_groups: Option<usize>,
+}
+
+/// Column types we know how to fill with random values
+#[derive(Debug, Clone, Copy)]
+enum ColumnKind {
+    Int64,
+    Double,
+    Boolean,
+    Utf8,
+}
+
+impl ColumnKind {
+    fn random(rng: &mut impl Rng) -> Self {
+        match rng.gen_range(0..4) {
+            0 => ColumnKind::Int64,
+            1 => ColumnKind::Double,
+            2 => ColumnKind::Boolean,
+            _ => ColumnKind::Utf8,
+        }
+    }
+
+    fn physical_type(&self) -> PhysicalType {
+        match self {
+            ColumnKind::Int64 => PhysicalType::INT64,
+            ColumnKind::Double => PhysicalType::DOUBLE,
+            ColumnKind::Boolean => PhysicalType::BOOLEAN,
+            ColumnKind::Utf8 => PhysicalType::BYTE_ARRAY,
+        }
+    }
+
+    /// Rough encoded size of a single value, used to derive the row count
+    fn estimated_bytes(&self) -> usize {
+        match self {
+            ColumnKind::Int64 | ColumnKind::Double => 8,
+            ColumnKind::Boolean => 1,
+            ColumnKind::Utf8 => 25, // 4 byte length prefix + ~21 chars
+        }
+    }
+}
+
+struct ColumnSpec {
+    name: String,
+    kind: ColumnKind,
+    nullable: bool,
+}
+
+/// Generates Parquet files with a random schema, sized to roughly hit a target
+pub struct ParquetGenerator {
+    columns: Vec<ColumnSpec>,
+    row_groups: usize,
+}
+
+impl ParquetGenerator {
+    pub fn new(column_count: usize, row_groups: usize) -> Self {
+        let mut rng = thread_rng();
+        let mut generator = RandomDataGenerator::new();
+        let mut names = HashSet::new();
+
+        let columns = (0..column_count)
+            .map(|_| {
+                // Column names need to be unique and path-safe, so skip the garbled charset
+                let mut name = generator.generate_random_string(rng.gen_range(4..16));
+                while !names.insert(name.clone()) {
+                    name.push_str(&generator.generate_random_string(2));
+                }
+                ColumnSpec {
+                    name,
+                    kind: ColumnKind::random(&mut rng),
+                    nullable: rng.gen_bool(0.5),
+                }
+            })
+            .collect();
+
+        Self {
+            columns,
+            row_groups: row_groups.max(1),
+        }
+    }
+
+    /// Write a complete Parquet file of approximately `target_size` bytes
+    pub fn generate(&self, target_size: usize) -> ParquetResult<Vec<u8>> {
+        let rows = self.rows_for_size(target_size);
+        let row_groups = self.row_groups.min(rows);
+
+        let properties = WriterProperties::builder()
+            // Dictionary pages make the output size unpredictable for random data
+            .set_dictionary_enabled(false)
+            .set_created_by(format!("daddle {}", env!("CARGO_PKG_VERSION")))
+            .build();
+
+        let mut buffer = Vec::with_capacity(target_size + FILE_OVERHEAD_BYTES);
+        let mut writer =
+            SerializedFileWriter::new(&mut buffer, self.schema()?, Arc::new(properties))?;
+
+        let mut rng = thread_rng();
+        let mut generator = RandomDataGenerator::new();
+
+        for group in 0..row_groups {
+            // Spread rows evenly, giving the remainder to the first groups
+            let group_rows = rows / row_groups + usize::from(group < rows % row_groups);
+            let mut row_group = writer.next_row_group()?;
+            let mut index = 0;
+
+            while let Some(mut column) = row_group.next_column()? {
+                self.write_column(
+                    &mut column,
+                    &self.columns[index],
+                    group_rows,
+                    &mut rng,
+                    &mut generator,
+                )?;
+                column.close()?;
+                index += 1;
+            }
+
+            row_group.close()?;
+        }
+
+        writer.close()?;
+        Ok(buffer)
+    }
+
+    fn schema(&self) -> ParquetResult<Arc<Type>> {
+        let fields = self
+            .columns
+            .iter()
+            .map(|column| {
+                let repetition = if column.nullable {
+                    Repetition::OPTIONAL
+                } else {
+                    Repetition::REQUIRED
+                };
+                let mut builder =
+                    Type::primitive_type_builder(&column.name, column.kind.physical_type())
+                        .with_repetition(repetition);
+                if let ColumnKind::Utf8 = column.kind {
+                    builder = builder.with_converted_type(ConvertedType::UTF8);
+                }
+                builder.build().map(Arc::new)
+            })
+            .collect::<ParquetResult<Vec<_>>>()?;
+
+        Ok(Arc::new(
+            Type::group_type_builder("garble")
+                .with_fields(fields)
+                .build()?,
+        ))
+    }
+
+    fn rows_for_size(&self, target_size: usize) -> usize {
+        let overhead = FILE_OVERHEAD_BYTES + self.columns.len() * COLUMN_OVERHEAD_BYTES;
+        let bytes_per_row: usize = self
+            .columns
+            .iter()
+            .map(|column| column.kind.estimated_bytes())
+            .sum();
+
+        (target_size.saturating_sub(overhead) / bytes_per_row.max(1)).max(1)
+    }
+
+    fn write_column(
+        &self,
+        column: &mut SerializedColumnWriter<'_>,
+        spec: &ColumnSpec,
+        rows: usize,
+        rng: &mut ThreadRng,
+        generator: &mut RandomDataGenerator,
+    ) -> ParquetResult<()> {
+        // Definition level 0 marks a null in an optional column
+        let def_levels: Option<Vec<i16>> = spec.nullable.then(|| {
+            (0..rows)
+                .map(|_| i16::from(rng.gen_ratio(100 - NULL_PERCENT, 100)))
+                .collect()
+        });
+        let present = def_levels
+            .as_ref()
+            .map(|levels| levels.iter().filter(|&&level| level == 1).count())
+            .unwrap_or(rows);
+        let def_levels = def_levels.as_deref();
+
+        match spec.kind {
+            ColumnKind::Int64 => {
+                let values: Vec<i64> = (0..present).map(|_| rng.gen()).collect();
+                column
+                    .typed::<Int64Type>()
+                    .write_batch(&values, def_levels, None)?;
+            }
+            ColumnKind::Double => {
+                let values: Vec<f64> = (0..present)
+                    .map(|_| rng.gen::<f64>() * rng.gen_range(-1e9..1e9))
+                    .collect();
+                column
+                    .typed::<DoubleType>()
+                    .write_batch(&values, def_levels, None)?;
+            }
+            ColumnKind::Boolean => {
+                let values: Vec<bool> = (0..present).map(|_| rng.gen()).collect();
+                column
+                    .typed::<BoolType>()
+                    .write_batch(&values, def_levels, None)?;
+            }
+            ColumnKind::Utf8 => {
+                let values: Vec<ByteArray> = (0..present)
+                    .map(|_| ByteArray::from(generator.generate_garbled_string().as_str()))
+                    .collect();
+                column
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, def_levels, None)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub async fn parquet_handler(
+    garble_params: Result<Query<GarbleParams>, QueryRejection>,
+    parquet_params: Result<Query<ParquetParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    tenant: Tenant,
+) -> Result<impl IntoResponse, ApiError> {
+    let strict = tenant.garble.strict_validation;
+    let garble_params = strict_query(garble_params, raw_query.as_deref(), strict)?;
+    let parquet_params = strict_query(parquet_params, raw_query.as_deref(), strict)?;
+
+    let (target_size, wait_duration_ms) = resolve_targets(&garble_params, &tenant.garble)?;
+    let content_type_label = garble_params.content_type_label()?;
+
+    if wait_duration_ms > 0 {
+        sleep(Duration::from_millis(wait_duration_ms)).await;
+    }
+
+    // File assembly is CPU bound, keep it off the async workers
+    let file = tokio::task::spawn_blocking(move || {
+        let mut rng = thread_rng();
+        let column_count = parquet_params
+            .columns
+            .unwrap_or_else(|| rng.gen_range(3..12))
+            .clamp(1, MAX_COLUMNS);
+        let row_groups = parquet_params
+            .row_groups
+            .unwrap_or_else(|| rng.gen_range(1..=4));
+
+        ParquetGenerator::new(column_count, row_groups).generate(target_size)
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("Parquet generation task failed: {}", e);
+        ApiError::internal("parquet generation task failed")
+    })?
+    .map_err(|e| {
+        tracing::error!("Parquet generation failed: {}", e);
+        ApiError::internal(format!("parquet generation failed: {}", e))
+    })?;
+
+    tracing::info!(
+        "Generated GARBLED parquet file: tenant={}, target_size={}B, actual_size={}B, wait={}ms",
+        tenant.name,
+        target_size,
+        file.len(),
+        wait_duration_ms
+    );
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/vnd.apache.parquet")
+        .header(
+            header::CONTENT_DISPOSITION,
+            r#"attachment; filename="garble.parquet""#,
+        )
+        .header("X-Garble-Mode", "parquet")
+        .body(Body::from(file))
+        .unwrap();
+    relabel_content_type(&mut response, content_type_label);
+    METRICS.record_response(&tenant.segment(), "parquet", target_size, wait_duration_ms);
+    ResponseDecision {
+        strategy: "parquet",
+        target_size,
+        wait_ms: wait_duration_ms,
+    }
+    .apply_headers(response.headers_mut());
+
+    Ok(response)
+}
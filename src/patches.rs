@@ -0,0 +1,545 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A seeded JSON document followed by a stream of RFC 6902 JSON Patches that
+//! mutate it over time, for testing clients that keep state from patch
+//! streams. Every patch applies cleanly to the document as left by the ones
+//! before it, except for deliberate conflicts: patches ending in a `test` that
+//! fails, which a client has to reject as a whole. Periodic snapshots of the
+//! document let clients check for drift.
+
+use async_stream::stream;
+use axum::{
+    body::{Body, Bytes},
+    extract::{rejection::QueryRejection, Query, RawQuery},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+use crate::drain::DRAIN;
+use crate::error::ApiError;
+use crate::generator::{GeneratorOptions, RandomDataGenerator};
+use crate::metrics::METRICS;
+use crate::tenants::Tenant;
+use crate::validation::strict_query;
+
+const DEFAULT_DOCUMENT_BYTES: usize = 2048;
+const MAX_DOCUMENT_BYTES: usize = 1_000_000;
+
+const DEFAULT_EVENT_INTERVAL_MS: u64 = 1000;
+const MAX_EVENT_INTERVAL_MS: u64 = 60_000;
+
+const DEFAULT_OPS_PER_PATCH: usize = 3;
+const MAX_OPS_PER_PATCH: usize = 100;
+
+/// Largest value copied by a `copy`, so copies cannot blow up the document
+const MAX_COPY_BYTES: usize = 1024;
+
+/// Chance of stopping at each level when picking a node, so shallow and deep
+/// nodes both get touched
+const STOP_PROBABILITY: f64 = 0.3;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PatchFormat {
+    /// One JSON message per line
+    #[default]
+    Ndjson,
+    /// Server-sent events, with the sequence number as the event id
+    Sse,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PatchParams {
+    #[serde(rename = "documentBytes")]
+    document_bytes: Option<usize>,
+    #[serde(rename = "eventIntervalMs")]
+    event_interval_ms: Option<u64>,
+    #[serde(rename = "maxEvents")]
+    max_events: Option<u64>,
+    #[serde(rename = "opsPerPatch")]
+    ops_per_patch: Option<usize>,
+    #[serde(rename = "snapshotEvery")]
+    snapshot_every: Option<u64>,
+    #[serde(rename = "conflictRate")]
+    conflict_rate: Option<f64>,
+    seed: Option<u64>,
+    format: Option<PatchFormat>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PatchSettings {
+    document_bytes: usize,
+    interval: Duration,
+    max_events: Option<u64>,
+    ops_per_patch: usize,
+    snapshot_every: u64,
+    conflict_rate: f64,
+    format: PatchFormat,
+}
+
+/// One step of a JSON Pointer
+#[derive(Debug, Clone)]
+enum Step {
+    Key(String),
+    Index(usize),
+    /// The `-` past the end of an array, only ever last
+    End,
+}
+
+fn pointer(path: &[Step]) -> String {
+    path.iter()
+        .map(|step| match step {
+            Step::Key(key) => format!("/{}", key.replace('~', "~0").replace('/', "~1")),
+            Step::Index(index) => format!("/{}", index),
+            Step::End => "/-".to_string(),
+        })
+        .collect()
+}
+
+fn get<'a>(mut value: &'a Value, path: &[Step]) -> &'a Value {
+    for step in path {
+        value = match (value, step) {
+            (Value::Object(map), Step::Key(key)) => &map[key],
+            (Value::Array(items), Step::Index(index)) => &items[*index],
+            _ => unreachable!("paths are picked from the document"),
+        };
+    }
+    value
+}
+
+fn get_mut<'a>(mut value: &'a mut Value, path: &[Step]) -> &'a mut Value {
+    for step in path {
+        value = match (value, step) {
+            (Value::Object(map), Step::Key(key)) => map.get_mut(key).unwrap(),
+            (Value::Array(items), Step::Index(index)) => &mut items[*index],
+            _ => unreachable!("paths are picked from the document"),
+        };
+    }
+    value
+}
+
+fn serialized_len(value: &Value) -> usize {
+    serde_json::to_string(value).map_or(0, |json| json.len())
+}
+
+/// A document and the generators that keep patching it
+struct PatchStream {
+    document: Value,
+    /// Serialized size of the document, kept up to date without reserializing it
+    size: usize,
+    generator: RandomDataGenerator,
+    rng: StdRng,
+    settings: PatchSettings,
+}
+
+impl PatchStream {
+    fn new(seed: u64, settings: PatchSettings) -> Self {
+        let mut generator = RandomDataGenerator::seeded(GeneratorOptions::default(), seed);
+        let document = generator.generate_payload(settings.document_bytes);
+        Self {
+            size: serialized_len(&document),
+            document,
+            generator,
+            rng: StdRng::seed_from_u64(seed.wrapping_add(1)),
+            settings,
+        }
+    }
+
+    /// A random node, walking down from the root and stopping at each level by chance
+    fn descend(&mut self, stop_probability: f64) -> Vec<Step> {
+        let mut path = Vec::new();
+        let mut node = &self.document;
+        loop {
+            let children = match node {
+                Value::Object(map) => map.len(),
+                Value::Array(items) => items.len(),
+                _ => 0,
+            };
+            if children == 0 || (!path.is_empty() && self.rng.gen_bool(stop_probability)) {
+                return path;
+            }
+            let index = self.rng.gen_range(0..children);
+            node = match node {
+                Value::Object(map) => {
+                    let (key, child) = map.iter().nth(index).unwrap();
+                    path.push(Step::Key(key.clone()));
+                    child
+                }
+                Value::Array(items) => {
+                    path.push(Step::Index(index));
+                    &items[index]
+                }
+                _ => unreachable!("only containers have children"),
+            };
+        }
+    }
+
+    /// A random object or array, which can be the root
+    fn container(&mut self) -> Vec<Step> {
+        let mut path = self.descend(STOP_PROBABILITY);
+        while !matches!(
+            get(&self.document, &path),
+            Value::Object(_) | Value::Array(_)
+        ) {
+            path.pop();
+        }
+        path
+    }
+
+    /// A random node other than the root, unless the document is empty
+    fn member(&mut self) -> Option<Vec<Step>> {
+        Some(self.descend(STOP_PROBABILITY)).filter(|path| !path.is_empty())
+    }
+
+    /// A random scalar or empty container, unless the document is empty
+    fn leaf(&mut self) -> Option<Vec<Step>> {
+        Some(self.descend(0.0)).filter(|path| !path.is_empty())
+    }
+
+    fn new_value(&mut self) -> Value {
+        if self.rng.gen_bool(0.2) {
+            let size = self.rng.gen_range(32..256);
+            self.generator.generate_sized_record(size)
+        } else {
+            self.generator.generate_scalar()
+        }
+    }
+
+    /// A free spot for a new member of a random container
+    fn new_location(&mut self) -> Vec<Step> {
+        let mut path = self.container();
+        let step = match get(&self.document, &path) {
+            Value::Object(map) => {
+                let mut key = self.generator.generate_random_key();
+                while map.contains_key(&key) {
+                    key = self.generator.generate_random_key();
+                }
+                Step::Key(key)
+            }
+            Value::Array(_) if self.rng.gen_bool(0.3) => Step::End,
+            Value::Array(items) => Step::Index(self.rng.gen_range(0..=items.len())),
+            _ => unreachable!("containers are objects or arrays"),
+        };
+        path.push(step);
+        path
+    }
+
+    fn insert(&mut self, path: &[Step], value: Value) {
+        let (last, parent) = path.split_last().expect("the root is never added to");
+        self.size += serialized_len(&value) + 1;
+        match (get_mut(&mut self.document, parent), last) {
+            (Value::Object(map), Step::Key(key)) => {
+                self.size += key.len() + 3;
+                map.insert(key.clone(), value);
+            }
+            (Value::Array(items), Step::Index(index)) => items.insert(*index, value),
+            (Value::Array(items), Step::End) => items.push(value),
+            _ => unreachable!("locations are picked from containers"),
+        }
+    }
+
+    fn remove(&mut self, path: &[Step]) -> Value {
+        let (last, parent) = path.split_last().expect("the root is never removed");
+        let removed = match (get_mut(&mut self.document, parent), last) {
+            (Value::Object(map), Step::Key(key)) => {
+                self.size = self.size.saturating_sub(key.len() + 3);
+                map.remove(key).unwrap()
+            }
+            (Value::Array(items), Step::Index(index)) => items.remove(*index),
+            _ => unreachable!("paths are picked from the document"),
+        };
+        self.size = self.size.saturating_sub(serialized_len(&removed) + 1);
+        removed
+    }
+
+    fn add_op(&mut self) -> Value {
+        let path = self.new_location();
+        let value = self.new_value();
+        let op = json!({ "op": "add", "path": pointer(&path), "value": value });
+        self.insert(&path, value);
+        op
+    }
+
+    /// One operation, applied to the document, leaning toward growing or
+    /// shrinking it to keep near the requested size
+    fn operation(&mut self) -> Value {
+        let target = self.settings.document_bytes;
+        let weights: [u32; 6] = if self.size > target + target / 4 {
+            // add, remove, replace, move, copy, test
+            [1, 6, 2, 2, 0, 1]
+        } else if self.size < target - target / 4 {
+            [6, 1, 2, 2, 2, 1]
+        } else {
+            [3, 3, 3, 2, 1, 1]
+        };
+        let kind = rand::distributions::WeightedIndex::new(weights)
+            .unwrap()
+            .sample(&mut self.rng);
+
+        match kind {
+            1 => {
+                let Some(path) = self.member() else {
+                    return self.add_op();
+                };
+                self.remove(&path);
+                json!({ "op": "remove", "path": pointer(&path) })
+            }
+            2 => {
+                let Some(path) = self.member() else {
+                    return self.add_op();
+                };
+                let value = self.new_value();
+                let target = get_mut(&mut self.document, &path);
+                let old_len = serialized_len(target);
+                *target = value.clone();
+                self.size = (self.size + serialized_len(&value)).saturating_sub(old_len);
+                json!({ "op": "replace", "path": pointer(&path), "value": value })
+            }
+            3 => {
+                let Some(from) = self.member() else {
+                    return self.add_op();
+                };
+                // A move is a remove followed by an add, so the destination is
+                // picked after the removal and can never lie inside what moved
+                let value = self.remove(&from);
+                let path = self.new_location();
+                self.insert(&path, value);
+                json!({ "op": "move", "from": pointer(&from), "path": pointer(&path) })
+            }
+            4 => {
+                let Some(from) = self.member() else {
+                    return self.add_op();
+                };
+                let value = get(&self.document, &from).clone();
+                if serialized_len(&value) > MAX_COPY_BYTES {
+                    return self.add_op();
+                }
+                let path = self.new_location();
+                self.insert(&path, value);
+                json!({ "op": "copy", "from": pointer(&from), "path": pointer(&path) })
+            }
+            5 => {
+                let Some(path) = self.leaf() else {
+                    return self.add_op();
+                };
+                let value = get(&self.document, &path).clone();
+                json!({ "op": "test", "path": pointer(&path), "value": value })
+            }
+            _ => self.add_op(),
+        }
+    }
+
+    /// A `test` that fails against the current document, unless it is empty
+    fn failing_test(&mut self) -> Option<Value> {
+        let path = self.leaf()?;
+        let current = get(&self.document, &path).clone();
+        let mut value = self.generator.generate_scalar();
+        while value == current {
+            value = self.generator.generate_scalar();
+        }
+        Some(json!({ "op": "test", "path": pointer(&path), "value": value }))
+    }
+
+    /// The next patch. A conflicting patch is built against a copy of the
+    /// document and ends in a failing `test`, so a client that applies
+    /// operations one by one without rolling back drifts from the stream.
+    fn patch(&mut self) -> Vec<Value> {
+        let count = self.rng.gen_range(1..=self.settings.ops_per_patch);
+        let conflict = self.rng.gen_bool(self.settings.conflict_rate);
+        let saved = conflict.then(|| (self.document.clone(), self.size));
+
+        let mut operations: Vec<Value> = (0..count).map(|_| self.operation()).collect();
+
+        if let Some((document, size)) = saved {
+            if let Some(test) = self.failing_test() {
+                operations.push(test);
+            }
+            self.document = document;
+            self.size = size;
+        }
+        operations
+    }
+}
+
+#[derive(Serialize)]
+struct Message<'a> {
+    seq: u64,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    document: Option<&'a Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    patch: Option<&'a [Value]>,
+}
+
+impl Message<'_> {
+    fn encode(&self, format: PatchFormat, out: &mut String) {
+        match format {
+            PatchFormat::Ndjson => {
+                out.push_str(&serde_json::to_string(self).unwrap_or_default());
+                out.push('\n');
+            }
+            PatchFormat::Sse => {
+                let data = match (self.document, self.patch) {
+                    (Some(document), _) => serde_json::to_string(document),
+                    (None, patch) => serde_json::to_string(&patch.unwrap_or_default()),
+                };
+                out.push_str(&format!(
+                    "id: {}\nevent: {}\ndata: {}\n\n",
+                    self.seq,
+                    self.kind,
+                    data.unwrap_or_default()
+                ));
+            }
+        }
+    }
+}
+
+/// The initial snapshot, then a patch every interval with snapshots in
+/// between, until `maxEvents` patches were sent, the client disconnects or
+/// the instance starts draining
+fn messages(
+    seed: u64,
+    settings: PatchSettings,
+) -> impl futures::Stream<Item = Result<Bytes, std::io::Error>> + Send {
+    stream! {
+        // Generation is CPU bound, keep it off the async workers
+        let Ok((mut state, first)) = tokio::task::spawn_blocking(move || {
+            let state = PatchStream::new(seed, settings);
+            let mut out = String::new();
+            Message { seq: 0, kind: "snapshot", document: Some(&state.document), patch: None }
+                .encode(settings.format, &mut out);
+            (state, out)
+        })
+        .await
+        else {
+            return;
+        };
+        yield Ok(Bytes::from(first));
+
+        let mut ticker = tokio::time::interval(settings.interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        ticker.tick().await;
+        let mut seq = 0;
+
+        while settings.max_events.is_none_or(|max| seq < max) {
+            ticker.tick().await;
+            if DRAIN.is_draining() {
+                break;
+            }
+            seq += 1;
+
+            let Ok((next, out)) = tokio::task::spawn_blocking(move || {
+                let patch = state.patch();
+                let mut out = String::new();
+                Message { seq, kind: "patch", document: None, patch: Some(&patch) }
+                    .encode(settings.format, &mut out);
+                if settings.snapshot_every > 0 && seq % settings.snapshot_every == 0 {
+                    Message { seq, kind: "snapshot", document: Some(&state.document), patch: None }
+                        .encode(settings.format, &mut out);
+                }
+                (state, out)
+            })
+            .await
+            else {
+                break;
+            };
+            state = next;
+            yield Ok(Bytes::from(out));
+        }
+    }
+}
+
+pub async fn patches_handler(
+    params: Result<Query<PatchParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    tenant: Tenant,
+) -> Result<Response, ApiError> {
+    if DRAIN.is_draining() {
+        return Err(ApiError::draining());
+    }
+
+    let params = strict_query(
+        params,
+        raw_query.as_deref(),
+        tenant.garble.strict_validation,
+    )?;
+    let document_bytes = params.document_bytes.unwrap_or(DEFAULT_DOCUMENT_BYTES);
+    if document_bytes == 0 || document_bytes > MAX_DOCUMENT_BYTES {
+        return Err(ApiError::bad_request(format!(
+            "documentBytes must be between 1 and {}, got {}",
+            MAX_DOCUMENT_BYTES, document_bytes
+        )));
+    }
+    let interval_ms = params
+        .event_interval_ms
+        .unwrap_or(DEFAULT_EVENT_INTERVAL_MS);
+    if interval_ms == 0 || interval_ms > MAX_EVENT_INTERVAL_MS {
+        return Err(ApiError::bad_request(format!(
+            "eventIntervalMs must be between 1 and {}, got {}",
+            MAX_EVENT_INTERVAL_MS, interval_ms
+        )));
+    }
+    let ops_per_patch = params.ops_per_patch.unwrap_or(DEFAULT_OPS_PER_PATCH);
+    if ops_per_patch == 0 || ops_per_patch > MAX_OPS_PER_PATCH {
+        return Err(ApiError::bad_request(format!(
+            "opsPerPatch must be between 1 and {}, got {}",
+            MAX_OPS_PER_PATCH, ops_per_patch
+        )));
+    }
+    let conflict_rate = params.conflict_rate.unwrap_or(0.0);
+    if !(0.0..=1.0).contains(&conflict_rate) {
+        return Err(ApiError::bad_request(format!(
+            "conflictRate must be between 0 and 1, got {}",
+            conflict_rate
+        )));
+    }
+    let settings = PatchSettings {
+        document_bytes,
+        interval: Duration::from_millis(interval_ms),
+        max_events: params.max_events,
+        ops_per_patch,
+        snapshot_every: params.snapshot_every.unwrap_or(0),
+        conflict_rate,
+        format: params.format.unwrap_or_default(),
+    };
+    // Without a seed one is picked, and reported so the stream can be had again
+    let seed = params.seed.unwrap_or_else(|| thread_rng().gen());
+
+    tracing::info!(
+        "Opened patch stream: tenant={}, seed={}, document={}B, event_interval={}ms, conflict_rate={}",
+        tenant.name,
+        seed,
+        document_bytes,
+        interval_ms,
+        conflict_rate
+    );
+    METRICS.record_response(&tenant.segment(), "patches", document_bytes, 0);
+
+    let content_type = match settings.format {
+        PatchFormat::Ndjson => "application/x-ndjson",
+        PatchFormat::Sse => "text/event-stream",
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+            (
+                header::HeaderName::from_static("x-garble-mode"),
+                "patches".to_string(),
+            ),
+            (
+                header::HeaderName::from_static("x-garble-seed"),
+                seed.to_string(),
+            ),
+        ],
+        Body::from_stream(messages(seed, settings)),
+    )
+        .into_response())
+}
@@ -0,0 +1,163 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Faults attached to path patterns in the `path_faults` config section, so
+//! one instance can model a slow search endpoint next to a healthy profile
+//! endpoint. Unlike the chaos flags they apply to every endpoint, custom
+//! routes included, and are always on.
+
+use anyhow::Context;
+use axum::{
+    extract::Request,
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use once_cell::sync::OnceCell;
+use rand::prelude::*;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::config::PathFaultConfig;
+use crate::error::ApiError;
+use crate::flags;
+use crate::routes::{self, PathPattern};
+use crate::rules::EXEMPT_PREFIXES;
+
+/// Size truncation is cut within when the response does not declare its length
+const UNKNOWN_LENGTH_TRUNCATION_BYTES: usize = 64 * 1024;
+
+struct PathFault {
+    path: String,
+    pattern: PathPattern,
+    /// `None` applies to every method
+    method: Option<Method>,
+    error_rate: f64,
+    error_status: StatusCode,
+    latency: Duration,
+    latency_rate: f64,
+    truncation_rate: f64,
+    matched: AtomicU64,
+    errors: AtomicU64,
+    delayed: AtomicU64,
+    truncated: AtomicU64,
+}
+
+impl PathFault {
+    fn load(config: &PathFaultConfig) -> anyhow::Result<Self> {
+        config.validate()?;
+        Ok(Self {
+            path: config.path.clone(),
+            pattern: PathPattern::parse(&config.path)?,
+            method: routes::parse_method(&config.method)?,
+            error_rate: config.error_rate,
+            error_status: StatusCode::from_u16(config.error_status)?,
+            latency: Duration::from_millis(config.latency_ms),
+            latency_rate: config.latency_rate,
+            truncation_rate: config.truncation_rate,
+            matched: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            delayed: AtomicU64::new(0),
+            truncated: AtomicU64::new(0),
+        })
+    }
+
+    fn applies_to(&self, method: &Method, path: &str) -> bool {
+        self.method.as_ref().is_none_or(|m| m == method) && self.pattern.matches(path).is_some()
+    }
+
+    /// Roll for a fault, counting it when it fires
+    fn roll(&self, rate: f64, counter: &AtomicU64) -> bool {
+        if rate <= 0.0 || !thread_rng().gen_bool(rate) {
+            return false;
+        }
+        counter.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PathFaultStats {
+    pub path: String,
+    pub matched: u64,
+    pub errors: u64,
+    pub delayed: u64,
+    pub truncated: u64,
+}
+
+/// Load the path faults; call once at startup
+pub fn load(configs: &[PathFaultConfig]) -> anyhow::Result<()> {
+    let faults = configs
+        .iter()
+        .map(|config| {
+            PathFault::load(config).with_context(|| format!("invalid path fault {}", config.path))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if !faults.is_empty() {
+        tracing::info!("Loaded {} path faults", faults.len());
+    }
+    let _ = PATH_FAULTS.set(faults);
+    Ok(())
+}
+
+/// How often each path fault matched and fired, in definition order
+pub fn get_stats() -> Vec<PathFaultStats> {
+    PATH_FAULTS
+        .get()
+        .map(|faults| {
+            faults
+                .iter()
+                .map(|fault| PathFaultStats {
+                    path: fault.path.clone(),
+                    matched: fault.matched.load(Ordering::Relaxed),
+                    errors: fault.errors.load(Ordering::Relaxed),
+                    delayed: fault.delayed.load(Ordering::Relaxed),
+                    truncated: fault.truncated.load(Ordering::Relaxed),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Middleware injecting the faults of the first path fault matching the request
+pub async fn inject_path_faults(request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    if EXEMPT_PREFIXES
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+    {
+        return next.run(request).await;
+    }
+    let Some(fault) = PATH_FAULTS.get().and_then(|faults| {
+        faults
+            .iter()
+            .find(|fault| fault.applies_to(request.method(), path))
+    }) else {
+        return next.run(request).await;
+    };
+    fault.matched.fetch_add(1, Ordering::Relaxed);
+
+    if !fault.latency.is_zero() && fault.roll(fault.latency_rate, &fault.delayed) {
+        tokio::time::sleep(fault.latency).await;
+    }
+    if fault.roll(fault.error_rate, &fault.errors) {
+        return ApiError::path_fault(fault.error_status, &fault.path).into_response();
+    }
+
+    let response = next.run(request).await;
+    if fault.roll(fault.truncation_rate, &fault.truncated) {
+        let length = response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(UNKNOWN_LENGTH_TRUNCATION_BYTES);
+        return flags::truncate(response, length);
+    }
+    response
+}
+
+// Global path faults, loaded once at startup
+static PATH_FAULTS: OnceCell<Vec<PathFault>> = OnceCell::new();
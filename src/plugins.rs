@@ -0,0 +1,282 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Named value generators for values garble cannot produce on its own, such
+//! as valid IBANs or an in-house ID format. Templates invoke them with
+//! `$gen(name)`, and the `generators` config mixes them into garble by weight.
+//! Besides the built-ins and config patterns, embedders can register any
+//! `ValueGenerator` or closure before calling `configure`.
+
+use anyhow::Context;
+use once_cell::sync::{Lazy, OnceCell};
+use rand::distributions::WeightedIndex;
+use rand::prelude::*;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use crate::config::{GeneratorsConfig, PatternGeneratorConfig};
+
+/// A custom generator of single values
+pub trait ValueGenerator: Send + Sync {
+    /// Produce one value, drawing all randomness from `rng` so seeded output repeats
+    fn generate(&self, rng: &mut dyn RngCore) -> Value;
+}
+
+impl<F> ValueGenerator for F
+where
+    F: Fn(&mut dyn RngCore) -> Value + Send + Sync,
+{
+    fn generate(&self, rng: &mut dyn RngCore) -> Value {
+        self(rng)
+    }
+}
+
+/// A registered generator, cheap to clone into compiled templates
+#[derive(Clone)]
+pub struct Plugin {
+    name: Arc<str>,
+    generator: Arc<dyn ValueGenerator>,
+}
+
+impl Plugin {
+    pub fn generate(&self, rng: &mut dyn RngCore) -> Value {
+        self.generator.generate(rng)
+    }
+}
+
+impl fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Plugin").field(&self.name).finish()
+    }
+}
+
+/// Register a generator under `name`; names must be unique
+pub fn register(name: &str, generator: impl ValueGenerator + 'static) -> anyhow::Result<()> {
+    let mut registry = REGISTRY.write().unwrap();
+    if registry.contains_key(name) {
+        anyhow::bail!("a value generator named {} is already registered", name);
+    }
+    registry.insert(
+        name.to_string(),
+        Plugin {
+            name: Arc::from(name),
+            generator: Arc::new(generator),
+        },
+    );
+    Ok(())
+}
+
+/// Register a closure under `name`, such as
+/// `register_generator("email", |rng| ...)`; the closure form of `register`
+pub fn register_generator<F>(name: &str, generator: F) -> anyhow::Result<()>
+where
+    F: Fn(&mut dyn RngCore) -> Value + Send + Sync + 'static,
+{
+    register(name, generator)
+}
+
+/// The generator registered under `name`
+pub fn get(name: &str) -> Option<Plugin> {
+    REGISTRY.read().unwrap().get(name).cloned()
+}
+
+/// Names of all registered generators, sorted
+pub fn names() -> Vec<String> {
+    let mut names: Vec<_> = REGISTRY.read().unwrap().keys().cloned().collect();
+    names.sort();
+    names
+}
+
+/// Generators mixed into garble in place of ordinary scalars
+pub struct PluginMix {
+    usage_ratio: f64,
+    plugins: Vec<Plugin>,
+    weights: WeightedIndex<u32>,
+}
+
+impl PluginMix {
+    /// A value from one of the generators, or `None` when this scalar stays ordinary garble
+    pub fn sample(&self, rng: &mut impl Rng) -> Option<Value> {
+        if !rng.gen_bool(self.usage_ratio) {
+            return None;
+        }
+        let plugin = &self.plugins[self.weights.sample(rng)];
+        Some(plugin.generate(rng))
+    }
+}
+
+/// Register the configured pattern generators and set up the garble mix; call
+/// once at startup, before templates are compiled
+pub fn configure(config: &GeneratorsConfig) -> anyhow::Result<()> {
+    config.validate()?;
+    for pattern in &config.patterns {
+        let generator = PatternGenerator::parse(pattern)
+            .with_context(|| format!("invalid pattern generator {}", pattern.name))?;
+        register(&pattern.name, generator)?;
+    }
+    tracing::info!("Value generators: {}", names().join(", "));
+
+    if config.usage_ratio > 0.0 {
+        let plugins = config
+            .weights
+            .keys()
+            .map(|name| {
+                get(name).ok_or_else(|| anyhow::anyhow!("unknown value generator {}", name))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let weights = WeightedIndex::new(config.weights.values().copied())?;
+        let _ = MIX.set(PluginMix {
+            usage_ratio: config.usage_ratio,
+            plugins,
+            weights,
+        });
+    }
+    Ok(())
+}
+
+/// The garble mix, when generators are mixed into garble
+pub fn mix() -> Option<&'static PluginMix> {
+    MIX.get()
+}
+
+/// One position of a pattern
+#[derive(Debug, Clone, Copy)]
+enum Slot {
+    Literal(char),
+    Digit,
+    Upper,
+    Lower,
+    Hex,
+    Alphanumeric,
+}
+
+/// Strings following a pattern such as `ORD-####-AA`
+struct PatternGenerator {
+    slots: Vec<Slot>,
+}
+
+impl PatternGenerator {
+    fn parse(config: &PatternGeneratorConfig) -> anyhow::Result<Self> {
+        if config.pattern.is_empty() {
+            anyhow::bail!("pattern is empty");
+        }
+        let mut slots = Vec::new();
+        let mut chars = config.pattern.chars();
+        while let Some(c) = chars.next() {
+            slots.push(match c {
+                '#' => Slot::Digit,
+                'A' => Slot::Upper,
+                'a' => Slot::Lower,
+                'X' => Slot::Hex,
+                '?' => Slot::Alphanumeric,
+                '\\' => Slot::Literal(
+                    chars
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("pattern ends with a lone backslash"))?,
+                ),
+                literal => Slot::Literal(literal),
+            });
+        }
+        Ok(Self { slots })
+    }
+}
+
+impl ValueGenerator for PatternGenerator {
+    fn generate(&self, rng: &mut dyn RngCore) -> Value {
+        const ALPHANUMERIC: &[u8] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+        const HEX: &[u8] = b"0123456789ABCDEF";
+        let text = self
+            .slots
+            .iter()
+            .map(|slot| match slot {
+                Slot::Literal(c) => *c,
+                Slot::Digit => char::from(b'0' + rng.gen_range(0..10)),
+                Slot::Upper => char::from(b'A' + rng.gen_range(0..26)),
+                Slot::Lower => char::from(b'a' + rng.gen_range(0..26)),
+                Slot::Hex => char::from(HEX[rng.gen_range(0..HEX.len())]),
+                Slot::Alphanumeric => {
+                    char::from(ALPHANUMERIC[rng.gen_range(0..ALPHANUMERIC.len())])
+                }
+            })
+            .collect();
+        Value::String(text)
+    }
+}
+
+/// Countries and the layout of their basic bank account numbers
+const IBAN_LAYOUTS: &[(&str, &str)] = &[
+    ("DE", "##################"),
+    ("GB", "AAAA##############"),
+    ("NL", "AAAA##########"),
+    ("FR", "#######################"),
+    ("ES", "####################"),
+];
+
+/// An IBAN with valid ISO 13616 check digits
+fn iban(rng: &mut dyn RngCore) -> Value {
+    let (country, layout) = IBAN_LAYOUTS[rng.gen_range(0..IBAN_LAYOUTS.len())];
+    let bban: String = layout
+        .chars()
+        .map(|slot| match slot {
+            '#' => char::from(b'0' + rng.gen_range(0..10)),
+            _ => char::from(b'A' + rng.gen_range(0..26)),
+        })
+        .collect();
+
+    // Check digits make the account, country and digits read as a number equal 1 mod 97
+    let remainder = format!("{}{}00", bban, country)
+        .chars()
+        .fold(0u32, |remainder, c| {
+            let value = c.to_digit(36).unwrap_or(0);
+            let width = if value < 10 { 10 } else { 100 };
+            (remainder * width + value) % 97
+        });
+    Value::String(format!("{}{:02}{}", country, 98 - remainder, bban))
+}
+
+/// A 16 digit card number in the Visa range with a valid Luhn check digit
+fn card_number(rng: &mut dyn RngCore) -> Value {
+    let mut digits: Vec<u32> = std::iter::once(4)
+        .chain((0..14).map(|_| rng.gen_range(0..10)))
+        .collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &digit)| match i % 2 {
+            // Doubled digits, counting from the one left of the check digit
+            0 => (digit * 2) / 10 + (digit * 2) % 10,
+            _ => digit,
+        })
+        .sum();
+    digits.push((10 - sum % 10) % 10);
+    Value::String(digits.iter().map(|digit| digit.to_string()).collect())
+}
+
+// Global registry, holding the built-in generators from the start
+static REGISTRY: Lazy<RwLock<HashMap<String, Plugin>>> = Lazy::new(|| {
+    let builtins: [(&str, Arc<dyn ValueGenerator>); 2] = [
+        ("iban", Arc::new(iban)),
+        ("card_number", Arc::new(card_number)),
+    ];
+    let registry = builtins
+        .into_iter()
+        .map(|(name, generator)| {
+            (
+                name.to_string(),
+                Plugin {
+                    name: Arc::from(name),
+                    generator,
+                },
+            )
+        })
+        .collect();
+    RwLock::new(registry)
+});
+
+// Global garble mix, set once at startup
+static MIX: OnceCell<PluginMix> = OnceCell::new();
@@ -0,0 +1,450 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Chains of transformations applied to a finished garble body, selected with
+//! `?postProcess=`, so composite payloads such as a gzipped, base64-wrapped
+//! document inside a JSON envelope take one parameter. A chain is a comma
+//! separated list of steps, or the name of one kept in the `post_processors`
+//! config. The body is assembled whole before the first step runs.
+//!
+//! Corrupting steps damage bytes at random positions, and a `digest` step
+//! records the SHA-256 of the body as it is at that point in a
+//! `Content-Digest` header, so a digest taken before the damage makes clients
+//! that verify checksums reject the body.
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, HeaderValue},
+    response::Response,
+};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use once_cell::sync::OnceCell;
+use rand::Rng;
+use serde::de::IgnoredAny;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::error::ApiError;
+
+/// Most steps in one chain
+const MAX_STEPS: usize = 16;
+
+/// Longest accepted envelope field name
+const MAX_FIELD_LENGTH: usize = 128;
+
+/// Brotli quality and window of `br` steps
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+/// How many bytes a corrupting step damages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Amount {
+    Bytes(usize),
+    /// Share of the body, from 0 to 100
+    Percent(f64),
+}
+
+impl Amount {
+    fn parse(amount: &str) -> Option<Self> {
+        match amount.strip_suffix('%') {
+            Some(percent) => percent
+                .parse()
+                .ok()
+                .filter(|percent: &f64| (0.0..=100.0).contains(percent))
+                .map(Amount::Percent),
+            None => amount.parse().ok().map(Amount::Bytes),
+        }
+    }
+
+    /// Bytes damaged in a body of `len` bytes
+    fn of(&self, len: usize) -> usize {
+        match *self {
+            Amount::Bytes(bytes) => bytes.min(len),
+            Amount::Percent(percent) => (len as f64 * percent / 100.0).round() as usize,
+        }
+    }
+
+    fn label(&self) -> String {
+        match self {
+            Amount::Bytes(bytes) => bytes.to_string(),
+            Amount::Percent(percent) => format!("{}%", percent),
+        }
+    }
+}
+
+/// One transformation of the body
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    Gzip,
+    Brotli,
+    /// Overwrite bytes at random positions with different values
+    Corrupt(Amount),
+    /// Flip a single bit of bytes at random positions
+    Flip(Amount),
+    /// Record the SHA-256 of the body so far in `Content-Digest`
+    Digest,
+    Base64,
+    /// Wrap the body as the value of this field of a JSON object
+    Envelope(String),
+    /// Wrap the body as a JSON string in this field of a JSON object
+    Stringify(String),
+}
+
+/// What the steps of a chain leave behind besides the body
+#[derive(Debug, Default)]
+struct Outcome {
+    /// Value of the `Content-Digest` header, from the last `digest` step
+    digest: Option<String>,
+    /// Bytes damaged by corrupting steps
+    corrupted: usize,
+    /// Whether the last step left an envelope, which needs no checking to be
+    /// known as JSON
+    enveloped: bool,
+}
+
+impl Step {
+    fn parse(step: &str) -> Result<Self, String> {
+        let (name, argument) = match step.split_once(':') {
+            Some((name, argument)) => (name, Some(argument)),
+            None => (step, None),
+        };
+        match (name, argument) {
+            ("gzip", None) => Ok(Step::Gzip),
+            ("br", None) => Ok(Step::Brotli),
+            ("digest", None) => Ok(Step::Digest),
+            ("base64", None) => Ok(Step::Base64),
+            ("corrupt" | "flip", Some(amount)) => {
+                let amount = Amount::parse(amount).ok_or_else(|| {
+                    format!(
+                        "{} needs a byte count or a percentage up to 100%, got {:?}",
+                        name, amount
+                    )
+                })?;
+                Ok(match name {
+                    "corrupt" => Step::Corrupt(amount),
+                    _ => Step::Flip(amount),
+                })
+            }
+            ("envelope", Some(field)) if valid_field(field) => {
+                Ok(Step::Envelope(field.to_string()))
+            }
+            ("stringify", Some(field)) if valid_field(field) => {
+                Ok(Step::Stringify(field.to_string()))
+            }
+            ("envelope" | "stringify", _) => Err(format!(
+                "{} needs a field name of 1 to {} characters, as in {}:data",
+                name, MAX_FIELD_LENGTH, name
+            )),
+            ("corrupt" | "flip", None) => Err(format!(
+                "{} needs a byte count or a percentage, as in {}:16 or {}:0.5%",
+                name, name, name
+            )),
+            _ => Err(format!(
+                "unknown post-processing step {:?}; expected gzip, br, corrupt:N, flip:N, digest, base64, envelope:FIELD or stringify:FIELD",
+                step
+            )),
+        }
+    }
+
+    /// Content type of the body after this step, if the step changes it
+    fn content_type(&self) -> Option<&'static str> {
+        match self {
+            Step::Gzip => Some("application/gzip"),
+            Step::Brotli => Some("application/x-brotli"),
+            Step::Corrupt(_) | Step::Flip(_) | Step::Digest => None,
+            Step::Base64 => Some("text/plain"),
+            Step::Envelope(_) | Step::Stringify(_) => Some("application/json"),
+        }
+    }
+
+    fn label(&self) -> String {
+        match self {
+            Step::Gzip => "gzip".to_string(),
+            Step::Brotli => "br".to_string(),
+            Step::Corrupt(amount) => format!("corrupt:{}", amount.label()),
+            Step::Flip(amount) => format!("flip:{}", amount.label()),
+            Step::Digest => "digest".to_string(),
+            Step::Base64 => "base64".to_string(),
+            Step::Envelope(field) => format!("envelope:{}", field),
+            Step::Stringify(field) => format!("stringify:{}", field),
+        }
+    }
+
+    fn apply(&self, body: Vec<u8>, outcome: &mut Outcome) -> Vec<u8> {
+        let enveloped = std::mem::take(&mut outcome.enveloped);
+        match self {
+            Step::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                let _ = encoder.write_all(&body);
+                encoder.finish().unwrap_or_default()
+            }
+            Step::Brotli => {
+                let mut compressed = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(
+                        &mut compressed,
+                        4096,
+                        BROTLI_QUALITY,
+                        BROTLI_WINDOW,
+                    );
+                    let _ = encoder.write_all(&body);
+                }
+                compressed
+            }
+            Step::Corrupt(amount) => {
+                let count = amount.of(body.len());
+                outcome.corrupted += count;
+                damage(body, count, |rng| rng.gen_range(1..=u8::MAX))
+            }
+            Step::Flip(amount) => {
+                let count = amount.of(body.len());
+                outcome.corrupted += count;
+                damage(body, count, |rng| 1 << rng.gen_range(0..8))
+            }
+            Step::Digest => {
+                outcome.digest = Some(format!(
+                    "sha-256=:{}:",
+                    STANDARD.encode(Sha256::digest(&body))
+                ));
+                body
+            }
+            Step::Base64 => STANDARD.encode(&body).into_bytes(),
+            Step::Envelope(field) => {
+                // JSON goes in as it is, anything else as a string
+                let json = enveloped || serde_json::from_slice::<IgnoredAny>(&body).is_ok();
+                outcome.enveloped = true;
+                wrap(field, if json { body } else { string_value(body) })
+            }
+            Step::Stringify(field) => {
+                outcome.enveloped = true;
+                wrap(field, string_value(body))
+            }
+        }
+    }
+}
+
+fn valid_field(field: &str) -> bool {
+    !field.is_empty() && field.len() <= MAX_FIELD_LENGTH
+}
+
+/// `body` as a JSON string: its text, or its base64 encoding if it is binary
+fn string_value(body: Vec<u8>) -> Vec<u8> {
+    let text = String::from_utf8(body).unwrap_or_else(|e| STANDARD.encode(e.into_bytes()));
+    serde_json::to_vec(&text).unwrap_or_default()
+}
+
+/// A JSON object with `value`, already JSON, as its only member `field`
+fn wrap(field: &str, value: Vec<u8>) -> Vec<u8> {
+    let key = serde_json::to_string(field).unwrap_or_default();
+    let mut envelope = Vec::with_capacity(key.len() + value.len() + 3);
+    envelope.push(b'{');
+    envelope.extend_from_slice(key.as_bytes());
+    envelope.push(b':');
+    envelope.extend(value);
+    envelope.push(b'}');
+    envelope
+}
+
+/// `count` distinct bytes of `body`, at most all of them, XORed with a
+/// non-zero `mask`, so every one of them changes
+fn damage(
+    mut body: Vec<u8>,
+    count: usize,
+    mask: impl Fn(&mut rand::rngs::ThreadRng) -> u8,
+) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let count = count.min(body.len());
+    for position in rand::seq::index::sample(&mut rng, body.len(), count) {
+        body[position] ^= mask(&mut rng);
+    }
+    body
+}
+
+/// A parsed chain of steps, with the text it was given as
+#[derive(Debug, Clone)]
+pub struct Chain {
+    label: String,
+    steps: Vec<Step>,
+}
+
+impl Chain {
+    fn parse(steps: &[&str]) -> Result<Self, String> {
+        if steps.is_empty() || steps.len() > MAX_STEPS {
+            return Err(format!(
+                "a post-processing chain needs 1 to {} steps, got {}",
+                MAX_STEPS,
+                steps.len()
+            ));
+        }
+        let steps = steps
+            .iter()
+            .map(|step| Step::parse(step.trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Chain::of(steps))
+    }
+
+    fn of(steps: Vec<Step>) -> Self {
+        let label = steps.iter().map(Step::label).collect::<Vec<_>>().join(",");
+        Chain { label, steps }
+    }
+
+    /// `layers` envelopes around the body, each an object whose only member
+    /// `field` holds the next, or with `stringify` the next as a JSON string
+    pub fn nested(layers: usize, field: &str, stringify: bool) -> Result<Self, String> {
+        if !valid_field(field) {
+            return Err(format!(
+                "envelopeKey must be 1 to {} characters",
+                MAX_FIELD_LENGTH
+            ));
+        }
+        let step = match stringify {
+            true => Step::Stringify(field.to_string()),
+            false => Step::Envelope(field.to_string()),
+        };
+        Ok(Chain::of(vec![step; layers]))
+    }
+
+    /// This chain followed by the steps of `next`
+    pub fn then(self, next: Chain) -> Self {
+        Chain::of(self.steps.into_iter().chain(next.steps).collect())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PostProcessStats {
+    /// Bodies run through a chain
+    pub responses: u64,
+    /// Bytes of those bodies before and after their chain
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Bytes damaged by `corrupt` and `flip` steps
+    pub corrupted_bytes: u64,
+}
+
+struct Counters {
+    responses: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    corrupted_bytes: AtomicU64,
+}
+
+/// Check and keep the named chains; call once at startup
+pub fn load(configs: &BTreeMap<String, Vec<String>>) -> anyhow::Result<()> {
+    let chains = configs
+        .iter()
+        .map(|(name, steps)| {
+            let steps: Vec<&str> = steps.iter().map(String::as_str).collect();
+            let chain = Chain::parse(&steps)
+                .map_err(|e| anyhow::anyhow!("post_processors.{}: {}", name, e))?;
+            Ok((name.clone(), chain))
+        })
+        .collect::<anyhow::Result<BTreeMap<_, _>>>()?;
+    if !chains.is_empty() {
+        tracing::info!(
+            "Post-processing chains: {}",
+            chains.keys().cloned().collect::<Vec<_>>().join(", ")
+        );
+    }
+    let _ = CHAINS.set(chains);
+    Ok(())
+}
+
+/// The chain `postProcess` asks for: a configured one by name, or else a
+/// comma separated list of steps
+pub fn resolve(param: &str) -> Result<Chain, ApiError> {
+    if let Some(chain) = CHAINS.get().and_then(|chains| chains.get(param)) {
+        return Ok(chain.clone());
+    }
+    let steps: Vec<&str> = param.split(',').collect();
+    Chain::parse(&steps).map_err(|e| ApiError::bad_request(format!("invalid postProcess: {}", e)))
+}
+
+/// Replace the body of `response` with what `chain` makes of it. The body is
+/// buffered whole first, and goes out with a length instead of chunked
+pub async fn apply(response: Response, chain: Chain) -> Result<Response, ApiError> {
+    let (mut parts, body) = response.into_parts();
+    let original = to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to assemble response: {}", e)))?;
+    let bytes_in = original.len();
+
+    let content_type = chain.steps.iter().rev().find_map(Step::content_type);
+    let corrupts = chain
+        .steps
+        .iter()
+        .any(|step| matches!(step, Step::Corrupt(_) | Step::Flip(_)));
+    let steps = chain.steps;
+    let (processed, outcome) = tokio::task::spawn_blocking(move || {
+        let mut outcome = Outcome::default();
+        let body = steps.iter().fold(original.to_vec(), |body, step| {
+            step.apply(body, &mut outcome)
+        });
+        (body, outcome)
+    })
+    .await
+    .map_err(|e| ApiError::internal(format!("Failed to post-process response: {}", e)))?;
+
+    STATS.responses.fetch_add(1, Ordering::Relaxed);
+    STATS.bytes_in.fetch_add(bytes_in as u64, Ordering::Relaxed);
+    STATS
+        .bytes_out
+        .fetch_add(processed.len() as u64, Ordering::Relaxed);
+    STATS
+        .corrupted_bytes
+        .fetch_add(outcome.corrupted as u64, Ordering::Relaxed);
+
+    parts.headers.remove(header::TRANSFER_ENCODING);
+    parts.headers.remove(header::TRAILER);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    if let Some(content_type) = content_type {
+        parts
+            .headers
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    }
+    if let Ok(label) = HeaderValue::from_str(&chain.label) {
+        parts.headers.insert("x-garble-post-process", label);
+    }
+    parts
+        .headers
+        .insert("x-garble-unprocessed-bytes", HeaderValue::from(bytes_in));
+    if let Some(digest) = outcome
+        .digest
+        .and_then(|digest| HeaderValue::from_str(&digest).ok())
+    {
+        parts.headers.insert("content-digest", digest);
+    }
+    if corrupts {
+        parts.headers.insert(
+            "x-garble-corrupted-bytes",
+            HeaderValue::from(outcome.corrupted),
+        );
+    }
+    Ok(Response::from_parts(parts, Body::from(processed)))
+}
+
+pub fn get_stats() -> PostProcessStats {
+    PostProcessStats {
+        responses: STATS.responses.load(Ordering::Relaxed),
+        bytes_in: STATS.bytes_in.load(Ordering::Relaxed),
+        bytes_out: STATS.bytes_out.load(Ordering::Relaxed),
+        corrupted_bytes: STATS.corrupted_bytes.load(Ordering::Relaxed),
+    }
+}
+
+// Named chains from the config, checked once at startup
+static CHAINS: OnceCell<BTreeMap<String, Chain>> = OnceCell::new();
+
+// Global post-processing counters
+static STATS: Counters = Counters {
+    responses: AtomicU64::new(0),
+    bytes_in: AtomicU64::new(0),
+    bytes_out: AtomicU64::new(0),
+    corrupted_bytes: AtomicU64::new(0),
+};
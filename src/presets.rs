@@ -0,0 +1,110 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Named structure presets selected with `?preset=`, bundling depth, fan-out
+//! and value type weights so common document shapes take one parameter. The
+//! built-ins can be replaced, and new presets added, in the `presets` config.
+
+use anyhow::Context;
+use once_cell::sync::OnceCell;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::config::{LengthDistribution, StringLengthConfig, StructurePreset, TypeWeights};
+use crate::generator::Structure;
+
+/// Presets available without any configuration
+fn builtins() -> BTreeMap<String, StructurePreset> {
+    let flat_wide = StructurePreset {
+        max_depth: 0,
+        min_fields: 50,
+        max_fields: 200,
+        ..StructurePreset::default()
+    };
+    let deep_narrow = StructurePreset {
+        max_depth: 12,
+        min_fields: 1,
+        max_fields: 2,
+        max_array_length: 2,
+        nest_probability: 0.6,
+        ..StructurePreset::default()
+    };
+    let array_heavy = StructurePreset {
+        max_depth: 3,
+        min_fields: 2,
+        max_fields: 6,
+        max_array_length: 12,
+        nest_probability: 0.1,
+        type_weights: TypeWeights {
+            array: 8,
+            ..TypeWeights::default()
+        },
+        ..StructurePreset::default()
+    };
+    let string_blob = StructurePreset {
+        max_depth: 0,
+        min_fields: 1,
+        max_fields: 8,
+        nest_probability: 0.0,
+        type_weights: TypeWeights {
+            text: 1,
+            garbled: 4,
+            ..TypeWeights::none()
+        },
+        string_lengths: Some(StringLengthConfig {
+            distribution: LengthDistribution::Uniform,
+            min_length: 1_000,
+            max_length: 20_000,
+            ..StringLengthConfig::default()
+        }),
+        ..StructurePreset::default()
+    };
+
+    [
+        ("flat-wide", flat_wide),
+        ("deep-narrow", deep_narrow),
+        ("array-heavy", array_heavy),
+        ("string-blob", string_blob),
+    ]
+    .into_iter()
+    .map(|(name, preset)| (name.to_string(), preset))
+    .collect()
+}
+
+/// Compile the built-in and configured presets; call once at startup
+pub fn load(configs: &BTreeMap<String, StructurePreset>) -> anyhow::Result<()> {
+    let mut presets = builtins();
+    presets.extend(configs.clone());
+    let compiled = presets
+        .iter()
+        .map(|(name, preset)| {
+            preset.validate(name)?;
+            let structure =
+                Structure::compile(preset).with_context(|| format!("invalid preset {}", name))?;
+            Ok((name.clone(), Arc::new(structure)))
+        })
+        .collect::<anyhow::Result<BTreeMap<_, _>>>()?;
+    tracing::info!(
+        "Structure presets: {}",
+        compiled.keys().cloned().collect::<Vec<_>>().join(", ")
+    );
+    let _ = PRESETS.set(compiled);
+    Ok(())
+}
+
+/// The preset named `name`
+pub fn get(name: &str) -> Option<Arc<Structure>> {
+    PRESETS.get()?.get(name).cloned()
+}
+
+/// Names of all presets, sorted
+pub fn names() -> Vec<String> {
+    PRESETS
+        .get()
+        .map(|presets| presets.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+// Global presets, compiled once at startup
+static PRESETS: OnceCell<BTreeMap<String, Arc<Structure>>> = OnceCell::new();
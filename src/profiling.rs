@@ -0,0 +1,114 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use axum::{
+    body::Body,
+    extract::{rejection::QueryRejection, Query},
+    http::{header, StatusCode},
+    response::Response,
+};
+use pprof::protos::Message;
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::error::ApiError;
+
+/// Bounds for CPU profile captures
+const DEFAULT_PROFILE_SECONDS: u64 = 10;
+const MAX_PROFILE_SECONDS: u64 = 300;
+const DEFAULT_PROFILE_FREQUENCY: i32 = 99;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileFormat {
+    Flamegraph,
+    Pprof,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PprofParams {
+    seconds: Option<u64>,
+    frequency: Option<i32>,
+    format: Option<ProfileFormat>,
+}
+
+/// Capture a CPU profile over the requested number of seconds
+pub async fn pprof_handler(
+    params: Result<Query<PprofParams>, QueryRejection>,
+) -> Result<Response, ApiError> {
+    let Query(params) = params?;
+    let seconds = params
+        .seconds
+        .unwrap_or(DEFAULT_PROFILE_SECONDS)
+        .clamp(1, MAX_PROFILE_SECONDS);
+    let frequency = params
+        .frequency
+        .unwrap_or(DEFAULT_PROFILE_FREQUENCY)
+        .clamp(1, 1000);
+    let format = params.format.unwrap_or(ProfileFormat::Flamegraph);
+
+    tracing::info!(
+        "Capturing CPU profile for {}s at {}Hz ({:?})",
+        seconds,
+        frequency,
+        format
+    );
+
+    // The profiler guard is not Send, so the whole capture runs on a blocking thread
+    let (content_type, body) = tokio::task::spawn_blocking(move || {
+        let guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(frequency)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+            .map_err(|e| {
+                // Only one profile can run at a time
+                tracing::warn!("Failed to start CPU profiler: {}", e);
+                ApiError::new(
+                    StatusCode::CONFLICT,
+                    "profile-in-progress",
+                    "Profile already running",
+                    format!("failed to start CPU profiler: {}", e),
+                )
+            })?;
+
+        std::thread::sleep(Duration::from_secs(seconds));
+
+        let report = guard.report().build().map_err(|e| {
+            tracing::error!("Failed to build CPU profile report: {}", e);
+            ApiError::internal(format!("failed to build CPU profile report: {}", e))
+        })?;
+
+        let mut body = Vec::new();
+        let content_type = match format {
+            ProfileFormat::Flamegraph => {
+                report.flamegraph(&mut body).map_err(|e| {
+                    tracing::error!("Failed to render flamegraph: {}", e);
+                    ApiError::internal(format!("failed to render flamegraph: {}", e))
+                })?;
+                "image/svg+xml"
+            }
+            ProfileFormat::Pprof => {
+                let profile = report.pprof().map_err(|e| {
+                    tracing::error!("Failed to build pprof profile: {}", e);
+                    ApiError::internal(format!("failed to build pprof profile: {}", e))
+                })?;
+                profile.encode(&mut body).map_err(|e| {
+                    tracing::error!("Failed to encode pprof profile: {}", e);
+                    ApiError::internal(format!("failed to encode pprof profile: {}", e))
+                })?;
+                "application/octet-stream"
+            }
+        };
+
+        Ok::<_, ApiError>((content_type, body))
+    })
+    .await
+    .map_err(|_| ApiError::internal("profiling task panicked"))??;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .unwrap())
+}
@@ -0,0 +1,521 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Learn-and-garble proxy. Requests no endpoint or route answers are forwarded
+//! to the configured upstream, and the shape of every JSON response is learned
+//! per route. When the upstream is unavailable, or recording is off, responses
+//! are garbled values following the learned shape instead, which turns real
+//! traffic into a chaotic mock of the upstream.
+
+use anyhow::Context;
+use axum::{
+    body::Bytes,
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri},
+    response::{IntoResponse, Json, Response},
+};
+use chrono::DateTime;
+use once_cell::sync::OnceCell;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::config::ProxyConfig;
+use crate::error::ApiError;
+use crate::generator::{ColumnKind, RandomDataGenerator};
+use crate::metrics::METRICS;
+use crate::tenants::Tenant;
+
+/// Tells clients whether a response came from the upstream or a learned schema
+const SOURCE_HEADER: HeaderName = HeaderName::from_static("x-daddle-proxy");
+
+/// Headers that only describe a single connection and are never forwarded
+const HOP_BY_HOP_HEADERS: &[HeaderName] = &[
+    header::CONNECTION,
+    header::CONTENT_LENGTH,
+    header::HOST,
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// The learned structure of a route's JSON responses
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Shape {
+    Null,
+    Scalar {
+        kind: ColumnKind,
+        nullable: bool,
+    },
+    Array {
+        items: Box<Shape>,
+        max_length: usize,
+    },
+    Object {
+        fields: BTreeMap<String, Shape>,
+        /// Fields missing from some responses
+        #[serde(default)]
+        optional: BTreeSet<String>,
+    },
+    /// Values of conflicting types
+    Any,
+}
+
+impl Shape {
+    fn infer(value: &Value) -> Self {
+        match value {
+            Value::Null => Shape::Null,
+            Value::Bool(_) => Shape::scalar(ColumnKind::Boolean),
+            Value::Number(n) if n.is_i64() || n.is_u64() => Shape::scalar(ColumnKind::Integer),
+            Value::Number(_) => Shape::scalar(ColumnKind::Float),
+            Value::String(text) => Shape::scalar(string_kind(text)),
+            Value::Array(items) => Shape::Array {
+                items: Box::new(
+                    items
+                        .iter()
+                        .map(Shape::infer)
+                        .reduce(Shape::merge)
+                        .unwrap_or(Shape::Null),
+                ),
+                max_length: items.len(),
+            },
+            Value::Object(members) => Shape::Object {
+                fields: members
+                    .iter()
+                    .map(|(key, value)| (key.clone(), Shape::infer(value)))
+                    .collect(),
+                optional: BTreeSet::new(),
+            },
+        }
+    }
+
+    fn scalar(kind: ColumnKind) -> Self {
+        Shape::Scalar {
+            kind,
+            nullable: false,
+        }
+    }
+
+    /// The shape covering both `self` and `other`
+    fn merge(self, other: Shape) -> Shape {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (Shape::Null, Shape::Scalar { kind, .. })
+            | (Shape::Scalar { kind, .. }, Shape::Null) => Shape::Scalar {
+                kind,
+                nullable: true,
+            },
+            (Shape::Null, shape) | (shape, Shape::Null) => shape,
+            (
+                Shape::Scalar {
+                    kind: a,
+                    nullable: a_nullable,
+                },
+                Shape::Scalar {
+                    kind: b,
+                    nullable: b_nullable,
+                },
+            ) => match merge_kinds(a, b) {
+                Some(kind) => Shape::Scalar {
+                    kind,
+                    nullable: a_nullable || b_nullable,
+                },
+                None => Shape::Any,
+            },
+            (
+                Shape::Array {
+                    items: a,
+                    max_length: a_length,
+                },
+                Shape::Array {
+                    items: b,
+                    max_length: b_length,
+                },
+            ) => Shape::Array {
+                items: Box::new(a.merge(*b)),
+                max_length: a_length.max(b_length),
+            },
+            (
+                Shape::Object {
+                    fields: mut a,
+                    optional: a_optional,
+                },
+                Shape::Object {
+                    fields: b,
+                    optional: b_optional,
+                },
+            ) => {
+                let mut optional: BTreeSet<String> =
+                    a_optional.union(&b_optional).cloned().collect();
+                optional.extend(a.keys().filter(|key| !b.contains_key(*key)).cloned());
+                optional.extend(b.keys().filter(|key| !a.contains_key(*key)).cloned());
+                for (key, shape) in b {
+                    let merged = match a.remove(&key) {
+                        Some(existing) => existing.merge(shape),
+                        None => shape,
+                    };
+                    a.insert(key, merged);
+                }
+                Shape::Object {
+                    fields: a,
+                    optional,
+                }
+            }
+            _ => Shape::Any,
+        }
+    }
+
+    /// A garbled value following the shape
+    fn render(&self, generator: &mut RandomDataGenerator) -> Value {
+        let mut rng = thread_rng();
+        match self {
+            Shape::Null => Value::Null,
+            Shape::Scalar { kind, nullable } => {
+                if *nullable && rng.gen_bool(0.1) {
+                    Value::Null
+                } else {
+                    generator.generate_column(*kind)
+                }
+            }
+            Shape::Array { items, max_length } => {
+                let length = rng.gen_range(0..=*max_length);
+                Value::Array((0..length).map(|_| items.render(generator)).collect())
+            }
+            Shape::Object { fields, optional } => Value::Object(
+                fields
+                    .iter()
+                    .filter(|(key, _)| !optional.contains(*key) || rng.gen_bool(0.5))
+                    .map(|(key, shape)| (key.clone(), shape.render(generator)))
+                    .collect::<Map<_, _>>(),
+            ),
+            Shape::Any => generator.generate_scalar(),
+        }
+    }
+}
+
+/// Most specific column type describing a string
+fn string_kind(text: &str) -> ColumnKind {
+    if Uuid::parse_str(text).is_ok() {
+        ColumnKind::Uuid
+    } else if DateTime::parse_from_rfc3339(text).is_ok() {
+        ColumnKind::Timestamp
+    } else if text.len() >= 16 && text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        ColumnKind::Hex
+    } else {
+        ColumnKind::Text
+    }
+}
+
+fn merge_kinds(a: ColumnKind, b: ColumnKind) -> Option<ColumnKind> {
+    use ColumnKind::*;
+    match (a, b) {
+        (a, b) if a == b => Some(a),
+        (Integer, Float) | (Float, Integer) => Some(Float),
+        (Text | Uuid | Timestamp | Hex, Text | Uuid | Timestamp | Hex) => Some(Text),
+        _ => None,
+    }
+}
+
+/// Key schemas are stored under: the method and path, with segments that look
+/// like identifiers replaced by `:id` so `/users/1` and `/users/2` share one
+fn route_key(method: &Method, path: &str) -> String {
+    let path = path
+        .split('/')
+        .map(|segment| {
+            if is_identifier(segment) {
+                ":id"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    format!("{} {}", method, path)
+}
+
+fn is_identifier(segment: &str) -> bool {
+    !segment.is_empty()
+        && (segment.bytes().all(|b| b.is_ascii_digit())
+            || Uuid::parse_str(segment).is_ok()
+            || (segment.len() >= 16 && segment.bytes().all(|b| b.is_ascii_hexdigit())))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProxyStats {
+    pub upstream: Option<String>,
+    pub record: bool,
+    pub forwarded: u64,
+    pub upstream_failures: u64,
+    pub served_from_schema: u64,
+    pub schemas: usize,
+}
+
+pub struct Proxy {
+    upstream: Option<String>,
+    record: bool,
+    schema_file: Option<PathBuf>,
+    client: reqwest::Client,
+    schemas: Mutex<BTreeMap<String, Shape>>,
+    /// Keeps schema file writes from overlapping
+    save_lock: Mutex<()>,
+    forwarded: AtomicU64,
+    upstream_failures: AtomicU64,
+    served_from_schema: AtomicU64,
+}
+
+impl Proxy {
+    pub fn get_stats(&self) -> ProxyStats {
+        ProxyStats {
+            upstream: self.upstream.clone(),
+            record: self.record,
+            forwarded: self.forwarded.load(Ordering::Relaxed),
+            upstream_failures: self.upstream_failures.load(Ordering::Relaxed),
+            served_from_schema: self.served_from_schema.load(Ordering::Relaxed),
+            schemas: self.schemas.lock().unwrap().len(),
+        }
+    }
+
+    /// Forward a request to the upstream, learning from a JSON response
+    async fn forward(
+        &'static self,
+        upstream: &str,
+        key: &str,
+        method: Method,
+        uri: &Uri,
+        mut headers: HeaderMap,
+        body: Bytes,
+    ) -> reqwest::Result<(Response, usize)> {
+        let path = uri.path_and_query().map_or("/", |p| p.as_str());
+        for name in HOP_BY_HOP_HEADERS {
+            headers.remove(name);
+        }
+        let upstream_response = self
+            .client
+            .request(method, format!("{}{}", upstream, path))
+            .headers(headers)
+            .body(body)
+            .send()
+            .await?;
+
+        let status = upstream_response.status();
+        let mut headers = upstream_response.headers().clone();
+        let body = upstream_response.bytes().await?;
+        self.forwarded.fetch_add(1, Ordering::Relaxed);
+
+        let is_json = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("json"));
+        if status.is_success() && is_json {
+            if let Ok(value) = serde_json::from_slice::<Value>(&body) {
+                self.learn(key, &value);
+            }
+        }
+
+        for name in HOP_BY_HOP_HEADERS {
+            headers.remove(name);
+        }
+        headers.insert(SOURCE_HEADER, HeaderValue::from_static("upstream"));
+        let size = body.len();
+        Ok(((status, headers, body).into_response(), size))
+    }
+
+    /// Merge a response into the route's schema, saving the schemas when it changed
+    fn learn(&'static self, key: &str, value: &Value) {
+        let shape = Shape::infer(value);
+        {
+            let mut schemas = self.schemas.lock().unwrap();
+            let merged = match schemas.get(key) {
+                Some(existing) => existing.clone().merge(shape),
+                None => shape,
+            };
+            if schemas.get(key) == Some(&merged) {
+                return;
+            }
+            tracing::debug!("Learned a new shape for {}", key);
+            schemas.insert(key.to_string(), merged);
+        }
+
+        if self.schema_file.is_some() {
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = self.save() {
+                    tracing::warn!("Failed to save learned schemas: {}", e);
+                }
+            });
+        }
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.schema_file else {
+            return Ok(());
+        };
+        let _saving = self.save_lock.lock().unwrap();
+        let json = serde_json::to_string_pretty(&*self.schemas.lock().unwrap())?;
+        // Write aside and rename, so a crash never leaves a half-written file
+        let partial = path.with_extension("partial");
+        std::fs::write(&partial, json)?;
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    /// Answer with garble following the route's learned schema
+    async fn serve_schema(&self, key: String) -> Result<(Response, usize), ApiError> {
+        let Some(shape) = self.schemas.lock().unwrap().get(&key).cloned() else {
+            let detail = if self.record {
+                format!(
+                    "upstream is unavailable and no schema has been learned for {}",
+                    key
+                )
+            } else {
+                format!("no schema has been learned for {}", key)
+            };
+            return Err(ApiError::new(
+                StatusCode::BAD_GATEWAY,
+                "upstream-unavailable",
+                "Upstream unavailable",
+                detail,
+            )
+            .with_extension("route", key));
+        };
+        self.served_from_schema.fetch_add(1, Ordering::Relaxed);
+
+        let body = tokio::task::spawn_blocking(move || {
+            let value = shape.render(&mut RandomDataGenerator::new());
+            serde_json::to_string(&value).unwrap_or_default()
+        })
+        .await
+        .map_err(|e| ApiError::internal(format!("failed to render schema: {}", e)))?;
+
+        let size = body.len();
+        let headers = [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            ),
+            (SOURCE_HEADER, HeaderValue::from_static("schema")),
+        ];
+        Ok(((headers, body).into_response(), size))
+    }
+}
+
+/// Whether requests no route answers are handled by the proxy
+pub fn is_enabled() -> bool {
+    PROXY.get().is_some()
+}
+
+/// Answer a request no endpoint or route matched: from the upstream while it
+/// is reachable and recording is on, otherwise from the learned schema
+pub async fn handle(
+    tenant: &Tenant,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let Some(proxy) = PROXY.get() else {
+        return Err(ApiError::not_found(uri.path()));
+    };
+    let key = route_key(&method, uri.path());
+
+    if let (true, Some(upstream)) = (proxy.record, proxy.upstream.as_deref()) {
+        match proxy
+            .forward(upstream, &key, method, &uri, headers, body)
+            .await
+        {
+            Ok((response, size)) => {
+                METRICS.record_response(&tenant.segment(), "proxy", size, 0);
+                return Ok(response);
+            }
+            Err(e) => {
+                proxy.upstream_failures.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(
+                    "Upstream unavailable for {}, serving the learned schema: {}",
+                    key,
+                    e
+                );
+            }
+        }
+    }
+
+    let (response, size) = proxy.serve_schema(key).await?;
+    METRICS.record_response(&tenant.segment(), "schema", size, 0);
+    Ok(response)
+}
+
+/// Set up the proxy from the `proxy` config section; call once at startup
+pub fn configure(config: &ProxyConfig) -> anyhow::Result<()> {
+    if config.upstream.is_none() && config.schema_file.is_none() {
+        return Ok(());
+    }
+    if config.record && config.upstream.is_none() {
+        anyhow::bail!(
+            "proxy.record needs proxy.upstream; set record to false to serve learned schemas only"
+        );
+    }
+
+    let schema_file = config.schema_file.as_ref().map(PathBuf::from);
+    let schemas = match &schema_file {
+        Some(path) if path.exists() => {
+            let schemas: BTreeMap<String, Shape> = serde_json::from_str(
+                &std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read {}", path.display()))?,
+            )
+            .with_context(|| format!("{} does not hold learned schemas", path.display()))?;
+            tracing::info!(
+                "Loaded {} learned schemas from {}",
+                schemas.len(),
+                path.display()
+            );
+            schemas
+        }
+        _ => BTreeMap::new(),
+    };
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_millis(config.timeout_ms))
+        .build()?;
+    let upstream = config
+        .upstream
+        .as_ref()
+        .map(|url| url.trim_end_matches('/').to_string());
+    match (&upstream, config.record) {
+        (Some(upstream), true) => tracing::info!("Proxying unmatched requests to {}", upstream),
+        _ => tracing::info!("Serving unmatched requests from learned schemas"),
+    }
+
+    let _ = PROXY.set(Proxy {
+        upstream,
+        record: config.record,
+        schema_file,
+        client,
+        schemas: Mutex::new(schemas),
+        save_lock: Mutex::new(()),
+        forwarded: AtomicU64::new(0),
+        upstream_failures: AtomicU64::new(0),
+        served_from_schema: AtomicU64::new(0),
+    });
+    Ok(())
+}
+
+/// The schemas learned so far, keyed by route
+pub async fn schemas_handler() -> Json<BTreeMap<String, Shape>> {
+    Json(
+        PROXY
+            .get()
+            .map(|proxy| proxy.schemas.lock().unwrap().clone())
+            .unwrap_or_default(),
+    )
+}
+
+// Global proxy instance, set when proxying is configured
+pub static PROXY: OnceCell<Proxy> = OnceCell::new();
@@ -0,0 +1,204 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Component-level detail for `/health`, so operators can tell why an
+//! instance is degraded: a cold chunk pool, a stalled background task, memory
+//! close to the ceiling or a remote configuration that stopped refreshing.
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::chunk_pool::CHUNK_POOL;
+use crate::config::LiveConfig;
+use crate::memory::MEMORY_GUARD;
+use crate::remote_config::REMOTE_CONFIG;
+
+/// Missed runs after which a background task counts as stalled
+const STALL_INTERVALS: u32 = 3;
+
+/// Slack added to the stall threshold, for slow rounds such as generating large chunks
+const STALL_GRACE: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ComponentStatus {
+    Ok,
+    Degraded,
+}
+
+impl ComponentStatus {
+    fn from_ok(ok: bool) -> Self {
+        if ok {
+            Self::Ok
+        } else {
+            Self::Degraded
+        }
+    }
+}
+
+/// When a periodic background task last ran
+pub struct Heartbeat {
+    last_beat: Mutex<Option<DateTime<Utc>>>,
+    interval_ms: AtomicU64,
+}
+
+impl Heartbeat {
+    fn new() -> Self {
+        Self {
+            last_beat: Mutex::new(None),
+            interval_ms: AtomicU64::new(0),
+        }
+    }
+
+    /// Record a run of a task that runs every `interval`
+    pub fn beat(&self, interval: Duration) {
+        *self.last_beat.lock().unwrap() = Some(Utc::now());
+        self.interval_ms
+            .store(interval.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn report(&self) -> TaskReport {
+        let last_run = *self.last_beat.lock().unwrap();
+        let interval = Duration::from_millis(self.interval_ms.load(Ordering::Relaxed));
+        let stall_after = interval * STALL_INTERVALS + STALL_GRACE;
+        let alive = last_run.is_some_and(|last_run| {
+            (Utc::now() - last_run)
+                .to_std()
+                .map_or(true, |since| since <= stall_after)
+        });
+        TaskReport {
+            status: ComponentStatus::from_ok(alive),
+            last_run,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskReport {
+    pub status: ComponentStatus,
+    pub last_run: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TierReport {
+    pub chunks: usize,
+    pub target: usize,
+    pub fill_percent: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolReport {
+    pub status: ComponentStatus,
+    pub tiers: BTreeMap<String, TierReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MemoryReport {
+    pub status: ComponentStatus,
+    pub rss_bytes: usize,
+    pub ceiling_bytes: Option<usize>,
+    /// Bytes left below the ceiling, counting responses in flight
+    pub headroom_bytes: Option<usize>,
+    /// Memory in use as a fraction of the tightest of the cgroup, host and ceiling limits
+    pub pressure: f64,
+    /// Share of its usual targets the chunk pool keeps
+    pub pool_scale: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigReport {
+    pub status: ComponentStatus,
+    /// Configurations applied since startup, starting at 1
+    pub generation: u64,
+    /// Refreshes of a remote configuration that failed in a row
+    pub consecutive_failures: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Components {
+    pub chunk_pool: PoolReport,
+    pub background_tasks: BTreeMap<&'static str, TaskReport>,
+    pub memory: MemoryReport,
+    pub config: ConfigReport,
+}
+
+impl Components {
+    /// Whether every component is ok
+    pub fn is_ok(&self) -> bool {
+        self.chunk_pool.status == ComponentStatus::Ok
+            && self
+                .background_tasks
+                .values()
+                .all(|task| task.status == ComponentStatus::Ok)
+            && self.memory.status == ComponentStatus::Ok
+            && self.config.status == ComponentStatus::Ok
+    }
+}
+
+/// The state of every component, as of now
+pub fn report(live: &LiveConfig) -> Components {
+    let tiers: BTreeMap<_, _> = CHUNK_POOL
+        .fill_levels()
+        .into_iter()
+        .map(|(size, chunks, target)| {
+            let fill_percent = if target == 0 {
+                100.0
+            } else {
+                (chunks as f64 / target as f64 * 100.0).min(100.0)
+            };
+            let tier = TierReport {
+                chunks,
+                target,
+                fill_percent,
+            };
+            (format!("{:?}", size).to_lowercase(), tier)
+        })
+        .collect();
+    let chunk_pool = PoolReport {
+        status: ComponentStatus::from_ok(tiers.values().all(|tier| tier.chunks >= tier.target)),
+        tiers,
+    };
+
+    let background_tasks = BTreeMap::from([
+        ("chunk_pool", POOL_HEARTBEAT.report()),
+        ("memory_monitor", MEMORY_HEARTBEAT.report()),
+    ]);
+
+    let stats = MEMORY_GUARD.get_stats();
+    let memory = MemoryReport {
+        status: ComponentStatus::from_ok(!stats.background_paused && stats.pool_scale == 1.0),
+        rss_bytes: stats.rss_bytes,
+        ceiling_bytes: stats.ceiling_bytes,
+        headroom_bytes: stats
+            .ceiling_bytes
+            .map(|ceiling| ceiling.saturating_sub(stats.rss_bytes + stats.in_flight_bytes)),
+        pressure: stats.pressure,
+        pool_scale: stats.pool_scale,
+    };
+
+    let consecutive_failures = REMOTE_CONFIG
+        .get()
+        .map(|remote| remote.get_stats().consecutive_failures);
+    let config = ConfigReport {
+        status: ComponentStatus::from_ok(consecutive_failures.unwrap_or(0) == 0),
+        generation: live.generation(),
+        consecutive_failures,
+    };
+
+    Components {
+        chunk_pool,
+        background_tasks,
+        memory,
+        config,
+    }
+}
+
+// Global heartbeats of the background tasks
+pub static POOL_HEARTBEAT: Lazy<Heartbeat> = Lazy::new(Heartbeat::new);
+pub static MEMORY_HEARTBEAT: Lazy<Heartbeat> = Lazy::new(Heartbeat::new);
@@ -0,0 +1,330 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Configuration loaded from an HTTP(S) URL instead of `config.json`, and
+//! refreshed periodically so a fleet can be reconfigured mid-test. Garble
+//! profiles, tenants, error detail garbling and dependency faults change in
+//! place; other sections only take effect after a restart.
+
+use anyhow::Context;
+use axum::http::{header, StatusCode};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{Config, DependencyConfig, DependencyFault, LiveConfig};
+use crate::{dependencies, error};
+
+/// Environment variable naming the configuration URL
+pub const CONFIG_URL_VAR: &str = "DADDLE_CONFIG_URL";
+
+/// Environment variable overriding how often the URL is checked for changes
+pub const REFRESH_INTERVAL_VAR: &str = "DADDLE_CONFIG_REFRESH_MS";
+
+const DEFAULT_REFRESH_INTERVAL_MS: u64 = 30_000;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the configuration comes from and how refreshing it is going
+pub struct RemoteConfig {
+    url: String,
+    client: reqwest::Client,
+    etag: Mutex<Option<String>>,
+    refreshes: AtomicU64,
+    changes: AtomicU64,
+    failures: AtomicU64,
+    consecutive_failures: AtomicU64,
+    last_refreshed: Mutex<Option<chrono::DateTime<chrono::Utc>>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RemoteConfigStats {
+    pub url: String,
+    pub etag: Option<String>,
+    pub refreshes: u64,
+    pub changes: u64,
+    pub failures: u64,
+    pub consecutive_failures: u64,
+    pub last_refreshed: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl RemoteConfig {
+    pub fn get_stats(&self) -> RemoteConfigStats {
+        RemoteConfigStats {
+            url: self.url.clone(),
+            etag: self.etag.lock().unwrap().clone(),
+            refreshes: self.refreshes.load(Ordering::Relaxed),
+            changes: self.changes.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            consecutive_failures: self.consecutive_failures.load(Ordering::Relaxed),
+            last_refreshed: *self.last_refreshed.lock().unwrap(),
+        }
+    }
+
+    /// Fetch the configuration, or `None` when the server reports it unchanged
+    async fn fetch(&self) -> anyhow::Result<Option<Config>> {
+        let mut request = self.client.get(&self.url);
+        if let Some(etag) = self.etag.lock().unwrap().as_deref() {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        let response = response.error_for_status()?;
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        let config: Config = serde_json::from_slice(&response.bytes().await?)
+            .context("remote configuration is not valid")?;
+        validate(&config)?;
+
+        // Only remember the ETag of a configuration that was accepted
+        *self.etag.lock().unwrap() = etag;
+        Ok(Some(config))
+    }
+}
+
+/// Checks that would otherwise only run at startup
+pub fn validate(config: &Config) -> anyhow::Result<()> {
+    config.validate_profiles()?;
+    config.string_lengths.validate()?;
+    config.date_formats.validate()?;
+    config.flags.validate()?;
+    for dependency in &config.dependencies {
+        dependency.fault.validate()?;
+    }
+    Ok(())
+}
+
+/// Load the configuration from `url` at startup
+pub async fn load(url: &str) -> anyhow::Result<Config> {
+    let remote = RemoteConfig {
+        url: url.to_string(),
+        client: reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?,
+        etag: Mutex::new(None),
+        refreshes: AtomicU64::new(0),
+        changes: AtomicU64::new(0),
+        failures: AtomicU64::new(0),
+        consecutive_failures: AtomicU64::new(0),
+        last_refreshed: Mutex::new(Some(chrono::Utc::now())),
+    };
+    let config = remote
+        .fetch()
+        .await
+        .with_context(|| format!("failed to load configuration from {}", url))?
+        .context("configuration server answered 304 without a cached copy")?;
+    let _ = REMOTE_CONFIG.set(remote);
+    tracing::info!("Loaded configuration from {}", url);
+    Ok(config)
+}
+
+fn unchanged(previous: &Config, next: &Config) -> bool {
+    serde_json::to_value(previous).ok() == serde_json::to_value(next).ok()
+}
+
+/// Sections that are only read at startup and changed between `previous` and `next`
+fn restart_only_changes(previous: &Config, next: &Config) -> Vec<&'static str> {
+    fn differs<T: Serialize>(a: &T, b: &T) -> bool {
+        serde_json::to_value(a).ok() != serde_json::to_value(b).ok()
+    }
+    // Faults change in place, everything else about a dependency needs a restart
+    fn without_faults(dependencies: &[DependencyConfig]) -> Vec<DependencyConfig> {
+        dependencies
+            .iter()
+            .cloned()
+            .map(|dependency| DependencyConfig {
+                fault: DependencyFault::None,
+                ..dependency
+            })
+            .collect()
+    }
+
+    let sections = [
+        ("server", differs(&previous.server, &next.server)),
+        (
+            "performance",
+            differs(&previous.performance, &next.performance),
+        ),
+        (
+            "dictionary",
+            differs(&previous.dictionary, &next.dictionary),
+        ),
+        ("markov", differs(&previous.markov, &next.markov)),
+        (
+            "string_lengths",
+            differs(&previous.string_lengths, &next.string_lengths),
+        ),
+        (
+            "date_formats",
+            differs(&previous.date_formats, &next.date_formats),
+        ),
+        ("mirror", differs(&previous.mirror, &next.mirror)),
+        ("cluster", differs(&previous.cluster, &next.cluster)),
+        ("flags", differs(&previous.flags, &next.flags)),
+        ("routes", differs(&previous.routes, &next.routes)),
+        ("rules", differs(&previous.rules, &next.rules)),
+        (
+            "path_faults",
+            differs(&previous.path_faults, &next.path_faults),
+        ),
+        ("corpus", differs(&previous.corpus, &next.corpus)),
+        (
+            "generators",
+            differs(&previous.generators, &next.generators),
+        ),
+        ("presets", differs(&previous.presets, &next.presets)),
+        (
+            "post_processors",
+            differs(&previous.post_processors, &next.post_processors),
+        ),
+        ("hang", differs(&previous.hang, &next.hang)),
+        ("admission", differs(&previous.admission, &next.admission)),
+        (
+            "compression",
+            differs(&previous.compression, &next.compression),
+        ),
+        (
+            "response_cache",
+            differs(&previous.response_cache, &next.response_cache),
+        ),
+        (
+            "encryption",
+            differs(&previous.encryption, &next.encryption),
+        ),
+        ("outlier", differs(&previous.outlier, &next.outlier)),
+        (
+            "connection_shaping",
+            differs(&previous.connection_shaping, &next.connection_shaping),
+        ),
+        ("http2", differs(&previous.http2, &next.http2)),
+        (
+            "stats_export",
+            differs(&previous.stats_export, &next.stats_export),
+        ),
+        ("faults", differs(&previous.faults, &next.faults)),
+        (
+            "header_hazards",
+            differs(&previous.header_hazards, &next.header_hazards),
+        ),
+        ("catch_all", differs(&previous.catch_all, &next.catch_all)),
+        (
+            "dependencies",
+            differs(
+                &without_faults(&previous.dependencies),
+                &without_faults(&next.dependencies),
+            ),
+        ),
+    ];
+    sections
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect()
+}
+
+/// Switch to `next`, applying what can change while running; returns the
+/// changed sections that only take effect after a restart
+pub fn apply(live: &LiveConfig, next: Config) -> Vec<&'static str> {
+    let previous = live.get();
+    let restart_only = restart_only_changes(&previous, &next);
+    if !restart_only.is_empty() {
+        tracing::warn!(
+            "Changes to {} take effect after a restart",
+            restart_only.join(", ")
+        );
+    }
+
+    error::set_garble_details(next.garble.garble_error_details);
+
+    // Leave faults switched at runtime alone unless the configuration changed them
+    let faults = |config: &Config| -> BTreeMap<String, DependencyFault> {
+        config
+            .dependencies
+            .iter()
+            .map(|dependency| (dependency.name.clone(), dependency.fault))
+            .collect()
+    };
+    let next_faults = faults(&next);
+    if faults(&previous) != next_faults {
+        dependencies::apply_faults(&next_faults);
+    }
+
+    live.replace(next);
+    restart_only
+}
+
+/// Check the URL for changes until shutdown
+async fn refresh(live: Arc<LiveConfig>, interval: Duration, shutdown: CancellationToken) {
+    let Some(remote) = REMOTE_CONFIG.get() else {
+        return;
+    };
+
+    let mut interval = tokio::time::interval(interval);
+    // The first tick completes immediately, and the configuration was just loaded
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                remote.refreshes.fetch_add(1, Ordering::Relaxed);
+                let result = remote.fetch().await;
+                if result.is_ok() {
+                    remote.consecutive_failures.store(0, Ordering::Relaxed);
+                }
+                match result {
+                    // Servers without ETags send the whole file every time
+                    Ok(Some(config)) if unchanged(&live.get(), &config) => {
+                        *remote.last_refreshed.lock().unwrap() = Some(chrono::Utc::now());
+                    }
+                    Ok(Some(config)) => {
+                        tracing::info!("Configuration at {} changed, applying", remote.url);
+                        apply(&live, config);
+                        remote.changes.fetch_add(1, Ordering::Relaxed);
+                        *remote.last_refreshed.lock().unwrap() = Some(chrono::Utc::now());
+                    }
+                    Ok(None) => {
+                        *remote.last_refreshed.lock().unwrap() = Some(chrono::Utc::now());
+                    }
+                    Err(e) => {
+                        // Keep serving the last good configuration
+                        tracing::warn!("Failed to refresh configuration from {}: {:#}", remote.url, e);
+                        remote.failures.fetch_add(1, Ordering::Relaxed);
+                        remote.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+            _ = shutdown.cancelled() => break,
+        }
+    }
+}
+
+/// Start refreshing a configuration loaded with [`load`]
+pub fn start(live: Arc<LiveConfig>, shutdown: CancellationToken) -> Option<JoinHandle<()>> {
+    let remote = REMOTE_CONFIG.get()?;
+    let interval_ms = std::env::var(REFRESH_INTERVAL_VAR)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_REFRESH_INTERVAL_MS)
+        .max(100);
+    tracing::info!(
+        "Refreshing configuration from {} every {}ms",
+        remote.url,
+        interval_ms
+    );
+    Some(tokio::spawn(refresh(
+        live,
+        Duration::from_millis(interval_ms),
+        shutdown,
+    )))
+}
+
+// Global remote configuration source, set once at startup when one is used
+pub static REMOTE_CONFIG: OnceCell<RemoteConfig> = OnceCell::new();
@@ -0,0 +1,279 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Cache of fully assembled garble responses. Benchmarks tend to hammer one
+//! exact size over and over; with `response_cache.enabled`, the first request
+//! for a size, format and profile assembles the body as usual and later ones
+//! replay it until it expires, skipping assembly entirely.
+
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::compression::ContentCoding;
+use crate::config::ResponseCacheConfig;
+use crate::encoding::BodyEncoding;
+use crate::error::ApiError;
+use crate::metadata::MetadataLevel;
+use crate::streaming::{
+    GarbleResponse, ResponseDecision, ResponseOptions, ResponseShape, ResponseStrategy,
+};
+
+/// Header telling whether a response was replayed from the cache
+const CACHE_HEADER: &str = "x-garble-cache";
+
+/// Everything that makes two cacheable responses differ
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    target_size: usize,
+    /// Tenant whose garble profile the request was served under
+    profile: String,
+    encoding: BodyEncoding,
+    coding: Option<ContentCoding>,
+    metadata: MetadataLevel,
+    strategy: Option<ResponseStrategy>,
+}
+
+struct CachedResponse {
+    headers: HeaderMap,
+    body: Bytes,
+    strategy: &'static str,
+}
+
+struct Entry {
+    response: Arc<CachedResponse>,
+    stored_at: Instant,
+    last_used: Instant,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, Entry>,
+    bytes: usize,
+}
+
+/// Cache contents and outcomes since startup
+#[derive(Debug, Clone, Serialize)]
+pub struct ResponseCacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub max_bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub stored: u64,
+    pub expired: u64,
+    pub evicted: u64,
+}
+
+struct ResponseCache {
+    ttl: Duration,
+    max_bytes: usize,
+    max_entry_size: usize,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stored: AtomicU64,
+    expired: AtomicU64,
+    evicted: AtomicU64,
+}
+
+impl ResponseCache {
+    fn lookup(&self, key: &CacheKey) -> Option<Arc<CachedResponse>> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let entry = state.entries.get_mut(key)?;
+        if now.duration_since(entry.stored_at) < self.ttl {
+            entry.last_used = now;
+            return Some(entry.response.clone());
+        }
+        if let Some(stale) = state.entries.remove(key) {
+            state.bytes -= stale.response.body.len();
+            self.expired.fetch_add(1, Ordering::Relaxed);
+        }
+        None
+    }
+
+    /// Keep a response, making room by dropping expired and then least recently used entries
+    fn store(&self, key: CacheKey, response: CachedResponse) {
+        let size = response.body.len();
+        if size > self.max_bytes {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let ttl = self.ttl;
+        let before = state.entries.len();
+        state
+            .entries
+            .retain(|_, entry| now.duration_since(entry.stored_at) < ttl);
+        self.expired
+            .fetch_add((before - state.entries.len()) as u64, Ordering::Relaxed);
+        state.entries.remove(&key);
+        state.bytes = state
+            .entries
+            .values()
+            .map(|entry| entry.response.body.len())
+            .sum();
+
+        while state.bytes + size > self.max_bytes {
+            let Some(oldest) = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some(evicted) = state.entries.remove(&oldest) {
+                state.bytes -= evicted.response.body.len();
+                self.evicted.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        state.bytes += size;
+        state.entries.insert(
+            key,
+            Entry {
+                response: Arc::new(response),
+                stored_at: now,
+                last_used: now,
+            },
+        );
+        self.stored.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get_stats(&self) -> ResponseCacheStats {
+        let state = self.state.lock().unwrap();
+        ResponseCacheStats {
+            entries: state.entries.len(),
+            bytes: state.bytes,
+            max_bytes: self.max_bytes,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            stored: self.stored.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Set up the cache, if enabled; call once at startup
+pub fn configure(config: &ResponseCacheConfig) {
+    if !config.enabled {
+        return;
+    }
+    tracing::info!(
+        "Response cache: {}MB for responses up to {}MB, kept {}ms",
+        config.max_memory_mb,
+        config.max_entry_mb,
+        config.ttl_ms
+    );
+    let _ = RESPONSE_CACHE.set(ResponseCache {
+        ttl: Duration::from_millis(config.ttl_ms),
+        max_bytes: config.max_memory_mb * 1024 * 1024,
+        max_entry_size: config.max_entry_mb * 1024 * 1024,
+        state: Mutex::new(CacheState::default()),
+        hits: AtomicU64::new(0),
+        misses: AtomicU64::new(0),
+        stored: AtomicU64::new(0),
+        expired: AtomicU64::new(0),
+        evicted: AtomicU64::new(0),
+    });
+}
+
+/// Key to cache a response under, when the cache is on and the body depends only
+/// on its size and format; echoes, dependency outcomes, presets, pacing and
+/// chunk layouts differ per request and are never cached
+pub fn key_for(profile: &str, target_size: usize, options: &ResponseOptions) -> Option<CacheKey> {
+    let cache = RESPONSE_CACHE.get()?;
+    let cacheable = target_size <= cache.max_entry_size
+        && options.uses_pool()
+        && options.shape == ResponseShape::Garble
+        && options.jsonp_callback.is_none()
+        && !options.pacing.is_active()
+        && options.chunk_delay.is_none()
+        && !options.chunking.is_set()
+        && options.metadata.request_echo.is_none()
+        && options.metadata.dependencies.is_none();
+    cacheable.then(|| CacheKey {
+        target_size,
+        profile: profile.to_string(),
+        encoding: options.encoding,
+        coding: options.coding,
+        metadata: options.metadata.level,
+        strategy: options.strategy,
+    })
+}
+
+/// Replay the cached response for `key`, or assemble one with `build`, buffer it
+/// whole and keep it; returns the response with the strategy that produced it
+pub async fn serve(
+    key: CacheKey,
+    wait_ms: u64,
+    build: impl FnOnce() -> Result<GarbleResponse, ApiError>,
+) -> Result<(Response, &'static str), ApiError> {
+    let cache = RESPONSE_CACHE
+        .get()
+        .expect("cache keys are only handed out when the cache is configured");
+    let target_size = key.target_size;
+
+    if let Some(cached) = cache.lookup(&key) {
+        cache.hits.fetch_add(1, Ordering::Relaxed);
+        let mut response = (StatusCode::OK, cached.body.clone()).into_response();
+        *response.headers_mut() = cached.headers.clone();
+        let decision = ResponseDecision {
+            strategy: cached.strategy,
+            target_size,
+            wait_ms,
+        };
+        decision.apply_headers(response.headers_mut());
+        response
+            .headers_mut()
+            .insert(CACHE_HEADER, HeaderValue::from_static("hit"));
+        return Ok((response, cached.strategy));
+    }
+
+    cache.misses.fetch_add(1, Ordering::Relaxed);
+    let garble_response = build()?;
+    let strategy = garble_response.decision.strategy;
+    let (mut parts, body) = garble_response.into_response().into_parts();
+    let body = to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to assemble response: {}", e)))?;
+
+    // The whole body is at hand now, so it goes out with a length instead of chunked
+    parts.headers.remove(header::TRANSFER_ENCODING);
+    parts.headers.remove(header::TRAILER);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    cache.store(
+        key,
+        CachedResponse {
+            headers: parts.headers.clone(),
+            body: body.clone(),
+            strategy,
+        },
+    );
+
+    parts
+        .headers
+        .insert(CACHE_HEADER, HeaderValue::from_static("miss"));
+    Ok((Response::from_parts(parts, Body::from(body)), strategy))
+}
+
+/// Cache contents and outcomes, if the cache is enabled
+pub fn get_stats() -> Option<ResponseCacheStats> {
+    RESPONSE_CACHE.get().map(ResponseCache::get_stats)
+}
+
+// Global response cache, set once at startup when enabled
+static RESPONSE_CACHE: OnceCell<ResponseCache> = OnceCell::new();
@@ -0,0 +1,319 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Custom endpoints defined in the `routes` config section. They are served
+//! from the router fallback, so built-in endpoints always take precedence and
+//! a route definition can never break startup by overlapping one.
+
+use anyhow::Context;
+use axum::{
+    body::Body,
+    extract::{rejection::QueryRejection, Query, RawQuery, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use once_cell::sync::OnceCell;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::catch_all;
+use crate::config::{LiveConfig, RouteConfig};
+use crate::drain::DRAIN;
+use crate::error::ApiError;
+use crate::generator::RandomDataGenerator;
+use crate::handlers::GarbleParams;
+use crate::memory::MEMORY_GUARD;
+use crate::metrics::METRICS;
+use crate::proxy;
+use crate::server::ConnectionControl;
+use crate::streaming::ResponseStrategy;
+use crate::template::{RequestValues, Source, Template};
+use crate::tenants::Tenant;
+use crate::upload;
+
+/// Values captured from the request path by `:name` and `*name` segments
+pub type PathParams = BTreeMap<String, String>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    /// Everything that is left, captured when named
+    Rest(Option<String>),
+}
+
+/// A path such as `/orders/:id/items` or `/files/*path`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathPattern {
+    segments: Vec<Segment>,
+}
+
+impl PathPattern {
+    pub fn parse(pattern: &str) -> anyhow::Result<Self> {
+        let Some(path) = pattern.strip_prefix('/') else {
+            anyhow::bail!("path pattern {:?} must start with /", pattern);
+        };
+        let parts: Vec<&str> = path.split('/').collect();
+        let segments = parts
+            .iter()
+            .enumerate()
+            .map(|(i, part)| {
+                if let Some(name) = part.strip_prefix(':') {
+                    if name.is_empty() {
+                        anyhow::bail!("unnamed parameter in path pattern {:?}", pattern);
+                    }
+                    Ok(Segment::Param(name.to_string()))
+                } else if let Some(name) = part.strip_prefix('*') {
+                    if i + 1 != parts.len() {
+                        anyhow::bail!("* must be the last segment of path pattern {:?}", pattern);
+                    }
+                    Ok(Segment::Rest((!name.is_empty()).then(|| name.to_string())))
+                } else {
+                    Ok(Segment::Literal(part.to_string()))
+                }
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { segments })
+    }
+
+    /// Parameters captured from `path`, or `None` when it does not match
+    pub fn matches(&self, path: &str) -> Option<PathParams> {
+        let mut parts = path.strip_prefix('/')?.split('/');
+        let mut params = PathParams::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Rest(name) => {
+                    let rest: Vec<&str> = parts.by_ref().collect();
+                    if let Some(name) = name {
+                        params.insert(name.clone(), rest.join("/"));
+                    }
+                    return Some(params);
+                }
+                Segment::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    let part = parts.next().filter(|part| !part.is_empty())?;
+                    params.insert(name.clone(), part.to_string());
+                }
+            }
+        }
+        parts.next().is_none().then_some(params)
+    }
+
+    /// Whether a match captures a parameter called `name`
+    pub fn captures(&self, name: &str) -> bool {
+        self.segments.iter().any(|segment| match segment {
+            Segment::Param(param) => param == name,
+            Segment::Rest(rest) => rest.as_deref() == Some(name),
+            Segment::Literal(_) => false,
+        })
+    }
+}
+
+/// A response built from a JSON skeleton, shared by routes and rules
+pub struct CannedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    template: Template,
+}
+
+impl CannedResponse {
+    pub fn load(
+        status: u16,
+        extra_headers: &BTreeMap<String, String>,
+        body: Option<&serde_json::Value>,
+        body_file: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let status = StatusCode::from_u16(status)?;
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        for (name, value) in extra_headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+
+        let skeleton = match (body, body_file) {
+            (Some(_), Some(_)) => anyhow::bail!("set either body or body_file, not both"),
+            (Some(body), None) => body.clone(),
+            (None, Some(file)) => serde_json::from_str(
+                &std::fs::read_to_string(file)
+                    .with_context(|| format!("failed to read {}", file))?,
+            )
+            .with_context(|| format!("{} is not valid JSON", file))?,
+            (None, None) => serde_json::Value::Null,
+        };
+
+        Ok(Self {
+            status,
+            headers,
+            template: Template::compile(&skeleton)?,
+        })
+    }
+
+    /// Fail unless every `$path(name)` placeholder is captured by `pattern`
+    pub fn check_path_params(&self, pattern: Option<&PathPattern>) -> anyhow::Result<()> {
+        for source in self.template.sources() {
+            if let Source::Path(name) = source {
+                if !pattern.is_some_and(|pattern| pattern.captures(name)) {
+                    anyhow::bail!("$path({}) is not captured by the path", name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Render the skeleton for a request, returning the response and its body size
+    pub async fn render(
+        &'static self,
+        request: RequestValues,
+    ) -> Result<(Response, usize), ApiError> {
+        let garble_bytes = self.template.garble_bytes();
+        let _in_flight = MEMORY_GUARD
+            .try_reserve(garble_bytes, ResponseStrategy::for_size(garble_bytes))
+            .ok_or_else(|| ApiError::memory_ceiling(garble_bytes))?;
+
+        let body = tokio::task::spawn_blocking(move || {
+            let value = self
+                .template
+                .render(&mut RandomDataGenerator::new(), &request);
+            serde_json::to_string(&value).unwrap_or_default()
+        })
+        .await
+        .map_err(|e| ApiError::internal(format!("failed to render response: {}", e)))?;
+
+        let size = body.len();
+        Ok((
+            (self.status, self.headers.clone(), body).into_response(),
+            size,
+        ))
+    }
+}
+
+/// A loaded route definition
+struct Route {
+    pattern: PathPattern,
+    /// `None` answers every method
+    method: Option<Method>,
+    response: CannedResponse,
+}
+
+impl Route {
+    fn load(config: &RouteConfig) -> anyhow::Result<Self> {
+        let pattern = PathPattern::parse(&config.path)?;
+        let method = parse_method(&config.method)?;
+        let response = CannedResponse::load(
+            config.status,
+            &config.headers,
+            config.body.as_ref(),
+            config.body_file.as_deref(),
+        )?;
+        response.check_path_params(Some(&pattern))?;
+
+        Ok(Self {
+            pattern,
+            method,
+            response,
+        })
+    }
+}
+
+/// Parse a configured method, where `*` stands for any
+pub fn parse_method(method: &str) -> anyhow::Result<Option<Method>> {
+    match method {
+        "*" => Ok(None),
+        method => Ok(Some(Method::from_bytes(
+            method.to_ascii_uppercase().as_bytes(),
+        )?)),
+    }
+}
+
+/// Load the route definitions; call once at startup
+pub fn load(configs: &[RouteConfig]) -> anyhow::Result<()> {
+    let routes = configs
+        .iter()
+        .map(|config| Route::load(config).with_context(|| format!("invalid route {}", config.path)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if !routes.is_empty() {
+        tracing::info!("Loaded {} custom routes", routes.len());
+    }
+    let _ = ROUTES.set(routes);
+    Ok(())
+}
+
+/// First route answering `method` on `path`, in definition order
+fn find(method: &Method, path: &str) -> Option<(&'static Route, PathParams)> {
+    ROUTES.get()?.iter().find_map(|route| {
+        if route.method.as_ref().is_some_and(|m| m != method) {
+            return None;
+        }
+        route.pattern.matches(path).map(|params| (route, params))
+    })
+}
+
+/// Serve custom routes, passing anything else to the proxy or answering it with a 404
+#[allow(clippy::too_many_arguments)]
+pub async fn route_handler(
+    State(config): State<Arc<LiveConfig>>,
+    garble_params: Result<Query<GarbleParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    connection: Option<Extension<Arc<ConnectionControl>>>,
+    body: Body,
+) -> Result<Response, ApiError> {
+    let found = find(&method, uri.path());
+    if found.is_none() && !proxy::is_enabled() && !catch_all::is_enabled() {
+        return Err(ApiError::not_found(uri.path()));
+    }
+    if DRAIN.is_draining() {
+        return Err(ApiError::draining());
+    }
+    let tenant = Tenant::resolve(&headers, &config.get())?;
+    let Some((route, params)) = found else {
+        // The proxy has the real service to answer with, so it goes first
+        if proxy::is_enabled() {
+            let body = upload::buffer(body).await?;
+            return proxy::handle(&tenant, method, uri, headers, body).await;
+        }
+        return catch_all::handle(
+            garble_params,
+            raw_query,
+            tenant,
+            method,
+            uri,
+            headers,
+            connection,
+            body,
+        )
+        .await;
+    };
+
+    let body = upload::buffer(body).await?;
+    let request = RequestValues::new(params, &uri, headers, &body);
+    let (response, size) = route.response.render(request).await?;
+
+    tracing::info!(
+        "Served custom route: tenant={}, path={}, size={}B",
+        tenant.name,
+        uri.path(),
+        size
+    );
+    METRICS.record_response(&tenant.segment(), "route", size, 0);
+
+    Ok(response)
+}
+
+// Global route definitions, loaded once at startup
+static ROUTES: OnceCell<Vec<Route>> = OnceCell::new();
@@ -0,0 +1,390 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Scripted test cases selected by the client. Rules from the `rules` config
+//! section are checked in order before routing, and the first one whose
+//! conditions all hold answers the request with a fixed response or passes it
+//! on with a different garble profile, optionally after a delay.
+
+use anyhow::Context;
+use axum::{
+    body::to_bytes,
+    extract::{Request, State},
+    http::{HeaderName, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::{
+    GarbleConfig, LiveConfig, RuleAction, RuleConditions, RuleConfig, SequenceScope,
+};
+use crate::drain::DRAIN;
+use crate::error::ApiError;
+use crate::metrics::METRICS;
+use crate::routes::{self, CannedResponse, PathParams, PathPattern};
+use crate::template::RequestValues;
+use crate::tenants::Tenant;
+
+/// Paths rules never apply to, so a rule cannot lock the admin API away
+pub const EXEMPT_PREFIXES: &[&str] = &["/admin", "/stats", "/metrics", "/health", "/debug"];
+
+/// Largest request body read for `$body(...)` placeholders in rule responses
+const MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+/// Clients a `client` scoped sequence keeps positions for before starting over
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Garble profile chosen by a rule, left in the request extensions for the
+/// `Tenant` extractor
+#[derive(Debug, Clone)]
+pub struct RuleProfile {
+    pub rule: String,
+    pub garble: GarbleConfig,
+}
+
+/// Value accepted by a header or query condition
+enum Expected {
+    Any,
+    Exactly(String),
+}
+
+impl Expected {
+    fn new(value: &str) -> Self {
+        match value {
+            "*" => Expected::Any,
+            value => Expected::Exactly(value.to_string()),
+        }
+    }
+
+    fn accepts(&self, value: Option<&str>) -> bool {
+        match (self, value) {
+            (_, None) => false,
+            (Expected::Any, Some(_)) => true,
+            (Expected::Exactly(expected), Some(value)) => expected == value,
+        }
+    }
+}
+
+struct Conditions {
+    path: Option<PathPattern>,
+    method: Option<Method>,
+    headers: Vec<(HeaderName, Expected)>,
+    query: Vec<(String, Expected)>,
+}
+
+impl Conditions {
+    fn load(config: &RuleConditions) -> anyhow::Result<Self> {
+        Ok(Self {
+            path: config.path.as_deref().map(PathPattern::parse).transpose()?,
+            method: match config.method.as_deref() {
+                Some(method) => routes::parse_method(method)?,
+                None => None,
+            },
+            headers: config
+                .headers
+                .iter()
+                .map(|(name, value)| {
+                    Ok((
+                        HeaderName::from_bytes(name.as_bytes())?,
+                        Expected::new(value),
+                    ))
+                })
+                .collect::<anyhow::Result<_>>()?,
+            query: config
+                .query
+                .iter()
+                .map(|(name, value)| (name.clone(), Expected::new(value)))
+                .collect(),
+        })
+    }
+
+    /// Path parameters captured from the request when every condition holds
+    fn matches(&self, request: &Request) -> Option<PathParams> {
+        if self.method.as_ref().is_some_and(|m| m != request.method()) {
+            return None;
+        }
+        let headers = request.headers();
+        let headers_match = self.headers.iter().all(|(name, expected)| {
+            expected.accepts(headers.get(name).and_then(|v| v.to_str().ok()))
+        });
+        if !headers_match {
+            return None;
+        }
+        if !self.query.is_empty() {
+            let query: BTreeMap<String, String> =
+                serde_urlencoded::from_str(request.uri().query().unwrap_or_default())
+                    .unwrap_or_default();
+            let query_matches = self
+                .query
+                .iter()
+                .all(|(name, expected)| expected.accepts(query.get(name).map(String::as_str)));
+            if !query_matches {
+                return None;
+            }
+        }
+        match &self.path {
+            Some(pattern) => pattern.matches(request.uri().path()),
+            None => Some(PathParams::new()),
+        }
+    }
+}
+
+enum Action {
+    /// Let the request through unchanged, after the delay
+    Continue,
+    Profile(GarbleConfig),
+    Respond(CannedResponse),
+}
+
+/// One scripted outcome
+struct Step {
+    delay: Duration,
+    action: Action,
+}
+
+impl Step {
+    fn load(config: &RuleAction, conditions: &Conditions) -> anyhow::Result<Self> {
+        config.validate()?;
+        let action = match (&config.garble, &config.response) {
+            (Some(garble), _) => Action::Profile(garble.clone()),
+            (None, Some(response)) => {
+                let response = CannedResponse::load(
+                    response.status,
+                    &response.headers,
+                    response.body.as_ref(),
+                    response.body_file.as_deref(),
+                )?;
+                response.check_path_params(conditions.path.as_ref())?;
+                Action::Respond(response)
+            }
+            (None, None) => Action::Continue,
+        };
+        Ok(Self {
+            delay: Duration::from_millis(config.delay_ms),
+            action,
+        })
+    }
+}
+
+/// How far into its steps a rule is
+enum Position {
+    /// Shared by every client, counted by `Rule::matched`
+    Global,
+    Client {
+        header: HeaderName,
+        counts: Mutex<HashMap<String, u64>>,
+    },
+}
+
+struct Rule {
+    name: String,
+    conditions: Conditions,
+    steps: Vec<Step>,
+    position: Position,
+    /// Start over after the last step, rather than staying on it
+    repeat: bool,
+    matched: AtomicU64,
+}
+
+impl Rule {
+    fn load(config: &RuleConfig, name: String) -> anyhow::Result<Self> {
+        let conditions = Conditions::load(&config.when)?;
+        let (steps, position, repeat) = match (&config.then, &config.sequence) {
+            (Some(_), Some(_)) => anyhow::bail!("a rule can set either then or sequence, not both"),
+            (None, Some(sequence)) => {
+                if sequence.steps.is_empty() {
+                    anyhow::bail!("sequence needs at least one step");
+                }
+                let position = match sequence.scope {
+                    SequenceScope::Global => Position::Global,
+                    SequenceScope::Client => Position::Client {
+                        header: HeaderName::from_bytes(sequence.client_header.as_bytes())?,
+                        counts: Mutex::new(HashMap::new()),
+                    },
+                };
+                let steps = sequence
+                    .steps
+                    .iter()
+                    .enumerate()
+                    .map(|(i, step)| {
+                        Step::load(step, &conditions).with_context(|| format!("step {}", i + 1))
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                (steps, position, sequence.repeat)
+            }
+            (then, None) => {
+                let step =
+                    Step::load(then.as_ref().unwrap_or(&RuleAction::default()), &conditions)?;
+                (vec![step], Position::Global, true)
+            }
+        };
+
+        Ok(Self {
+            name,
+            conditions,
+            steps,
+            position,
+            repeat,
+            matched: AtomicU64::new(0),
+        })
+    }
+
+    /// Count a match and pick the step it gets
+    fn advance(&self, request: &Request) -> &Step {
+        let matched = self.matched.fetch_add(1, Ordering::Relaxed);
+        let count = match &self.position {
+            Position::Global => matched,
+            Position::Client { header, counts } => {
+                let client = request
+                    .headers()
+                    .get(header)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default();
+                let mut counts = counts.lock().unwrap();
+                if counts.len() >= MAX_TRACKED_CLIENTS && !counts.contains_key(client) {
+                    tracing::warn!(
+                        "Rule {} tracks {} clients, starting every sequence over",
+                        self.name,
+                        counts.len()
+                    );
+                    counts.clear();
+                }
+                let count = counts.entry(client.to_string()).or_insert(0);
+                *count += 1;
+                *count - 1
+            }
+        };
+
+        let last = self.steps.len() as u64 - 1;
+        let index = if self.repeat {
+            count % self.steps.len() as u64
+        } else {
+            count.min(last)
+        };
+        &self.steps[index as usize]
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleStats {
+    pub name: String,
+    pub matched: u64,
+}
+
+/// Load the rules; call once at startup
+pub fn load(configs: &[RuleConfig]) -> anyhow::Result<()> {
+    let rules = configs
+        .iter()
+        .enumerate()
+        .map(|(i, config)| {
+            let name = config
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("rule-{}", i + 1));
+            Rule::load(config, name.clone()).with_context(|| format!("invalid rule {}", name))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if !rules.is_empty() {
+        tracing::info!("Loaded {} rules", rules.len());
+    }
+    let _ = RULES.set(rules);
+    Ok(())
+}
+
+/// How often each rule matched, in definition order
+pub fn get_stats() -> Vec<RuleStats> {
+    RULES
+        .get()
+        .map(|rules| {
+            rules
+                .iter()
+                .map(|rule| RuleStats {
+                    name: rule.name.clone(),
+                    matched: rule.matched.load(Ordering::Relaxed),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Middleware applying the first rule that matches the request
+pub async fn apply_rules(
+    State(config): State<Arc<LiveConfig>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    if EXEMPT_PREFIXES
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+    {
+        return next.run(request).await;
+    }
+    let Some((rule, params)) = RULES.get().and_then(|rules| {
+        rules.iter().find_map(|rule| {
+            let params = rule.conditions.matches(&request)?;
+            Some((rule, params))
+        })
+    }) else {
+        return next.run(request).await;
+    };
+
+    let step = rule.advance(&request);
+    tracing::debug!("Request to {} matched rule {}", path, rule.name);
+    if !step.delay.is_zero() {
+        tokio::time::sleep(step.delay).await;
+    }
+
+    match &step.action {
+        Action::Continue => next.run(request).await,
+        Action::Profile(garble) => {
+            request.extensions_mut().insert(RuleProfile {
+                rule: rule.name.clone(),
+                garble: garble.clone(),
+            });
+            next.run(request).await
+        }
+        Action::Respond(response) => respond(&config, rule, response, params, request)
+            .await
+            .unwrap_or_else(IntoResponse::into_response),
+    }
+}
+
+async fn respond(
+    config: &LiveConfig,
+    rule: &Rule,
+    response: &'static CannedResponse,
+    params: PathParams,
+    request: Request,
+) -> Result<Response, ApiError> {
+    if DRAIN.is_draining() {
+        return Err(ApiError::draining());
+    }
+    let (parts, body) = request.into_parts();
+    let tenant = Tenant::resolve(&parts.headers, &config.get())?;
+    let body = to_bytes(body, MAX_BODY_BYTES).await.unwrap_or_default();
+
+    let request = RequestValues::new(params, &parts.uri, parts.headers, &body);
+    let (response, size) = response.render(request).await?;
+
+    tracing::info!(
+        "Served rule response: tenant={}, rule={}, size={}B",
+        tenant.name,
+        rule.name,
+        size
+    );
+    METRICS.record_response(&tenant.segment(), "rule", size, 0);
+
+    Ok(response)
+}
+
+// Global rules, loaded once at startup
+static RULES: OnceCell<Vec<Rule>> = OnceCell::new();
@@ -0,0 +1,269 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The accept loop, serving HTTP/1.1 and cleartext HTTP/2 like `axum::serve`
+//! does, but keeping hold of each connection: its requests find the shaping
+//! link they share and a handle to send a GOAWAY partway through a stream, or
+//! to write interim responses hyper has no way of sending.
+
+use axum::{Extension, Router};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use std::future::{poll_fn, Future};
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Notify};
+use tower::Layer;
+
+use crate::http2;
+use crate::shaping;
+
+/// What an HTTP/2 client sends first, telling its connections apart from HTTP/1.1 ones
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0";
+
+/// Number given to the next accepted connection
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Handle on the connection a request arrived on, for faults that reach past its stream
+pub struct ConnectionControl {
+    id: u64,
+    goaway: Notify,
+    goaway_sent: AtomicBool,
+    http2: AtomicBool,
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+}
+
+impl ConnectionControl {
+    /// Number of the connection, counted from one since startup
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Ask the connection to send a GOAWAY, so streams in flight finish and new
+    /// ones are refused, or over HTTP/1.1 to close after the response; false
+    /// when one was asked for already
+    pub fn goaway(&self) -> bool {
+        if self.goaway_sent.swap(true, Ordering::Relaxed) {
+            return false;
+        }
+        self.goaway.notify_one();
+        true
+    }
+
+    /// Whether the client spoke HTTP/2 on this connection
+    pub fn is_http2(&self) -> bool {
+        self.http2.load(Ordering::Relaxed)
+    }
+
+    /// Write `bytes` straight to an HTTP/1.1 connection, before hyper writes
+    /// the response to the request being served
+    pub async fn write_raw(&self, bytes: &[u8]) -> io::Result<()> {
+        let mut written = 0;
+        while written < bytes.len() {
+            written += poll_fn(|cx| {
+                let mut writer = self.writer.lock().unwrap();
+                Pin::new(&mut *writer).poll_write(cx, &bytes[written..])
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Shut the sending side of an HTTP/1.1 connection after a response
+    /// written with `write_raw` in place of hyper's, so what hyper writes
+    /// for the request fails to go out and the connection ends
+    pub async fn close(&self) {
+        let shutdown = poll_fn(|cx| {
+            let mut writer = self.writer.lock().unwrap();
+            Pin::new(&mut *writer).poll_shutdown(cx)
+        })
+        .await;
+        if let Err(e) = shutdown {
+            tracing::debug!("Failed to close connection {}: {}", self.id, e);
+        }
+    }
+}
+
+/// A connection's socket, read by hyper and written by hyper and the
+/// connection's requests in turn
+struct ConnectionIo {
+    reader: OwnedReadHalf,
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<ConnectionControl>,
+    /// Whether the first bytes were looked at for the HTTP/2 preface yet
+    sniffed: bool,
+}
+
+impl ConnectionIo {
+    fn new(stream: TcpStream) -> (Self, Arc<ConnectionControl>) {
+        let (reader, writer) = stream.into_split();
+        let writer = Arc::new(Mutex::new(writer));
+        let control = Arc::new(ConnectionControl {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            goaway: Notify::new(),
+            goaway_sent: AtomicBool::new(false),
+            http2: AtomicBool::new(false),
+            writer: writer.clone(),
+        });
+        let io = Self {
+            reader,
+            writer,
+            control: control.clone(),
+            sniffed: false,
+        };
+        (io, control)
+    }
+}
+
+impl AsyncRead for ConnectionIo {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let start = buf.filled().len();
+        let result = Pin::new(&mut self.reader).poll_read(cx, buf);
+        let read = &buf.filled()[start..];
+        if !self.sniffed && !read.is_empty() {
+            let prefix = read.len().min(HTTP2_PREFACE.len());
+            self.control
+                .http2
+                .store(read[..prefix] == HTTP2_PREFACE[..prefix], Ordering::Relaxed);
+            self.sniffed = true;
+        }
+        result
+    }
+}
+
+impl AsyncWrite for ConnectionIo {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.writer.lock().unwrap()).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.writer.lock().unwrap()).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.writer.lock().unwrap()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.writer.lock().unwrap()).poll_shutdown(cx)
+    }
+}
+
+/// Serve `app` on `listener` until `signal` resolves, then stop accepting and
+/// wait for the open connections to finish what they are serving
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    signal: impl Future<Output = ()> + Send + 'static,
+) -> io::Result<()> {
+    let mut builder = Builder::new(TokioExecutor::new());
+    http2::apply_settings(&mut builder);
+
+    let (signal_tx, signal_rx) = watch::channel(());
+    let signal_tx = Arc::new(signal_tx);
+    tokio::spawn(async move {
+        signal.await;
+        drop(signal_rx);
+    });
+    let (close_tx, close_rx) = watch::channel(());
+
+    loop {
+        let stream = tokio::select! {
+            stream = accept(&listener) => match stream {
+                Some(stream) => stream,
+                None => continue,
+            },
+            _ = signal_tx.closed() => break,
+        };
+
+        let (io, control) = ConnectionIo::new(stream);
+        let service =
+            Extension(control.clone()).layer(Extension(shaping::connect()).layer(app.clone()));
+        let builder = builder.clone();
+        let signal_tx = signal_tx.clone();
+        let close_rx = close_rx.clone();
+
+        tokio::spawn(async move {
+            let connection = builder.serve_connection_with_upgrades(
+                TokioIo::new(io),
+                TowerToHyperService::new(service),
+            );
+            tokio::pin!(connection);
+            let mut closing = false;
+            loop {
+                tokio::select! {
+                    result = connection.as_mut() => {
+                        if let Err(e) = result {
+                            tracing::trace!("Failed to serve connection: {:#}", e);
+                        }
+                        break;
+                    }
+                    _ = signal_tx.closed(), if !closing => {
+                        connection.as_mut().graceful_shutdown();
+                        closing = true;
+                    }
+                    _ = control.goaway.notified(), if !closing => {
+                        connection.as_mut().graceful_shutdown();
+                        closing = true;
+                    }
+                }
+            }
+            drop(close_rx);
+        });
+    }
+
+    drop(close_rx);
+    drop(listener);
+    close_tx.closed().await;
+    Ok(())
+}
+
+/// Accept the next connection; errors that only concern one connection are
+/// skipped, others are logged and retried after a pause, as when out of file descriptors
+async fn accept(listener: &TcpListener) -> Option<TcpStream> {
+    match listener.accept().await {
+        Ok((stream, _)) => Some(stream),
+        Err(e) if is_connection_error(&e) => None,
+        Err(e) => {
+            tracing::error!("Failed to accept a connection: {}", e);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            None
+        }
+    }
+}
+
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
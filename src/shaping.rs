@@ -0,0 +1,294 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Bandwidth shaped per connection rather than per response. Each accepted
+//! connection is put on one of the configured links, and every request on it,
+//! one after another on a keep-alive connection or side by side over HTTP/2,
+//! draws from the same budget the way requests share a constrained last-mile
+//! link. Request bodies are read, and response bodies written, no faster than
+//! the link allows.
+
+use axum::{
+    body::{Body, Bytes},
+    extract::Request,
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+use http_body::{Frame, SizeHint};
+use once_cell::sync::OnceCell;
+use rand::distributions::{Distribution, WeightedIndex};
+use serde::Serialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::Sleep;
+
+use crate::config::{ConnectionShapingConfig, LinkConfig};
+
+/// Most bytes let through at once, so large frames trickle out instead of
+/// leaving in one burst after a long pause
+const SLICE_BYTES: usize = 16 * 1024;
+
+/// Names the link a response went out over
+const LINK_HEADER: &str = "x-daddle-link";
+
+/// A token bucket that may go into debt, so bodies sharing it queue up
+/// behind each other instead of each getting the full rate
+struct Bucket {
+    bytes_per_second: f64,
+    burst: f64,
+    /// Tokens left and when they were last topped up
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(bytes_per_second: u64, burst: u64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second as f64,
+            burst: burst as f64,
+            state: Mutex::new((burst as f64, Instant::now())),
+        }
+    }
+
+    /// Take `bytes` out of the bucket, returning how long to hold them back
+    fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, refilled) = &mut *state;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*refilled).as_secs_f64() * self.bytes_per_second)
+            .min(self.burst);
+        *refilled = now;
+        *tokens -= bytes as f64;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.bytes_per_second)
+        }
+    }
+}
+
+/// A configured link and the traffic it carried
+struct Link {
+    config: LinkConfig,
+    open_connections: AtomicU64,
+    connections: AtomicU64,
+    bytes_down: AtomicU64,
+    bytes_up: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Down,
+    Up,
+}
+
+/// The link a connection was put on, with the budgets its requests share
+pub struct Connection {
+    link: Arc<Link>,
+    down: Option<Bucket>,
+    up: Option<Bucket>,
+}
+
+impl Connection {
+    fn bucket(&self, direction: Direction) -> Option<&Bucket> {
+        match direction {
+            Direction::Down => self.down.as_ref(),
+            Direction::Up => self.up.as_ref(),
+        }
+    }
+
+    fn take(&self, direction: Direction, bytes: usize) -> Duration {
+        let counter = match direction {
+            Direction::Down => &self.link.bytes_down,
+            Direction::Up => &self.link.bytes_up,
+        };
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.bucket(direction)
+            .map_or(Duration::ZERO, |bucket| bucket.take(bytes))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.link.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct Shaping {
+    links: Vec<Arc<Link>>,
+    weights: WeightedIndex<u32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkStats {
+    pub name: String,
+    pub open_connections: u64,
+    pub connections: u64,
+    pub bytes_down: u64,
+    pub bytes_up: u64,
+}
+
+/// Load the links, if any are configured; call once at startup
+pub fn configure(config: &ConnectionShapingConfig) -> anyhow::Result<()> {
+    config.validate()?;
+    if config.links.is_empty() {
+        return Ok(());
+    }
+    let weights = WeightedIndex::new(config.links.iter().map(|link| link.weight))?;
+    let links = config
+        .links
+        .iter()
+        .map(|link| {
+            Arc::new(Link {
+                config: link.clone(),
+                open_connections: AtomicU64::new(0),
+                connections: AtomicU64::new(0),
+                bytes_down: AtomicU64::new(0),
+                bytes_up: AtomicU64::new(0),
+            })
+        })
+        .collect();
+    tracing::info!("Shaping connections over {} links", config.links.len());
+    let _ = SHAPING.set(Shaping { links, weights });
+    Ok(())
+}
+
+/// Put a newly accepted connection on a link picked by weight, if shaping is configured
+pub fn connect() -> Option<Arc<Connection>> {
+    let shaping = SHAPING.get()?;
+    let link = shaping.links[shaping.weights.sample(&mut rand::thread_rng())].clone();
+    link.connections.fetch_add(1, Ordering::Relaxed);
+    link.open_connections.fetch_add(1, Ordering::Relaxed);
+    let bucket = |rate: Option<u64>| rate.map(|rate| Bucket::new(rate, link.config.burst_bytes));
+    Some(Arc::new(Connection {
+        down: bucket(link.config.down_bytes_per_second),
+        up: bucket(link.config.up_bytes_per_second),
+        link,
+    }))
+}
+
+/// Traffic per link, if shaping is configured
+pub fn get_stats() -> Option<Vec<LinkStats>> {
+    SHAPING.get().map(|shaping| {
+        shaping
+            .links
+            .iter()
+            .map(|link| LinkStats {
+                name: link.config.name.clone(),
+                open_connections: link.open_connections.load(Ordering::Relaxed),
+                connections: link.connections.load(Ordering::Relaxed),
+                bytes_down: link.bytes_down.load(Ordering::Relaxed),
+                bytes_up: link.bytes_up.load(Ordering::Relaxed),
+            })
+            .collect()
+    })
+}
+
+/// Middleware holding request and response bodies to the link of the
+/// connection they travel over
+pub async fn shape_connection(request: Request, next: Next) -> Response {
+    let Some(connection) = request
+        .extensions()
+        .get::<Option<Arc<Connection>>>()
+        .cloned()
+        .flatten()
+    else {
+        return next.run(request).await;
+    };
+
+    let upload = connection.clone();
+    let request = request.map(|body| Throttled::wrap(body, upload, Direction::Up));
+    let mut response = next.run(request).await;
+    if let Ok(name) = HeaderValue::from_str(&connection.link.config.name) {
+        response.headers_mut().insert(LINK_HEADER, name);
+    }
+    response.map(|body| Throttled::wrap(body, connection, Direction::Down))
+}
+
+/// A body passed on in slices, each held back until the connection's budget allows it
+struct Throttled {
+    inner: Body,
+    connection: Arc<Connection>,
+    direction: Direction,
+    /// Data taken from the inner body and not passed on yet
+    pending: Bytes,
+    /// A slice waiting for its turn
+    held: Option<(Pin<Box<Sleep>>, Bytes)>,
+}
+
+impl Throttled {
+    fn wrap(body: Body, connection: Arc<Connection>, direction: Direction) -> Body {
+        Body::new(Self {
+            inner: body,
+            connection,
+            direction,
+            pending: Bytes::new(),
+            held: None,
+        })
+    }
+}
+
+impl http_body::Body for Throttled {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        let this = &mut *self;
+        loop {
+            if let Some((sleep, _)) = &mut this.held {
+                ready!(sleep.as_mut().poll(cx));
+                let (_, slice) = this.held.take().expect("a slice is held");
+                return Poll::Ready(Some(Ok(Frame::data(slice))));
+            }
+
+            if !this.pending.is_empty() {
+                let slice = this.pending.split_to(this.pending.len().min(SLICE_BYTES));
+                let wait = this.connection.take(this.direction, slice.len());
+                if wait.is_zero() {
+                    return Poll::Ready(Some(Ok(Frame::data(slice))));
+                }
+                this.held = Some((Box::pin(tokio::time::sleep(wait)), slice));
+                continue;
+            }
+
+            match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => this.pending = data,
+                    // Trailers take no bandwidth worth shaping
+                    Err(frame) => return Poll::Ready(Some(Ok(frame))),
+                },
+                other => return Poll::Ready(other),
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.held.is_none() && self.pending.is_empty() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let buffered =
+            (self.pending.len() + self.held.as_ref().map_or(0, |(_, slice)| slice.len())) as u64;
+        let inner = self.inner.size_hint();
+        if let Some(exact) = inner.exact() {
+            return SizeHint::with_exact(exact + buffered);
+        }
+        let mut hint = SizeHint::new();
+        hint.set_lower(inner.lower() + buffered);
+        if let Some(upper) = inner.upper() {
+            hint.set_upper(upper + buffered);
+        }
+        hint
+    }
+}
+
+// Global links, set at startup when connection shaping is configured
+static SHAPING: OnceCell<Shaping> = OnceCell::new();
@@ -0,0 +1,433 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! `daddle sink` writes generated payloads straight to S3-compatible object
+//! storage, for testing data-lake ingestion and lifecycle policies against bulk
+//! synthetic objects. Uploads are plain SigV4-signed PUTs with path-style
+//! addressing, so MinIO and other S3 lookalikes work as well as S3 itself.
+
+use anyhow::Context;
+use flate2::{write::GzEncoder, Compression as GzLevel};
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use rand::prelude::*;
+use reqwest::{header, Url};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::streaming::{ResponseOptions, StreamingGarbleResponse};
+use crate::template::parse_size;
+
+const DEFAULT_PREFIX: &str = "daddle/";
+const DEFAULT_COUNT: usize = 100;
+const DEFAULT_MIN_SIZE: usize = 1024;
+const DEFAULT_MAX_SIZE: usize = 1024 * 1024;
+const DEFAULT_REGION: &str = "us-east-1";
+const DEFAULT_CONCURRENCY: usize = 4;
+const MAX_CONCURRENCY: usize = 64;
+/// Largest object a single PUT is allowed to carry
+const MAX_OBJECT_SIZE: usize = 512 * 1024 * 1024;
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How object sizes are spread between the minimum and maximum
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SizeDistribution {
+    #[default]
+    Uniform,
+    /// Uniform over the logarithm of the size, so small objects dominate as they
+    /// tend to in real buckets
+    LogUniform,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+}
+
+#[derive(Debug)]
+pub struct SinkOptions {
+    pub bucket: String,
+    pub prefix: String,
+    pub count: usize,
+    pub min_size: usize,
+    pub max_size: usize,
+    pub distribution: SizeDistribution,
+    pub compression: Compression,
+    /// Defaults to `AWS_ENDPOINT_URL`, then the regional AWS endpoint
+    pub endpoint: Option<String>,
+    /// Defaults to `AWS_REGION`, then us-east-1
+    pub region: Option<String>,
+    pub concurrency: usize,
+}
+
+impl SinkOptions {
+    /// Parse `daddle sink` command line arguments
+    pub fn from_args(args: &[String]) -> anyhow::Result<Self> {
+        let mut bucket = None;
+        let mut options = SinkOptions {
+            bucket: String::new(),
+            prefix: DEFAULT_PREFIX.to_string(),
+            count: DEFAULT_COUNT,
+            min_size: DEFAULT_MIN_SIZE,
+            max_size: DEFAULT_MAX_SIZE,
+            distribution: SizeDistribution::default(),
+            compression: Compression::default(),
+            endpoint: None,
+            region: None,
+            concurrency: DEFAULT_CONCURRENCY,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| anyhow::anyhow!("{} requires a value", arg))
+            };
+            match arg.as_str() {
+                "--bucket" | "-b" => bucket = Some(value()?.clone()),
+                "--prefix" | "-p" => options.prefix = value()?.clone(),
+                "--count" | "-n" => options.count = value()?.parse()?,
+                "--size" => {
+                    let size = size_arg(value()?)?;
+                    options.min_size = size;
+                    options.max_size = size;
+                }
+                "--min-size" => options.min_size = size_arg(value()?)?,
+                "--max-size" => options.max_size = size_arg(value()?)?,
+                "--distribution" => {
+                    options.distribution = match value()?.as_str() {
+                        "uniform" => SizeDistribution::Uniform,
+                        "log-uniform" => SizeDistribution::LogUniform,
+                        other => anyhow::bail!("unknown size distribution: {}", other),
+                    }
+                }
+                "--compression" => {
+                    options.compression = match value()?.as_str() {
+                        "none" => Compression::None,
+                        "gzip" => Compression::Gzip,
+                        other => anyhow::bail!("unknown compression: {}", other),
+                    }
+                }
+                "--endpoint" => options.endpoint = Some(value()?.clone()),
+                "--region" => options.region = Some(value()?.clone()),
+                "--concurrency" | "-c" => {
+                    options.concurrency = value()?.parse::<usize>()?.clamp(1, MAX_CONCURRENCY)
+                }
+                other => anyhow::bail!("unknown sink argument: {}", other),
+            }
+        }
+
+        options.bucket = bucket.ok_or_else(|| anyhow::anyhow!("--bucket is required"))?;
+        if options.min_size == 0 || options.min_size > options.max_size {
+            anyhow::bail!("object sizes must satisfy 0 < min-size <= max-size");
+        }
+        if options.max_size > MAX_OBJECT_SIZE {
+            anyhow::bail!(
+                "objects can be at most {}MB",
+                MAX_OBJECT_SIZE / (1024 * 1024)
+            );
+        }
+        Ok(options)
+    }
+
+    fn pick_size(&self, rng: &mut impl Rng) -> usize {
+        if self.min_size == self.max_size {
+            return self.min_size;
+        }
+        match self.distribution {
+            SizeDistribution::Uniform => rng.gen_range(self.min_size..=self.max_size),
+            SizeDistribution::LogUniform => {
+                let (min, max) = (self.min_size as f64, self.max_size as f64);
+                (rng.gen_range(min.ln()..=max.ln()).exp() as usize)
+                    .clamp(self.min_size, self.max_size)
+            }
+        }
+    }
+}
+
+fn size_arg(text: &str) -> anyhow::Result<usize> {
+    parse_size(text).ok_or_else(|| anyhow::anyhow!("invalid size: {}", text))
+}
+
+#[derive(Debug, Serialize)]
+pub struct SinkReport {
+    pub endpoint: String,
+    pub bucket: String,
+    pub prefix: String,
+    pub distribution: SizeDistribution,
+    pub compression: Compression,
+    pub objects: u64,
+    pub failed: u64,
+    /// Bytes generated, before compression
+    pub generated_bytes: u64,
+    /// Bytes stored, after compression
+    pub uploaded_bytes: u64,
+    pub duration_ms: f64,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+/// Access key for SigV4 signing, read from the standard AWS environment variables
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl Credentials {
+    fn from_env() -> anyhow::Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        Ok(Self {
+            access_key_id: var("AWS_ACCESS_KEY_ID")
+                .context("AWS_ACCESS_KEY_ID must be set to write to object storage")?,
+            secret_access_key: var("AWS_SECRET_ACCESS_KEY")
+                .context("AWS_SECRET_ACCESS_KEY must be set to write to object storage")?,
+            session_token: var("AWS_SESSION_TOKEN"),
+        })
+    }
+}
+
+struct Bucket {
+    client: reqwest::Client,
+    endpoint: Url,
+    name: String,
+    region: String,
+    credentials: Credentials,
+}
+
+impl Bucket {
+    fn new(options: &SinkOptions) -> anyhow::Result<Self> {
+        let region = options
+            .region
+            .clone()
+            .or_else(|| std::env::var("AWS_REGION").ok())
+            .filter(|region| !region.is_empty())
+            .unwrap_or_else(|| DEFAULT_REGION.to_string());
+        let endpoint = options
+            .endpoint
+            .clone()
+            .or_else(|| std::env::var("AWS_ENDPOINT_URL").ok())
+            .filter(|endpoint| !endpoint.is_empty())
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+        let endpoint = Url::parse(&endpoint)
+            .with_context(|| format!("invalid object storage endpoint {}", endpoint))?;
+        if endpoint.host_str().is_none() {
+            anyhow::bail!("object storage endpoint {} has no host", endpoint);
+        }
+
+        Ok(Self {
+            client: reqwest::Client::builder().timeout(UPLOAD_TIMEOUT).build()?,
+            endpoint,
+            name: options.bucket.clone(),
+            region,
+            credentials: Credentials::from_env()?,
+        })
+    }
+
+    /// PUT one object, signing the request with SigV4
+    async fn put(&self, key: &str, body: Vec<u8>, compression: Compression) -> anyhow::Result<()> {
+        let path = format!("/{}/{}", uri_encode(&self.name), uri_encode(key));
+        let mut url = self.endpoint.clone();
+        url.set_path(&format!(
+            "{}{}",
+            self.endpoint.path().trim_end_matches('/'),
+            path
+        ));
+
+        let now = chrono::Utc::now();
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let payload_hash = hex(&Sha256::digest(&body));
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        // Canonical headers, sorted by name as SigV4 requires
+        let mut signed = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            signed.push(("x-amz-security-token", token.clone()));
+        }
+        let canonical_headers: String = signed
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect();
+        let signed_headers = signed
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+
+        let canonical_request = format!(
+            "PUT\n{}\n\n{}\n{}\n{}",
+            url.path(),
+            canonical_headers,
+            signed_headers,
+            payload_hash
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signing_key = [self.region.as_str(), "s3", "aws4_request"].iter().fold(
+            hmac_sha256(
+                format!("AWS4{}", self.credentials.secret_access_key).as_bytes(),
+                date.as_bytes(),
+            ),
+            |key, part| hmac_sha256(&key, part.as_bytes()),
+        );
+        let signature = hex(&hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.credentials.access_key_id, scope, signed_headers, signature
+        );
+
+        let mut request = self
+            .client
+            .put(url)
+            .header(header::AUTHORIZATION, authorization)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", timestamp);
+        if let Some(token) = &self.credentials.session_token {
+            request = request.header("x-amz-security-token", token);
+        }
+        if compression == Compression::Gzip {
+            request = request.header(header::CONTENT_ENCODING, "gzip");
+        }
+
+        let response = request.body(body).send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let detail = response.text().await.unwrap_or_default();
+            anyhow::bail!("{} {}", status, detail.trim());
+        }
+        Ok(())
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Percent-encode an object key for the canonical URI, keeping `/` separators
+fn uri_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Generate one garbled JSON document of roughly `size` bytes
+async fn generate(size: usize) -> Vec<u8> {
+    let mut body = Vec::with_capacity(size);
+    let mut stream = StreamingGarbleResponse::new(size, ResponseOptions::default()).into_stream();
+    while let Some(Ok(piece)) = stream.next().await {
+        body.extend_from_slice(piece.as_bytes());
+    }
+    body
+}
+
+fn compress(body: Vec<u8>, compression: Compression) -> std::io::Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(body),
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), GzLevel::default());
+            encoder.write_all(&body)?;
+            encoder.finish()
+        }
+    }
+}
+
+/// Generate and upload `count` objects, reporting how many made it
+pub async fn run(options: SinkOptions) -> anyhow::Result<SinkReport> {
+    let bucket = Bucket::new(&options)?;
+    let started = Instant::now();
+    let run_id = uuid::Uuid::new_v4().simple().to_string();
+    let extension = match options.compression {
+        Compression::None => "json",
+        Compression::Gzip => "json.gz",
+    };
+    tracing::info!(
+        "Writing {} objects to {}/{}/{}",
+        options.count,
+        bucket.endpoint.as_str().trim_end_matches('/'),
+        bucket.name,
+        options.prefix
+    );
+
+    let failed = AtomicU64::new(0);
+    let generated_bytes = AtomicU64::new(0);
+    let uploaded_bytes = AtomicU64::new(0);
+    futures::stream::iter(0..options.count)
+        .for_each_concurrent(options.concurrency, |index| {
+            let (bucket, options, run_id) = (&bucket, &options, &run_id);
+            let (failed, generated_bytes, uploaded_bytes) =
+                (&failed, &generated_bytes, &uploaded_bytes);
+            async move {
+                let key = format!("{}{}-{:06}.{}", options.prefix, run_id, index, extension);
+                let size = options.pick_size(&mut thread_rng());
+                let body = generate(size).await;
+                generated_bytes.fetch_add(body.len() as u64, Ordering::Relaxed);
+
+                let compression = options.compression;
+                let upload =
+                    match tokio::task::spawn_blocking(move || compress(body, compression)).await {
+                        Ok(Ok(body)) => {
+                            let length = body.len() as u64;
+                            bucket.put(&key, body, compression).await.map(|_| length)
+                        }
+                        Ok(Err(e)) => Err(e.into()),
+                        Err(e) => Err(e.into()),
+                    };
+                match upload {
+                    Ok(length) => {
+                        uploaded_bytes.fetch_add(length, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        failed.fetch_add(1, Ordering::Relaxed);
+                        tracing::warn!("Failed to upload {}: {}", key, e);
+                    }
+                }
+            }
+        })
+        .await;
+
+    let failed = failed.into_inner();
+    Ok(SinkReport {
+        endpoint: bucket.endpoint.to_string(),
+        bucket: bucket.name,
+        prefix: options.prefix,
+        distribution: options.distribution,
+        compression: options.compression,
+        objects: options.count as u64 - failed,
+        failed,
+        generated_bytes: generated_bytes.into_inner(),
+        uploaded_bytes: uploaded_bytes.into_inner(),
+        duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+        timestamp: chrono::Utc::now(),
+    })
+}
@@ -0,0 +1,80 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Cumulative stats, latency histograms included, written to a file while
+//! running and once more on shutdown, so a short-lived instance in CI leaves
+//! a machine-readable record of its run behind without a metrics stack.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::StatsExportConfig;
+use crate::handlers;
+
+#[derive(Serialize)]
+struct Export {
+    written_at: DateTime<Utc>,
+    /// Whether this is the snapshot written on shutdown, after the last request
+    r#final: bool,
+    stats: Value,
+}
+
+/// Write the snapshot next to `path` and move it into place, so a reader
+/// never sees a partly written file
+async fn write(path: &Path, r#final: bool) -> anyhow::Result<()> {
+    let export = Export {
+        written_at: Utc::now(),
+        r#final,
+        stats: handlers::stats_snapshot(),
+    };
+    let content = serde_json::to_vec_pretty(&export)?;
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    tokio::fs::write(&partial, &content).await?;
+    tokio::fs::rename(&partial, path).await?;
+    Ok(())
+}
+
+async fn export(path: PathBuf, interval: Option<Duration>, shutdown: CancellationToken) {
+    if let Some(interval) = interval {
+        let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = ticks.tick() => {
+                    if let Err(e) = write(&path, false).await {
+                        tracing::warn!("Failed to export stats to {}: {}", path.display(), e);
+                    }
+                }
+            }
+        }
+    } else {
+        shutdown.cancelled().await;
+    }
+
+    match write(&path, true).await {
+        Ok(()) => tracing::info!("Exported final stats to {}", path.display()),
+        Err(e) => tracing::warn!("Failed to export final stats to {}: {}", path.display(), e),
+    }
+}
+
+/// Start exporting stats when a path is configured; the final snapshot is
+/// written once `shutdown` is cancelled
+pub fn start(
+    config: &StatsExportConfig,
+    shutdown: CancellationToken,
+) -> anyhow::Result<Option<JoinHandle<()>>> {
+    config.validate()?;
+    let Some(path) = config.path.as_deref().map(PathBuf::from) else {
+        return Ok(None);
+    };
+    tracing::info!("Exporting stats to {}", path.display());
+    let interval = config.interval_ms.map(Duration::from_millis);
+    Ok(Some(tokio::spawn(export(path, interval, shutdown))))
+}
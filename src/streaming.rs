@@ -4,26 +4,277 @@
 
 use async_stream::stream;
 use axum::{
-    body::Body,
-    http::{header, StatusCode},
-    response::{IntoResponse, Response},
+    body::{Body, Bytes},
+    extract::{rejection::QueryRejection, Query, RawQuery},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
 };
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use futures::{Stream, StreamExt};
+use http_body::Frame;
+use http_body_util::StreamBody;
+use once_cell::sync::OnceCell;
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::{Map, Value};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::chunk_pool::{ChunkSize, CHUNK_POOL};
-use crate::generator::RandomDataGenerator;
+use crate::admission::AdmissionPermit;
+use crate::budget;
+use crate::chunk_pool::{ChunkSize, PooledChunk, CHUNK_POOL, MAX_MISFITS};
+use crate::compression::{self, ContentCoding, Piece};
+use crate::config::PerformanceConfig;
+use crate::drain::DRAIN;
+use crate::emit::{self, EmitOptions};
+use crate::encoding::{BodyEncoding, TextEncoding};
+use crate::error::ApiError;
+use crate::generator::{GeneratorOptions, RandomDataGenerator};
+use crate::memory::InFlightGuard;
+use crate::metadata::{self, MetadataOptions, ResponseMetadata};
+use crate::metrics::{ClientAbortWatch, Segment, METRICS};
+use crate::pacing::{paced, FlushPacing};
+use crate::tenants::Tenant;
+use crate::validation::strict_query;
+
+/// Overall layout of a generated body
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResponseShape {
+    /// Heterogeneous garble, assembled according to the size strategy
+    #[default]
+    Garble,
+    /// A top-level array of records sharing one schema; `count` records, or
+    /// as many as it takes to reach the target size
+    Records { count: Option<usize> },
+    /// A random binary blob, base64 encoded into a string member of an object
+    /// describing it, sized so that the encoded document reaches the target size
+    Base64,
+}
+
+/// Per-request options that shape how a response body is assembled
+#[derive(Debug, Clone, Default)]
+pub struct ResponseOptions {
+    pub metadata: MetadataOptions,
+    pub generator: GeneratorOptions,
+    pub shape: ResponseShape,
+    pub emit: EmitOptions,
+    /// Wrap the body in a call to this JSONP function
+    pub jsonp_callback: Option<String>,
+    pub encoding: BodyEncoding,
+    pub pacing: FlushPacing,
+    /// Sleep between streamed chunks; forces the streaming strategy
+    pub chunk_delay: Option<ChunkDelay>,
+    pub chunking: ChunkLayout,
+    /// Content coding negotiated with the client, if the body is compressed
+    pub coding: Option<ContentCoding>,
+    /// Strategy the caller asked for, instead of picking one by size
+    pub strategy: Option<ResponseStrategy>,
+    /// Time by which a body built in memory has to be generated, per the generation budget
+    pub deadline: Option<Instant>,
+    /// Stats segment that pool hits are attributed to
+    pub segment: Option<Segment>,
+    /// Seed every part of the body is generated from, for content that is the
+    /// same on every request
+    pub seed: Option<u64>,
+}
+
+/// Caller-dictated partitioning of the chunk array, overriding adaptive sizing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChunkLayout {
+    /// Exact number of array elements
+    pub count: Option<usize>,
+    /// Target size of each element
+    pub bytes: Option<usize>,
+}
+
+impl ChunkLayout {
+    pub fn is_set(&self) -> bool {
+        self.count.is_some() || self.bytes.is_some()
+    }
+}
+
+/// Delay inserted between streamed chunks, picked per chunk from an inclusive range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkDelay {
+    pub min_ms: u64,
+    pub max_ms: u64,
+}
+
+impl ChunkDelay {
+    fn sample(&self) -> Duration {
+        Duration::from_millis(rand::thread_rng().gen_range(self.min_ms..=self.max_ms))
+    }
+}
+
+/// A piece of a streamed body: generated text, or a chunk taken from the pool
+enum BodyPiece {
+    Text(String),
+    Pooled(PooledChunk),
+}
+
+impl BodyPiece {
+    fn len(&self) -> usize {
+        match self {
+            BodyPiece::Text(text) => text.len(),
+            BodyPiece::Pooled(chunk) => chunk.json.len(),
+        }
+    }
+
+    fn into_text(self) -> String {
+        match self {
+            BodyPiece::Text(text) => text,
+            BodyPiece::Pooled(chunk) => chunk.json,
+        }
+    }
+
+    /// Pre-compressed chunk forms are UTF-8, so other encodings compress on demand
+    fn into_compressible(self, encoding: BodyEncoding) -> Piece {
+        match self {
+            BodyPiece::Pooled(chunk) if encoding.encoding == TextEncoding::Utf8 => Piece::Chunk {
+                plain: Bytes::from(chunk.json),
+                variants: chunk.variants,
+            },
+            piece => Piece::Plain(encoding.encode(piece.into_text())),
+        }
+    }
+}
+
+/// Body bytes on their way to the socket
+type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send>>;
+
+/// Apply flush pacing to a body, if any was requested
+fn with_pacing(body: ByteStream, pacing: FlushPacing) -> ByteStream {
+    if pacing.is_active() {
+        Box::pin(paced(body, pacing))
+    } else {
+        body
+    }
+}
+
+/// Content type of JSONP responses
+const JSONP_CONTENT_TYPE: &str = "application/javascript";
+
+impl ResponseOptions {
+    /// Pooled chunks are generated and serialized with default options, so
+    /// only requests that keep the defaults can be served from the pool
+    pub fn uses_pool(&self) -> bool {
+        self.generator.uses_pool() && self.emit == EmitOptions::default() && self.seed.is_none()
+    }
+
+    /// Whether the generation deadline, if any, has passed
+    pub fn out_of_time(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// A generator following these options and deadline
+    pub fn generator(&self) -> RandomDataGenerator {
+        self.part_generator(0)
+    }
+
+    /// A generator for one of the parts a body is assembled from, such as a
+    /// chunk; with a seed, each part gets its own so the parts differ but
+    /// come out the same on every request
+    pub fn part_generator(&self, part: u64) -> RandomDataGenerator {
+        let generator = match self.seed {
+            Some(seed) => RandomDataGenerator::seeded(
+                self.generator.clone(),
+                seed ^ part.wrapping_mul(0x9E37_79B9_7F4A_7C15),
+            ),
+            None => RandomDataGenerator::with_options(self.generator.clone()),
+        };
+        generator.with_deadline(self.deadline)
+    }
+
+    /// Content type matching the body as written
+    fn content_type(&self) -> &'static str {
+        if self.jsonp_callback.is_some() {
+            JSONP_CONTENT_TYPE
+        } else {
+            "application/json"
+        }
+    }
+
+    /// Text written before the JSON document, opening the JSONP call if any
+    fn body_prefix(&self) -> String {
+        match &self.jsonp_callback {
+            Some(callback) => format!("{}(", callback),
+            None => String::new(),
+        }
+    }
+
+    /// Text written after the JSON document, closing the JSONP call if any
+    fn body_suffix(&self) -> &'static str {
+        if self.jsonp_callback.is_some() {
+            ");"
+        } else {
+            ""
+        }
+    }
+}
+
+/// Sent as a trailer on streaming responses, since pool usage is only known once the body is done.
+/// HTTP/1.1 clients only get trailers when they ask with `TE: trailers`, so
+/// most load-test tools never see it there; `metadata=full` and `/stats` carry the count too
+const POOL_HITS_HEADER: &str = "x-garble-pool-hits";
+
+/// Member of directly generated documents that makes up their size shortfall
+const PADDING_KEY: &str = "padding";
+
+/// Decisions made while producing a response, echoed back as `X-Garble-*` headers
+#[derive(Debug, Clone, Copy)]
+pub struct ResponseDecision {
+    pub strategy: &'static str,
+    pub target_size: usize,
+    pub wait_ms: u64,
+}
+
+impl ResponseDecision {
+    pub fn apply_headers(&self, headers: &mut HeaderMap) {
+        headers.insert("x-garble-strategy", HeaderValue::from_static(self.strategy));
+        headers.insert("x-garble-target-size", HeaderValue::from(self.target_size));
+        headers.insert("x-garble-wait-ms", HeaderValue::from(self.wait_ms));
+    }
+}
+
+/// Bytes a stream generated and the time spent generating them, leaving out
+/// waits for the client and requested delays
+#[derive(Default)]
+struct GenerationTally {
+    bytes: AtomicUsize,
+    nanos: AtomicU64,
+}
 
 /// Streaming response for large JSON payloads
 pub struct StreamingGarbleResponse {
     target_size: usize,
     chunk_size: usize,
+    options: ResponseOptions,
+    // Chunks served from the pool so far, reported in the trailer once the stream ends
+    pool_hits: Arc<AtomicUsize>,
+    // Generation work so far, recorded in the strategy stats once the stream ends
+    generation: Arc<GenerationTally>,
+    // Held for the lifetime of the stream so memory accounting covers the whole transfer
+    in_flight: Option<InFlightGuard>,
+    // Held for the lifetime of the stream so the admission queue counts the whole transfer
+    admission: Option<AdmissionPermit>,
 }
 
 impl StreamingGarbleResponse {
-    pub fn new(target_size: usize) -> Self {
-        // Use adaptive chunk size based on target size
-        let chunk_size = if target_size > 10_000_000 {
+    pub fn new(target_size: usize, options: ResponseOptions) -> Self {
+        let layout = options.chunking;
+        // Use the requested chunk size, or adapt it to the target size
+        let chunk_size = if let Some(bytes) = layout.bytes {
+            bytes
+        } else if let Some(count) = layout.count {
+            target_size.div_ceil(count).max(1)
+        } else if target_size > 10_000_000 {
             ChunkSize::XLarge.target_bytes() // 1MB chunks for very large responses
         } else if target_size > 1_000_000 {
             ChunkSize::Large.target_bytes() // 100KB chunks for large responses
@@ -34,57 +285,161 @@ impl StreamingGarbleResponse {
         Self {
             target_size,
             chunk_size,
+            options,
+            pool_hits: Arc::new(AtomicUsize::new(0)),
+            generation: Arc::default(),
+            in_flight: None,
+            admission: None,
         }
     }
 
+    pub fn with_in_flight(mut self, guard: InFlightGuard) -> Self {
+        self.in_flight = Some(guard);
+        self
+    }
+
+    pub fn with_admission(mut self, permit: AdmissionPermit) -> Self {
+        self.admission = Some(permit);
+        self
+    }
+
     /// Create a stream of JSON chunks
     pub fn into_stream(self) -> Pin<Box<dyn Stream<Item = Result<String, std::io::Error>> + Send>> {
+        Box::pin(self.pieces().map(|piece| piece.map(BodyPiece::into_text)))
+    }
+
+    /// Create a stream of body pieces, keeping pooled chunks apart so their
+    /// compressed forms can be used
+    fn pieces(self) -> Pin<Box<dyn Stream<Item = Result<BodyPiece, std::io::Error>> + Send>> {
         let stream = stream! {
+            // The server drops this stream at the first write the client is no longer
+            // there for, taking its pooled chunks and the rest of the generation with it
+            let mut client = ClientAbortWatch::new("streaming");
             let mut remaining = self.target_size;
-            let mut chunk_count = 0;
-            let total_chunks = self.target_size.div_ceil(self.chunk_size);
+            let mut metadata =
+                ResponseMetadata::new(&self.options.metadata, "streaming", self.target_size)
+                    .with_seed(self.options.seed);
+            let exact_count = self.options.chunking.count;
+            let total_chunks =
+                exact_count.unwrap_or_else(|| self.target_size.div_ceil(self.chunk_size));
 
             // Start JSON structure - use same format as chunk pool
-            yield Ok(r#"{"garbled_chunks":["#.to_string());
+            let emit_options = self.options.emit;
+            let prefix = self.options.body_prefix();
+            if !prefix.is_empty() {
+                yield Ok(BodyPiece::Text(prefix));
+            }
+
+            let opening = emit::chunks_open(&emit_options);
+            let mut bytes_sent = opening.len();
+            yield Ok(BodyPiece::Text(opening));
 
-            while remaining > 500 && chunk_count < total_chunks {
-                if chunk_count > 0 {
-                    yield Ok(",".to_string());
+            // A requested layout dictates the chunks; otherwise they fill the
+            // target size and whatever they fall short by is padded
+            let layout = self.options.chunking.is_set();
+            let mut misfits = 0;
+            let mut generated = 0;
+            loop {
+                let room = metadata.room(bytes_sent, &emit_options);
+                let more = if layout {
+                    // An exact chunk count ignores the remaining size
+                    (exact_count.is_some() || remaining > 500) && metadata.chunk_count < total_chunks
+                } else {
+                    room > 500 && misfits < MAX_MISFITS
+                };
+                if !more {
+                    break;
                 }
 
                 // Determine chunk size for this iteration
-                let current_chunk_size = remaining.min(self.chunk_size);
+                let current_chunk_size = if layout {
+                    self.chunk_size
+                } else {
+                    room.min(self.chunk_size)
+                };
 
                 // Try to get from chunk pool first
-                let chunk_data = if let Some(pooled_chunk) = self.get_pooled_chunk(current_chunk_size) {
-                    pooled_chunk
-                } else {
-                    // Generate on-demand if pool is empty
-                    self.generate_chunk(current_chunk_size)
+                let started = Instant::now();
+                let pooled_chunk = self.get_pooled_chunk(current_chunk_size);
+                let pooled = pooled_chunk.is_some();
+                // Generate on-demand if pool is empty
+                let chunk_data = match pooled_chunk {
+                    Some(chunk) => BodyPiece::Pooled(chunk),
+                    None => {
+                        generated += 1;
+                        BodyPiece::Text(self.generate_chunk(current_chunk_size, generated))
+                    }
                 };
 
-                // Update remaining based on actual chunk size, not target size
+                let generating = Duration::from_nanos(
+                    self.generation
+                        .nanos
+                        .fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed),
+                ) + started.elapsed();
+                if budget::limit().is_some_and(|limit| generating > limit) {
+                    // Headers are out already, so all that is left is to break off the body
+                    let _ = budget::exceeded("streaming", self.target_size, bytes_sent, generating);
+                    client.finish();
+                    yield Err(std::io::Error::other("generation budget exceeded"));
+                    return;
+                }
+
+                let separator =
+                    emit::element_separator(metadata.chunk_count, &emit_options, emit::CHUNK_DEPTH);
                 let actual_chunk_size = chunk_data.len();
+                if !layout && separator.len() + actual_chunk_size > room {
+                    misfits += 1;
+                    continue;
+                }
+
+                if let (Some(delay), true) = (self.options.chunk_delay, metadata.chunk_count > 0) {
+                    tokio::time::sleep(delay.sample()).await;
+                }
+
+                metadata.record_chunk(pooled);
+                self.pool_hits.store(metadata.chunk_hits, Ordering::Relaxed);
+                if !separator.is_empty() {
+                    bytes_sent += separator.len();
+                    yield Ok(BodyPiece::Text(separator));
+                }
+
+                // Update remaining based on actual chunk size, not target size
                 remaining = remaining.saturating_sub(actual_chunk_size);
+                bytes_sent += actual_chunk_size;
+                self.generation.bytes.store(bytes_sent, Ordering::Relaxed);
 
                 yield Ok(chunk_data);
-                chunk_count += 1;
 
                 // Yield control to allow other tasks to run
                 tokio::task::yield_now().await;
             }
 
             // Close JSON structure - use same format as chunk pool
-            yield Ok(format!(
-                r#"],"metadata":{{"generated_by":"streaming","target_size":{},"actual_size":{},"chunk_count":{},"streaming":true}}}}"#,
-                self.target_size, self.target_size, chunk_count
-            ));
+            let close = if layout {
+                let close =
+                    emit::array_close(metadata.chunk_count, &emit_options, emit::CHUNK_DEPTH);
+                let metadata_block = metadata.render(bytes_sent + close.len(), &emit_options);
+                format!("{}{}{}", close, metadata_block, emit::chunks_close(&emit_options))
+            } else {
+                metadata.close(bytes_sent, &emit_options)
+            };
+            let closing = format!("{}{}", close, self.options.body_suffix());
+            self.generation
+                .bytes
+                .store(bytes_sent + closing.len(), Ordering::Relaxed);
+            yield Ok(BodyPiece::Text(closing));
+            client.finish();
         };
 
         Box::pin(stream)
     }
 
-    fn get_pooled_chunk(&self, target_size: usize) -> Option<String> {
+    fn get_pooled_chunk(&self, target_size: usize) -> Option<PooledChunk> {
+        // Pooled chunks come in fixed size classes, so they cannot honor a requested layout
+        if !self.options.uses_pool() || self.options.chunking.is_set() {
+            return None;
+        }
+
         // Determine best chunk size from pool
         let chunk_size = if target_size >= ChunkSize::XLarge.target_bytes() {
             ChunkSize::XLarge
@@ -97,118 +452,254 @@ impl StreamingGarbleResponse {
         };
 
         // Get chunk from pool - these are already JSON array elements
-        CHUNK_POOL.get_chunk(chunk_size)
+        CHUNK_POOL.take_chunk(chunk_size)
     }
 
-    fn generate_chunk(&self, size: usize) -> String {
-        let mut generator = RandomDataGenerator::new();
+    fn generate_chunk(&self, size: usize, part: u64) -> String {
+        // Whatever is left of the budget bounds this chunk
+        let deadline = budget::limit().map(|limit| {
+            let spent = Duration::from_nanos(self.generation.nanos.load(Ordering::Relaxed));
+            Instant::now() + limit.saturating_sub(spent)
+        });
+        let mut generator = self.options.part_generator(part).with_deadline(deadline);
         let payload = generator.generate_array_element(size);
-        serde_json::to_string(&payload).unwrap_or_else(|_| "{}".to_string())
+        emit::to_json_at(&payload, &self.options.emit, emit::CHUNK_DEPTH)
     }
 }
 
 impl IntoResponse for StreamingGarbleResponse {
     fn into_response(self) -> Response {
-        let stream = self.into_stream();
+        let pool_hits = self.pool_hits.clone();
+        let segment = self.options.segment.clone();
+        let generation = self.generation.clone();
+        let encoding = self.options.encoding;
+        let pacing = self.options.pacing;
+        let content_type = encoding.content_type(self.options.content_type());
+        let coding = self.options.coding;
+        let pieces = self.pieces();
 
-        // Convert string stream to bytes stream
-        let byte_stream = stream.map(|result| {
-            result
-                .map(|s| axum::body::Bytes::from(s.into_bytes()))
-                .map_err(std::io::Error::other)
+        // Encode and maybe compress the pieces, then convert them to data frames finishing with the pool hits trailer
+        let bom = (!encoding.bom().is_empty()).then(|| Bytes::from_static(encoding.bom()));
+        let bytes: ByteStream =
+            match coding {
+                Some(coding) => {
+                    let bom = futures::stream::iter(bom.map(|bom| Ok(Piece::Plain(bom))));
+                    let pieces = pieces
+                        .map(move |result| result.map(|piece| piece.into_compressible(encoding)));
+                    Box::pin(compression::compress_stream(coding, bom.chain(pieces)))
+                }
+                None => {
+                    let bom = futures::stream::iter(bom.map(Ok));
+                    Box::pin(bom.chain(
+                        pieces.map(move |result| {
+                            result.map(|piece| encoding.encode(piece.into_text()))
+                        }),
+                    ))
+                }
+            };
+        let data_frames = with_pacing(bytes, pacing).map(|result| result.map(Frame::data));
+        let trailer = futures::stream::once(async move {
+            METRICS.record_generation(
+                ResponseStrategy::Streaming.name(),
+                generation.bytes.load(Ordering::Relaxed),
+                Duration::from_nanos(generation.nanos.load(Ordering::Relaxed)),
+            );
+            let pool_hits = pool_hits.load(Ordering::Relaxed);
+            if let Some(segment) = &segment {
+                METRICS.record_pool_hits(segment, pool_hits);
+            }
+            let mut trailers = HeaderMap::new();
+            trailers.insert(POOL_HITS_HEADER, HeaderValue::from(pool_hits));
+            Ok(Frame::trailers(trailers))
         });
 
-        Response::builder()
+        let mut response = Response::builder()
             .status(StatusCode::OK)
-            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_TYPE, content_type)
             .header(header::TRANSFER_ENCODING, "chunked")
+            .header(header::TRAILER, POOL_HITS_HEADER)
             .header("X-Garble-Mode", "streaming")
-            .body(Body::from_stream(byte_stream))
-            .unwrap()
+            .body(Body::new(StreamBody::new(data_frames.chain(trailer))))
+            .unwrap();
+        if let Some(coding) = coding {
+            response.headers_mut().insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(coding.name()),
+            );
+        }
+        response
     }
 }
 
 /// Fast response builder for medium-sized responses using chunk pool
 pub struct FastGarbleResponse {
     target_size: usize,
+    options: ResponseOptions,
 }
 
 impl FastGarbleResponse {
-    pub fn new(target_size: usize) -> Self {
-        Self { target_size }
+    pub fn new(target_size: usize, options: ResponseOptions) -> Self {
+        Self {
+            target_size,
+            options,
+        }
     }
 
-    /// Build response using parallel chunk assembly
-    pub fn build(self) -> String {
+    /// Build response using parallel chunk assembly, returning the body and
+    /// the number of chunks served from the pool
+    pub fn build(self) -> (String, usize) {
         if self.target_size < 100_000 {
             // For small responses, use the chunk pool's build method
-            CHUNK_POOL.build_response(self.target_size)
+            CHUNK_POOL.build_response(self.target_size, &self.options)
         } else {
             // For larger responses, use parallel assembly
             self.build_parallel()
         }
     }
 
-    fn build_parallel(self) -> String {
+    fn build_parallel(self) -> (String, usize) {
         use rayon::prelude::*;
 
         // Calculate how many chunks we need
         let chunk_size = ChunkSize::Large.target_bytes(); // 100KB chunks
         let num_chunks = self.target_size.div_ceil(chunk_size);
 
-        // Generate chunks in parallel
-        let chunks: Vec<String> = (0..num_chunks)
+        let mut metadata =
+            ResponseMetadata::new(&self.options.metadata, "parallel", self.target_size)
+                .with_seed(self.options.seed);
+        let use_pool = self.options.uses_pool();
+
+        // Generate chunks in parallel, noting whether each came from the pool;
+        // chunks not started by the deadline are left out
+        let chunks: Vec<(String, bool)> = (0..num_chunks)
             .into_par_iter()
-            .map(|i| {
+            .filter_map(|i| {
                 let remaining = self.target_size - (i * chunk_size);
                 let current_size = remaining.min(chunk_size);
 
                 // Try pool first, then generate
-                if let Some(chunk) = CHUNK_POOL.get_chunk(ChunkSize::Large) {
-                    chunk
+                if let Some(chunk) = use_pool
+                    .then(|| CHUNK_POOL.get_chunk(ChunkSize::Large))
+                    .flatten()
+                {
+                    Some((chunk, true))
+                } else if self.options.out_of_time() {
+                    None
                 } else {
-                    let mut generator = RandomDataGenerator::new();
+                    let mut generator = self.options.part_generator(i as u64 + 1);
                     let payload = generator.generate_array_element(current_size);
-                    serde_json::to_string(&payload).unwrap_or_else(|_| "{}".to_string())
+                    let chunk = emit::to_json_at(&payload, &self.options.emit, emit::CHUNK_DEPTH);
+                    Some((chunk, false))
                 }
             })
             .collect();
 
         // Assemble final response - use same format as chunk pool
         let mut result = String::with_capacity(self.target_size + 1024);
-        result.push_str(r#"{"garbled_chunks":["#);
+        let emit_options = &self.options.emit;
+        result.push_str(&emit::chunks_open(emit_options));
 
-        for (i, chunk) in chunks.iter().enumerate() {
-            if i > 0 {
-                result.push(',');
+        for (chunk, pooled) in chunks {
+            let separator =
+                emit::element_separator(metadata.chunk_count, emit_options, emit::CHUNK_DEPTH);
+            if separator.len() + chunk.len() > metadata.room(result.len(), emit_options) {
+                continue;
             }
-            result.push_str(chunk);
+            result.push_str(&separator);
+            result.push_str(&chunk);
+            metadata.record_chunk(pooled);
         }
 
-        result.push_str(r#"],"metadata":{"generated_by":"parallel","target_size":"#);
-        result.push_str(&self.target_size.to_string());
-        result.push_str(r#","chunk_count":"#);
-        result.push_str(&chunks.len().to_string());
-        result.push_str(r#","actual_size":"#);
-        result.push_str(&result.len().to_string());
-        result.push_str(r#"}}"#);
+        // Top up whatever the parallel chunks fell short of the target by
+        CHUNK_POOL.fill(&mut result, &mut metadata, &self.options);
+        // A body past the deadline is thrown away, so it is not worth padding
+        if !self.options.out_of_time() {
+            result.push_str(&metadata.close(result.len(), emit_options));
+        }
 
-        result
+        (result, metadata.chunk_hits)
     }
 }
 
 /// Determine the best response strategy based on size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ResponseStrategy {
-    Direct,    // < 10KB - generate directly
-    Fast,      // 10KB - 1MB - use chunk pool + parallel
-    Streaming, // > 1MB - use streaming
+    Direct,    // below the fast threshold - generate directly
+    Fast,      // up to the streaming threshold - use chunk pool + parallel
+    Streaming, // from the streaming threshold - use streaming
+    Records,   // shape=records - buffered array of same-schema records
+    Base64,    // shape=base64 - buffered binary blob embedded as base64
+}
+
+/// Body sizes at which responses move to the next strategy
+#[derive(Debug, Clone, Copy)]
+struct StrategyThresholds {
+    fast: usize,
+    streaming: usize,
+}
+
+/// Thresholds used until `configure_thresholds` runs
+const DEFAULT_THRESHOLDS: StrategyThresholds = StrategyThresholds {
+    fast: 10_000,
+    streaming: 1_000_000,
+};
+
+/// Set the sizes at which responses switch strategy; call once at startup
+pub fn configure_thresholds(performance: &PerformanceConfig) -> anyhow::Result<()> {
+    if performance.fast_response_threshold_bytes > performance.streaming_threshold_bytes {
+        anyhow::bail!(
+            "performance.fast_response_threshold_bytes ({}) is greater than performance.streaming_threshold_bytes ({})",
+            performance.fast_response_threshold_bytes,
+            performance.streaming_threshold_bytes
+        );
+    }
+    let _ = THRESHOLDS.set(StrategyThresholds {
+        fast: performance.fast_response_threshold_bytes,
+        streaming: performance.streaming_threshold_bytes,
+    });
+    Ok(())
 }
 
 impl ResponseStrategy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResponseStrategy::Direct => "direct",
+            ResponseStrategy::Fast => "fast",
+            ResponseStrategy::Streaming => "streaming",
+            ResponseStrategy::Records => "records",
+            ResponseStrategy::Base64 => "base64",
+        }
+    }
+
+    /// The strategy a response with these options is built with; everything
+    /// that depends on the strategy, from memory reservations to stats, asks here
+    pub fn choose(target_size: usize, options: &ResponseOptions) -> Self {
+        match options.shape {
+            ResponseShape::Records { .. } => return ResponseStrategy::Records,
+            ResponseShape::Base64 => return ResponseStrategy::Base64,
+            ResponseShape::Garble => {}
+        }
+        if options.chunk_delay.is_some() || options.chunking.is_set() {
+            return ResponseStrategy::Streaming;
+        }
+        if let Some(strategy) = options.strategy {
+            return strategy;
+        }
+        match ResponseStrategy::for_size(target_size) {
+            // Compressed bodies are stitched from pre-compressed pool chunks as they stream
+            ResponseStrategy::Fast if options.coding.is_some() && options.uses_pool() => {
+                ResponseStrategy::Streaming
+            }
+            strategy => strategy,
+        }
+    }
+
+    /// The strategy for a body of `size` bytes by the configured thresholds
     pub fn for_size(size: usize) -> Self {
-        if size < 10_000 {
+        let thresholds = THRESHOLDS.get().copied().unwrap_or(DEFAULT_THRESHOLDS);
+        if size < thresholds.fast {
             ResponseStrategy::Direct
-        } else if size < 1_000_000 {
+        } else if size < thresholds.streaming {
             ResponseStrategy::Fast
         } else {
             ResponseStrategy::Streaming
@@ -216,42 +707,606 @@ impl ResponseStrategy {
     }
 }
 
-/// Response type that can be either regular JSON or streaming
-pub enum GarbleResponse {
-    Json(String),
-    Streaming(StreamingGarbleResponse),
+/// Response body that can be either regular JSON or streaming
+pub enum GarbleBody {
+    Json { json: String, pool_hits: usize },
+    Streaming(Box<StreamingGarbleResponse>),
+}
+
+/// A generated response together with the decisions that shaped it
+pub struct GarbleResponse {
+    pub body: GarbleBody,
+    pub decision: ResponseDecision,
+    content_type: &'static str,
+    encoding: BodyEncoding,
+    pacing: FlushPacing,
+    coding: Option<ContentCoding>,
+}
+
+impl GarbleResponse {
+    /// Attach an in-flight memory reservation; streaming responses keep it until the body finishes
+    pub fn with_in_flight(mut self, guard: InFlightGuard) -> Self {
+        if let GarbleBody::Streaming(streaming) = self.body {
+            self.body = GarbleBody::Streaming(Box::new(streaming.with_in_flight(guard)));
+        }
+        self
+    }
+
+    /// Attach an admission slot; streaming responses keep it until the body finishes
+    pub fn with_admission(mut self, permit: AdmissionPermit) -> Self {
+        if let GarbleBody::Streaming(streaming) = self.body {
+            self.body = GarbleBody::Streaming(Box::new(streaming.with_admission(permit)));
+        }
+        self
+    }
+
+    /// Record how long the handler waited before generating
+    pub fn with_wait_ms(mut self, wait_ms: u64) -> Self {
+        self.decision.wait_ms = wait_ms;
+        self
+    }
 }
 
 impl IntoResponse for GarbleResponse {
     fn into_response(self) -> Response {
-        match self {
-            GarbleResponse::Json(json) => Response::builder()
-                .status(StatusCode::OK)
-                .header(header::CONTENT_TYPE, "application/json")
-                .header("X-Garble-Mode", "fast")
-                .body(Body::from(json))
-                .unwrap(),
-            GarbleResponse::Streaming(streaming) => streaming.into_response(),
+        let mut response = match self.body {
+            GarbleBody::Json { json, pool_hits } => {
+                let mut bytes = self.encoding.encode_document(json);
+                if let Some(coding) = self.coding {
+                    bytes = compression::compress_document(coding, &bytes);
+                }
+                let body = if self.pacing.is_active() {
+                    let whole: ByteStream = Box::pin(futures::stream::once(async { Ok(bytes) }));
+                    Body::from_stream(with_pacing(whole, self.pacing))
+                } else {
+                    Body::from(bytes)
+                };
+
+                let mut response = Response::builder()
+                    .status(StatusCode::OK)
+                    .header(
+                        header::CONTENT_TYPE,
+                        self.encoding.content_type(self.content_type),
+                    )
+                    .header("X-Garble-Mode", "fast")
+                    .header(POOL_HITS_HEADER, pool_hits)
+                    .body(body)
+                    .unwrap();
+                if let Some(coding) = self.coding {
+                    response.headers_mut().insert(
+                        header::CONTENT_ENCODING,
+                        HeaderValue::from_static(coding.name()),
+                    );
+                }
+                response
+            }
+            GarbleBody::Streaming(streaming) => streaming.into_response(),
+        };
+
+        self.decision.apply_headers(response.headers_mut());
+        if compression::is_enabled() {
+            response
+                .headers_mut()
+                .insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+        }
+        response
+    }
+}
+
+/// Build a `shape=records` body: an array of records that all follow one random schema
+fn create_records_body(
+    target_size: usize,
+    count: Option<usize>,
+    options: &ResponseOptions,
+) -> GarbleBody {
+    let mut generator = options.generator();
+    let schema = generator.generate_schema();
+
+    let mut json = String::with_capacity(target_size + 1024);
+    json.push('[');
+    let mut records = 0;
+    let mut misfits = 0;
+    while count.map_or(misfits < MAX_MISFITS, |count| records < count) && !options.out_of_time() {
+        let separator = emit::element_separator(records, &options.emit, 1);
+        let record = emit::to_json_at(&generator.generate_record(&schema), &options.emit, 1);
+        if count.is_none() {
+            // Only take records that fit beside the closing bracket
+            let close = emit::array_close(records + 1, &options.emit, 1);
+            if json.len() + separator.len() + record.len() + close.len() > target_size {
+                misfits += 1;
+                continue;
+            }
+        }
+
+        json.push_str(&separator);
+        json.push_str(&record);
+        records += 1;
+    }
+    let close = emit::array_close(records, &options.emit, 1);
+    if count.is_none() {
+        // Whitespace keeps every element a record while filling the shortfall
+        let shortfall = target_size.saturating_sub(json.len() + close.len());
+        json.push_str(&" ".repeat(shortfall));
+    }
+    json.push_str(&close);
+
+    GarbleBody::Json { json, pool_hits: 0 }
+}
+
+/// The `shape=base64` document carrying `data`, a blob of `size` bytes once decoded
+fn base64_document(size: usize, data: String, options: &ResponseOptions) -> String {
+    let mut document = Map::new();
+    document.insert(
+        "filename".to_string(),
+        Value::String(format!("blob-{}.bin", size)),
+    );
+    document.insert(
+        "content_type".to_string(),
+        Value::String("application/octet-stream".to_string()),
+    );
+    document.insert("size".to_string(), Value::from(size));
+    document.insert("data".to_string(), Value::String(data));
+    emit::to_json(&Value::Object(document), &options.emit)
+}
+
+/// Build a `shape=base64` body: the largest blob whose base64 encoding,
+/// 4 characters for every 3 bytes begun, still fits the document into
+/// `target_size`, with trailing whitespace making up the rest
+fn create_base64_body(target_size: usize, options: &ResponseOptions) -> GarbleBody {
+    // The encoding needs no escaping, so a document is its overhead plus the encoding
+    let overhead = |size| base64_document(size, String::new(), options).len();
+    let encoded = |size: usize| size.div_ceil(3) * 4;
+    let mut size = target_size.saturating_sub(overhead(0)) / 4 * 3;
+    // Longer size digits in the overhead can push the document over by a few bytes
+    while size > 0 && overhead(size) + encoded(size) > target_size {
+        size -= 1;
+    }
+
+    let blob = options.generator().generate_blob(size);
+    let mut json = base64_document(size, BASE64.encode(blob), options);
+    json.push_str(&" ".repeat(target_size.saturating_sub(json.len())));
+    GarbleBody::Json { json, pool_hits: 0 }
+}
+
+/// Write a directly generated payload as exactly `target_size` bytes where
+/// possible: members are dropped while it is too long, and a `padding` member
+/// makes up any shortfall. Payloads that are not objects are written as they are
+pub fn sized_document(mut payload: Value, target_size: usize, options: &ResponseOptions) -> String {
+    let block = options.metadata.direct_block();
+    let Value::Object(map) = &mut payload else {
+        return emit::to_json(&payload, &options.emit);
+    };
+
+    // Length of the document with the block and an empty padding member; the
+    // padding member stays, so each member removed takes its comma with it
+    let mut document = map.clone();
+    if let Some(block) = &block {
+        document.insert("metadata".to_string(), block.clone());
+    }
+    document.insert(PADDING_KEY.to_string(), Value::String(String::new()));
+    let mut length = emit::to_json(&Value::Object(document), &options.emit).len();
+    while length > target_size {
+        let Some(last) = map.keys().next_back().cloned() else {
+            break;
+        };
+        let value = map.remove(&last).unwrap_or_default();
+        length -= emit::member_len(&last, &value, &options.emit, 0);
+    }
+
+    if let Some(block) = block {
+        map.insert("metadata".to_string(), block);
+    }
+    if let Some(shortfall) = target_size.checked_sub(length) {
+        map.insert(
+            PADDING_KEY.to_string(),
+            Value::String(metadata::padding(shortfall, options.seed)),
+        );
+    }
+    let mut json = emit::to_json(&payload, &options.emit);
+    // Too small for even an empty padding member; trailing whitespace fills the rest
+    json.push_str(&" ".repeat(target_size.saturating_sub(json.len())));
+    json
+}
+
+/// Fewest bytes a document for `target_size` can be written in with these
+/// options, however little data it carries: its metadata block cannot be left out
+pub fn smallest_body(target_size: usize, options: &ResponseOptions) -> usize {
+    let chunked = |generated_by| {
+        let opening = emit::chunks_open(&options.emit).len();
+        let metadata = ResponseMetadata::new(&options.metadata, generated_by, target_size);
+        opening + metadata.smallest_close(opening, &options.emit)
+    };
+    match ResponseStrategy::choose(target_size, options) {
+        ResponseStrategy::Records => 1 + emit::array_close(0, &options.emit, 1).len(),
+        ResponseStrategy::Base64 => base64_document(0, String::new(), options).len(),
+        ResponseStrategy::Streaming => chunked("streaming"),
+        ResponseStrategy::Fast if target_size >= ChunkSize::Small.target_bytes() => {
+            chunked("chunk_pool")
+        }
+        ResponseStrategy::Direct | ResponseStrategy::Fast => {
+            sized_document(Value::Object(Map::new()), 0, options).len()
         }
     }
 }
 
-/// Create the optimal response for the given target size
-pub fn create_optimal_response(target_size: usize) -> GarbleResponse {
-    match ResponseStrategy::for_size(target_size) {
+/// Create the optimal response for the given target size, failing when
+/// building it in memory takes longer than the generation budget
+pub fn create_optimal_response(
+    target_size: usize,
+    mut options: ResponseOptions,
+) -> Result<GarbleResponse, ApiError> {
+    let content_type = options.content_type();
+    let encoding = options.encoding;
+    let pacing = options.pacing;
+    let coding = options.coding;
+    let prefix = options.body_prefix();
+    let suffix = options.body_suffix();
+
+    let strategy = ResponseStrategy::choose(target_size, &options);
+    let decision = ResponseDecision {
+        strategy: strategy.name(),
+        target_size,
+        wait_ms: 0,
+    };
+    METRICS.record_strategy(strategy.name());
+
+    let started = Instant::now();
+    // Streamed bodies are held to the budget chunk by chunk as they are sent
+    let deadline = budget::deadline().filter(|_| strategy != ResponseStrategy::Streaming);
+    options.deadline = deadline;
+    let body = match (strategy, options.shape) {
+        (ResponseStrategy::Records, ResponseShape::Records { count }) => {
+            create_records_body(target_size, count, &options)
+        }
+        (ResponseStrategy::Base64, _) => create_base64_body(target_size, &options),
+        _ => create_garble_body(target_size, strategy, options),
+    };
+    // Streaming bodies are generated as they are sent and report when they finish
+    if let GarbleBody::Json { json, .. } = &body {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(budget::exceeded(
+                strategy.name(),
+                target_size,
+                json.len(),
+                started.elapsed(),
+            ));
+        }
+        METRICS.record_generation(strategy.name(), json.len(), started.elapsed());
+    }
+
+    // Streaming bodies wrap themselves as they go
+    let body = match body {
+        GarbleBody::Json { json, pool_hits } if !prefix.is_empty() => GarbleBody::Json {
+            json: format!("{}{}{}", prefix, json, suffix),
+            pool_hits,
+        },
+        body => body,
+    };
+
+    Ok(GarbleResponse {
+        body,
+        decision,
+        content_type,
+        encoding,
+        pacing,
+        coding,
+    })
+}
+
+/// Assemble a heterogeneous garble body with the chosen strategy, streaming
+/// anything that is not built in memory
+fn create_garble_body(
+    target_size: usize,
+    strategy: ResponseStrategy,
+    options: ResponseOptions,
+) -> GarbleBody {
+    match strategy {
         ResponseStrategy::Direct => {
-            let mut generator = RandomDataGenerator::new();
+            let mut generator = options.generator();
             let payload = generator.generate_payload(target_size);
-            let json = serde_json::to_string(&payload).unwrap_or_else(|_| "{}".to_string());
-            GarbleResponse::Json(json)
+            // Padding a payload cut short by the deadline would only spend more time
+            let json = if generator.out_of_time() {
+                emit::to_json(&payload, &options.emit)
+            } else {
+                sized_document(payload, target_size, &options)
+            };
+            GarbleBody::Json { json, pool_hits: 0 }
         }
         ResponseStrategy::Fast => {
-            let response = FastGarbleResponse::new(target_size).build();
-            GarbleResponse::Json(response)
+            let segment = options.segment.clone();
+            let (json, pool_hits) = FastGarbleResponse::new(target_size, options).build();
+            if let Some(segment) = &segment {
+                METRICS.record_pool_hits(segment, pool_hits);
+            }
+            GarbleBody::Json { json, pool_hits }
+        }
+        ResponseStrategy::Streaming | ResponseStrategy::Records | ResponseStrategy::Base64 => {
+            GarbleBody::Streaming(Box::new(StreamingGarbleResponse::new(target_size, options)))
+        }
+    }
+}
+
+const DEFAULT_SSE_INTERVAL_MS: u64 = 1000;
+const MAX_SSE_INTERVAL_MS: u64 = 3_600_000;
+
+const DEFAULT_SSE_EVENT_BYTES: usize = 256;
+const MAX_SSE_EVENT_BYTES: usize = 1_000_000;
+
+/// Longest accepted `durationMs` of an event stream
+const MAX_SSE_DURATION_MS: u64 = 86_400_000;
+
+/// Longest accepted `retryMs`
+const MAX_SSE_RETRY_MS: u64 = 3_600_000;
+
+#[derive(Debug, Deserialize)]
+pub struct SseParams {
+    #[serde(rename = "intervalMs")]
+    interval_ms: Option<u64>,
+    #[serde(rename = "eventBytes")]
+    event_bytes: Option<usize>,
+    #[serde(rename = "durationMs")]
+    duration_ms: Option<u64>,
+    #[serde(rename = "eventCount")]
+    event_count: Option<u64>,
+    #[serde(rename = "retryMs")]
+    retry_ms: Option<u64>,
+}
+
+/// Garble events numbered from `first`, one every `interval`, until the id
+/// reaches `event_count`, `duration` passes, the client disconnects or the
+/// instance starts draining
+fn garble_events(
+    first: u64,
+    interval: Duration,
+    event_bytes: usize,
+    event_count: Option<u64>,
+    duration: Option<Duration>,
+    retry: Option<Duration>,
+) -> impl Stream<Item = Result<Event, std::convert::Infallible>> + Send {
+    stream! {
+        let started = Instant::now();
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut client = ClientAbortWatch::new("streaming");
+        let mut id = first;
+        while event_count.is_none_or(|count| id < count) {
+            ticker.tick().await;
+            if DRAIN.is_draining() || duration.is_some_and(|duration| started.elapsed() >= duration) {
+                break;
+            }
+            // Generation is CPU bound, keep it off the async workers
+            let Ok(data) = tokio::task::spawn_blocking(move || {
+                let record = RandomDataGenerator::new().generate_sized_record(event_bytes);
+                serde_json::to_string(&record).unwrap_or_default()
+            })
+            .await
+            else {
+                break;
+            };
+            let mut event = Event::default().id(id.to_string()).event("garble").data(data);
+            if let (Some(retry), true) = (retry, id == first) {
+                event = event.retry(retry);
+            }
+            yield Ok(event);
+            id += 1;
+        }
+        client.finish();
+    }
+}
+
+/// Stream random JSON payloads as server-sent events. A reconnecting client
+/// sending `Last-Event-ID` picks up after that event, and once `eventCount`
+/// events were sent it is told to stop with a `204`
+pub async fn sse_handler(
+    params: Result<Query<SseParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    headers: HeaderMap,
+    tenant: Tenant,
+) -> Result<Response, ApiError> {
+    if DRAIN.is_draining() {
+        return Err(ApiError::draining());
+    }
+
+    let params = strict_query(
+        params,
+        raw_query.as_deref(),
+        tenant.garble.strict_validation,
+    )?;
+    let interval_ms = params.interval_ms.unwrap_or(DEFAULT_SSE_INTERVAL_MS);
+    if interval_ms == 0 || interval_ms > MAX_SSE_INTERVAL_MS {
+        return Err(ApiError::bad_request(format!(
+            "intervalMs must be between 1 and {}, got {}",
+            MAX_SSE_INTERVAL_MS, interval_ms
+        )));
+    }
+    let event_bytes = params.event_bytes.unwrap_or(DEFAULT_SSE_EVENT_BYTES);
+    if event_bytes == 0 || event_bytes > MAX_SSE_EVENT_BYTES {
+        return Err(ApiError::bad_request(format!(
+            "eventBytes must be between 1 and {}, got {}",
+            MAX_SSE_EVENT_BYTES, event_bytes
+        )));
+    }
+    if let Some(duration_ms) = params.duration_ms.filter(|&ms| ms > MAX_SSE_DURATION_MS) {
+        return Err(ApiError::bad_request(format!(
+            "durationMs must be at most {}, got {}",
+            MAX_SSE_DURATION_MS, duration_ms
+        )));
+    }
+    if let Some(retry_ms) = params.retry_ms.filter(|&ms| ms > MAX_SSE_RETRY_MS) {
+        return Err(ApiError::bad_request(format!(
+            "retryMs must be at most {}, got {}",
+            MAX_SSE_RETRY_MS, retry_ms
+        )));
+    }
+
+    // Ids are event numbers, so a reconnect resumes right after the last one seen
+    let first = match headers.get("last-event-id") {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|id| id.trim().parse::<u64>().ok())
+            .and_then(|id| id.checked_add(1))
+            .ok_or_else(|| {
+                ApiError::bad_request("Last-Event-ID must be an event id sent earlier")
+            })?,
+        None => 0,
+    };
+    if params.event_count.is_some_and(|count| first >= count) {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+
+    tracing::info!(
+        "Streaming SSE: tenant={}, first_id={}, interval={}ms, event_bytes={}B",
+        tenant.name,
+        first,
+        interval_ms,
+        event_bytes
+    );
+    METRICS.record_response(&tenant.segment(), "sse", 0, 0);
+
+    let events = garble_events(
+        first,
+        Duration::from_millis(interval_ms),
+        event_bytes,
+        params.event_count,
+        params.duration_ms.map(Duration::from_millis),
+        params.retry_ms.map(Duration::from_millis),
+    );
+    let mut response = Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response();
+    response.headers_mut().insert(
+        header::HeaderName::from_static("x-garble-mode"),
+        HeaderValue::from_static("sse"),
+    );
+    Ok(response)
+}
+
+// Global strategy thresholds, set once at startup
+static THRESHOLDS: OnceCell<StrategyThresholds> = OnceCell::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const SIZES: &[usize] = &[0, 1, 3, 50, 100, 120, 129, 1000, 4096, 70_000, 300_000];
+
+    async fn body_len(target_size: usize, options: ResponseOptions) -> usize {
+        let response = create_optimal_response(target_size, options)
+            .expect("response")
+            .into_response();
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("body")
+            .len()
+    }
+
+    /// Every size a strategy has room for comes out exactly that size
+    async fn assert_exact(options: ResponseOptions) {
+        for &size in SIZES {
+            if fits(size, &options) {
+                assert_eq!(
+                    body_len(size, options.clone()).await,
+                    size,
+                    "{:?} at {} bytes",
+                    options.strategy,
+                    size
+                );
+            }
+        }
+    }
+
+    fn fits(size: usize, options: &ResponseOptions) -> bool {
+        smallest_body(size, options) <= size
+    }
+
+    fn with_strategy(strategy: ResponseStrategy) -> ResponseOptions {
+        ResponseOptions {
+            strategy: Some(strategy),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn direct_bodies_are_the_target_size() {
+        assert_exact(with_strategy(ResponseStrategy::Direct)).await;
+    }
+
+    #[tokio::test]
+    async fn fast_bodies_are_the_target_size() {
+        assert_exact(with_strategy(ResponseStrategy::Fast)).await;
+    }
+
+    #[tokio::test]
+    async fn streaming_bodies_are_the_target_size() {
+        assert_exact(with_strategy(ResponseStrategy::Streaming)).await;
+    }
+
+    #[tokio::test]
+    async fn records_bodies_are_the_target_size() {
+        assert_exact(ResponseOptions {
+            shape: ResponseShape::Records { count: None },
+            ..Default::default()
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn base64_bodies_are_the_target_size() {
+        assert_exact(ResponseOptions {
+            shape: ResponseShape::Base64,
+            ..Default::default()
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn pretty_sorted_bodies_with_metadata_are_the_target_size() {
+        for strategy in [
+            ResponseStrategy::Direct,
+            ResponseStrategy::Fast,
+            ResponseStrategy::Streaming,
+        ] {
+            let mut options = with_strategy(strategy);
+            options.emit = EmitOptions {
+                sorted_keys: true,
+                indent: Some(2),
+            };
+            options.metadata.request_echo = Some(json!({"method": "GET", "path": "/garble"}));
+            assert_exact(options).await;
         }
-        ResponseStrategy::Streaming => {
-            let streaming = StreamingGarbleResponse::new(target_size);
-            GarbleResponse::Streaming(streaming)
+    }
+
+    #[tokio::test]
+    async fn smallest_bodies_are_written_at_that_size() {
+        for strategy in [
+            ResponseStrategy::Direct,
+            ResponseStrategy::Fast,
+            ResponseStrategy::Streaming,
+        ] {
+            let options = with_strategy(strategy);
+            let mut size = 1;
+            while !fits(size, &options) {
+                size = smallest_body(size, &options);
+            }
+            assert_eq!(body_len(size, options).await, size, "{:?}", strategy);
         }
     }
+
+    #[tokio::test]
+    async fn last_event_ids_with_nothing_after_them_are_refused() {
+        let config = crate::config::Config::default();
+        let tenant = Tenant::resolve(&HeaderMap::new(), &config).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "last-event-id",
+            HeaderValue::from_static("18446744073709551615"),
+        );
+        let params = serde_urlencoded::from_str("eventCount=3").unwrap();
+        let refused = sse_handler(Ok(Query(params)), RawQuery(None), headers, tenant)
+            .await
+            .unwrap_err();
+        assert_eq!(refused.into_response().status(), StatusCode::BAD_REQUEST);
+    }
 }
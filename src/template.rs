@@ -0,0 +1,354 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Placeholder expansion for JSON skeletons. A skeleton is compiled once at
+//! startup; string values of the form `$garble(100kb)` are replaced by a
+//! garbled value of about that size every time it is rendered, and
+//! `$path(id)`, `$query(name)`, `$header(name)` and `$body(/json/pointer)`
+//! are replaced by values taken from the request being answered. `$gen(name)`
+//! is replaced by a value from the named custom generator.
+
+use axum::http::{HeaderMap, HeaderName, Uri};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+use crate::generator::RandomDataGenerator;
+use crate::plugins::{self, Plugin};
+
+/// Size used by a bare `$garble` placeholder
+const DEFAULT_GARBLE_BYTES: usize = 1024;
+
+/// Largest size a single placeholder may ask for
+const MAX_GARBLE_BYTES: usize = 100 * 1024 * 1024;
+
+/// Request values placeholders can refer to
+#[derive(Debug, Default)]
+pub struct RequestValues {
+    pub path: BTreeMap<String, String>,
+    pub query: BTreeMap<String, String>,
+    pub headers: HeaderMap,
+    /// The request body, when it is JSON
+    pub body: Option<Value>,
+}
+
+impl RequestValues {
+    pub fn new(path: BTreeMap<String, String>, uri: &Uri, headers: HeaderMap, body: &[u8]) -> Self {
+        Self {
+            path,
+            query: serde_urlencoded::from_str(uri.query().unwrap_or_default()).unwrap_or_default(),
+            headers,
+            body: serde_json::from_slice(body).ok(),
+        }
+    }
+}
+
+/// Where a request placeholder takes its value from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Path(String),
+    Query(String),
+    Header(HeaderName),
+    /// JSON pointer into the request body
+    Body(String),
+}
+
+impl Source {
+    const KINDS: [&'static str; 4] = ["path", "query", "header", "body"];
+
+    fn parse(kind: &str, argument: &str) -> anyhow::Result<Self> {
+        if argument.is_empty() {
+            anyhow::bail!("${}() placeholder needs an argument", kind);
+        }
+        Ok(match kind {
+            "path" => Source::Path(argument.to_string()),
+            "query" => Source::Query(argument.to_string()),
+            "header" => Source::Header(HeaderName::from_bytes(argument.as_bytes())?),
+            _ => {
+                if !argument.starts_with('/') {
+                    anyhow::bail!("JSON pointer {:?} must start with /", argument);
+                }
+                Source::Body(argument.to_string())
+            }
+        })
+    }
+
+    /// The value in the request, or `None` when the request does not have it
+    fn lookup(&self, request: &RequestValues) -> Option<Value> {
+        match self {
+            Source::Path(name) => request.path.get(name).cloned().map(Value::String),
+            Source::Query(name) => request.query.get(name).cloned().map(Value::String),
+            Source::Header(name) => request
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| Value::String(value.to_string())),
+            Source::Body(pointer) => request.body.as_ref()?.pointer(pointer).cloned(),
+        }
+    }
+}
+
+/// Piece of a string with placeholders inside it
+#[derive(Debug, Clone)]
+pub enum Part {
+    Literal(String),
+    Value(Source),
+    Generated(Plugin),
+}
+
+/// A compiled JSON skeleton
+#[derive(Debug, Clone)]
+pub enum Template {
+    /// Copied into every response unchanged
+    Literal(Value),
+    /// Garbled value of about this many bytes
+    Garble(usize),
+    /// A string that is a single request placeholder, keeping the value's JSON type
+    Value(Source),
+    /// A string that is a single `$gen` placeholder, keeping the value's JSON type
+    Generated(Plugin),
+    /// A string mixing text and placeholders
+    Text(Vec<Part>),
+    Array(Vec<Template>),
+    Object(Vec<(String, Template)>),
+}
+
+impl Template {
+    /// Compile a skeleton, rejecting malformed placeholders
+    pub fn compile(skeleton: &Value) -> anyhow::Result<Self> {
+        let template = match skeleton {
+            Value::String(text) if text.starts_with("$garble") => {
+                Template::Garble(parse_placeholder(text)?)
+            }
+            Value::String(text) => {
+                let mut parts = parse_text(text)?;
+                match parts.as_slice() {
+                    [] | [Part::Literal(_)] => Template::Literal(skeleton.clone()),
+                    [Part::Value(_)] | [Part::Generated(_)] => match parts.pop() {
+                        Some(Part::Value(source)) => Template::Value(source),
+                        Some(Part::Generated(plugin)) => Template::Generated(plugin),
+                        _ => unreachable!(),
+                    },
+                    _ => Template::Text(parts),
+                }
+            }
+            Value::Array(items) => Template::Array(
+                items
+                    .iter()
+                    .map(Template::compile)
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            Value::Object(members) => Template::Object(
+                members
+                    .iter()
+                    .map(|(key, value)| Ok((key.clone(), Template::compile(value)?)))
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            literal => Template::Literal(literal.clone()),
+        };
+        Ok(template.collapse())
+    }
+
+    /// Fold subtrees without placeholders into single literals, so rendering copies them wholesale
+    fn collapse(self) -> Self {
+        match self {
+            Template::Array(items) if items.iter().all(Template::is_literal) => Template::Literal(
+                Value::Array(items.into_iter().map(Template::into_literal).collect()),
+            ),
+            Template::Object(members) if members.iter().all(|(_, t)| t.is_literal()) => {
+                Template::Literal(Value::Object(
+                    members
+                        .into_iter()
+                        .map(|(key, t)| (key, t.into_literal()))
+                        .collect(),
+                ))
+            }
+            template => template,
+        }
+    }
+
+    fn is_literal(&self) -> bool {
+        matches!(self, Template::Literal(_))
+    }
+
+    fn into_literal(self) -> Value {
+        match self {
+            Template::Literal(value) => value,
+            _ => Value::Null,
+        }
+    }
+
+    /// Every request placeholder in the template
+    pub fn sources(&self) -> Vec<&Source> {
+        match self {
+            Template::Literal(_) | Template::Garble(_) | Template::Generated(_) => Vec::new(),
+            Template::Value(source) => vec![source],
+            Template::Text(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    Part::Value(source) => Some(source),
+                    Part::Literal(_) | Part::Generated(_) => None,
+                })
+                .collect(),
+            Template::Array(items) => items.iter().flat_map(Template::sources).collect(),
+            Template::Object(members) => members.iter().flat_map(|(_, t)| t.sources()).collect(),
+        }
+    }
+
+    /// Total bytes of garble the template asks for, roughly the size of a rendered response
+    pub fn garble_bytes(&self) -> usize {
+        match self {
+            Template::Literal(_)
+            | Template::Value(_)
+            | Template::Generated(_)
+            | Template::Text(_) => 0,
+            Template::Garble(size) => *size,
+            Template::Array(items) => items.iter().map(Template::garble_bytes).sum(),
+            Template::Object(members) => members.iter().map(|(_, t)| t.garble_bytes()).sum(),
+        }
+    }
+
+    /// Fill in every placeholder. Request values that are missing render as
+    /// `null`, or as nothing when part of a longer string
+    pub fn render(&self, generator: &mut RandomDataGenerator, request: &RequestValues) -> Value {
+        match self {
+            Template::Literal(value) => value.clone(),
+            Template::Garble(size) => generator.generate_payload(*size),
+            Template::Value(source) => source.lookup(request).unwrap_or(Value::Null),
+            Template::Generated(plugin) => generator.generate_plugin(plugin),
+            Template::Text(parts) => Value::String(
+                parts
+                    .iter()
+                    .map(|part| match part {
+                        Part::Literal(text) => text.clone(),
+                        Part::Value(source) => match source.lookup(request) {
+                            Some(Value::String(text)) => text,
+                            Some(value) => value.to_string(),
+                            None => String::new(),
+                        },
+                        Part::Generated(plugin) => match generator.generate_plugin(plugin) {
+                            Value::String(text) => text,
+                            value => value.to_string(),
+                        },
+                    })
+                    .collect(),
+            ),
+            Template::Array(items) => {
+                Value::Array(items.iter().map(|t| t.render(generator, request)).collect())
+            }
+            Template::Object(members) => Value::Object(
+                members
+                    .iter()
+                    .map(|(key, t)| (key.clone(), t.render(generator, request)))
+                    .collect::<Map<_, _>>(),
+            ),
+        }
+    }
+}
+
+/// Split a string into text and placeholders. A `$` that does not start a
+/// known placeholder is kept as text
+fn parse_text(text: &str) -> anyhow::Result<Vec<Part>> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('$') {
+        literal.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let placeholder = match parse_plugin(after)? {
+            Some((plugin, length)) => Some((Part::Generated(plugin), length)),
+            None => parse_source(after)?.map(|(source, length)| (Part::Value(source), length)),
+        };
+        match placeholder {
+            Some((part, length)) => {
+                if !literal.is_empty() {
+                    parts.push(Part::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(part);
+                rest = &after[length..];
+            }
+            None => {
+                literal.push('$');
+                rest = after;
+            }
+        }
+    }
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal));
+    }
+    Ok(parts)
+}
+
+/// Request placeholder at the start of `text`, with the length it takes up
+fn parse_source(text: &str) -> anyhow::Result<Option<(Source, usize)>> {
+    for kind in Source::KINDS {
+        let Some(arguments) = text
+            .strip_prefix(kind)
+            .and_then(|rest| rest.strip_prefix('('))
+        else {
+            continue;
+        };
+        let end = arguments
+            .find(')')
+            .ok_or_else(|| anyhow::anyhow!("unclosed ${}( placeholder", kind))?;
+        let source = Source::parse(kind, &arguments[..end])?;
+        return Ok(Some((source, kind.len() + end + 2)));
+    }
+    Ok(None)
+}
+
+/// `$gen(name)` placeholder at the start of `text`, with the length it takes up
+fn parse_plugin(text: &str) -> anyhow::Result<Option<(Plugin, usize)>> {
+    let Some(arguments) = text.strip_prefix("gen(") else {
+        return Ok(None);
+    };
+    let end = arguments
+        .find(')')
+        .ok_or_else(|| anyhow::anyhow!("unclosed $gen( placeholder"))?;
+    let name = &arguments[..end];
+    let plugin = plugins::get(name).ok_or_else(|| {
+        anyhow::anyhow!(
+            "unknown value generator {:?} in $gen placeholder, expected one of {}",
+            name,
+            plugins::names().join(", ")
+        )
+    })?;
+    Ok(Some((plugin, "gen(".len() + end + 1)))
+}
+
+/// Size of a `$garble` or `$garble(SIZE)` placeholder
+fn parse_placeholder(text: &str) -> anyhow::Result<usize> {
+    let argument = match text.strip_prefix("$garble") {
+        Some("") => return Ok(DEFAULT_GARBLE_BYTES),
+        Some(rest) => rest
+            .strip_prefix('(')
+            .and_then(|rest| rest.strip_suffix(')'))
+            .ok_or_else(|| anyhow::anyhow!("malformed placeholder {:?}", text))?,
+        None => anyhow::bail!("malformed placeholder {:?}", text),
+    };
+    let size = parse_size(argument.trim())
+        .ok_or_else(|| anyhow::anyhow!("invalid size in placeholder {:?}", text))?;
+    if size == 0 || size > MAX_GARBLE_BYTES {
+        anyhow::bail!(
+            "placeholder {:?} must ask for between 1 byte and {}MB",
+            text,
+            MAX_GARBLE_BYTES / (1024 * 1024)
+        );
+    }
+    Ok(size)
+}
+
+/// Parse sizes such as `512`, `512b`, `100kb` or `2mb`, in multiples of 1024
+pub fn parse_size(text: &str) -> Option<usize> {
+    let text = text.to_ascii_lowercase();
+    let digits = text.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier = match &text[digits.len()..] {
+        "" | "b" => 1,
+        "kb" | "k" => 1024,
+        "mb" | "m" => 1024 * 1024,
+        _ => return None,
+    };
+    digits.trim().parse::<usize>().ok()?.checked_mul(multiplier)
+}
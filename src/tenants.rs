@@ -0,0 +1,167 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::{header, request::Parts, HeaderMap, StatusCode},
+};
+use std::sync::Arc;
+
+use crate::config::{Config, GarbleConfig, LiveConfig};
+use crate::error::ApiError;
+use crate::metrics::{Segment, SegmentSlot};
+use crate::rules::RuleProfile;
+
+/// Tenant used when a request carries neither an API key nor a tenant header
+pub const DEFAULT_TENANT: &str = "default";
+
+/// Profile name of the global `garble` section
+pub const DEFAULT_PROFILE: &str = "default";
+
+const API_KEY_HEADER: &str = "x-api-key";
+const TENANT_HEADER: &str = "x-daddle-tenant";
+
+/// The tenant a request belongs to and the garble profile it runs with
+#[derive(Debug, Clone)]
+pub struct Tenant {
+    pub name: String,
+    /// Where `garble` came from: `default`, the tenant's own name, or `rule:<name>`
+    pub profile: String,
+    pub garble: GarbleConfig,
+}
+
+impl Tenant {
+    /// Identify the tenant from an API key (`X-Api-Key` or a bearer token) or
+    /// the `X-Daddle-Tenant` header, falling back to the default profile. The
+    /// key headers are only looked at once a tenant has API keys configured, so
+    /// clients sending their own credentials along are not turned away
+    pub fn resolve(headers: &HeaderMap, config: &Config) -> Result<Self, ApiError> {
+        let keyed = config
+            .tenants
+            .iter()
+            .any(|tenant| !tenant.api_keys.is_empty());
+        if let Some(api_key) = api_key(headers).filter(|_| keyed) {
+            let tenant = config
+                .tenants
+                .iter()
+                .find(|tenant| tenant.api_keys.iter().any(|key| key == api_key))
+                .ok_or_else(|| {
+                    ApiError::new(
+                        StatusCode::UNAUTHORIZED,
+                        "unauthorized",
+                        "Unauthorized",
+                        "API key does not belong to a configured tenant",
+                    )
+                })?;
+            return Ok(Self::from_config(
+                &tenant.name,
+                tenant.garble.as_ref(),
+                config,
+            ));
+        }
+
+        if let Some(name) = headers.get(TENANT_HEADER).and_then(|v| v.to_str().ok()) {
+            let tenant = config
+                .tenants
+                .iter()
+                .find(|tenant| tenant.name == name)
+                .ok_or_else(|| ApiError::bad_request(format!("unknown tenant: {}", name)))?;
+            return Ok(Self::from_config(
+                &tenant.name,
+                tenant.garble.as_ref(),
+                config,
+            ));
+        }
+
+        Ok(Self::from_config(DEFAULT_TENANT, None, config))
+    }
+
+    fn from_config(name: &str, garble: Option<&GarbleConfig>, config: &Config) -> Self {
+        Self {
+            name: name.to_string(),
+            profile: match garble {
+                Some(_) => name.to_string(),
+                None => DEFAULT_PROFILE.to_string(),
+            },
+            garble: garble.unwrap_or(&config.garble).clone(),
+        }
+    }
+
+    /// The stats segment this tenant's requests are counted under
+    pub fn segment(&self) -> Segment {
+        Segment {
+            tenant: self.name.clone(),
+            profile: self.profile.clone(),
+        }
+    }
+}
+
+fn api_key(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get(API_KEY_HEADER).and_then(|v| v.to_str().ok()) {
+        return Some(key);
+    }
+
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Tenant
+where
+    Arc<LiveConfig>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let config = Arc::<LiveConfig>::from_ref(state).get();
+        let mut tenant = Tenant::resolve(&parts.headers, &config)?;
+        if let Some(RuleProfile { rule, garble }) = parts.extensions.get() {
+            tenant.profile = format!("rule:{}", rule);
+            tenant.garble = garble.clone();
+        }
+        if let Some(slot) = parts.extensions.get::<SegmentSlot>() {
+            slot.fill(tenant.segment());
+        }
+        Ok(tenant)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TenantConfig;
+    use axum::http::HeaderValue;
+    use axum::response::IntoResponse;
+
+    fn bearer(token: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static(token));
+        headers
+    }
+
+    #[test]
+    fn bearer_tokens_are_ignored_without_keyed_tenants() {
+        let config = Config::default();
+        let tenant = Tenant::resolve(&bearer("Bearer production-token"), &config).unwrap();
+        assert_eq!(tenant.name, DEFAULT_TENANT);
+    }
+
+    #[test]
+    fn unknown_keys_are_refused_once_tenants_have_keys() {
+        let mut config = Config::default();
+        config.tenants.push(TenantConfig {
+            name: "team-a".to_string(),
+            api_keys: vec!["team-a-key".to_string()],
+            garble: None,
+        });
+        let tenant = Tenant::resolve(&bearer("Bearer team-a-key"), &config).unwrap();
+        assert_eq!(tenant.name, "team-a");
+        let refused = Tenant::resolve(&bearer("Bearer production-token"), &config).unwrap_err();
+        assert_eq!(refused.into_response().status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,190 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Requests tagged with the test run they belong to, by an `X-Test-Run-Id`
+//! header or a `runId` query parameter, so overlapping test runs against one
+//! instance can be told apart. A tagged request is logged within a `test_run`
+//! span carrying the id, mirrored with it, and counted in stats of its own
+//! that `/stats?runId=...` reports.
+
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::Instrument;
+
+use crate::error::ApiError;
+use crate::metrics::BucketStats;
+
+/// Header naming the test run a request belongs to; echoed on the response
+pub const RUN_ID_HEADER: HeaderName = HeaderName::from_static("x-test-run-id");
+
+/// Query parameter naming the test run, for clients that cannot set headers
+const RUN_ID_PARAM: &str = "runId";
+
+/// Longest accepted run id
+const MAX_RUN_ID_LENGTH: usize = 128;
+
+/// Runs counted at once; a new run beyond this pushes out the one seen least recently
+const MAX_TRACKED_RUNS: usize = 1_000;
+
+tokio::task_local! {
+    /// Run of the request being served, for counters recorded deep inside its handler
+    static CURRENT_RUN: Arc<str>;
+}
+
+/// The test run a request was tagged with, left in its extensions
+#[derive(Debug, Clone)]
+pub struct TestRun(pub Arc<str>);
+
+/// What a test run's requests added up to
+#[derive(Debug, Clone, Serialize)]
+pub struct RunStats {
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    /// Every request tagged with the run
+    pub requests: u64,
+    /// Responses by status class, such as `2xx`
+    pub statuses: BTreeMap<String, u64>,
+    /// Generated responses, as counted per tenant
+    pub responses: BucketStats,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TestRunStats {
+    /// Runs counted now
+    pub tracked: usize,
+    /// Runs pushed out to make room for newer ones
+    pub evicted: u64,
+}
+
+struct TestRuns {
+    runs: Mutex<HashMap<Arc<str>, RunStats>>,
+    evicted: AtomicU64,
+}
+
+impl TestRuns {
+    fn update(&self, run: &Arc<str>, update: impl FnOnce(&mut RunStats)) {
+        let now = Utc::now();
+        let mut runs = self.runs.lock().unwrap_or_else(|e| e.into_inner());
+        if !runs.contains_key(run) && runs.len() >= MAX_TRACKED_RUNS {
+            let stalest = runs
+                .iter()
+                .min_by_key(|(_, stats)| stats.last_seen)
+                .map(|(run, _)| run.clone());
+            if let Some(stalest) = stalest {
+                runs.remove(&stalest);
+                self.evicted.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let stats = runs.entry(run.clone()).or_insert_with(|| RunStats {
+            first_seen: now,
+            last_seen: now,
+            requests: 0,
+            statuses: BTreeMap::new(),
+            responses: BucketStats::default(),
+        });
+        stats.last_seen = now;
+        update(stats);
+    }
+}
+
+/// The run id a request carries, the header winning over the query parameter
+fn run_id(request: &Request) -> Result<Option<String>, ApiError> {
+    let id = match request.headers().get(RUN_ID_HEADER) {
+        Some(value) => Some(
+            value
+                .to_str()
+                .map_err(|_| ApiError::bad_request("X-Test-Run-Id must be visible ASCII"))?
+                .to_string(),
+        ),
+        None => serde_urlencoded::from_str::<Vec<(String, String)>>(
+            request.uri().query().unwrap_or_default(),
+        )
+        .unwrap_or_default()
+        .into_iter()
+        .find(|(name, _)| name == RUN_ID_PARAM)
+        .map(|(_, value)| value),
+    };
+    let Some(id) = id else {
+        return Ok(None);
+    };
+    let valid = !id.is_empty()
+        && id.len() <= MAX_RUN_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'));
+    if !valid {
+        return Err(ApiError::bad_request(format!(
+            "test run id must be 1 to {} letters, digits, '-', '_', '.' or ':', got {:?}",
+            MAX_RUN_ID_LENGTH, id
+        )));
+    }
+    Ok(Some(id))
+}
+
+/// Middleware tagging requests with their test run: logs, mirrored records
+/// and stats of the request carry the run id, and the response echoes it
+pub async fn tag_run(mut request: Request, next: Next) -> Response {
+    let id: Arc<str> = match run_id(&request) {
+        Ok(Some(id)) => id.into(),
+        Ok(None) => return next.run(request).await,
+        Err(e) => return e.into_response(),
+    };
+
+    request.extensions_mut().insert(TestRun(id.clone()));
+    let span = tracing::info_span!("test_run", run_id = %id);
+    let mut response = CURRENT_RUN
+        .scope(id.clone(), next.run(request))
+        .instrument(span)
+        .await;
+
+    let status = format!("{}xx", response.status().as_u16() / 100);
+    RUNS.update(&id, |stats| {
+        stats.requests += 1;
+        *stats.statuses.entry(status).or_default() += 1;
+    });
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(RUN_ID_HEADER, value);
+    }
+    response
+}
+
+/// Apply `update` to the bucket of the run the current request belongs to, if any
+pub fn record(update: impl FnOnce(&mut BucketStats)) {
+    // Streamed bodies finish outside the request, where no run is current
+    if let Ok(run) = CURRENT_RUN.try_with(|run| run.clone()) {
+        RUNS.update(&run, |stats| update(&mut stats.responses));
+    }
+}
+
+/// Everything counted for `run`, if it was seen and not pushed out since
+pub fn run_stats(run: &str) -> Option<RunStats> {
+    RUNS.runs
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(run)
+        .cloned()
+}
+
+pub fn get_stats() -> TestRunStats {
+    TestRunStats {
+        tracked: RUNS.runs.lock().unwrap_or_else(|e| e.into_inner()).len(),
+        evicted: RUNS.evicted.load(Ordering::Relaxed),
+    }
+}
+
+// Global counters per test run
+static RUNS: Lazy<TestRuns> = Lazy::new(|| TestRuns {
+    runs: Mutex::new(HashMap::new()),
+    evicted: AtomicU64::new(0),
+});
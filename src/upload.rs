@@ -0,0 +1,99 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! How `/garble` takes in request bodies, so uploads can be modeled the way
+//! real upstreams treat them: read at a limited speed, and with the wait
+//! running either from the request headers or only once the body is complete.
+//! Reading can also stall partway and then fail, the mirror image of a
+//! truncated response, for testing client write timeouts and proxies that
+//! buffer request bodies.
+
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    http::StatusCode,
+};
+use futures::StreamExt;
+use http_body_util::LengthLimitError;
+use serde::Deserialize;
+use std::error::Error;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::error::ApiError;
+
+/// Largest body buffered for routes and the proxy, matching axum's default limit
+pub const MAX_BUFFERED_BODY: usize = 2 * 1024 * 1024;
+
+/// When the requested wait starts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WaitFrom {
+    /// Once the headers arrived, while the body is still being read
+    #[default]
+    Headers,
+    /// Once the whole body has been read
+    Body,
+}
+
+/// Reading that stops after `after_bytes` and fails once it has stopped for `duration`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stall {
+    pub after_bytes: usize,
+    pub duration: Duration,
+}
+
+/// How fast the body is read, and whether reading gives up partway
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadPacing {
+    pub bytes_per_second: Option<u64>,
+    pub stall: Option<Stall>,
+}
+
+/// Read the body to its end and drop it, taking in at most `bytes_per_second`
+/// so a slow reader pushes back on the client; returns the body's length
+pub async fn consume(body: Body, pacing: ReadPacing) -> Result<usize, ApiError> {
+    let started = Instant::now();
+    let mut read = 0;
+    let mut frames = body.into_data_stream();
+    loop {
+        // Stop reading, so the client's writes back up until the stall fails the request
+        if let Some(stall) = pacing.stall.filter(|stall| read >= stall.after_bytes) {
+            tracing::info!(
+                "Stalling request body after {} bytes for {}ms",
+                read,
+                stall.duration.as_millis()
+            );
+            tokio::time::sleep(stall.duration).await;
+            return Err(ApiError::upload_stalled(read, stall.duration));
+        }
+        let Some(data) = frames.next().await else {
+            break;
+        };
+        let data = data.map_err(|e| {
+            ApiError::bad_request(format!("failed to read the request body: {}", e))
+        })?;
+        read += data.len();
+        if let Some(bytes_per_second) = pacing.bytes_per_second {
+            let due = Duration::from_secs_f64(read as f64 / bytes_per_second as f64);
+            tokio::time::sleep_until(started + due).await;
+        }
+    }
+    Ok(read)
+}
+
+/// Buffer a body that is needed whole, such as for templates or the proxy
+pub async fn buffer(body: Body) -> Result<Bytes, ApiError> {
+    to_bytes(body, MAX_BUFFERED_BODY).await.map_err(|e| {
+        if e.source().is_some_and(|e| e.is::<LengthLimitError>()) {
+            ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload-too-large",
+                "Payload too large",
+                format!("request bodies are limited to {} bytes", MAX_BUFFERED_BODY),
+            )
+        } else {
+            ApiError::bad_request(format!("failed to read the request body: {}", e))
+        }
+    })
+}
@@ -0,0 +1,145 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use axum::extract::{rejection::QueryRejection, Query};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::ApiError;
+
+/// A single problem with a request parameter
+#[derive(Debug, Serialize)]
+pub struct ParamProblem {
+    pub parameter: String,
+    pub value: Option<String>,
+    pub reason: String,
+}
+
+/// Every problem found with a request's parameters, reported as a 400 in strict mode
+#[derive(Debug, Default, Serialize)]
+pub struct ValidationError {
+    pub problems: Vec<ParamProblem>,
+}
+
+impl ValidationError {
+    pub fn push(&mut self, parameter: &str, value: Option<String>, reason: String) {
+        self.problems.push(ParamProblem {
+            parameter: parameter.to_string(),
+            value,
+            reason,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Report each parameter in the raw query string that fails to deserialize
+    /// into `T`, with the reason serde gives for it. Parsing starts over
+    /// without each bad parameter found, so every one of them is listed
+    pub fn from_query<T: DeserializeOwned>(raw_query: Option<&str>) -> Self {
+        let mut error = ValidationError::default();
+        let mut pairs: Vec<(String, String)> =
+            serde_urlencoded::from_str(raw_query.unwrap_or_default()).unwrap_or_default();
+
+        loop {
+            let query = serde_urlencoded::to_string(&pairs).unwrap_or_default();
+            let deserializer =
+                serde_urlencoded::Deserializer::new(form_urlencoded::parse(query.as_bytes()));
+            let Err(e) = serde_path_to_error::deserialize::<_, T>(deserializer) else {
+                break;
+            };
+            let name = e.path().to_string();
+            let Some(position) = pairs.iter().position(|(key, _)| *key == name) else {
+                break;
+            };
+            let (name, value) = pairs.remove(position);
+            error.push(&name, Some(value), e.into_inner().to_string());
+            pairs.retain(|(key, _)| *key != name);
+        }
+
+        if error.is_empty() {
+            error.push(
+                "query",
+                raw_query.map(str::to_string),
+                "malformed query string".to_string(),
+            );
+        }
+
+        error
+    }
+}
+
+/// Unwrap a query extraction, listing each bad parameter in strict mode and
+/// falling back to axum's rejection message otherwise
+pub fn strict_query<T: DeserializeOwned>(
+    result: Result<Query<T>, QueryRejection>,
+    raw_query: Option<&str>,
+    strict: bool,
+) -> Result<T, ApiError> {
+    match result {
+        Ok(Query(params)) => Ok(params),
+        Err(_) if strict => Err(ValidationError::from_query::<T>(raw_query).into()),
+        Err(rejection) => Err(rejection.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::GarbleParams;
+
+    fn problems(query: &str) -> Vec<(String, String)> {
+        ValidationError::from_query::<GarbleParams>(Some(query))
+            .problems
+            .into_iter()
+            .map(|problem| (problem.parameter, problem.reason))
+            .collect()
+    }
+
+    #[test]
+    fn bad_integers_are_named() {
+        let problems = problems("minBodySize=abc&maxBodySize=100");
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].0, "minBodySize");
+        assert_eq!(problems[0].1, "invalid digit found in string");
+    }
+
+    #[test]
+    fn bad_floats_and_shares_are_named() {
+        let problems = problems("zipfExponent=abc&errorRate=half&stringifiedJson=0.5");
+        let names: Vec<&str> = problems.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["zipfExponent", "errorRate"]);
+        assert!(problems
+            .iter()
+            .all(|(_, reason)| reason == "invalid float literal"));
+    }
+
+    #[test]
+    fn bad_flags_and_choices_are_named() {
+        let problems = problems("mislabel=maybe&metadata=everything");
+        let names: Vec<&str> = problems.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["mislabel", "metadata"]);
+    }
+
+    fn strict(query: &str) -> Result<GarbleParams, ApiError> {
+        let uri = format!("/garble?{}", query).parse().unwrap();
+        strict_query(Query::try_from_uri(&uri), Some(query), true)
+    }
+
+    #[test]
+    fn error_rate_shares_are_accepted_in_strict_mode() {
+        assert!(strict("errorRate=0.5").is_ok());
+        let problems = problems("errorRate=0.5&minBodySize=abc");
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].0, "minBodySize");
+    }
+
+    #[test]
+    fn stringified_json_shares_are_accepted_in_strict_mode() {
+        assert!(strict("stringifiedJson=0.5").is_ok());
+        let problems = problems("stringifiedJson=0.5&maxBodySize=abc");
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].0, "maxBodySize");
+    }
+}
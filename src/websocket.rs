@@ -0,0 +1,234 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use axum::{
+    extract::{
+        rejection::QueryRejection,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query, RawQuery,
+    },
+    response::Response,
+};
+use rand::prelude::*;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::drain::DRAIN;
+use crate::error::ApiError;
+use crate::generator::RandomDataGenerator;
+use crate::handlers::{resolve_targets, GarbleParams};
+use crate::metrics::METRICS;
+use crate::tenants::Tenant;
+use crate::validation::strict_query;
+
+/// Largest accepted `sizeMultiplier`
+const MAX_SIZE_MULTIPLIER: f64 = 100.0;
+
+/// Upper bound on the size of a single reply
+const MAX_REPLY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Most `push` messages per second accepted for `rate`
+const MAX_RATE: u64 = 100_000;
+
+/// How far a rate-paced push may fall behind before it stops catching up
+const MAX_RATE_LAG: Duration = Duration::from_secs(1);
+
+/// What the server sends over the socket
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum WsMode {
+    /// Garbled messages sized and paced like `/garble` responses, until the client closes
+    #[default]
+    Push,
+    /// One garbled mutation in reply to every client message
+    Echo,
+}
+
+/// What `push` messages carry
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum WsFormat {
+    /// Garbled JSON in text messages
+    #[default]
+    Json,
+    /// Random bytes in binary messages
+    Binary,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WsParams {
+    mode: Option<WsMode>,
+    #[serde(rename = "sizeMultiplier")]
+    size_multiplier: Option<f64>,
+    format: Option<WsFormat>,
+    /// `push` messages per second, instead of a wait before each one
+    rate: Option<u64>,
+}
+
+pub async fn websocket_handler(
+    upgrade: WebSocketUpgrade,
+    garble_params: Result<Query<GarbleParams>, QueryRejection>,
+    ws_params: Result<Query<WsParams>, QueryRejection>,
+    RawQuery(raw_query): RawQuery,
+    tenant: Tenant,
+) -> Result<Response, ApiError> {
+    if DRAIN.is_draining() {
+        return Err(ApiError::draining());
+    }
+
+    let strict = tenant.garble.strict_validation;
+    let garble_params = strict_query(garble_params, raw_query.as_deref(), strict)?;
+    let ws_params = strict_query(ws_params, raw_query.as_deref(), strict)?;
+    // Validate the size range now, so a bad request fails before the upgrade
+    resolve_targets(&garble_params, &tenant.garble)?;
+
+    let multiplier = ws_params.size_multiplier.unwrap_or(1.0);
+    if !multiplier.is_finite() || multiplier <= 0.0 || multiplier > MAX_SIZE_MULTIPLIER {
+        return Err(ApiError::bad_request(format!(
+            "sizeMultiplier must be greater than 0 and at most {}, got {}",
+            MAX_SIZE_MULTIPLIER, multiplier
+        )));
+    }
+
+    if let Some(rate) = ws_params.rate.filter(|&rate| rate == 0 || rate > MAX_RATE) {
+        return Err(ApiError::bad_request(format!(
+            "rate must be between 1 and {}, got {}",
+            MAX_RATE, rate
+        )));
+    }
+
+    let mode = ws_params.mode.unwrap_or_default();
+    let format = ws_params.format.unwrap_or_default();
+    let rate = ws_params.rate;
+    tracing::info!(
+        "Opening garble WebSocket: tenant={}, mode={:?}, format={:?}, rate={:?}",
+        tenant.name,
+        mode,
+        format,
+        rate
+    );
+
+    Ok(upgrade.on_upgrade(move |socket| async move {
+        match mode {
+            WsMode::Push => push(socket, garble_params, format, rate, tenant).await,
+            WsMode::Echo => echo(socket, multiplier, tenant).await,
+        }
+    }))
+}
+
+/// Send garbled payloads, waiting the resolved wait duration before each one,
+/// or at `rate` messages per second when given
+async fn push(
+    mut socket: WebSocket,
+    garble_params: GarbleParams,
+    format: WsFormat,
+    rate: Option<u64>,
+    tenant: Tenant,
+) {
+    let period = rate.map(|rate| Duration::from_secs_f64(1.0 / rate as f64));
+    let mut next = Instant::now();
+    while let Ok((target_size, wait_ms)) = resolve_targets(&garble_params, &tenant.garble) {
+        let (due, wait_ms) = match period {
+            Some(_) => (next, 0),
+            None => (Instant::now() + Duration::from_millis(wait_ms), wait_ms),
+        };
+        tokio::select! {
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+            _ = tokio::time::sleep_until(due) => {}
+        }
+        // Paced sends are scheduled back to back, so slow sends are caught up on
+        if let Some(period) = period {
+            next = next.max(Instant::now() - MAX_RATE_LAG) + period;
+        }
+        if DRAIN.is_draining() {
+            break;
+        }
+
+        let Ok(message) = tokio::task::spawn_blocking(move || match format {
+            WsFormat::Json => {
+                let payload = RandomDataGenerator::new().generate_payload(target_size);
+                Message::Text(serde_json::to_string(&payload).unwrap_or_default())
+            }
+            WsFormat::Binary => {
+                let mut bytes = vec![0; target_size];
+                thread_rng().fill_bytes(&mut bytes);
+                Message::Binary(bytes)
+            }
+        })
+        .await
+        else {
+            break;
+        };
+
+        METRICS.record_response(&tenant.segment(), "websocket", target_size, wait_ms);
+        if socket.send(message).await.is_err() {
+            break;
+        }
+    }
+    let _ = socket.close().await;
+}
+
+/// Answer every client message with a garbled mutation of it
+async fn echo(mut socket: WebSocket, multiplier: f64, tenant: Tenant) {
+    while let Some(Ok(message)) = socket.recv().await {
+        let reply = match message {
+            Message::Text(text) => {
+                let target_size = scaled_size(text.len(), multiplier);
+                Message::Text(mutate_text(&text, target_size))
+            }
+            Message::Binary(bytes) => {
+                let target_size = scaled_size(bytes.len(), multiplier);
+                Message::Binary(mutate_bytes(&bytes, target_size))
+            }
+            Message::Close(_) => break,
+            // Pings are answered by the socket itself
+            Message::Ping(_) | Message::Pong(_) => continue,
+        };
+
+        METRICS.record_response(&tenant.segment(), "websocket-echo", 0, 0);
+        if socket.send(reply).await.is_err() {
+            break;
+        }
+    }
+    let _ = socket.close().await;
+}
+
+fn scaled_size(len: usize, multiplier: f64) -> usize {
+    ((len as f64 * multiplier).round() as usize).clamp(1, MAX_REPLY_BYTES)
+}
+
+/// Mutate JSON structurally, and any other text character by character
+pub fn mutate_text(text: &str, target_size: usize) -> String {
+    let mut generator = RandomDataGenerator::new();
+    if let Ok(value) = serde_json::from_str::<serde_json::Value>(text) {
+        let mutated = generator.mutate(&value, target_size);
+        return serde_json::to_string(&mutated).unwrap_or_default();
+    }
+
+    let mut garbled = generator.garble_characters(text);
+    while garbled.len() < target_size {
+        garbled.push_str(&generator.generate_garbled_string());
+    }
+    let mut end = target_size;
+    while !garbled.is_char_boundary(end) {
+        end -= 1;
+    }
+    garbled.truncate(end);
+    garbled
+}
+
+/// Flip about a quarter of the bytes, then pad with random bytes or truncate
+fn mutate_bytes(bytes: &[u8], target_size: usize) -> Vec<u8> {
+    let mut rng = thread_rng();
+    let mut mutated: Vec<u8> = bytes
+        .iter()
+        .map(|&b| if rng.gen_bool(0.25) { rng.gen() } else { b })
+        .collect();
+    mutated.resize_with(target_size, || rng.gen());
+    mutated
+}